@@ -0,0 +1,235 @@
+//! Batch mode: collecting ROMs from files and directories, running several at once,
+//! and summarizing the outcome of each.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use citrust_core::decrypt::Error;
+use citrust_core::progress::ProgressEvent;
+
+use crate::Cli;
+
+/// Expand `paths` into the ROM files to process, in order.
+///
+/// Files named explicitly are always included. Directories contribute the files whose
+/// extension is in `extensions` (case-insensitive), sorted by name, and with `recursive`
+/// also those of their subdirectories.
+pub fn collect_roms(
+    paths: &[PathBuf],
+    recursive: bool,
+    extensions: &[String],
+) -> io::Result<Vec<PathBuf>> {
+    let extensions: Vec<String> = extensions
+        .iter()
+        .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+        .collect();
+    let mut roms = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            collect_dir(path, recursive, &extensions, &mut roms)?;
+        } else {
+            roms.push(path.clone());
+        }
+    }
+    Ok(roms)
+}
+
+fn collect_dir(
+    dir: &Path,
+    recursive: bool,
+    extensions: &[String],
+    roms: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                collect_dir(&path, recursive, extensions, roms)?;
+            }
+        } else if path
+            .extension()
+            .is_some_and(|ext| extensions.contains(&ext.to_string_lossy().to_ascii_lowercase()))
+        {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `event` means the ROM is being written to.
+pub fn changes_rom(event: &ProgressEvent) -> bool {
+    matches!(
+        event,
+        ProgressEvent::RegionStart { .. }
+            | ProgressEvent::TitleKeyLayer { .. }
+            | ProgressEvent::SettingNoCryptoFlag { .. }
+            | ProgressEvent::RollingBack { .. }
+            | ProgressEvent::Resuming { .. }
+    )
+}
+
+/// The operation selected on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Decrypt,
+    Encrypt,
+    Repair,
+    Rollback,
+}
+
+impl Mode {
+    pub fn of(cli: &Cli) -> Self {
+        if cli.rollback {
+            Mode::Rollback
+        } else if cli.repair {
+            Mode::Repair
+        } else if cli.encrypt.is_some() {
+            Mode::Encrypt
+        } else {
+            Mode::Decrypt
+        }
+    }
+}
+
+/// What happened to one ROM, for the per-file lines and the summary table.
+pub enum Outcome<'a> {
+    Changed(Mode),
+    Unchanged(Mode),
+    Failed(&'a Error),
+}
+
+impl<'a> Outcome<'a> {
+    pub fn of(result: &'a Result<bool, Error>, mode: Mode) -> Self {
+        match result {
+            Ok(true) => Outcome::Changed(mode),
+            Ok(false) => Outcome::Unchanged(mode),
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+}
+
+impl fmt::Display for Outcome<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Changed(Mode::Decrypt) => f.write_str("decrypted"),
+            Outcome::Changed(Mode::Encrypt) => f.write_str("encrypted"),
+            Outcome::Changed(Mode::Repair) => f.write_str("repaired"),
+            Outcome::Changed(Mode::Rollback) => f.write_str("rolled back"),
+            Outcome::Unchanged(Mode::Decrypt) => f.write_str("already decrypted"),
+            Outcome::Unchanged(Mode::Encrypt) => f.write_str("already encrypted"),
+            Outcome::Unchanged(Mode::Repair) => f.write_str("nothing to repair"),
+            Outcome::Unchanged(Mode::Rollback) => f.write_str("nothing to roll back"),
+            Outcome::Failed(e) => write!(f, "failed: {e}"),
+        }
+    }
+}
+
+/// Run `f` on every ROM, up to `jobs` at a time, and return the results in ROM order.
+///
+/// The workers run on the global rayon pool, which also decrypts the regions of each
+/// ROM in parallel, so `--jobs` does not multiply the number of threads.
+pub fn run<F>(roms: &[PathBuf], jobs: usize, f: F) -> Vec<Result<bool, Error>>
+where
+    F: Fn(&Path) -> Result<bool, Error> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<Result<bool, Error>>>> =
+        roms.iter().map(|_| Mutex::new(None)).collect();
+    rayon::scope(|scope| {
+        for _ in 0..jobs.min(roms.len()) {
+            scope.spawn(|_| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(rom) = roms.get(i) else { break };
+                    let result = f(rom);
+                    *results[i].lock().unwrap() = Some(result);
+                }
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|slot| slot.into_inner().unwrap().expect("every ROM is processed"))
+        .collect()
+}
+
+/// Print how many ROMs changed, were left alone or failed, and a table of every ROM.
+/// With `quiet`, only the failures are printed, to stderr.
+///
+/// Returns whether every ROM succeeded.
+pub fn print_summary(
+    roms: &[PathBuf],
+    results: &[Result<bool, Error>],
+    mode: Mode,
+    quiet: bool,
+) -> bool {
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    if quiet {
+        for (rom, result) in roms.iter().zip(results) {
+            if let Err(e) = result {
+                eprintln!("{}: {e}", rom.display());
+            }
+        }
+        return failed == 0;
+    }
+
+    let changed = results.iter().filter(|r| matches!(r, Ok(true))).count();
+    let unchanged = results.len() - changed - failed;
+    println!();
+    println!(
+        "{} ROMs: {changed} {}, {unchanged} {}, {failed} failed",
+        roms.len(),
+        Outcome::Changed(mode),
+        Outcome::Unchanged(mode)
+    );
+    let labels: Vec<String> = results
+        .iter()
+        .map(|r| match r {
+            Err(_) => "failed".to_string(),
+            Ok(_) => Outcome::of(r, mode).to_string(),
+        })
+        .collect();
+    let width = labels.iter().map(String::len).max().unwrap_or(0);
+    for ((rom, result), label) in roms.iter().zip(results).zip(&labels) {
+        match result {
+            Err(e) => println!("  {label:<width$}  {}  ({e})", rom.display()),
+            Ok(_) => println!("  {label:<width$}  {}", rom.display()),
+        }
+    }
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_roms_filters_and_recurses() {
+        let dir = PathBuf::from("test-fixtures").join("batch");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.3ds", "a.CIA", "notes.txt", "sub/c.cxi"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let explicit = dir.join("notes.txt");
+        let exts = vec!["3ds".to_string(), ".cia".to_string(), "cxi".to_string()];
+
+        let roms = collect_roms(&[dir.clone(), explicit.clone()], false, &exts).unwrap();
+        assert_eq!(roms, [dir.join("a.CIA"), dir.join("b.3ds"), explicit]);
+
+        let roms = collect_roms(std::slice::from_ref(&dir), true, &exts).unwrap();
+        assert_eq!(
+            roms,
+            [dir.join("a.CIA"), dir.join("b.3ds"), dir.join("sub/c.cxi")]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,33 +1,272 @@
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fs::File;
+use std::io::BufReader;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::process;
 
+use citrust_core::exefs;
+use citrust_core::inspect::{self, RomInfo};
 use citrust_core::keydb::KeyDatabase;
+use citrust_core::keys::CryptoMethod;
+use citrust_core::progress::ProgressEvent;
+use citrust_core::repair;
+use citrust_core::romfs::RomFs;
+use citrust_core::romfs_builder;
+use citrust_core::seeddb::SeedDatabase;
+use citrust_core::verify;
+
+mod batch;
+mod progress;
+
+use progress::Reporter;
 
 #[derive(Parser)]
-#[command(name = "citrust", about = "3DS ROM decryption tool")]
+#[command(
+    name = "citrust",
+    about = "3DS ROM decryption tool",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    /// Path to the .3ds ROM file
-    rom: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// ROM files (.3ds, .cci, .cia, .cxi, .cfa or .app), or directories holding them
+    #[arg(required = true, value_name = "ROM")]
+    roms: Vec<PathBuf>,
+
+    /// Also search subdirectories of the given directories
+    #[arg(short = 'r', long = "recursive")]
+    recursive: bool,
+
+    /// Extensions of the files to pick up from directories (comma-separated)
+    #[arg(
+        long = "ext",
+        value_name = "EXT",
+        value_delimiter = ',',
+        default_value = "3ds,cci,cia,cxi,cfa,app"
+    )]
+    extensions: Vec<String>,
+
+    /// Number of ROMs to process at once
+    #[arg(short = 'j', long = "jobs", value_name = "N", default_value = "1")]
+    jobs: NonZeroUsize,
 
     /// Path to aes_keys.txt key file
     #[arg(long = "keys", value_name = "PATH")]
     keys: Option<PathBuf>,
+
+    /// Path to seeddb.bin for titles using seed crypto
+    #[arg(long = "seeddb", value_name = "PATH")]
+    seeddb: Option<PathBuf>,
+
+    /// Seed (32 hex characters) for a single seed-crypto title
+    #[arg(long = "seed", value_name = "HEX")]
+    seed: Option<String>,
+
+    /// Write the decrypted ROM to PATH instead of decrypting in-place
+    #[arg(short = 'o', long = "output", value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Re-encrypt a decrypted ROM in-place with the given crypto method (partition 0 gets
+    /// back the flags backed up in the NCSD header, if any)
+    #[arg(long = "encrypt", value_name = "METHOD", conflicts_with = "output")]
+    encrypt: Option<Method>,
+
+    /// Undo an interrupted in-place operation, restoring the original ROM
+    #[arg(long = "rollback", conflicts_with_all = ["output", "encrypt", "decompress_code"])]
+    rollback: bool,
+
+    /// Decrypt only the regions of a partly decrypted ROM that are still encrypted
+    #[arg(long = "repair", conflicts_with_all = ["output", "encrypt", "rollback"])]
+    repair: bool,
+
+    /// Also write the decompressed ExeFS .code next to the decrypted ROM
+    #[arg(long = "decompress-code", conflicts_with = "encrypt")]
+    decompress_code: bool,
+
+    /// Print nothing but errors
+    #[arg(short = 'q', long = "quiet")]
+    quiet: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Extract ExeFS files (.code, icon, banner, logo) from a decrypted ROM
+    ExtractExefs {
+        /// Path to the decrypted ROM file
+        rom: PathBuf,
+
+        /// Directory to extract into (defaults to <ROM name>_exefs next to the ROM)
+        #[arg(short = 'o', long = "output", value_name = "DIR")]
+        output: Option<PathBuf>,
+
+        /// Extract only the named file (e.g. .code or icon)
+        #[arg(long = "file", value_name = "NAME")]
+        file: Option<String>,
+    },
+
+    /// Extract (or list) the RomFS file tree of a decrypted ROM
+    ExtractRomfs {
+        /// Path to the decrypted ROM file
+        rom: PathBuf,
+
+        /// Directory to extract into (defaults to <ROM name>_romfs next to the ROM)
+        #[arg(short = 'o', long = "output", value_name = "DIR")]
+        output: Option<PathBuf>,
+
+        /// Only list the files and their sizes
+        #[arg(long = "list")]
+        list: bool,
+    },
+
+    /// Check the content hashes of a decrypted ROM
+    Verify {
+        /// Path to the decrypted ROM file
+        rom: PathBuf,
+    },
+
+    /// Show the title, partitions and crypto state of a ROM without modifying it
+    Info {
+        /// Path to the ROM file
+        rom: PathBuf,
+
+        /// Print the report as JSON
+        #[arg(long = "json")]
+        json: bool,
+    },
+
+    /// Replace the RomFS of a decrypted ROM with one built from a directory
+    RebuildRomfs {
+        /// Path to the decrypted ROM file
+        rom: PathBuf,
+
+        /// Directory holding the new RomFS contents
+        dir: PathBuf,
+
+        /// Output ROM path (defaults to <ROM name>_patched next to the ROM)
+        #[arg(short = 'o', long = "output", value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+/// NCCH crypto methods selectable with `--encrypt`.
+#[derive(Clone, Copy, ValueEnum)]
+enum Method {
+    Original,
+    Key7x,
+    Key93,
+    Key96,
+}
+
+impl From<Method> for CryptoMethod {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Original => CryptoMethod::Original,
+            Method::Key7x => CryptoMethod::Key7x,
+            Method::Key93 => CryptoMethod::Key93,
+            Method::Key96 => CryptoMethod::Key96,
+        }
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    println!("{}", cli.rom.display());
+    match cli.command {
+        Some(Command::ExtractExefs {
+            ref rom,
+            ref output,
+            ref file,
+        }) => extract_exefs(rom, output.as_deref(), file.as_deref()),
+        Some(Command::ExtractRomfs {
+            ref rom,
+            ref output,
+            list,
+        }) => extract_romfs(rom, output.as_deref(), list),
+        Some(Command::Verify { ref rom }) => verify_rom(rom),
+        Some(Command::Info { ref rom, json }) => show_info(rom, json),
+        Some(Command::RebuildRomfs {
+            ref rom,
+            ref dir,
+            ref output,
+        }) => rebuild_romfs(rom, dir, output.as_deref()),
+        None => crypt_roms(&cli),
+    }
+}
 
-    let keydb = if let Some(ref keys_path) = cli.keys {
+/// Decrypt (or re-encrypt) the ROMs given on the command line.
+///
+/// A single ROM file is processed with full progress output. Several files, or any
+/// directory, switch to batch mode: keys are loaded once, up to `--jobs` ROMs are
+/// processed at a time, and a summary table is printed at the end.
+fn crypt_roms(cli: &Cli) {
+    let roms = match batch::collect_roms(&cli.roms, cli.recursive, &cli.extensions) {
+        Ok(roms) => roms,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+    };
+    let single = roms.len() == 1 && !cli.roms[0].is_dir();
+    if roms.is_empty() {
+        eprintln!("Error: no ROM files found");
+        process::exit(1);
+    }
+    if cli.output.is_some() && !single {
+        eprintln!("Error: --output needs exactly one ROM file");
+        process::exit(1);
+    }
+
+    let keydb = (!cli.rollback).then(|| load_keys(cli, &Reporter::new(cli.quiet)));
+    let keydb = keydb.as_ref();
+
+    if single {
+        let mut reporter = Reporter::new(cli.quiet);
+        let result = crypt_rom(&roms[0], cli, keydb, &mut reporter);
+        reporter.finish();
+        if let Err(e) = result {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    let jobs = cli.jobs.get();
+    let results = batch::run(&roms, jobs, |rom| {
+        if jobs == 1 {
+            let mut reporter = Reporter::new(cli.quiet);
+            let result = crypt_rom(rom, cli, keydb, &mut reporter);
+            reporter.finish();
+            result
+        } else {
+            // Bars and log lines of concurrent ROMs would interleave; report each once done
+            let result = crypt_rom(rom, cli, keydb, &mut Reporter::new(true));
+            if !cli.quiet {
+                let outcome = batch::Outcome::of(&result, batch::Mode::of(cli));
+                println!("{}: {outcome}", rom.display());
+            }
+            result
+        }
+    });
+
+    if !batch::print_summary(&roms, &results, batch::Mode::of(cli), cli.quiet) {
+        process::exit(1);
+    }
+}
+
+/// Load the key file and seed database named on the command line, or found in the
+/// default locations. Exits if there is no usable key file.
+fn load_keys(cli: &Cli, reporter: &Reporter) -> KeyDatabase {
+    let mut keydb = if let Some(ref keys_path) = cli.keys {
         match KeyDatabase::from_file(keys_path) {
             Ok(db) => {
-                println!(
+                reporter.line(format_args!(
                     "Loaded key file: {} ({} keys)",
                     keys_path.display(),
                     db.len()
-                );
+                ));
                 db
             }
             Err(e) => {
@@ -38,11 +277,11 @@ fn main() {
     } else if let Some(found_path) = KeyDatabase::search_default_locations() {
         match KeyDatabase::from_file(&found_path) {
             Ok(db) => {
-                println!(
+                reporter.line(format_args!(
                     "Found key file: {} ({} keys)",
                     found_path.display(),
                     db.len()
-                );
+                ));
                 db
             }
             Err(e) => {
@@ -68,10 +307,362 @@ fn main() {
         process::exit(1);
     };
 
-    if let Err(e) = citrust_core::decrypt::decrypt_rom(&cli.rom, &keydb, |msg| {
-        println!("{msg}");
-    }) {
+    let mut seeds = if let Some(ref seeddb_path) = cli.seeddb {
+        match SeedDatabase::from_file(seeddb_path) {
+            Ok(db) => {
+                reporter.line(format_args!(
+                    "Loaded seed database: {} ({} seeds)",
+                    seeddb_path.display(),
+                    db.len()
+                ));
+                db
+            }
+            Err(e) => {
+                eprintln!("Error loading seed database: {e}");
+                process::exit(1);
+            }
+        }
+    } else if let Some(found_path) = SeedDatabase::search_default_locations() {
+        match SeedDatabase::from_file(&found_path) {
+            Ok(db) => {
+                reporter.line(format_args!(
+                    "Found seed database: {} ({} seeds)",
+                    found_path.display(),
+                    db.len()
+                ));
+                db
+            }
+            Err(e) => {
+                eprintln!(
+                    "Warning: found seed database at {} but failed to parse: {e}",
+                    found_path.display()
+                );
+                SeedDatabase::new()
+            }
+        }
+    } else {
+        SeedDatabase::new()
+    };
+    if let Some(ref hex) = cli.seed {
+        match SeedDatabase::parse_seed(hex) {
+            Ok(seed) => seeds.set_fallback(seed),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    keydb.set_seeds(seeds);
+    keydb
+}
+
+/// Decrypt, re-encrypt, repair or roll back one ROM as selected on the command line.
+///
+/// Returns whether the ROM was changed; `keydb` is only `None` for `--rollback`.
+fn crypt_rom(
+    rom: &Path,
+    cli: &Cli,
+    keydb: Option<&KeyDatabase>,
+    reporter: &mut Reporter,
+) -> Result<bool, citrust_core::decrypt::Error> {
+    reporter.line(rom.display());
+    if cli.repair
+        && let Some(keydb) = keydb
+    {
+        print_region_status(rom, keydb, reporter)?;
+    }
+
+    let mut changed = false;
+    let on_progress = |event: &ProgressEvent| {
+        changed |= batch::changes_rom(event);
+        reporter.event(event);
+    };
+
+    let Some(keydb) = keydb else {
+        citrust_core::decrypt::rollback_rom(rom, on_progress)?;
+        return Ok(changed);
+    };
+    match (cli.encrypt, &cli.output) {
+        _ if cli.repair => repair::repair_rom(rom, keydb, on_progress)?,
+        (Some(method), _) => {
+            citrust_core::decrypt::encrypt_rom(rom, keydb, method.into(), on_progress)?
+        }
+        (None, Some(output)) => {
+            citrust_core::decrypt::decrypt_rom_to(rom, output, keydb, on_progress)?
+        }
+        (None, None) => citrust_core::decrypt::decrypt_rom(rom, keydb, on_progress)?,
+    }
+
+    if cli.decompress_code {
+        let decrypted = cli.output.as_deref().unwrap_or(rom);
+        write_decompressed_code(decrypted, reporter)?;
+    }
+    Ok(changed)
+}
+
+/// Print the encryption state of every region, before `--repair` decrypts the ones still
+/// encrypted.
+fn print_region_status(
+    rom: &Path,
+    keydb: &KeyDatabase,
+    reporter: &Reporter,
+) -> Result<(), citrust_core::decrypt::Error> {
+    for partition in repair::region_status(rom, keydb)? {
+        let flag = if partition.flagged_decrypted {
+            "flagged decrypted"
+        } else {
+            "flagged encrypted"
+        };
+        reporter.line(format_args!("Partition {} ({flag}):", partition.index));
+        for status in &partition.regions {
+            reporter.line(format_args!(
+                "  {:<22} {}",
+                status.region.to_string(),
+                status.state
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Write the (decompressed) `.code` of a decrypted ROM to `<ROM name>_code.bin`.
+fn write_decompressed_code(
+    rom: &Path,
+    reporter: &Reporter,
+) -> Result<(), citrust_core::decrypt::Error> {
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    let dest = rom.with_file_name(format!("{stem}_code.bin"));
+
+    let mut reader = BufReader::new(File::open(rom)?);
+    let code = citrust_core::blz::read_code(&mut reader)?;
+    std::fs::write(&dest, code)?;
+    reporter.line(format_args!("Wrote decompressed code: {}", dest.display()));
+    Ok(())
+}
+
+/// Extract ExeFS files from a decrypted ROM into a directory.
+fn extract_exefs(rom: &Path, output: Option<&Path>, file: Option<&str>) {
+    let dest = output.map(Path::to_path_buf).unwrap_or_else(|| {
+        let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+        rom.with_file_name(format!("{stem}_exefs"))
+    });
+
+    let result = File::open(rom)
+        .map(BufReader::new)
+        .map_err(citrust_core::decrypt::Error::from)
+        .and_then(|mut reader| {
+            let (exefs, offset) = exefs::locate(&mut reader)?;
+            let written = match file {
+                Some(name) => {
+                    let entry = exefs.find(name).ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("ExeFS has no file named \"{name}\""),
+                        )
+                    })?;
+                    std::fs::create_dir_all(&dest)?;
+                    let path = dest.join(entry.file_name()?);
+                    exefs.extract_file(&mut reader, offset, name, &path)?;
+                    vec![path]
+                }
+                None => exefs.extract_all(&mut reader, offset, &dest)?,
+            };
+            Ok(written)
+        });
+
+    match result {
+        Ok(written) => {
+            for path in written {
+                println!("Extracted {}", path.display());
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Extract or list the RomFS of a decrypted ROM.
+fn extract_romfs(rom: &Path, output: Option<&Path>, list: bool) {
+    let dest = output.map(Path::to_path_buf).unwrap_or_else(|| {
+        let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+        rom.with_file_name(format!("{stem}_romfs"))
+    });
+
+    let result = File::open(rom)
+        .map(BufReader::new)
+        .map_err(citrust_core::decrypt::Error::from)
+        .and_then(RomFs::locate)
+        .and_then(|mut romfs| {
+            if list {
+                for file in romfs.list()? {
+                    println!("{:>12}  {}", file.size, file.path);
+                }
+            } else {
+                let count = romfs.extract_all(&dest)?;
+                println!("Extracted {count} files to {}", dest.display());
+            }
+            Ok(())
+        });
+
+    if let Err(e) = result {
         eprintln!("Error: {e}");
         process::exit(1);
     }
 }
+
+/// Check every hashed region of a decrypted ROM; exits nonzero on any failure.
+fn verify_rom(rom: &Path) {
+    let report = match File::open(rom)
+        .map(BufReader::new)
+        .map_err(citrust_core::decrypt::Error::from)
+        .and_then(|mut reader| verify::verify(&mut reader))
+    {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+    };
+
+    for partition in &report.partitions {
+        println!("Partition {}:", partition.index);
+        if partition.encrypted {
+            println!("  encrypted, not checked (decrypt the ROM first)");
+        }
+        for check in &partition.checks {
+            println!("  {:<20} {}", check.region.to_string(), check.status);
+        }
+    }
+
+    if report.is_ok() {
+        println!("All hashes verified ✓");
+    } else {
+        eprintln!("Verification failed");
+        process::exit(1);
+    }
+}
+
+/// Print what `inspect` reports about a ROM, as text or JSON.
+fn show_info(rom: &Path, json: bool) {
+    let info = match File::open(rom)
+        .map(BufReader::new)
+        .map_err(citrust_core::decrypt::Error::from)
+        .and_then(|mut reader| inspect::inspect(&mut reader))
+    {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&info) {
+            Ok(text) => println!("{text}"),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                process::exit(1);
+            }
+        }
+    } else {
+        print_info(&info);
+    }
+}
+
+fn print_info(info: &RomInfo) {
+    let unknown = || "unknown".to_string();
+    println!("Format:        {}", info.format);
+    println!("File size:     {} bytes", info.file_size);
+    if let Some(sector_size) = info.sector_size {
+        println!("Sector size:   {sector_size:#X}");
+    }
+    println!(
+        "Title ID:      {}",
+        info.title_id
+            .map_or_else(unknown, |id| format!("{id:016X}"))
+    );
+    println!(
+        "Product code:  {}",
+        info.product_code
+            .clone()
+            .filter(|code| !code.is_empty())
+            .unwrap_or_else(unknown)
+    );
+
+    for part in &info.partitions {
+        println!();
+        println!(
+            "Partition {}: offset {:#X}, {:#X} bytes",
+            part.index, part.offset, part.size
+        );
+        let Some(ncch) = &part.ncch else {
+            if part.title_key_encrypted {
+                println!("  title-key encrypted, NCCH header not readable");
+            } else {
+                println!("  no NCCH header");
+            }
+            continue;
+        };
+        if part.title_key_encrypted {
+            println!("  title-key encrypted");
+        }
+        println!("  Title ID      {:016X}", ncch.title_id);
+        println!("  Product code  {}", ncch.product_code);
+        println!("  Media unit    {:#X}", ncch.media_unit_size);
+        match ncch.crypto_method {
+            Some(method) => println!("  Crypto        {method:?} ({:#04X})", ncch.crypto_flag),
+            None => println!("  Crypto        unknown ({:#04X})", ncch.crypto_flag),
+        }
+        let flags: Vec<&str> = [
+            (ncch.no_crypto, "NoCrypto"),
+            (ncch.fixed_key, "FixedKey"),
+            (ncch.seed, "Seed"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect();
+        println!(
+            "  Flags         {}",
+            if flags.is_empty() {
+                "none".to_string()
+            } else {
+                flags.join(", ")
+            }
+        );
+        println!(
+            "  Content       {}",
+            if ncch.content_encrypted {
+                "encrypted"
+            } else {
+                "decrypted"
+            }
+        );
+    }
+}
+
+/// Write a copy of a decrypted ROM with its RomFS rebuilt from `dir`.
+fn rebuild_romfs(rom: &Path, dir: &Path, output: Option<&Path>) {
+    let output = output.map(Path::to_path_buf).unwrap_or_else(|| {
+        let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match rom.extension() {
+            Some(ext) => format!("{stem}_patched.{}", ext.to_string_lossy()),
+            None => format!("{stem}_patched"),
+        };
+        rom.with_file_name(name)
+    });
+
+    match romfs_builder::rebuild_rom(rom, dir, &output) {
+        Ok(built) => println!(
+            "Packed {} files into a {} byte RomFS: {}",
+            built.file_count,
+            built.size,
+            output.display()
+        ),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+    }
+}
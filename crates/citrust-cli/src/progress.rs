@@ -0,0 +1,147 @@
+//! Terminal output for ROM operations: progress bars on a terminal, plain log
+//! lines otherwise, or nothing but errors with `--quiet`.
+
+use std::fmt;
+use std::io::{self, IsTerminal};
+
+use citrust_core::progress::ProgressEvent;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+const OVERALL_TEMPLATE: &str = "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA {eta})";
+const REGION_TEMPLATE: &str = "{msg:<52} [{bar:30.green/white}] {bytes:>10}/{total_bytes}";
+
+enum Mode {
+    Quiet,
+    Plain,
+    Bars {
+        multi: MultiProgress,
+        /// Bar for the whole image, created on the first byte count.
+        overall: Option<ProgressBar>,
+        /// Bar for the region being processed, and the overall byte count it started at.
+        region: Option<(ProgressBar, u64)>,
+        processed: u64,
+    },
+}
+
+pub struct Reporter {
+    mode: Mode,
+}
+
+impl Reporter {
+    /// Draw bars when stdout is a terminal, print plain lines when it is not.
+    pub fn new(quiet: bool) -> Self {
+        let mode = if quiet {
+            Mode::Quiet
+        } else if io::stdout().is_terminal() {
+            Mode::Bars {
+                multi: MultiProgress::with_draw_target(ProgressDrawTarget::stdout()),
+                overall: None,
+                region: None,
+                processed: 0,
+            }
+        } else {
+            Mode::Plain
+        };
+        Reporter { mode }
+    }
+
+    /// Print an informational line, above the bars if any are drawn.
+    pub fn line(&self, msg: impl fmt::Display) {
+        match &self.mode {
+            Mode::Quiet => {}
+            Mode::Plain => println!("{msg}"),
+            Mode::Bars { multi, .. } => {
+                let _ = multi.println(msg.to_string());
+            }
+        }
+    }
+
+    pub fn event(&mut self, event: &ProgressEvent) {
+        let Mode::Bars {
+            multi,
+            overall,
+            region,
+            processed,
+        } = &mut self.mode
+        else {
+            if let Some(msg) = event.message() {
+                self.line(msg);
+            }
+            return;
+        };
+
+        match event {
+            ProgressEvent::Bytes {
+                processed: done,
+                total,
+            } => {
+                let bar = overall.get_or_insert_with(|| {
+                    let bar = multi.add(ProgressBar::new(*total));
+                    bar.set_style(style(OVERALL_TEMPLATE));
+                    bar
+                });
+                bar.set_length(*total);
+                bar.set_position(*done);
+                *processed = *done;
+                if let Some((bar, start)) = region {
+                    bar.set_position(done.saturating_sub(*start));
+                }
+            }
+            ProgressEvent::RegionStart { bytes, .. }
+            | ProgressEvent::TitleKeyLayer { bytes, .. } => {
+                let bar = ProgressBar::new(*bytes);
+                let bar = match overall {
+                    Some(overall) => multi.insert_before(overall, bar),
+                    None => multi.add(bar),
+                };
+                bar.set_style(style(REGION_TEMPLATE));
+                bar.set_message(event.to_string());
+                if let Some((previous, _)) = region.replace((bar, *processed)) {
+                    previous.finish();
+                }
+            }
+            ProgressEvent::RegionDone { .. } => match region.take() {
+                Some((bar, _)) => bar.finish_with_message(event.to_string()),
+                // Resumed part-way through the region, so no bar was started for it.
+                None => {
+                    let _ = multi.println(event.to_string());
+                }
+            },
+            ProgressEvent::Done => {
+                if let Some((bar, _)) = region.take() {
+                    bar.finish();
+                }
+                if let Some(bar) = overall.take() {
+                    bar.finish();
+                }
+                let _ = multi.println(event.to_string());
+            }
+            event => {
+                if let Some(msg) = event.message() {
+                    let _ = multi.println(msg);
+                }
+            }
+        }
+    }
+
+    /// Stop drawing, leaving unfinished bars where they are (e.g. before an error).
+    pub fn finish(&mut self) {
+        if let Mode::Bars {
+            overall, region, ..
+        } = &mut self.mode
+        {
+            if let Some((bar, _)) = region.take() {
+                bar.abandon();
+            }
+            if let Some(bar) = overall.take() {
+                bar.abandon();
+            }
+        }
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("valid progress template")
+        .progress_chars("=> ")
+}
@@ -1,5 +1,6 @@
 use citrust_core::crypto::{aes_ctr_decrypt, derive_normal_key, rol128};
-use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
 
 fn bench_rol128(c: &mut Criterion) {
     let mut group = c.benchmark_group("rol128");
@@ -34,13 +35,13 @@ fn bench_aes_ctr_decrypt(c: &mut Criterion) {
         0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f,
         0x3c,
     ];
-    let iv = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeFFu128;
+    let iv = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeffu128;
 
     // 1 MB buffer
     let mb = 1024 * 1024;
-    let mut data_1mb = vec![0u8; mb];
+    let data_1mb = vec![0u8; mb];
     group.throughput(Throughput::Bytes(mb as u64));
-    group.bench_with_input(BenchmarkId::new("1MB", mb), &mut data_1mb, |b, data| {
+    group.bench_with_input(BenchmarkId::new("1MB", mb), &data_1mb, |b, data| {
         b.iter(|| {
             let mut buf = data.clone();
             aes_ctr_decrypt(black_box(&key), black_box(iv), black_box(&mut buf));
@@ -49,9 +50,9 @@ fn bench_aes_ctr_decrypt(c: &mut Criterion) {
 
     // 16 MB buffer
     let mb16 = 16 * 1024 * 1024;
-    let mut data_16mb = vec![0u8; mb16];
+    let data_16mb = vec![0u8; mb16];
     group.throughput(Throughput::Bytes(mb16 as u64));
-    group.bench_with_input(BenchmarkId::new("16MB", mb16), &mut data_16mb, |b, data| {
+    group.bench_with_input(BenchmarkId::new("16MB", mb16), &data_16mb, |b, data| {
         b.iter(|| {
             let mut buf = data.clone();
             aes_ctr_decrypt(black_box(&key), black_box(iv), black_box(&mut buf));
@@ -0,0 +1,399 @@
+//! Backwards LZSS ("BLZ") compression used for ExeFS `.code`.
+//!
+//! A compressed file is an uncompressed prefix followed by a stream that is decoded
+//! from the end towards the start, then an 8-byte footer:
+//!
+//! * `u32` — bits 0-23: distance from the start of the stream to the end of the file;
+//!   bits 24-31: length of the footer plus padding
+//! * `u32` — decompressed size minus compressed size
+//!
+//! The format is designed to be decompressed in place, so the compressor keeps the
+//! write cursor from ever overtaking the unread input.
+
+use std::io::{Read, Seek};
+
+use crate::decrypt::Error;
+use crate::exheader::ExHeader;
+use crate::format;
+
+/// Shortest and longest back-reference.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 0xF + MIN_MATCH;
+/// Smallest and largest back-reference distance.
+const MIN_DISTANCE: usize = 3;
+const MAX_DISTANCE: usize = 0xFFF + MIN_DISTANCE;
+/// Candidates examined per position when searching for a match.
+const MAX_CHAIN: usize = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlzError {
+    #[error("compressed data too short ({0} bytes)")]
+    Truncated(usize),
+    #[error("invalid footer: {0}")]
+    InvalidFooter(String),
+    #[error("corrupt compressed stream at offset {0:#x}")]
+    Corrupt(usize),
+    #[error("data too large to compress ({0} bytes)")]
+    TooLarge(usize),
+    #[error("data does not compress ({0} bytes)")]
+    Incompressible(usize),
+}
+
+/// Size of the data once decompressed, read from the footer.
+pub fn decompressed_size(data: &[u8]) -> Result<usize, BlzError> {
+    if data.len() < 8 {
+        return Err(BlzError::Truncated(data.len()));
+    }
+    let extra = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+    Ok(data.len() + extra as usize)
+}
+
+/// Decompress a BLZ-compressed buffer.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, BlzError> {
+    let out_len = decompressed_size(data)?;
+    let len = data.len();
+    let footer = u32::from_le_bytes(data[len - 8..len - 4].try_into().unwrap());
+    let header_len = (footer >> 24) as usize;
+    let enc_len = (footer & 0x00FF_FFFF) as usize;
+    if header_len < 8 || header_len > enc_len || enc_len > len {
+        return Err(BlzError::InvalidFooter(format!(
+            "footer length {header_len:#x}, stream length {enc_len:#x}, file length {len:#x}"
+        )));
+    }
+    // Every 2 bytes of stream decode to at most MAX_MATCH bytes; a larger size is
+    // rejected before it is allocated
+    let max_len = (len - enc_len).saturating_add(enc_len / 2 * MAX_MATCH);
+    if out_len > max_len {
+        return Err(BlzError::InvalidFooter(format!(
+            "decompressed size {out_len:#x} is more than a {enc_len:#x}-byte stream can hold"
+        )));
+    }
+
+    let mut out = vec![0u8; out_len];
+    out[..len].copy_from_slice(data);
+
+    let stop = len - enc_len;
+    let mut index = len - header_len;
+    let mut dst = out_len;
+    while index > stop {
+        index -= 1;
+        let mut control = data[index];
+        for _ in 0..8 {
+            if index <= stop || dst == 0 {
+                break;
+            }
+            if control & 0x80 != 0 {
+                if index < stop + 2 {
+                    return Err(BlzError::Corrupt(index));
+                }
+                index -= 2;
+                let token = u16::from_le_bytes([data[index], data[index + 1]]) as usize;
+                let count = (token >> 12) + MIN_MATCH;
+                let distance = (token & 0xFFF) + MIN_DISTANCE;
+                if dst < count || dst - 1 + distance >= out_len {
+                    return Err(BlzError::Corrupt(index));
+                }
+                for _ in 0..count {
+                    dst -= 1;
+                    out[dst] = out[dst + distance];
+                }
+            } else {
+                index -= 1;
+                dst -= 1;
+                out[dst] = data[index];
+            }
+            control <<= 1;
+        }
+    }
+
+    if dst != stop {
+        return Err(BlzError::Corrupt(index));
+    }
+    Ok(out)
+}
+
+/// One step of the reversed stream.
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { distance: usize, count: usize },
+}
+
+/// Greedy LZ77 over `data` (already reversed), using hash chains on 3-byte prefixes.
+fn tokenize(data: &[u8]) -> Vec<Token> {
+    const HASH_BITS: u32 = 16;
+    let hash = |pos: usize| -> usize {
+        let v = (data[pos] as u32) | (data[pos + 1] as u32) << 8 | (data[pos + 2] as u32) << 16;
+        (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    };
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(pos)];
+            let mut depth = 0;
+            while candidate != usize::MAX && depth < MAX_CHAIN {
+                let distance = pos - candidate;
+                if distance > MAX_DISTANCE {
+                    break;
+                }
+                if distance >= MIN_DISTANCE {
+                    let len = (0..max_len)
+                        .take_while(|&i| data[candidate + i] == data[pos + i])
+                        .count();
+                    if len > best.1 {
+                        best = (distance, len);
+                        if len == max_len {
+                            break;
+                        }
+                    }
+                }
+                candidate = prev[candidate];
+                depth += 1;
+            }
+        }
+
+        let step = if best.1 >= MIN_MATCH {
+            tokens.push(Token::Match {
+                distance: best.0,
+                count: best.1,
+            });
+            best.1
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            1
+        };
+        for p in (pos..pos + step).filter(|p| p + MIN_MATCH <= data.len()) {
+            let h = hash(p);
+            prev[p] = head[h];
+            head[h] = p;
+        }
+        pos += step;
+    }
+    tokens
+}
+
+/// Compress a buffer so that [`decompress`] restores it.
+///
+/// Only the tail that actually shrinks is compressed; the rest is stored as a raw
+/// prefix so the result can be decompressed in place.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, BlzError> {
+    let reversed: Vec<u8> = data.iter().rev().copied().collect();
+    let tokens = tokenize(&reversed);
+
+    // Pick the cut point with the largest saving. Because it is a running maximum,
+    // every earlier point has a smaller saving, so the decoder's write cursor stays at
+    // or above its read cursor.
+    let (mut raw, mut packed) = (0usize, 0usize);
+    let (mut best_tokens, mut best_raw, mut best_packed) = (0, 0, 0);
+    for (i, token) in tokens.iter().enumerate() {
+        if i % 8 == 0 {
+            packed += 1;
+        }
+        match *token {
+            Token::Literal(_) => {
+                raw += 1;
+                packed += 1;
+            }
+            Token::Match { count, .. } => {
+                raw += count;
+                packed += 2;
+            }
+        }
+        if raw as isize - packed as isize >= best_raw as isize - best_packed as isize {
+            (best_tokens, best_raw, best_packed) = (i + 1, raw, packed);
+        }
+    }
+
+    let mut stream = Vec::with_capacity(best_packed);
+    let mut control_at = 0;
+    for (i, token) in tokens[..best_tokens].iter().enumerate() {
+        if i % 8 == 0 {
+            control_at = stream.len();
+            stream.push(0);
+        }
+        match *token {
+            Token::Literal(byte) => stream.push(byte),
+            Token::Match { distance, count } => {
+                stream[control_at] |= 0x80 >> (i % 8);
+                let value = ((count - MIN_MATCH) << 12 | (distance - MIN_DISTANCE)) as u16;
+                stream.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+
+    let prefix = data.len() - best_raw;
+    let padding = (4 - (prefix + stream.len()) % 4) % 4;
+    let header_len = padding + 8;
+    let enc_len = stream.len() + header_len;
+    let out_len = prefix + enc_len;
+    if enc_len > 0x00FF_FFFF {
+        return Err(BlzError::TooLarge(data.len()));
+    }
+    // Incompressible data still needs a footer; it must not make the file larger
+    // than its decompressed size.
+    if out_len > data.len() {
+        return Err(BlzError::Incompressible(data.len()));
+    }
+
+    let mut out = Vec::with_capacity(out_len);
+    out.extend_from_slice(&data[..prefix]);
+    out.extend(stream.iter().rev());
+    out.resize(out.len() + padding, 0xFF);
+    out.extend_from_slice(&(enc_len as u32 | (header_len as u32) << 24).to_le_bytes());
+    out.extend_from_slice(&((data.len() - out_len) as u32).to_le_bytes());
+    Ok(out)
+}
+
+/// Read the main NCCH's ExeFS `.code` from a decrypted ROM, decompressing it when
+/// the ExHeader marks it as compressed.
+pub fn read_code<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let (exefs, exefs_offset) = crate::exefs::locate(reader)?;
+    let code = exefs.read_file(reader, exefs_offset, ".code")?;
+
+    let (_, ncch_offset) = format::main_ncch(reader)?;
+    let exheader = ExHeader::parse(reader, ncch_offset)?;
+    if exheader.sci.is_code_compressed() {
+        Ok(decompress(&code)?)
+    } else {
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Somewhat compressible data: repeated words with a varying counter.
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| match i % 16 {
+                0..=7 => b"MOV R0,"[i % 7],
+                _ => (i / 64) as u8,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for len in [0x40, 0x1000, 0x12345] {
+            let data = sample(len);
+            let packed = compress(&data).unwrap();
+            assert!(packed.len() < data.len(), "no gain for {len:#x} bytes");
+            assert_eq!(decompressed_size(&packed).unwrap(), data.len());
+            assert_eq!(decompress(&packed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_keeps_incompressible_prefix_raw() {
+        // A pseudo-random head followed by a highly repetitive tail
+        let mut state = 0x1234_5678u32;
+        let mut data: Vec<u8> = (0..0x800)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        data.extend(std::iter::repeat_n(0xAA, 0x800));
+
+        let packed = compress(&data).unwrap();
+        assert_eq!(packed[..0x400], data[..0x400]);
+        assert_eq!(decompress(&packed).unwrap(), data);
+    }
+
+    /// Decompressing in place, with the compressed bytes at the start of the output
+    /// buffer, must never overwrite input that has not been read yet.
+    #[test]
+    fn test_in_place_safe() {
+        let data = sample(0x8000);
+        let packed = compress(&data).unwrap();
+        let len = packed.len();
+        let footer = u32::from_le_bytes(packed[len - 8..len - 4].try_into().unwrap());
+        let stop = len - (footer & 0xFF_FFFF) as usize;
+
+        let mut buf = packed.clone();
+        buf.resize(data.len(), 0);
+        let mut index = len - (footer >> 24) as usize;
+        let mut dst = data.len();
+        while index > stop {
+            index -= 1;
+            let mut control = buf[index];
+            for _ in 0..8 {
+                if index <= stop {
+                    break;
+                }
+                if control & 0x80 != 0 {
+                    index -= 2;
+                    let token = u16::from_le_bytes([buf[index], buf[index + 1]]) as usize;
+                    for _ in 0..(token >> 12) + 3 {
+                        dst -= 1;
+                        assert!(dst >= index, "overwrote unread input");
+                        buf[dst] = buf[dst + (token & 0xFFF) + 3];
+                    }
+                } else {
+                    index -= 1;
+                    dst -= 1;
+                    assert!(dst >= index, "overwrote unread input");
+                    buf[dst] = buf[index];
+                }
+                control <<= 1;
+            }
+        }
+        assert_eq!(buf, data);
+    }
+
+    /// `.code` is located through the ExeFS and decompressed when the ExHeader says so.
+    #[test]
+    fn test_read_code_from_ncch() {
+        use std::io::Cursor;
+
+        let code = sample(0x2000);
+        let packed = compress(&code).unwrap();
+        let exefs = 0xC00;
+        let exefs_len = 0x200 + packed.len().next_multiple_of(0x200);
+        let mut ncch = vec![0u8; exefs + exefs_len];
+        ncch[0x100..0x104].copy_from_slice(b"NCCH");
+        ncch[0x18F] = 0x04;
+        ncch[0x1A0..0x1A4].copy_from_slice(&6u32.to_le_bytes());
+        ncch[0x1A4..0x1A8].copy_from_slice(&((exefs_len / 0x200) as u32).to_le_bytes());
+        ncch[0x200 + 0x0D] = 0x01;
+        ncch[exefs..exefs + 5].copy_from_slice(b".code");
+        ncch[exefs + 12..exefs + 16].copy_from_slice(&(packed.len() as u32).to_le_bytes());
+        ncch[exefs + 0x200..exefs + 0x200 + packed.len()].copy_from_slice(&packed);
+
+        assert_eq!(read_code(&mut Cursor::new(&ncch)).unwrap(), code);
+
+        ncch[0x200 + 0x0D] = 0x00;
+        assert_eq!(read_code(&mut Cursor::new(&ncch)).unwrap(), packed);
+    }
+
+    #[test]
+    fn test_rejects_bad_footer() {
+        assert!(matches!(decompress(&[0; 4]), Err(BlzError::Truncated(4))));
+        let mut data = vec![0u8; 16];
+        data[8..12].copy_from_slice(&(0x20u32 | 8 << 24).to_le_bytes());
+        assert!(matches!(decompress(&data), Err(BlzError::InvalidFooter(_))));
+
+        // A footer claiming ~4 GiB of output from a 16-byte stream
+        data[8..12].copy_from_slice(&(0x10u32 | 8 << 24).to_le_bytes());
+        data[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decompress(&data), Err(BlzError::InvalidFooter(_))));
+    }
+
+    #[test]
+    fn test_incompressible_input() {
+        assert!(matches!(
+            compress(&[1, 2, 3]),
+            Err(BlzError::Incompressible(3))
+        ));
+    }
+}
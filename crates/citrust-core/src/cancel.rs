@@ -0,0 +1,28 @@
+//! Cooperative cancellation of long-running ROM operations.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A flag shared between a front-end and a running operation.
+///
+/// Clones share the same flag: hand one to the operation and call [`cancel`] on
+/// another, e.g. from a UI thread. The operation checks it between chunks and stops
+/// with [`Error::Cancelled`](crate::decrypt::Error::Cancelled).
+///
+/// [`cancel`]: CancelToken::cancel
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
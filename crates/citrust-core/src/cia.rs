@@ -0,0 +1,502 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::crypto::{aes_cbc_decrypt, derive_normal_key};
+use crate::decrypt::Error;
+use crate::keydb::KeyDatabase;
+use crate::keys::Key128;
+
+/// Size of the fixed CIA header, including the content index bitmap.
+pub const CIA_HEADER_SIZE: u32 = 0x2020;
+
+/// Every CIA section starts on a 64-byte boundary.
+const SECTION_ALIGN: u64 = 0x40;
+
+/// Offset of the content chunk records within a TMD body.
+const TMD_CHUNK_RECORDS: u64 = 0x9C4;
+
+/// Size of a single TMD content chunk record.
+const TMD_CHUNK_RECORD_SIZE: u64 = 0x30;
+
+fn align(value: u64) -> u64 {
+    value.div_ceil(SECTION_ALIGN) * SECTION_ALIGN
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn read_u16_be<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32_be<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64_be<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Read a fixed-size, NUL-padded ASCII field.
+fn read_name<R: Read>(reader: &mut R, len: usize) -> io::Result<String> {
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&buf[..end]).into_owned())
+}
+
+/// Total size of a signature block (type, signature and padding) for a signature type.
+fn signature_block_size(sig_type: u32) -> io::Result<u64> {
+    let (sig, pad) = match sig_type {
+        0x010000 | 0x010003 => (0x200, 0x3C),
+        0x010001 | 0x010004 => (0x100, 0x3C),
+        0x010002 | 0x010005 => (0x3C, 0x40),
+        _ => return Err(invalid(format!("unknown signature type {sig_type:#x}"))),
+    };
+    Ok(4 + sig + pad)
+}
+
+/// Seek past the signature block at `offset`, returning the offset of the signed body.
+fn skip_signature<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<u64> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let sig_type = read_u32_be(reader)?;
+    let body = offset + signature_block_size(sig_type)?;
+    reader.seek(SeekFrom::Start(body))?;
+    Ok(body)
+}
+
+/// Check whether `data` starts with a CIA header.
+pub fn is_cia(data: &[u8]) -> bool {
+    data.len() >= CIA_HEADER_SIZE as usize
+        && data[0..4] == CIA_HEADER_SIZE.to_le_bytes()
+        && data[4..6] == [0, 0]
+}
+
+#[derive(Debug, Clone)]
+pub struct CiaHeader {
+    pub header_size: u32,
+    pub cia_type: u16,
+    pub version: u16,
+    pub cert_chain_size: u32,
+    pub ticket_size: u32,
+    pub tmd_size: u32,
+    pub meta_size: u32,
+    pub content_size: u64,
+    /// Bitmap of which content indices are present in the content section.
+    pub content_index: Vec<u8>,
+}
+
+impl CiaHeader {
+    pub fn parse<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut buf = [0u8; 0x20];
+        reader.read_exact(&mut buf)?;
+        let le32 = |off: usize| u32::from_le_bytes(buf[off..off + 4].try_into().unwrap());
+
+        let header_size = le32(0x00);
+        if header_size != CIA_HEADER_SIZE {
+            return Err(invalid(format!("unexpected header size {header_size:#x}")));
+        }
+
+        let mut content_index = vec![0u8; 0x2000];
+        reader.read_exact(&mut content_index)?;
+
+        Ok(CiaHeader {
+            header_size,
+            cia_type: u16::from_le_bytes([buf[4], buf[5]]),
+            version: u16::from_le_bytes([buf[6], buf[7]]),
+            cert_chain_size: le32(0x08),
+            ticket_size: le32(0x0C),
+            tmd_size: le32(0x10),
+            meta_size: le32(0x14),
+            content_size: u64::from_le_bytes(buf[0x18..0x20].try_into().unwrap()),
+            content_index,
+        })
+    }
+
+    pub fn cert_chain_offset(&self) -> u64 {
+        align(self.header_size as u64)
+    }
+
+    pub fn ticket_offset(&self) -> u64 {
+        self.cert_chain_offset() + align(self.cert_chain_size as u64)
+    }
+
+    pub fn tmd_offset(&self) -> u64 {
+        self.ticket_offset() + align(self.ticket_size as u64)
+    }
+
+    pub fn content_offset(&self) -> u64 {
+        self.tmd_offset() + align(self.tmd_size as u64)
+    }
+
+    pub fn meta_offset(&self) -> u64 {
+        self.content_offset() + align(self.content_size)
+    }
+
+    /// Whether the content with the given index is present in this CIA.
+    pub fn has_content(&self, index: u16) -> bool {
+        let byte = index as usize >> 3;
+        byte < self.content_index.len() && self.content_index[byte] & (0x80 >> (index & 7)) != 0
+    }
+}
+
+/// A certificate from the CIA certificate chain. Only the identifying fields are kept.
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    pub issuer: String,
+    pub key_type: u32,
+    pub name: String,
+}
+
+impl Certificate {
+    /// Parse the certificate at `offset`, returning it together with its total size.
+    pub fn parse<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<(Self, u64)> {
+        let body = skip_signature(reader, offset)?;
+        let issuer = read_name(reader, 0x40)?;
+        let key_type = read_u32_be(reader)?;
+        let name = read_name(reader, 0x40)?;
+        let key_size = match key_type {
+            0 => 0x200 + 4 + 0x34,
+            1 => 0x100 + 4 + 0x34,
+            2 => 0x3C + 0x3C,
+            _ => return Err(invalid(format!("unknown public key type {key_type}"))),
+        };
+        // issuer + key type + name + expiration, then the public key
+        let size = body - offset + 0x40 + 4 + 0x40 + 4 + key_size;
+        Ok((
+            Certificate {
+                issuer,
+                key_type,
+                name,
+            },
+            size,
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub issuer: String,
+    /// Title key, encrypted with the common key selected by `common_key_index`.
+    pub title_key: [u8; 16],
+    pub ticket_id: u64,
+    pub console_id: u32,
+    pub title_id: u64,
+    pub title_version: u16,
+    pub common_key_index: u8,
+}
+
+impl Ticket {
+    pub fn parse<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<Self> {
+        skip_signature(reader, offset)?;
+        let mut buf = [0u8; 0xB2];
+        reader.read_exact(&mut buf)?;
+
+        let end = buf[..0x40].iter().position(|&b| b == 0).unwrap_or(0x40);
+        Ok(Ticket {
+            issuer: String::from_utf8_lossy(&buf[..end]).into_owned(),
+            title_key: buf[0x7F..0x8F].try_into().unwrap(),
+            ticket_id: u64::from_be_bytes(buf[0x90..0x98].try_into().unwrap()),
+            console_id: u32::from_be_bytes(buf[0x98..0x9C].try_into().unwrap()),
+            title_id: u64::from_be_bytes(buf[0x9C..0xA4].try_into().unwrap()),
+            title_version: u16::from_be_bytes([buf[0xA6], buf[0xA7]]),
+            common_key_index: buf[0xB1],
+        })
+    }
+
+    /// Decrypt the title key with the common key from the key database.
+    ///
+    /// Uses `common{N}N` directly when present, otherwise derives the normal key from
+    /// `slot0x3DKeyX` and `common{N}` (the slot 0x3D KeyY).
+    pub fn decrypt_title_key(&self, keydb: &KeyDatabase) -> Result<Key128, Error> {
+        let common_key = common_key(keydb, self.common_key_index)?.to_be_bytes();
+        let mut title_key = self.title_key;
+        aes_cbc_decrypt(&common_key, (self.title_id as u128) << 64, &mut title_key);
+        Ok(title_key)
+    }
+}
+
+/// Resolve the normal key for common key `idx`.
+fn common_key(keydb: &KeyDatabase, idx: u8) -> Result<u128, Error> {
+    if let Some(normal) = keydb.get_common_n(idx) {
+        return Ok(normal);
+    }
+    let key_y = keydb
+        .get_common(idx)
+        .ok_or_else(|| Error::KeyNotFound(format!("common{idx}")))?;
+    let key_x = keydb
+        .get_key_x(0x3D)
+        .ok_or_else(|| Error::KeyNotFound("slot0x3DKeyX".to_string()))?;
+    let constant = keydb
+        .generator()
+        .ok_or_else(|| Error::KeyNotFound("generator".to_string()))?;
+    Ok(derive_normal_key(key_x, key_y, constant))
+}
+
+/// A content chunk record from the TMD.
+#[derive(Debug, Clone, Copy)]
+pub struct ContentChunk {
+    pub id: u32,
+    pub index: u16,
+    pub content_type: u16,
+    pub size: u64,
+    pub hash: [u8; 32],
+    /// Position of this record within the TMD's chunk record list.
+    pub position: usize,
+}
+
+impl ContentChunk {
+    /// Whether the content is encrypted with the title key (content type bit 0).
+    pub fn is_encrypted(&self) -> bool {
+        self.content_type & 0x0001 != 0
+    }
+
+    /// AES-CBC IV for this content: the content index followed by zeros.
+    pub fn iv(&self) -> u128 {
+        (self.index as u128) << 112
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tmd {
+    pub title_id: u64,
+    pub title_version: u16,
+    pub contents: Vec<ContentChunk>,
+    /// Absolute offset of the body (just past the signature block).
+    body_offset: u64,
+}
+
+impl Tmd {
+    pub fn parse<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<Self> {
+        let body_offset = skip_signature(reader, offset)?;
+
+        reader.seek(SeekFrom::Start(body_offset + 0x4C))?;
+        let title_id = read_u64_be(reader)?;
+        reader.seek(SeekFrom::Start(body_offset + 0x9C))?;
+        let title_version = read_u16_be(reader)?;
+        let content_count = read_u16_be(reader)?;
+
+        reader.seek(SeekFrom::Start(body_offset + TMD_CHUNK_RECORDS))?;
+        let mut contents = Vec::with_capacity(content_count as usize);
+        for position in 0..content_count as usize {
+            let id = read_u32_be(reader)?;
+            let index = read_u16_be(reader)?;
+            let content_type = read_u16_be(reader)?;
+            let size = read_u64_be(reader)?;
+            let mut hash = [0u8; 32];
+            reader.read_exact(&mut hash)?;
+            contents.push(ContentChunk {
+                id,
+                index,
+                content_type,
+                size,
+                hash,
+                position,
+            });
+        }
+
+        Ok(Tmd {
+            title_id,
+            title_version,
+            contents,
+            body_offset,
+        })
+    }
+}
+
+/// A parsed CIA: header, certificate chain, ticket and TMD.
+#[derive(Debug, Clone)]
+pub struct Cia {
+    pub header: CiaHeader,
+    pub certificates: Vec<Certificate>,
+    pub ticket: Ticket,
+    pub tmd: Tmd,
+}
+
+impl Cia {
+    pub fn parse<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+        let header = CiaHeader::parse(reader)?;
+
+        let mut certificates = Vec::new();
+        let chain_start = header.cert_chain_offset();
+        let chain_end = chain_start + header.cert_chain_size as u64;
+        let mut offset = chain_start;
+        while offset < chain_end {
+            let (cert, size) = Certificate::parse(reader, offset)?;
+            certificates.push(cert);
+            offset += size;
+        }
+
+        let ticket = Ticket::parse(reader, header.ticket_offset())?;
+        let tmd = Tmd::parse(reader, header.tmd_offset())?;
+
+        Ok(Cia {
+            header,
+            certificates,
+            ticket,
+            tmd,
+        })
+    }
+
+    /// Contents present in this CIA with their absolute file offsets, in TMD order.
+    pub fn contents(&self) -> Vec<(ContentChunk, u64)> {
+        let mut offset = self.header.content_offset();
+        let mut contents = Vec::new();
+        for chunk in &self.tmd.contents {
+            if self.header.has_content(chunk.index) {
+                contents.push((*chunk, offset));
+                offset = offset.saturating_add(chunk.size);
+            }
+        }
+        contents
+    }
+
+    /// Absolute file offset of the TMD chunk record at `position`.
+    pub fn tmd_chunk_record_offset(&self, position: usize) -> usize {
+        (self.tmd.body_offset + TMD_CHUNK_RECORDS + position as u64 * TMD_CHUNK_RECORD_SIZE)
+            as usize
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::crypto::aes_cbc_encrypt;
+    use std::io::Cursor;
+
+    pub(crate) const TEST_TITLE_ID: u64 = 0x0004000000055D00;
+
+    /// Build a minimal CIA around `content`: one certificate, a ticket whose title key
+    /// is encrypted with `common_key`, and a TMD with a single content record.
+    /// When `encrypt` is set the content is AES-CBC encrypted with `title_key`.
+    pub(crate) fn build_test_cia(
+        content: &[u8],
+        title_key: Key128,
+        common_key: Key128,
+        encrypt: bool,
+    ) -> Vec<u8> {
+        let sig_block = |data: &mut Vec<u8>| {
+            data.extend_from_slice(&0x010004u32.to_be_bytes());
+            data.extend_from_slice(&[0u8; 0x100 + 0x3C]);
+        };
+        let pad64 = |data: &mut Vec<u8>| data.resize(align(data.len() as u64) as usize, 0);
+
+        // Certificate (RSA-2048 signed, RSA-2048 key)
+        let mut cert = Vec::new();
+        sig_block(&mut cert);
+        let mut issuer = [0u8; 0x40];
+        issuer[..4].copy_from_slice(b"Root");
+        cert.extend_from_slice(&issuer);
+        cert.extend_from_slice(&1u32.to_be_bytes());
+        let mut name = [0u8; 0x40];
+        name[..10].copy_from_slice(b"CA00000003");
+        cert.extend_from_slice(&name);
+        cert.extend_from_slice(&[0u8; 4 + 0x100 + 4 + 0x34]);
+
+        // Ticket
+        let mut ticket = Vec::new();
+        sig_block(&mut ticket);
+        let mut body = [0u8; 0x210];
+        let mut enc_key = title_key;
+        aes_cbc_encrypt(&common_key, (TEST_TITLE_ID as u128) << 64, &mut enc_key);
+        body[0x7F..0x8F].copy_from_slice(&enc_key);
+        body[0x9C..0xA4].copy_from_slice(&TEST_TITLE_ID.to_be_bytes());
+        body[0xB1] = 0;
+        ticket.extend_from_slice(&body);
+
+        // TMD with a single content record
+        let mut tmd = Vec::new();
+        sig_block(&mut tmd);
+        let mut body = vec![0u8; TMD_CHUNK_RECORDS as usize + TMD_CHUNK_RECORD_SIZE as usize];
+        body[0x4C..0x54].copy_from_slice(&TEST_TITLE_ID.to_be_bytes());
+        body[0x9E..0xA0].copy_from_slice(&1u16.to_be_bytes());
+        let rec = TMD_CHUNK_RECORDS as usize;
+        body[rec + 6..rec + 8].copy_from_slice(&(encrypt as u16).to_be_bytes());
+        body[rec + 8..rec + 16].copy_from_slice(&(content.len() as u64).to_be_bytes());
+        tmd.extend_from_slice(&body);
+
+        let mut cia = vec![0u8; CIA_HEADER_SIZE as usize];
+        cia[0..4].copy_from_slice(&CIA_HEADER_SIZE.to_le_bytes());
+        cia[0x08..0x0C].copy_from_slice(&(cert.len() as u32).to_le_bytes());
+        cia[0x0C..0x10].copy_from_slice(&(ticket.len() as u32).to_le_bytes());
+        cia[0x10..0x14].copy_from_slice(&(tmd.len() as u32).to_le_bytes());
+        cia[0x18..0x20].copy_from_slice(&(content.len() as u64).to_le_bytes());
+        cia[0x20] = 0x80; // content index 0 present
+
+        for section in [&cert, &ticket, &tmd] {
+            pad64(&mut cia);
+            cia.extend_from_slice(section);
+        }
+        pad64(&mut cia);
+
+        let mut data = content.to_vec();
+        if encrypt {
+            aes_cbc_encrypt(&title_key, 0, &mut data);
+        }
+        cia.extend_from_slice(&data);
+        cia
+    }
+
+    #[test]
+    fn test_parse_cia_sections() {
+        let content = vec![0xABu8; 0x400];
+        let data = build_test_cia(&content, [1u8; 16], [2u8; 16], false);
+        assert!(is_cia(&data));
+
+        let cia = Cia::parse(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(cia.header.header_size, CIA_HEADER_SIZE);
+        assert_eq!(cia.header.cert_chain_offset(), 0x2040);
+        assert_eq!(cia.certificates.len(), 1);
+        assert_eq!(cia.certificates[0].name, "CA00000003");
+        assert_eq!(cia.ticket.title_id, TEST_TITLE_ID);
+        assert_eq!(cia.tmd.title_id, TEST_TITLE_ID);
+        assert_eq!(cia.tmd.contents.len(), 1);
+
+        let contents = cia.contents();
+        assert_eq!(contents.len(), 1);
+        let (chunk, offset) = contents[0];
+        assert!(!chunk.is_encrypted());
+        assert_eq!(chunk.size, 0x400);
+        assert_eq!(offset, cia.header.content_offset());
+        assert_eq!(
+            &data[offset as usize..offset as usize + 0x400],
+            &content[..]
+        );
+    }
+
+    #[test]
+    fn test_decrypt_title_key_with_common_normal_key() {
+        let title_key = [0x5Au8; 16];
+        let common = 0x00112233445566778899AABBCCDDEEFFu128;
+        let data = build_test_cia(&[0u8; 0x200], title_key, common.to_be_bytes(), true);
+        let cia = Cia::parse(&mut Cursor::new(&data)).unwrap();
+
+        let keys_text = format!("common0N={common:032X}\n");
+        let keydb = KeyDatabase::from_reader(Cursor::new(keys_text)).unwrap();
+        assert_eq!(cia.ticket.decrypt_title_key(&keydb).unwrap(), title_key);
+    }
+
+    #[test]
+    fn test_decrypt_title_key_missing_common_key() {
+        let data = build_test_cia(&[0u8; 0x200], [0u8; 16], [0u8; 16], true);
+        let cia = Cia::parse(&mut Cursor::new(&data)).unwrap();
+
+        let keydb = KeyDatabase::from_reader(Cursor::new("")).unwrap();
+        let err = cia.ticket.decrypt_title_key(&keydb).unwrap_err();
+        assert!(matches!(err, Error::KeyNotFound(ref name) if name == "common0"));
+    }
+
+    #[test]
+    fn test_reject_non_cia() {
+        let data = vec![0u8; 0x3000];
+        assert!(!is_cia(&data));
+        assert!(CiaHeader::parse(&mut Cursor::new(&data)).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use aes::Aes128;
-use cipher::{KeyIvInit, StreamCipher};
+use cipher::{BlockDecrypt, BlockEncrypt, KeyInit, KeyIvInit, StreamCipher};
 
 type Aes128Ctr = ctr::Ctr128BE<Aes128>;
 
@@ -33,9 +33,109 @@ pub fn aes_ctr_decrypt(key: &[u8; 16], iv: u128, data: &mut [u8]) {
     cipher.apply_keystream(data);
 }
 
+/// AES-128-CBC decrypt in-place. A trailing partial block is left untouched.
+pub fn aes_cbc_decrypt(key: &[u8; 16], iv: u128, data: &mut [u8]) {
+    let cipher = Aes128::new(key.into());
+    let mut prev = iv.to_be_bytes();
+    for block in data.chunks_exact_mut(16) {
+        let ciphertext: [u8; 16] = (*block).try_into().unwrap();
+        cipher.decrypt_block(block.into());
+        for (b, p) in block.iter_mut().zip(prev) {
+            *b ^= p;
+        }
+        prev = ciphertext;
+    }
+}
+
+/// AES-128-CBC encrypt in-place. A trailing partial block is left untouched.
+pub fn aes_cbc_encrypt(key: &[u8; 16], iv: u128, data: &mut [u8]) {
+    let cipher = Aes128::new(key.into());
+    let mut prev = iv.to_be_bytes();
+    for block in data.chunks_exact_mut(16) {
+        for (b, p) in block.iter_mut().zip(prev) {
+            *b ^= p;
+        }
+        cipher.encrypt_block(block.into());
+        prev = (*block).try_into().unwrap();
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    /// Rotate a big-endian 128-bit value left one bit at a time.
+    fn reference_rol(mut bytes: [u8; 16], shift: u32) -> [u8; 16] {
+        for _ in 0..shift {
+            let top = bytes[0] >> 7;
+            for i in 0..16 {
+                let carry = if i == 15 { top } else { bytes[i + 1] >> 7 };
+                bytes[i] = (bytes[i] << 1) | carry;
+            }
+        }
+        bytes
+    }
+
+    /// Big-endian byte-wise addition, dropping the final carry.
+    fn reference_add(a: [u8; 16], b: [u8; 16]) -> [u8; 16] {
+        let mut out = [0u8; 16];
+        let mut carry = 0u16;
+        for i in (0..16).rev() {
+            let sum = a[i] as u16 + b[i] as u16 + carry;
+            out[i] = sum as u8;
+            carry = sum >> 8;
+        }
+        out
+    }
+
+    /// The key scrambler on byte arrays, sharing no arithmetic with [`derive_normal_key`]:
+    /// `NormalKey = (((KeyX <<< 2) ^ KeyY) + C) <<< 87`.
+    fn reference_normal_key(key_x: [u8; 16], key_y: [u8; 16], constant: [u8; 16]) -> [u8; 16] {
+        let mut x = reference_rol(key_x, 2);
+        for (x, y) in x.iter_mut().zip(key_y) {
+            *x ^= y;
+        }
+        reference_rol(reference_add(x, constant), 87)
+    }
+
+    /// CTR keystream one block at a time, with the counter wrapping at 2^128.
+    fn reference_ctr(key: &[u8; 16], iv: u128, data: &mut [u8]) {
+        let cipher = Aes128::new(key.into());
+        for (i, chunk) in data.chunks_mut(16).enumerate() {
+            let mut block = iv.wrapping_add(i as u128).to_be_bytes();
+            cipher.encrypt_block((&mut block).into());
+            for (b, k) in chunk.iter_mut().zip(block) {
+                *b ^= k;
+            }
+        }
+    }
+
+    /// Any IV, or one a few blocks short of the counter wrapping around.
+    pub(crate) fn iv_strategy() -> impl Strategy<Value = u128> {
+        prop_oneof![any::<u128>(), (0u128..64).prop_map(|d| u128::MAX - d)]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_derive_normal_key_matches_reference(x: u128, y: u128, c: u128) {
+            let expected = reference_normal_key(x.to_be_bytes(), y.to_be_bytes(), c.to_be_bytes());
+            prop_assert_eq!(derive_normal_key(x, y, c).to_be_bytes(), expected);
+        }
+
+        #[test]
+        fn prop_ctr_matches_block_model(
+            key: [u8; 16],
+            iv in iv_strategy(),
+            data in proptest::collection::vec(any::<u8>(), 0..300),
+        ) {
+            let mut actual = data.clone();
+            aes_ctr_decrypt(&key, iv, &mut actual);
+            let mut expected = data;
+            reference_ctr(&key, iv, &mut expected);
+            prop_assert_eq!(actual, expected);
+        }
+    }
 
     #[test]
     fn test_rol128_basic() {
@@ -67,7 +167,7 @@ mod tests {
     fn test_derive_normal_key_known_vector() {
         let key_x = 0x12345678_9ABCDEF0_11111111_22222222u128;
         let key_y = 0xAAAAAAAA_BBBBBBBB_CCCCCCCC_DDDDDDDDu128;
-        let constant = 0x1F_F9E9AA_C5FE0408_024591DC_5D52768Au128;
+        let constant = 0x1FF9E9AA_C5FE0408_024591DC_5D52768Au128;
 
         let rotated_x = rol128(key_x, 2);
         let xored = rotated_x ^ key_y;
@@ -101,7 +201,7 @@ mod tests {
             0xb6, 0xce,
         ];
 
-        let mut data = plaintext.clone();
+        let mut data = plaintext;
         aes_ctr_decrypt(&key, iv, &mut data);
         assert_eq!(data, expected_ciphertext);
 
@@ -109,6 +209,35 @@ mod tests {
         assert_eq!(data, plaintext);
     }
 
+    #[test]
+    fn test_aes_cbc_known_vector() {
+        // NIST SP 800-38A test vector: AES-128-CBC
+        let key: [u8; 16] = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let iv = 0x000102030405060708090a0b0c0d0e0fu128;
+
+        let plaintext = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac,
+            0x45, 0xaf, 0x8e, 0x51,
+        ];
+
+        let expected_ciphertext = [
+            0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46, 0xce, 0xe9, 0x8e, 0x9b, 0x12, 0xe9,
+            0x19, 0x7d, 0x50, 0x86, 0xcb, 0x9b, 0x50, 0x72, 0x19, 0xee, 0x95, 0xdb, 0x11, 0x3a,
+            0x91, 0x76, 0x78, 0xb2,
+        ];
+
+        let mut data = plaintext;
+        aes_cbc_encrypt(&key, iv, &mut data);
+        assert_eq!(data, expected_ciphertext);
+
+        aes_cbc_decrypt(&key, iv, &mut data);
+        assert_eq!(data, plaintext);
+    }
+
     #[test]
     fn test_u128_conversion_roundtrip() {
         let original = 0x12345678_9ABCDEF0_FEDCBA98_76543210u128;
@@ -1,30 +1,99 @@
 use std::fs::File;
 use std::io::{self, Cursor};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use memmap2::MmapMut;
 use rayon::prelude::*;
 
-use crate::crypto::aes_ctr_decrypt;
+use crate::cancel::CancelToken;
+use crate::cia::Cia;
+use crate::crypto::{aes_cbc_decrypt, aes_ctr_decrypt, derive_normal_key};
+use crate::exefs::ExefsHeader;
+use crate::format::RomFormat;
+use crate::journal::{self, Cursor as JournalCursor, Journal, Step};
 use crate::keydb::KeyDatabase;
 use crate::keys::{CryptoMethod, Key128};
-use crate::ncch::NcchHeader;
-use crate::ncsd::NcsdHeader;
+use crate::ncch::{NcchHeader, Region};
+use crate::ncsd::{NcsdHeader, PartitionEntry};
+use crate::progress::{Direction, Operation, ProgressEvent, Section, SkipReason};
+use crate::seeddb;
+use crate::verify::{self, HashStatus};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("not a 3DS ROM (no NCSD, NCCH or CIA header found)")]
+    UnknownFormat,
     #[error("not a 3DS ROM (invalid NCSD magic)")]
     NotNcsd,
     #[error("partition {0}: invalid NCCH header")]
-    InvalidNcch(u8),
+    InvalidNcch(u16),
+    #[error("partition {0} lies outside the file (truncated ROM?)")]
+    PartitionOutOfBounds(u16),
+    #[error("partition {partition}: {region} lies outside the file (truncated or corrupt ROM?)")]
+    RegionOutOfBounds { partition: u16, region: Region },
+    #[error("invalid CIA: {0}")]
+    InvalidCia(String),
     #[error("key not found in database: {0}")]
     KeyNotFound(String),
+    #[error("ROM is encrypted; decrypt it first")]
+    NotDecrypted,
+    #[error("NCCH has no ExeFS")]
+    NoExefs,
+    #[error("NCCH has no RomFS")]
+    NoRomfs,
+    #[error("seed for title {0:016X} not found (load a seeddb.bin or pass the seed)")]
+    SeedNotFound(u64),
+    #[error("seed for title {0:016X} does not match the NCCH seed check")]
+    SeedMismatch(u64),
+    #[error(
+        "partition {partition}: {region} does not match its hash after decryption (wrong key?)"
+    )]
+    KeyMismatch { partition: u16, region: Region },
+    #[error("partition {partition}: cannot tell whether {region} is encrypted (no matching hash)")]
+    RegionUnverified { partition: u16, region: Region },
+    #[error("cancelled")]
+    Cancelled,
+    #[error("journal error: {0}")]
+    Journal(String),
+    #[error("BLZ error: {0}")]
+    Blz(#[from] crate::blz::BlzError),
     #[error("I/O error: {0}")]
     Io(#[from] io::Error),
 }
 
 /// Chunk size for rayon parallel decryption
-const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+pub(crate) const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// `base + units * unit_size`, for offsets and lengths read from a header, or
+/// [`Error::RegionOutOfBounds`] for `region` if the arithmetic overflows.
+pub(crate) fn offset_of(
+    partition: u16,
+    region: Region,
+    base: usize,
+    units: u32,
+    unit_size: usize,
+) -> Result<usize, Error> {
+    usize::try_from(units)
+        .ok()
+        .and_then(|units| units.checked_mul(unit_size))
+        .and_then(|len| base.checked_add(len))
+        .ok_or(Error::RegionOutOfBounds { partition, region })
+}
+
+/// Byte offset of an NCSD partition, checked to leave room for its NCCH header inside a
+/// file of `file_len` bytes. The regions of the partition are checked separately, so a
+/// dump trimmed of trailing padding is still accepted.
+fn partition_offset(
+    p: u16,
+    part: &PartitionEntry,
+    sector_size: u32,
+    file_len: usize,
+) -> Result<usize, Error> {
+    usize::try_from(part.offset_bytes(sector_size))
+        .ok()
+        .filter(|&offset| offset.checked_add(0x200).is_some_and(|end| end <= file_len))
+        .ok_or(Error::PartitionOutOfBounds(p))
+}
 
 /// Check if a byte is valid ASCII (printable 0x20-0x7E or null 0x00).
 fn is_valid_ascii_byte(b: u8) -> bool {
@@ -33,9 +102,10 @@ fn is_valid_ascii_byte(b: u8) -> bool {
 
 /// Detect if a partition's content is already decrypted despite NoCrypto not being set.
 ///
-/// Checks the ExeFS filename table (first 8 bytes of the ExeFS region). Decrypted ExeFS
-/// entries contain valid ASCII names like ".code\0\0\0", "banner\0\0", "icon\0\0\0\0".
-/// Encrypted data will be random bytes that fail this check.
+/// The ExHeader and ExeFS superblock are checked against their hashes in the NCCH
+/// header first, and a recorded hash decides. Only when neither is recorded does this
+/// fall back to a heuristic on the first 8 bytes of the ExeFS filename table, which
+/// holds ASCII names like ".code\0\0\0" once decrypted and random bytes otherwise.
 ///
 /// If there is no ExeFS but an ExHeader exists, falls back to checking if the first 8
 /// bytes of the ExHeader are valid ASCII (decrypted ExHeaders start with the codeset name).
@@ -47,23 +117,54 @@ pub fn is_content_decrypted(
 ) -> bool {
     let ss = sector_size as usize;
 
-    // Primary check: ExeFS filename table
-    if ncch.exefs_length > 0 {
-        let exefs_base = part_offset + ncch.exefs_offset as usize * ss;
-        if exefs_base + 8 <= data.len() {
-            return data[exefs_base..exefs_base + 8]
-                .iter()
-                .all(|&b| is_valid_ascii_byte(b));
+    let hash_matches = |start: usize, len: usize, hash: &[u8; 32]| {
+        start
+            .checked_add(len)
+            .and_then(|end| data.get(start..end))
+            .is_some_and(|region| verify::check_hash(region, hash) == HashStatus::Valid)
+    };
+    // Offsets that overflow are treated like ranges past the end of `data`
+    let units = |base: usize, n: u32| offset_of(0, Region::ExefsHeader, base, n, ss).ok();
+    let exefs_base = units(part_offset, ncch.exefs_offset);
+    let mut hashed = false;
+    if ncch.exheader_length > 0 && ncch.exheader_hash != [0u8; 32] {
+        if hash_matches(
+            part_offset.saturating_add(0x200),
+            ncch.exheader_length as usize,
+            &ncch.exheader_hash,
+        ) {
+            return true;
+        }
+        hashed = true;
+    }
+    if ncch.exefs_length > 0
+        && ncch.exefs_hash_region_size > 0
+        && ncch.exefs_superblock_hash != [0u8; 32]
+    {
+        if let Some(exefs_base) = exefs_base
+            && let Some(len) = units(0, ncch.exefs_hash_region_size)
+            && hash_matches(exefs_base, len, &ncch.exefs_superblock_hash)
+        {
+            return true;
         }
+        hashed = true;
+    }
+    if hashed {
+        return false;
+    }
+
+    // Heuristic: ExeFS filename table
+    if ncch.exefs_length > 0
+        && let Some(names) = exefs_base.and_then(|base| data.get(base..base.checked_add(8)?))
+    {
+        return names.iter().all(|&b| is_valid_ascii_byte(b));
     }
 
     // Fallback: ExHeader (starts 0x200 bytes into the partition)
     if ncch.exheader_length > 0 {
-        let exheader_off = part_offset + ss;
-        if exheader_off + 8 <= data.len() {
-            return data[exheader_off..exheader_off + 8]
-                .iter()
-                .all(|&b| is_valid_ascii_byte(b));
+        let exheader_off = part_offset.saturating_add(ss);
+        if let Some(bytes) = data.get(exheader_off..exheader_off.saturating_add(8)) {
+            return bytes.iter().all(|&b| is_valid_ascii_byte(b));
         }
     }
 
@@ -71,23 +172,56 @@ pub fn is_content_decrypted(
 }
 
 /// Decrypt a slice in-place using parallel AES-CTR.
-fn decrypt_slice(
+///
+/// `cancel` is checked before each chunk. Once it is set the remaining chunks are left
+/// alone and [`Error::Cancelled`] is returned, so the slice may be partly decrypted.
+pub(crate) fn decrypt_slice(
     data: &mut [u8],
     key: &Key128,
-    key_second: Option<&Key128>,
     base_iv: u128,
     chunk_size: usize,
-) {
+    cancel: &CancelToken,
+) -> Result<(), Error> {
     data.par_chunks_mut(chunk_size)
         .enumerate()
-        .for_each(|(i, chunk)| {
+        .try_for_each(|(i, chunk)| {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
             let blocks_before = (i * chunk_size) as u128 / 16;
-            let chunk_iv = base_iv + blocks_before;
+            let chunk_iv = base_iv.wrapping_add(blocks_before);
             aes_ctr_decrypt(key, chunk_iv, chunk);
-            if let Some(k2) = key_second {
-                aes_ctr_decrypt(k2, chunk_iv, chunk);
+            Ok(())
+        })
+}
+
+/// Decrypt an AES-CBC slice in-place, in parallel chunks.
+///
+/// Each chunk's IV is the last ciphertext block of the chunk before it, so all IVs are
+/// captured up front before any chunk is overwritten. `cancel` is checked as in
+/// [`decrypt_slice`].
+pub(crate) fn cbc_decrypt_slice(
+    data: &mut [u8],
+    key: &Key128,
+    iv: u128,
+    chunk_size: usize,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    let ivs: Vec<u128> = std::iter::once(iv)
+        .chain(
+            data.chunks(chunk_size)
+                .map(|chunk| u128::from_be_bytes(chunk[chunk.len() - 16..].try_into().unwrap())),
+        )
+        .collect();
+    data.par_chunks_mut(chunk_size)
+        .zip(ivs.par_iter())
+        .try_for_each(|(chunk, &chunk_iv)| {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
             }
-        });
+            aes_cbc_decrypt(key, chunk_iv, chunk);
+            Ok(())
+        })
 }
 
 /// Resolve KeyX for a given crypto method from the key database.
@@ -117,233 +251,1056 @@ fn resolve_key_x_2c(keydb: &KeyDatabase) -> Result<u128, Error> {
         .ok_or_else(|| Error::KeyNotFound("slot0x2CKeyX".to_string()))
 }
 
+/// Decrypt a ROM in-place.
+///
+/// Progress is recorded in a sidecar journal (see [`journal`]) that is removed once the
+/// ROM has been fully decrypted and flushed. If a previous run was interrupted, its
+/// journal is picked up and the decryption resumes exactly where it stopped; use
+/// [`rollback_rom`] instead to return the ROM to its original encrypted state.
 pub fn decrypt_rom(
     path: &Path,
     keydb: &KeyDatabase,
-    mut on_progress: impl FnMut(&str),
+    on_progress: impl FnMut(&ProgressEvent),
 ) -> Result<(), Error> {
-    on_progress(&format!(
-        "Using external key database ({} keys loaded)",
-        keydb.len()
-    ));
+    decrypt_rom_cancellable(path, keydb, &CancelToken::new(), on_progress)
+}
 
+/// [`decrypt_rom`] that stops when `cancel` is set.
+///
+/// A cancelled decryption is rolled back, returning the ROM to its original state,
+/// before [`Error::Cancelled`] is returned. Should the rollback itself fail, the
+/// journal is left in place and the ROM can be rolled back or finished later.
+pub fn decrypt_rom_cancellable(
+    path: &Path,
+    keydb: &KeyDatabase,
+    cancel: &CancelToken,
+    mut on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    on_progress(&ProgressEvent::KeysLoaded { count: keydb.len() });
+
+    run_in_place(
+        path,
+        Operation::Decrypt,
+        |data| plan_image(data, keydb),
+        cancel,
+        &mut on_progress,
+    )?;
+    on_progress(&ProgressEvent::Done);
+    Ok(())
+}
+
+/// Apply the steps `plan` makes for the ROM at `path` in-place, journaled so that an
+/// interruption can be resumed or rolled back. A pending journal of the same
+/// `operation` is resumed instead of making a new plan; one of another operation is
+/// refused with [`Error::Journal`]. If `cancel` is set, everything done so far is
+/// rolled back.
+pub(crate) fn run_in_place(
+    path: &Path,
+    operation: Operation,
+    plan: impl FnOnce(&[u8]) -> Result<Vec<Step>, Error>,
+    cancel: &CancelToken,
+    on_progress: &mut impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
     let file = File::options().read(true).write(true).open(path)?;
     // SAFETY: we are the sole accessor of this file during decryption
     let mut mmap = unsafe { MmapMut::map_mut(&file)? };
 
-    // Parse NCSD header from the mapped memory
-    let ncsd = {
-        let mut cursor = Cursor::new(&mmap[..]);
-        NcsdHeader::parse(&mut cursor).map_err(|_| Error::NotNcsd)?
+    let journal_path = journal::journal_path(path);
+    if let Some(pending) = journal::pending_operation(&journal_path, mmap.len())?
+        && pending != operation
+    {
+        return Err(Error::Journal(format!(
+            "{} has an interrupted {pending}; finish it with the same command or roll it back first",
+            path.display()
+        )));
+    }
+    let (steps, from, mut journal) = match journal::recover(&journal_path, &mut mmap)? {
+        Some(interrupted) => {
+            on_progress(&ProgressEvent::Resuming { operation });
+            (
+                interrupted.steps,
+                interrupted.cursor,
+                Some(interrupted.journal),
+            )
+        }
+        None => {
+            let steps = plan(&mmap)?;
+            let journal = if steps.iter().any(Step::modifies) {
+                Some(Journal::create(
+                    &journal_path,
+                    operation,
+                    mmap.len(),
+                    journal::SEGMENT_SIZE,
+                    &steps,
+                )?)
+            } else {
+                None
+            };
+            (steps, JournalCursor::default(), journal)
+        }
     };
-    let sector_size = ncsd.sector_size;
 
-    for p in 0..8u8 {
-        let part = &ncsd.partitions[p as usize];
-        if part.is_empty() {
-            on_progress(&format!("Partition {p} Not found... Skipping..."));
-            continue;
+    let result = journal::run(
+        &mut mmap,
+        &steps,
+        journal.as_mut(),
+        from,
+        cancel,
+        on_progress,
+    );
+    if let Err(Error::Cancelled) = result
+        && journal.is_some()
+    {
+        drop(journal);
+        roll_back(&mut mmap, &journal_path, on_progress)?;
+        return Err(Error::Cancelled);
+    }
+    result?;
+
+    mmap.flush()?;
+    if let Some(journal) = journal {
+        journal.remove()?;
+    }
+    Ok(())
+}
+
+/// Undo an interrupted in-place operation, restoring the ROM byte-for-byte to the
+/// state it was in before [`decrypt_rom`], [`encrypt_rom`] or
+/// [`repair_rom`](crate::repair::repair_rom) started.
+///
+/// Returns `false` if there is no interrupted operation to roll back.
+pub fn rollback_rom(
+    path: &Path,
+    mut on_progress: impl FnMut(&ProgressEvent),
+) -> Result<bool, Error> {
+    let file = File::options().read(true).write(true).open(path)?;
+    // SAFETY: we are the sole accessor of this file during the rollback
+    let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+
+    let rolled_back = roll_back(&mut mmap, &journal::journal_path(path), &mut on_progress)?;
+    if rolled_back {
+        on_progress(&ProgressEvent::Done);
+    } else {
+        on_progress(&ProgressEvent::NothingToRollBack);
+    }
+    Ok(rolled_back)
+}
+
+/// Roll back the in-place run recorded in the journal at `journal_path`, if any.
+fn roll_back(
+    mmap: &mut MmapMut,
+    journal_path: &Path,
+    on_progress: &mut impl FnMut(&ProgressEvent),
+) -> Result<bool, Error> {
+    let Some(mut interrupted) = journal::recover(journal_path, mmap)? else {
+        return Ok(false);
+    };
+
+    on_progress(&ProgressEvent::RollingBack {
+        operation: interrupted.journal.operation(),
+    });
+    journal::rollback(
+        mmap,
+        &interrupted.steps,
+        &mut interrupted.journal,
+        interrupted.cursor,
+    )?;
+    mmap.flush()?;
+    interrupted.journal.remove()?;
+    Ok(true)
+}
+
+/// Refuse to work on a ROM left half-done by an interrupted in-place operation.
+fn ensure_no_pending_journal(path: &Path) -> Result<(), Error> {
+    if journal::is_pending(path) {
+        return Err(Error::Journal(format!(
+            "{} has an interrupted in-place operation; finish it or roll it back first",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Decrypt a ROM into a separate output file, leaving the input untouched.
+///
+/// The input is only ever opened for reading. The decrypted image is built in a
+/// temporary file next to `output` and renamed into place once it has been fully
+/// written and synced, so a crash never leaves a half-written target behind.
+pub fn decrypt_rom_to(
+    input: &Path,
+    output: &Path,
+    keydb: &KeyDatabase,
+    on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    decrypt_rom_to_cancellable(input, output, keydb, &CancelToken::new(), on_progress)
+}
+
+/// [`decrypt_rom_to`] that stops when `cancel` is set, returning [`Error::Cancelled`]
+/// without creating `output`.
+pub fn decrypt_rom_to_cancellable(
+    input: &Path,
+    output: &Path,
+    keydb: &KeyDatabase,
+    cancel: &CancelToken,
+    mut on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    on_progress(&ProgressEvent::KeysLoaded { count: keydb.len() });
+
+    ensure_no_pending_journal(input)?;
+    let tmp_path = temp_path_for(output);
+    if let Err(e) = decrypt_copy(input, &tmp_path, output, keydb, cancel, &mut on_progress) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    on_progress(&ProgressEvent::Done);
+    Ok(())
+}
+
+/// Copy `input` to `tmp_path`, decrypt the copy and atomically rename it to `output`.
+fn decrypt_copy(
+    input: &Path,
+    tmp_path: &Path,
+    output: &Path,
+    keydb: &KeyDatabase,
+    cancel: &CancelToken,
+    on_progress: &mut impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    std::fs::copy(input, tmp_path)?;
+    let file = File::options().read(true).write(true).open(tmp_path)?;
+    // SAFETY: the temporary file was just created by us and is not shared
+    let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+
+    decrypt_image(&mut mmap, keydb, cancel, on_progress)?;
+
+    mmap.flush()?;
+    drop(mmap);
+    file.sync_all()?;
+    std::fs::rename(tmp_path, output)?;
+    Ok(())
+}
+
+/// Temporary sibling path used while writing `output`.
+pub(crate) fn temp_path_for(output: &Path) -> PathBuf {
+    let name = output
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    output.with_file_name(format!(".{name}.citrust-tmp"))
+}
+
+/// Decrypt a ROM image held in memory, dispatching on its container format.
+///
+/// The whole decryption is planned, and every partition trial-decrypted, before the
+/// image is modified, so a wrong key fails without changing a byte. Unlike
+/// [`decrypt_rom`] nothing is journaled: if `cancel` is set part-way, the image is left
+/// partly decrypted.
+pub fn decrypt_image(
+    mmap: &mut [u8],
+    keydb: &KeyDatabase,
+    cancel: &CancelToken,
+    on_progress: &mut impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    let steps = plan_image(mmap, keydb)?;
+    journal::run(
+        mmap,
+        &steps,
+        None,
+        JournalCursor::default(),
+        cancel,
+        on_progress,
+    )
+}
+
+/// Check that `keydb` correctly decrypts every partition of a ROM image, without
+/// modifying it.
+///
+/// The ExHeader, the ExeFS superblock and the RomFS superblock of each encrypted NCCH
+/// are decrypted into scratch buffers and compared against the hashes in the NCCH
+/// header; a mismatch is reported as [`Error::KeyMismatch`]. Regions whose hash is not
+/// recorded (all zeros) are not checked.
+pub fn trial_decrypt_image(data: &[u8], keydb: &KeyDatabase) -> Result<(), Error> {
+    plan_image(data, keydb).map(|_| ())
+}
+
+/// Work out every change needed to decrypt a ROM image, trial-decrypting each partition
+/// on the way.
+fn plan_image(data: &[u8], keydb: &KeyDatabase) -> Result<Vec<Step>, Error> {
+    let raw =
+        |start: usize, len: usize| data.get(start..start.checked_add(len)?).map(<[u8]>::to_vec);
+    let mut steps = Vec::new();
+
+    match RomFormat::detect(data) {
+        Some(RomFormat::Ncsd) => {
+            let ncsd = NcsdHeader::parse(&mut Cursor::new(data)).map_err(|_| Error::NotNcsd)?;
+            for (p, part) in ncsd.partitions.iter().enumerate() {
+                if part.is_empty() {
+                    steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+                        partition: p as u16,
+                        reason: SkipReason::NotFound,
+                    }));
+                    continue;
+                }
+                let part_off = partition_offset(p as u16, part, ncsd.sector_size, data.len())?;
+                if raw(part_off + 0x100, 4).as_deref() != Some(b"NCCH") {
+                    steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+                        partition: p as u16,
+                        reason: SkipReason::InvalidHeader,
+                    }));
+                    continue;
+                }
+                // Backup crypto_method kept in the NCSD header, used to recover mis-flagged partitions
+                let backup_crypto = data.get(0x1188 + p * 8 + 3).copied();
+                plan_decrypt_partition(
+                    &raw,
+                    p as u16,
+                    part_off,
+                    Some(ncsd.sector_size),
+                    backup_crypto,
+                    keydb,
+                    &mut steps,
+                )?;
+            }
+        }
+        Some(RomFormat::Ncch) => {
+            plan_decrypt_partition(&raw, 0, 0, None, None, keydb, &mut steps)?;
+        }
+        Some(RomFormat::Cia) => plan_cia(data, keydb, &mut steps)?,
+        None => return Err(Error::UnknownFormat),
+    }
+
+    check_extents(&steps, data.len())?;
+    Ok(steps)
+}
+
+/// Reject a plan whose AES-CTR passes run past the end of the image.
+fn check_extents(steps: &[Step], len: usize) -> Result<(), Error> {
+    for step in steps {
+        if let Step::Ctr {
+            partition,
+            region,
+            start,
+            len: n,
+            ..
+        } = *step
+            && start.checked_add(n).is_none_or(|end| end > len)
+        {
+            return Err(Error::RegionOutOfBounds { partition, region });
         }
+    }
+    Ok(())
+}
 
-        let part_off = part.offset_bytes(sector_size) as usize;
+/// Plan the removal of the title-key layer from every content of a CIA, followed by the
+/// decryption of each NCCH inside.
+///
+/// Contents are decrypted in-place and their TMD chunk records are marked unencrypted,
+/// producing a standard "decrypted CIA". The ticket and TMD signatures are left as-is.
+fn plan_cia(data: &[u8], keydb: &KeyDatabase, steps: &mut Vec<Step>) -> Result<(), Error> {
+    let raw =
+        |start: usize, len: usize| data.get(start..start.checked_add(len)?).map(<[u8]>::to_vec);
+    let cia = Cia::parse(&mut Cursor::new(data)).map_err(|e| Error::InvalidCia(e.to_string()))?;
+
+    let mut title_key = None;
+    for (chunk, offset) in cia.contents() {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let end = usize::try_from(chunk.size)
+            .ok()
+            .and_then(|size| start.checked_add(size))
+            .unwrap_or(usize::MAX);
+        if end > data.len() {
+            return Err(Error::InvalidCia(format!(
+                "content {} extends past end of file",
+                chunk.index
+            )));
+        }
+        if chunk.size % 16 != 0 {
+            return Err(Error::InvalidCia(format!(
+                "content {} size is not a multiple of the AES block size",
+                chunk.index
+            )));
+        }
+        let p = chunk.index;
+        let not_ncch = Step::Note(ProgressEvent::ContentNotNcch {
+            content: chunk.index,
+        });
 
-        // Verify NCCH magic
-        if &mmap[part_off + 0x100..part_off + 0x104] != b"NCCH" {
-            on_progress(&format!("Partition {p} Unable to read NCCH header"));
+        if !chunk.is_encrypted() {
+            if chunk.size >= 0x200 && raw(start + 0x100, 4).as_deref() == Some(b"NCCH") {
+                plan_decrypt_partition(&raw, p, start, None, None, keydb, steps)?;
+            } else {
+                steps.push(not_ncch);
+            }
             continue;
         }
 
-        let ncch = {
-            let mut cursor = Cursor::new(&mmap[..]);
-            NcchHeader::parse(&mut cursor, part_off as u64)?
+        let key = match title_key {
+            Some(key) => key,
+            None => *title_key.insert(cia.ticket.decrypt_title_key(keydb)?),
         };
+        steps.push(Step::Cbc {
+            content: chunk.index,
+            start,
+            len: chunk.size as usize,
+            key,
+            iv: chunk.iv(),
+        });
+        let record = cia.tmd_chunk_record_offset(chunk.position);
+        steps.push(Step::Patch {
+            offset: record + 0x06,
+            old: chunk.content_type.to_be_bytes().to_vec(),
+            new: (chunk.content_type & !0x0001).to_be_bytes().to_vec(),
+        });
 
-        let ncch = if ncch.is_no_crypto() {
-            if is_content_decrypted(&mmap, &ncch, sector_size, part_off) {
-                on_progress(&format!("Partition {p}: Already Decrypted ✓"));
-                continue;
+        // The rest of the plan is made against the content as it will be once the CBC
+        // step has run; CBC can decrypt any block given the ciphertext before it
+        let cbc = |from: usize, len: usize| {
+            let to = from.checked_add(len)?;
+            if from < start
+                || to > end
+                || !(from - start).is_multiple_of(16)
+                || !len.is_multiple_of(16)
+            {
+                return None;
             }
-            // NoCrypto flag set but content is actually encrypted
-            on_progress(&format!(
-                "Partition {p}: Flagged as decrypted but content is encrypted, decrypting..."
-            ));
-            // Clear NoCrypto bit and recover backup crypto_method from NCSD header
-            mmap[part_off + 0x18F] &= !0x04;
-            let backup_offset = 0x1188 + (p as usize * 8) + 3;
-            if backup_offset < mmap.len() {
-                let backup_crypto = mmap[backup_offset];
-                if backup_crypto != 0 && CryptoMethod::from_flag(backup_crypto).is_some() {
-                    mmap[part_off + 0x18B] = backup_crypto;
-                }
-            }
-            // Re-parse NCCH with corrected flags
-            let mut cursor = Cursor::new(&mmap[..]);
-            NcchHeader::parse(&mut cursor, part_off as u64)?
-        } else {
-            ncch
+            let iv = if from == start {
+                chunk.iv()
+            } else {
+                u128::from_be_bytes(data[from - 16..from].try_into().unwrap())
+            };
+            let mut buf = data[from..to].to_vec();
+            aes_cbc_decrypt(&key, iv, &mut buf);
+            Some(buf)
         };
-
-        // Content-based detection: check if data is already plaintext despite NoCrypto not set
-        if is_content_decrypted(&mmap, &ncch, sector_size, part_off) {
-            on_progress(&format!(
-                "Partition {p}: Content already decrypted (mis-flagged ROM), setting NoCrypto flag..."
-            ));
-            // Skip decryption, just patch the flags
-            mmap[part_off + 0x18B] = 0x00;
-            let mut flag = ncch.partition_flags[7];
-            flag &= !0x01;
-            flag &= !0x20;
-            flag |= 0x04;
-            mmap[part_off + 0x18F] = flag;
-            continue;
+        if cbc(start, 0x200).is_some_and(|head| head[0x100..0x104] == *b"NCCH") {
+            plan_decrypt_partition(&cbc, p, start, None, None, keydb, steps)?;
+        } else {
+            steps.push(not_ncch);
         }
+    }
 
-        let key_y = ncch.key_y;
-        let (normal_key_2c, normal_key) = if ncch.is_fixed_key() {
-            if p == 0 {
-                on_progress("Encryption Method: Zero Key");
-            }
-            (0u128, 0u128)
+    Ok(())
+}
+
+/// Read a partition from its header to the end of the ExeFS superblock (or of the
+/// ExHeader if there is no ExeFS), which is what [`is_content_decrypted`] looks at.
+fn read_content_prefix(
+    read: &impl Fn(usize, usize) -> Option<Vec<u8>>,
+    p: u16,
+    part_off: usize,
+    ncch: &NcchHeader,
+    ss: usize,
+) -> Result<Vec<u8>, Error> {
+    let exheader_end = offset_of(p, Region::ExHeader, 0x200, ncch.exheader_length, 1)?;
+    let mut prefix_len = exheader_end;
+    if ncch.exefs_length > 0 {
+        let exefs_base = offset_of(p, Region::ExefsHeader, 0, ncch.exefs_offset, ss)?;
+        let exefs_region = offset_of(p, Region::ExefsHeader, 0, ncch.exefs_hash_region_size, ss)?;
+        prefix_len = exefs_base
+            .checked_add(exefs_region.max(ss))
+            .ok_or(Error::RegionOutOfBounds {
+                partition: p,
+                region: Region::ExefsHeader,
+            })?
+            .max(prefix_len);
+    }
+    read(part_off, prefix_len).ok_or(Error::RegionOutOfBounds {
+        partition: p,
+        region: if prefix_len > exheader_end {
+            Region::ExefsHeader
         } else {
-            let constant = resolve_constant(keydb)?;
-            let key_x_2c = resolve_key_x_2c(keydb)?;
-            let nk2c = crate::crypto::derive_normal_key(key_x_2c, key_y, constant);
-            let method = ncch.crypto_method().unwrap_or(CryptoMethod::Original);
-            let key_x = resolve_key_x(method, keydb)?;
-            let nk = crate::crypto::derive_normal_key(key_x, key_y, constant);
-            if p == 0 {
-                on_progress(&format!("Encryption Method: {method:?}"));
-            }
-            (nk2c, nk)
+            Region::ExHeader
+        },
+    })
+}
+
+/// Plan the decryption of one NCCH located at `part_off`, ending with a patch of its
+/// flags to NoCrypto, and trial-decrypt its hashed regions.
+///
+/// `read` returns the container-level plaintext of a byte range of the image,
+/// `sector_size` overrides the NCCH's own media unit size (NCSD partitions) and
+/// `backup_crypto` is the crypto method byte to restore when a partition is flagged
+/// NoCrypto but its content turns out to be encrypted.
+fn plan_decrypt_partition(
+    read: &impl Fn(usize, usize) -> Option<Vec<u8>>,
+    p: u16,
+    part_off: usize,
+    sector_size: Option<u32>,
+    backup_crypto: Option<u8>,
+    keydb: &KeyDatabase,
+    steps: &mut Vec<Step>,
+) -> Result<(), Error> {
+    let parse = |head: &[u8]| {
+        NcchHeader::parse(&mut Cursor::new(head), 0).map_err(|_| Error::InvalidNcch(p))
+    };
+    let original = read(part_off, 0x200).ok_or(Error::InvalidNcch(p))?;
+    let mut head = original.clone();
+    let mut ncch = parse(&head)?;
+    let sector_size = sector_size.unwrap_or(ncch.media_unit_size());
+    let ss = sector_size as usize;
+
+    let exefs_base = offset_of(p, Region::ExefsHeader, 0, ncch.exefs_offset, ss)?;
+    let prefix = read_content_prefix(read, p, part_off, &ncch, ss)?;
+
+    if ncch.is_no_crypto() {
+        if is_content_decrypted(&prefix, &ncch, sector_size, 0) {
+            steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+                partition: p,
+                reason: SkipReason::AlreadyDecrypted,
+            }));
+            return Ok(());
+        }
+        // NoCrypto flag set but content is actually encrypted
+        steps.push(Step::Note(ProgressEvent::FlaggedDecryptedButEncrypted {
+            partition: p,
+        }));
+        restore_crypto_flags(&mut head, backup_crypto);
+        ncch = parse(&head)?;
+    }
+    let patch_flags = no_crypto_patch(part_off, &original);
+
+    // Content-based detection: check if data is already plaintext despite NoCrypto not set
+    if is_content_decrypted(&prefix, &ncch, sector_size, 0) {
+        steps.push(Step::Note(ProgressEvent::ContentAlreadyDecrypted {
+            partition: p,
+        }));
+        steps.push(patch_flags);
+        return Ok(());
+    }
+
+    let keys = partition_keys(&ncch, keydb)?;
+    // The filename table must be read in plaintext to find `.code`
+    let mut table = prefix
+        .get(exefs_base..)
+        .and_then(|exefs| exefs.get(..ss))
+        .unwrap_or_default()
+        .to_vec();
+    aes_ctr_decrypt(&keys.base, ncch.exefs_iv(), &mut table);
+    let ops = plan_partition(p, 0, sector_size, &ncch, &keys, &table)?;
+
+    let mut windows = Vec::new();
+    if ncch.exheader_length > 0 {
+        let len = ncch.exheader_length as usize;
+        windows.push((Region::ExHeader, 0x200, len, ncch.exheader_hash));
+    }
+    if ncch.exefs_length > 0 && ncch.exefs_hash_region_size > 0 {
+        let len = offset_of(p, Region::ExefsHeader, 0, ncch.exefs_hash_region_size, ss)?;
+        windows.push((
+            Region::ExefsHeader,
+            exefs_base,
+            len,
+            ncch.exefs_superblock_hash,
+        ));
+    }
+    if ncch.romfs_offset != 0 && ncch.romfs_hash_region_size > 0 {
+        let start = offset_of(p, Region::RomFs, 0, ncch.romfs_offset, ss)?;
+        let len = offset_of(p, Region::RomFs, 0, ncch.romfs_hash_region_size, ss)?;
+        windows.push((Region::RomFs, start, len, ncch.romfs_superblock_hash));
+    }
+
+    for (region, start, len, hash) in windows {
+        if hash == [0u8; 32] {
+            continue;
+        }
+        let out_of_bounds = Error::RegionOutOfBounds {
+            partition: p,
+            region,
         };
+        let mut window = part_off
+            .checked_add(start)
+            .and_then(|at| read(at, len))
+            .ok_or(out_of_bounds)?;
+        for op in &ops {
+            let from = op.start.max(start);
+            let to = (op.start + op.len).min(start + len);
+            if from < to {
+                let iv = op.iv.wrapping_add(((from - op.start) / 0x10) as u128);
+                aes_ctr_decrypt(&op.key, iv, &mut window[from - start..to - start]);
+            }
+        }
+        if verify::check_hash(&window, &hash) != HashStatus::Valid {
+            return Err(Error::KeyMismatch {
+                partition: p,
+                region,
+            });
+        }
+    }
+
+    steps.push(Step::Note(ProgressEvent::PartitionStart {
+        partition: p,
+        bytes: ops.iter().map(|op| op.len as u64).sum(),
+    }));
+    if p == 0 {
+        steps.push(Step::Note(crypto_method_event(p, &ncch)));
+    }
+    let ops = ops
+        .into_iter()
+        .map(|op| {
+            let start = part_off
+                .checked_add(op.start)
+                .ok_or(Error::RegionOutOfBounds {
+                    partition: p,
+                    region: op.region,
+                })?;
+            Ok(CryptOp { start, ..op })
+        })
+        .collect::<Result<_, Error>>()?;
+    push_ctr_steps(p, &ncch, ops, Direction::Decrypt, steps);
+    steps.push(patch_flags);
+
+    Ok(())
+}
+
+/// Clear the NoCrypto bit of a partition's NCCH header and recover its crypto method
+/// from the NCSD backup, for a partition flagged NoCrypto whose content is encrypted.
+pub(crate) fn restore_crypto_flags(head: &mut [u8], backup_crypto: Option<u8>) {
+    head[0x18F] &= !0x04;
+    if let Some(backup_crypto) = backup_crypto
+        && backup_crypto != 0
+        && CryptoMethod::from_flag(backup_crypto).is_some()
+    {
+        head[0x18B] = backup_crypto;
+    }
+}
+
+/// The final flag patch of a decrypted partition, given its current NCCH header:
+/// crypto method byte cleared, NoCrypto set, FixedKey and seed cleared.
+pub(crate) fn no_crypto_patch(part_off: usize, head: &[u8]) -> Step {
+    let old = head[0x18B..0x190].to_vec();
+    let mut new = old.clone();
+    new[0] = 0x00;
+    new[4] = (new[4] & !0x01 & !0x20) | 0x04;
+    Step::Patch {
+        offset: part_off + 0x18B,
+        old,
+        new,
+    }
+}
+
+/// Decrypt a standalone NCCH (CXI, CFA or a CDN `.app` content) starting at offset 0
+/// of `data`, and patch its flags to NoCrypto.
+pub fn decrypt_ncch(
+    data: &mut [u8],
+    keydb: &KeyDatabase,
+    mut on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    if data.get(0x100..0x104) != Some(b"NCCH") {
+        return Err(Error::InvalidNcch(0));
+    }
+    let raw =
+        |start: usize, len: usize| data.get(start..start.checked_add(len)?).map(<[u8]>::to_vec);
+    let mut steps = Vec::new();
+    plan_decrypt_partition(&raw, 0, 0, None, None, keydb, &mut steps)?;
+    check_extents(&steps, data.len())?;
+    journal::run(
+        data,
+        &steps,
+        None,
+        JournalCursor::default(),
+        &CancelToken::new(),
+        &mut on_progress,
+    )
+}
+
+/// Re-encrypt a decrypted ROM in-place.
+///
+/// Every partition flagged NoCrypto is encrypted and its NoCrypto bit cleared, unless
+/// its content turns out to be encrypted already, in which case it is skipped. The NCSD
+/// header keeps a backup of partition 0's flags only, so partition 0 gets back its
+/// crypto method byte (flags[3]) and FixedKey and seed bits of flags[7] from there when
+/// the backup is set. Every other partition, and standalone NCCHs, are encrypted with
+/// `method`.
+///
+/// The encryption is journaled like [`decrypt_rom`]: an interrupted one is resumed by
+/// calling this again, or undone with [`rollback_rom`].
+pub fn encrypt_rom(
+    path: &Path,
+    keydb: &KeyDatabase,
+    method: CryptoMethod,
+    on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    encrypt_rom_cancellable(path, keydb, method, &CancelToken::new(), on_progress)
+}
+
+/// [`encrypt_rom`] that stops when `cancel` is set, rolling back like
+/// [`decrypt_rom_cancellable`].
+pub fn encrypt_rom_cancellable(
+    path: &Path,
+    keydb: &KeyDatabase,
+    method: CryptoMethod,
+    cancel: &CancelToken,
+    mut on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    on_progress(&ProgressEvent::KeysLoaded { count: keydb.len() });
+
+    run_in_place(
+        path,
+        Operation::Encrypt,
+        |data| plan_encrypt_image(data, keydb, method),
+        cancel,
+        &mut on_progress,
+    )?;
+    on_progress(&ProgressEvent::Done);
+    Ok(())
+}
+
+/// Encrypt a ROM image held in memory, without a journal.
+#[cfg(test)]
+pub(crate) fn encrypt_image(
+    data: &mut [u8],
+    keydb: &KeyDatabase,
+    method: CryptoMethod,
+    on_progress: &mut impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    let steps = plan_encrypt_image(data, keydb, method)?;
+    journal::run(
+        data,
+        &steps,
+        None,
+        JournalCursor::default(),
+        &CancelToken::new(),
+        on_progress,
+    )
+}
 
-        let key_2c: Key128 = normal_key_2c.to_be_bytes();
-        let key_main: Key128 = normal_key.to_be_bytes();
-
-        // ======= DECRYPT EXHEADER (2KB, too small to parallelize) =======
-        if ncch.exheader_length > 0 {
-            let off = (part.offset_sectors as usize + 1) * sector_size as usize;
-            aes_ctr_decrypt(&key_2c, ncch.plain_iv(), &mut mmap[off..off + 0x800]);
-            on_progress(&format!("Partition {p} ExeFS: Decrypting: ExHeader"));
-        }
-
-        // ======= DECRYPT EXEFS =======
-        if ncch.exefs_length > 0 {
-            let exefs_base =
-                (part.offset_sectors as usize + ncch.exefs_offset as usize) * sector_size as usize;
-            let exefs_iv = ncch.exefs_iv();
-            let ss = sector_size as usize;
-
-            // Step 1: Decrypt filename table (first sector, small)
-            aes_ctr_decrypt(&key_2c, exefs_iv, &mut mmap[exefs_base..exefs_base + ss]);
-            on_progress(&format!(
-                "Partition {p} ExeFS: Decrypting: ExeFS Filename Table"
-            ));
-
-            // Step 2: .code double-layer (only for 7.x/9.x keys)
-            let method = ncch.crypto_method().unwrap_or(CryptoMethod::Original);
-            if matches!(
-                method,
-                CryptoMethod::Key7x | CryptoMethod::Key93 | CryptoMethod::Key96
-            ) {
-                // Read .code entry from the now-decrypted filename table
-                for j in 0..10u32 {
-                    let slot = exefs_base + (j as usize * 0x10);
-                    if slot + 0x10 > exefs_base + ss {
-                        break;
-                    }
-                    if &mmap[slot..slot + 8] == b".code\x00\x00\x00" {
-                        let code_file_off =
-                            u32::from_le_bytes(mmap[slot + 8..slot + 12].try_into().unwrap());
-                        let code_file_len =
-                            u32::from_le_bytes(mmap[slot + 12..slot + 16].try_into().unwrap())
-                                as usize;
-
-                        if code_file_len > 0 {
-                            let ctr_offset = (code_file_off as u128 + ss as u128) / 0x10;
-                            let code_iv = exefs_iv + ctr_offset;
-                            let code_start = exefs_base + ss + code_file_off as usize;
-
-                            on_progress(&format!(
-                                "Partition {p} ExeFS: Decrypting: .code ({} mb)",
-                                code_file_len / (1024 * 1024)
-                            ));
-                            decrypt_slice(
-                                &mut mmap[code_start..code_start + code_file_len],
-                                &key_main,
-                                Some(&key_2c),
-                                code_iv,
-                                1024 * 1024,
-                            );
-                            on_progress(&format!(
-                                "Partition {p} ExeFS: Decrypting: .code... Done!"
-                            ));
-                        }
-                        break;
-                    }
+/// Work out every change needed to encrypt a ROM image, dispatching on its container
+/// format.
+fn plan_encrypt_image(
+    data: &[u8],
+    keydb: &KeyDatabase,
+    method: CryptoMethod,
+) -> Result<Vec<Step>, Error> {
+    let mut steps = Vec::new();
+    match RomFormat::detect(data) {
+        Some(RomFormat::Ncsd) => {
+            let ncsd = NcsdHeader::parse(&mut Cursor::new(data)).map_err(|_| Error::NotNcsd)?;
+            for (p, part) in ncsd.partitions.iter().enumerate() {
+                if part.is_empty() {
+                    steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+                        partition: p as u16,
+                        reason: SkipReason::NotFound,
+                    }));
+                    continue;
+                }
+                let part_off = partition_offset(p as u16, part, ncsd.sector_size, data.len())?;
+                if data.get(part_off + 0x100..part_off + 0x104) != Some(b"NCCH") {
+                    steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+                        partition: p as u16,
+                        reason: SkipReason::InvalidHeader,
+                    }));
+                    continue;
                 }
+                plan_encrypt_partition(
+                    data,
+                    p as u16,
+                    part_off,
+                    Some(ncsd.sector_size),
+                    keydb,
+                    method,
+                    &mut steps,
+                )?;
             }
+        }
+        Some(RomFormat::Ncch) => {
+            plan_encrypt_partition(data, 0, 0, None, keydb, method, &mut steps)?;
+        }
+        Some(RomFormat::Cia) | None => return Err(Error::UnknownFormat),
+    }
+    check_extents(&steps, data.len())?;
+    Ok(steps)
+}
 
-            // Step 3: Decrypt remaining ExeFS data (after first sector)
-            let exefs_data_sectors = ncch.exefs_length.saturating_sub(1) as usize;
-            if exefs_data_sectors > 0 {
-                let data_size = exefs_data_sectors * ss;
-                let data_iv = exefs_iv + (ss as u128 / 0x10);
-                let data_start = exefs_base + ss;
-
-                on_progress(&format!("Partition {p} ExeFS: Decrypting: data"));
-                decrypt_slice(
-                    &mut mmap[data_start..data_start + data_size],
-                    &key_2c,
-                    None,
-                    data_iv,
-                    1024 * 1024,
-                );
-                on_progress(&format!("Partition {p} ExeFS: Decrypting: Done"));
-            }
-        } else {
-            on_progress(&format!("Partition {p} ExeFS: No Data... Skipping..."));
-        }
-
-        // ======= DECRYPT ROMFS (bulk — parallel) =======
-        if ncch.romfs_offset != 0 {
-            let romfs_total = ncch.romfs_length as usize * sector_size as usize;
-            let romfs_iv = ncch.romfs_iv();
-            let romfs_start =
-                (part.offset_sectors as usize + ncch.romfs_offset as usize) * sector_size as usize;
-
-            on_progress(&format!(
-                "Partition {p} RomFS: Decrypting: {} mb",
-                romfs_total / (1024 * 1024)
-            ));
-            decrypt_slice(
-                &mut mmap[romfs_start..romfs_start + romfs_total],
-                &key_main,
-                None,
-                romfs_iv,
-                CHUNK_SIZE,
-            );
-            on_progress(&format!("Partition {p} RomFS: Decrypting: Done"));
-        } else {
-            on_progress(&format!("Partition {p} RomFS: No Data... Skipping..."));
+/// Set the crypto flags a decrypted partition is re-encrypted with: the method byte
+/// and FixedKey and seed bits of `backup_flags` (flags[3..8] as kept in the NCSD
+/// header), or `method` if there is no valid backup. An all-zero backup counts as
+/// none, like in [`restore_crypto_flags`].
+fn restore_encryption_flags(head: &mut [u8], backup_flags: Option<&[u8]>, method: CryptoMethod) {
+    match backup_flags {
+        Some(&[crypto, _, _, _, flags])
+            if (crypto, flags) != (0, 0)
+                && flags & 0x04 == 0
+                && CryptoMethod::from_flag(crypto).is_some() =>
+        {
+            head[0x18B] = crypto;
+            head[0x18F] = (head[0x18F] & !0x01 & !0x04 & !0x20) | (flags & (0x01 | 0x20));
+        }
+        _ => {
+            head[0x18B] = method.flag();
+            head[0x18F] &= !0x04;
         }
+    }
+}
 
-        // ======= PATCH FLAGS (direct byte writes, zero-copy) =======
-        mmap[part_off + 0x18B] = 0x00;
-        let mut flag = ncch.partition_flags[7];
-        flag &= !0x01;
-        flag &= !0x20;
-        flag |= 0x04;
-        mmap[part_off + 0x18F] = flag;
+/// Plan the encryption of a single decrypted NCCH located at `part_off`, ending with
+/// the patch of its crypto flags.
+///
+/// `sector_size` is given for NCSD partitions. Partition 0 of an NCSD has its flags
+/// restored from the NCSD backup (see [`restore_encryption_flags`]) before the keys are
+/// derived from them.
+fn plan_encrypt_partition(
+    data: &[u8],
+    p: u16,
+    part_off: usize,
+    sector_size: Option<u32>,
+    keydb: &KeyDatabase,
+    method: CryptoMethod,
+    steps: &mut Vec<Step>,
+) -> Result<(), Error> {
+    let parse = |head: &[u8]| {
+        NcchHeader::parse(&mut Cursor::new(head), 0).map_err(|_| Error::InvalidNcch(p))
+    };
+    let original = part_off
+        .checked_add(0x200)
+        .and_then(|end| data.get(part_off..end))
+        .ok_or(Error::InvalidNcch(p))?;
+    let flagged = parse(original)?;
+    if !flagged.is_no_crypto() {
+        steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+            partition: p,
+            reason: SkipReason::AlreadyEncrypted,
+        }));
+        return Ok(());
+    }
+    // A partition flagged NoCrypto whose content is still encrypted is left alone:
+    // encrypting it again would scramble it
+    let raw =
+        |start: usize, len: usize| data.get(start..start.checked_add(len)?).map(<[u8]>::to_vec);
+    let flagged_ss = sector_size.unwrap_or(flagged.media_unit_size()) as usize;
+    let prefix = read_content_prefix(&raw, p, part_off, &flagged, flagged_ss)?;
+    if !is_content_decrypted(&prefix, &flagged, flagged_ss as u32, 0) {
+        steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+            partition: p,
+            reason: SkipReason::ContentEncrypted,
+        }));
+        return Ok(());
     }
 
-    mmap.flush()?;
-    on_progress("Done...");
+    // Backup of partition 0's flags[3..8] kept in the NCSD header
+    let backup_flags = sector_size
+        .filter(|_| p == 0)
+        .and_then(|_| data.get(0x118B..0x1190));
+    let mut head = original.to_vec();
+    restore_encryption_flags(&mut head, backup_flags, method);
+    let ncch = parse(&head)?;
+    let sector_size = sector_size.unwrap_or(ncch.media_unit_size());
+
+    let keys = partition_keys(&ncch, keydb)?;
+    let ss = sector_size as usize;
+    let exefs_base = offset_of(p, Region::ExefsHeader, part_off, ncch.exefs_offset, ss)?;
+    let table = data
+        .get(exefs_base..)
+        .and_then(|exefs| exefs.get(..ss))
+        .unwrap_or_default();
+    let ops = plan_partition(p, part_off, sector_size, &ncch, &keys, table)?;
+
+    steps.push(Step::Note(ProgressEvent::PartitionStart {
+        partition: p,
+        bytes: ops.iter().map(|op| op.len as u64).sum(),
+    }));
+    if p == 0 {
+        steps.push(Step::Note(crypto_method_event(p, &ncch)));
+    }
+    push_ctr_steps(p, &ncch, ops, Direction::Encrypt, steps);
+    steps.push(Step::Patch {
+        offset: part_off + 0x18B,
+        old: original[0x18B..0x190].to_vec(),
+        new: head[0x18B..0x190].to_vec(),
+    });
     Ok(())
 }
 
+/// Queue a partition's AES-CTR passes, followed by notes for a missing ExeFS or RomFS.
+fn push_ctr_steps(
+    p: u16,
+    ncch: &NcchHeader,
+    ops: Vec<CryptOp>,
+    direction: Direction,
+    steps: &mut Vec<Step>,
+) {
+    for op in ops {
+        steps.push(Step::Ctr {
+            partition: p,
+            region: op.region,
+            direction,
+            start: op.start,
+            len: op.len,
+            key: op.key,
+            iv: op.iv,
+        });
+    }
+    if ncch.exefs_length == 0 {
+        steps.push(Step::Note(ProgressEvent::SectionMissing {
+            partition: p,
+            section: Section::Exefs,
+        }));
+    }
+    if ncch.romfs_offset == 0 {
+        steps.push(Step::Note(ProgressEvent::SectionMissing {
+            partition: p,
+            section: Section::Romfs,
+        }));
+    }
+}
+
+/// The two normal keys used by an NCCH partition.
+pub(crate) struct PartitionKeys {
+    /// Slot 0x2C key: ExHeader, ExeFS header and every ExeFS file except `.code`.
+    pub base: Key128,
+    /// Key for the partition's crypto method: `.code` and RomFS.
+    pub main: Key128,
+}
+
+/// The [`ProgressEvent::CryptoMethod`] for a partition, from its header flags.
+fn crypto_method_event(partition: u16, ncch: &NcchHeader) -> ProgressEvent {
+    ProgressEvent::CryptoMethod {
+        partition,
+        method: ncch.crypto_method(),
+        fixed_key: ncch.is_fixed_key(),
+        seed: ncch.uses_seed(),
+    }
+}
+
+/// Derive the normal keys for a partition from its header and the key database.
+pub(crate) fn partition_keys(
+    ncch: &NcchHeader,
+    keydb: &KeyDatabase,
+) -> Result<PartitionKeys, Error> {
+    if ncch.is_fixed_key() {
+        return Ok(PartitionKeys {
+            base: [0u8; 16],
+            main: [0u8; 16],
+        });
+    }
+
+    let constant = resolve_constant(keydb)?;
+    let key_x_2c = resolve_key_x_2c(keydb)?;
+    let method = ncch.crypto_method().unwrap_or(CryptoMethod::Original);
+    let key_x = resolve_key_x(method, keydb)?;
+
+    // Seed crypto only affects the method key; slot 0x2C still uses the header KeyY
+    let main_key_y = if ncch.uses_seed() {
+        let seed = keydb
+            .seeds()
+            .get(ncch.program_id)
+            .ok_or(Error::SeedNotFound(ncch.program_id))?;
+        if !seeddb::verify_seed(&seed, ncch.program_id, ncch.seed_check) {
+            return Err(Error::SeedMismatch(ncch.program_id));
+        }
+        seeddb::seeded_key_y(ncch.key_y, &seed)
+    } else {
+        ncch.key_y
+    };
+
+    Ok(PartitionKeys {
+        base: derive_normal_key(key_x_2c, ncch.key_y, constant).to_be_bytes(),
+        main: derive_normal_key(key_x, main_key_y, constant).to_be_bytes(),
+    })
+}
+
+/// A single AES-CTR pass over a contiguous byte range of the image.
+#[derive(Debug, Clone)]
+pub(crate) struct CryptOp {
+    pub region: Region,
+    pub start: usize,
+    pub len: usize,
+    pub iv: u128,
+    pub key: Key128,
+}
+
+/// Locate the `.code` entry in a plaintext ExeFS filename table.
+/// Returns the file's offset (relative to the end of the header) and size.
+fn find_code_entry(table: &[u8]) -> Option<(u32, u32)> {
+    let header = ExefsHeader::from_bytes(table).ok()?;
+    header.find(".code").map(|entry| (entry.offset, entry.size))
+}
+
+/// Work out every AES-CTR pass needed to decrypt or encrypt one NCCH partition.
+///
+/// The ExeFS is split around `.code`, which is the only ExeFS file protected by the
+/// partition's main key; everything else in the ExeFS uses the slot 0x2C key. `table`
+/// is the plaintext ExeFS header, used to find `.code`.
+///
+/// Offsets are computed with checked arithmetic, but not checked against the image
+/// size; an overflow is reported as [`Error::RegionOutOfBounds`] for partition `p`.
+pub(crate) fn plan_partition(
+    p: u16,
+    part_off: usize,
+    sector_size: u32,
+    ncch: &NcchHeader,
+    keys: &PartitionKeys,
+    table: &[u8],
+) -> Result<Vec<CryptOp>, Error> {
+    let ss = sector_size as usize;
+    let mut ops = Vec::new();
+
+    if ncch.exheader_length > 0 {
+        ops.push(CryptOp {
+            region: Region::ExHeader,
+            start: offset_of(p, Region::ExHeader, part_off, 0x200, 1)?,
+            len: 0x800,
+            iv: ncch.plain_iv(),
+            key: keys.base,
+        });
+    }
+
+    if ncch.exefs_length > 0 {
+        let exefs_base = offset_of(p, Region::ExefsHeader, part_off, ncch.exefs_offset, ss)?;
+        let exefs_iv = ncch.exefs_iv();
+        ops.push(CryptOp {
+            region: Region::ExefsHeader,
+            start: exefs_base,
+            len: ss,
+            iv: exefs_iv,
+            key: keys.base,
+        });
+
+        let data_start = offset_of(p, Region::ExefsData, exefs_base, 1, ss)?;
+        let data_end = offset_of(p, Region::ExefsData, exefs_base, ncch.exefs_length, ss)?;
+        let (code_start, code_end) = match find_code_entry(table) {
+            Some((off, len)) if len > 0 => {
+                let start = data_start.saturating_add(off as usize).min(data_end);
+                (start, start.saturating_add(len as usize).min(data_end))
+            }
+            _ => (data_start, data_start),
+        };
+
+        let mut push_exefs = |region: Region, start: usize, end: usize, key: Key128| {
+            if end > start {
+                ops.push(CryptOp {
+                    region,
+                    start,
+                    len: end - start,
+                    iv: exefs_iv.wrapping_add((start - exefs_base) as u128 / 0x10),
+                    key,
+                });
+            }
+        };
+        push_exefs(Region::Code, code_start, code_end, keys.main);
+        push_exefs(Region::ExefsData, data_start, code_start, keys.base);
+        push_exefs(Region::ExefsData, code_end, data_end, keys.base);
+    }
+
+    if ncch.romfs_offset != 0 {
+        let start = offset_of(p, Region::RomFs, part_off, ncch.romfs_offset, ss)?;
+        let len = offset_of(p, Region::RomFs, 0, ncch.romfs_length, ss)?;
+        start.checked_add(len).ok_or(Error::RegionOutOfBounds {
+            partition: p,
+            region: Region::RomFs,
+        })?;
+        ops.push(CryptOp {
+            region: Region::RomFs,
+            start,
+            len,
+            iv: ncch.romfs_iv(),
+            key: keys.main,
+        });
+    }
+
+    Ok(ops)
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::ncch::NcchHeader;
 
@@ -352,6 +1309,10 @@ mod tests {
         NcchHeader {
             key_y: 0,
             title_id: 0x0004000000055D00,
+            seed_check: [0u8; 4],
+            program_id: 0x0004000000055D00,
+            product_code: [0u8; 16],
+            exheader_hash: [0u8; 32],
             partition_flags: [0u8; 8],
             exheader_length: 0,
             plain_offset: 0,
@@ -360,8 +1321,12 @@ mod tests {
             logo_length: 0,
             exefs_offset,
             exefs_length,
+            exefs_hash_region_size: 0,
             romfs_offset: 0,
             romfs_length: 0,
+            romfs_hash_region_size: 0,
+            exefs_superblock_hash: [0u8; 32],
+            romfs_superblock_hash: [0u8; 32],
         }
     }
 
@@ -418,6 +1383,44 @@ mod tests {
         ));
     }
 
+    /// A matching ExHeader hash proves plaintext even when the ASCII heuristic fails.
+    #[test]
+    fn test_is_content_decrypted_with_exheader_hash() {
+        use sha2::{Digest, Sha256};
+
+        let sector_size = 0x200u32;
+        let part_offset = 0usize;
+        let mut ncch = make_ncch(0, 0);
+        ncch.exheader_length = 0x400;
+
+        let mut data = vec![0u8; 0x1000];
+        data[0x200..0x600].fill(0xFF);
+        ncch.exheader_hash = Sha256::digest(&data[0x200..0x600]).into();
+        assert!(is_content_decrypted(&data, &ncch, sector_size, part_offset));
+
+        data[0x300] = 0;
+        assert!(!is_content_decrypted(
+            &data,
+            &ncch,
+            sector_size,
+            part_offset
+        ));
+    }
+
+    /// A recorded hash that does not match outweighs an ExeFS filename table that
+    /// happens to look like ASCII.
+    #[test]
+    fn test_is_content_decrypted_trusts_mismatching_hash_over_names() {
+        let sector_size = 0x200u32;
+        let exefs_off = 4u32;
+        let mut ncch = make_ncch(exefs_off, 2);
+        ncch.exefs_hash_region_size = 1;
+        ncch.exefs_superblock_hash = [0x5A; 32];
+
+        let data = build_exefs_data(0, sector_size, exefs_off, b"%IuHL {V");
+        assert!(!is_content_decrypted(&data, &ncch, sector_size, 0));
+    }
+
     /// NCCH with exefs_length == 0 → can't determine, return false (proceed with decryption).
     #[test]
     fn test_is_content_decrypted_no_exefs() {
@@ -553,6 +1556,618 @@ mod tests {
         );
     }
 
+    /// Out-of-place decryption writes a decrypted copy and never modifies the input.
+    #[test]
+    fn test_decrypt_rom_to_leaves_input_untouched() {
+        use std::io::Write;
+
+        let sector_size = 0x200u32;
+        let part_sector: u32 = 1;
+        let part_offset = part_sector as usize * sector_size as usize;
+        let exefs_off_sectors: u32 = 4;
+        let exefs_len_sectors: u32 = 2;
+
+        let total_size = part_offset
+            + (exefs_off_sectors as usize + exefs_len_sectors as usize) * sector_size as usize;
+        let mut rom = vec![0u8; total_size];
+
+        // --- NCSD header ---
+        rom[0x100..0x104].copy_from_slice(b"NCSD");
+        let part_len = exefs_off_sectors + exefs_len_sectors + 1;
+        rom[0x120..0x124].copy_from_slice(&part_sector.to_le_bytes());
+        rom[0x124..0x128].copy_from_slice(&part_len.to_le_bytes());
+
+        // --- NCCH header at partition: FixedKey (zero-key), encrypted ---
+        let title_id = 0x0004000000055D00u64;
+        rom[part_offset + 0x100..part_offset + 0x104].copy_from_slice(b"NCCH");
+        rom[part_offset + 0x108..part_offset + 0x110].copy_from_slice(&title_id.to_le_bytes());
+        rom[part_offset + 0x18F] = 0x01;
+        rom[part_offset + 0x1A0..part_offset + 0x1A4]
+            .copy_from_slice(&exefs_off_sectors.to_le_bytes());
+        rom[part_offset + 0x1A4..part_offset + 0x1A8]
+            .copy_from_slice(&exefs_len_sectors.to_le_bytes());
+
+        // --- ExeFS filename table encrypted with the zero key ---
+        let exefs_base = part_offset + exefs_off_sectors as usize * sector_size as usize;
+        let exefs_end = exefs_base + exefs_len_sectors as usize * sector_size as usize;
+        rom[exefs_base..exefs_base + 8].copy_from_slice(b".code\x00\x00\x00");
+        let exefs_iv = ((title_id as u128) << 64) | 0x0200_0000_0000_0000u128;
+        aes_ctr_decrypt(&[0u8; 16], exefs_iv, &mut rom[exefs_base..exefs_end]);
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let in_path = tmp_dir.join("temp_out_of_place_in.3ds");
+        let out_path = tmp_dir.join("temp_out_of_place_out.3ds");
+        {
+            let mut f = std::fs::File::create(&in_path).expect("create temp file");
+            f.write_all(&rom).expect("write temp file");
+        }
+
+        let result = decrypt_rom_to(&in_path, &out_path, &make_test_keydb(), |_| {});
+
+        let input_after = std::fs::read(&in_path).expect("read input");
+        let output = std::fs::read(&out_path).expect("read output");
+        let tmp_exists = temp_path_for(&out_path).exists();
+        let _ = std::fs::remove_file(&in_path);
+        let _ = std::fs::remove_file(&out_path);
+
+        assert!(result.is_ok(), "decrypt_rom_to failed: {:?}", result.err());
+        assert_eq!(input_after, rom, "input ROM was modified");
+        assert!(!tmp_exists, "temporary file left behind");
+        assert_eq!(&output[exefs_base..exefs_base + 8], b".code\x00\x00\x00");
+        assert!(output[exefs_base + 8..exefs_end].iter().all(|&b| b == 0));
+        assert_eq!(output[part_offset + 0x18F] & 0x04, 0x04);
+    }
+
+    /// Helper: a decrypted (NoCrypto) single-partition ROM with an ExHeader, an ExeFS
+    /// holding `.code` and `banner`, and a RomFS. Returns the image and partition offset.
+    pub(crate) fn build_decrypted_rom() -> (Vec<u8>, usize) {
+        let ss = 0x200usize;
+        let part_offset = 0x4000usize;
+        let exefs_off_sectors = 6u32;
+        let exefs_len_sectors = 5u32;
+        let romfs_off_sectors = 12u32;
+        let romfs_len_sectors = 4u32;
+        let part_len = romfs_off_sectors + romfs_len_sectors;
+        let mut rom = vec![0u8; part_offset + part_len as usize * ss];
+
+        // --- NCSD header ---
+        rom[0x100..0x104].copy_from_slice(b"NCSD");
+        rom[0x120..0x124].copy_from_slice(&((part_offset / ss) as u32).to_le_bytes());
+        rom[0x124..0x128].copy_from_slice(&part_len.to_le_bytes());
+
+        // --- NCCH header ---
+        let p = part_offset;
+        rom[p..p + 16].copy_from_slice(&0x0123456789ABCDEF0123456789ABCDEFu128.to_be_bytes());
+        rom[p + 0x100..p + 0x104].copy_from_slice(b"NCCH");
+        rom[p + 0x108..p + 0x110].copy_from_slice(&0x0004000000055D00u64.to_le_bytes());
+        rom[p + 0x180..p + 0x184].copy_from_slice(&0x400u32.to_le_bytes());
+        rom[p + 0x18F] = 0x04;
+        rom[p + 0x1A0..p + 0x1A4].copy_from_slice(&exefs_off_sectors.to_le_bytes());
+        rom[p + 0x1A4..p + 0x1A8].copy_from_slice(&exefs_len_sectors.to_le_bytes());
+        rom[p + 0x1B0..p + 0x1B4].copy_from_slice(&romfs_off_sectors.to_le_bytes());
+        rom[p + 0x1B4..p + 0x1B8].copy_from_slice(&romfs_len_sectors.to_le_bytes());
+
+        // --- ExHeader ---
+        rom[p + 0x200..p + 0x208].copy_from_slice(b"CtrApp\x00\x00");
+
+        // --- ExeFS: .code (3 sectors) then banner (1 sector) ---
+        let exefs = p + exefs_off_sectors as usize * ss;
+        rom[exefs..exefs + 8].copy_from_slice(b".code\x00\x00\x00");
+        rom[exefs + 8..exefs + 12].copy_from_slice(&0u32.to_le_bytes());
+        rom[exefs + 12..exefs + 16].copy_from_slice(&0x600u32.to_le_bytes());
+        rom[exefs + 0x10..exefs + 0x18].copy_from_slice(b"banner\x00\x00");
+        rom[exefs + 0x18..exefs + 0x1C].copy_from_slice(&0x600u32.to_le_bytes());
+        rom[exefs + 0x1C..exefs + 0x20].copy_from_slice(&0x200u32.to_le_bytes());
+        for (i, b) in rom[exefs + ss..exefs + 5 * ss].iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+
+        // --- RomFS ---
+        let romfs = p + romfs_off_sectors as usize * ss;
+        for (i, b) in rom[romfs..romfs + 4 * ss].iter_mut().enumerate() {
+            *b = (i % 239) as u8;
+        }
+
+        (rom, part_offset)
+    }
+
+    pub(crate) fn make_7x_keydb() -> KeyDatabase {
+        use std::io::Cursor;
+        let keys_text = "\
+generator=FEDCBA9876543210FEDCBA9876543210
+slot0x2CKeyX=00000000000000000000000000000001
+slot0x25KeyX=0000000000000000000000000000FF02
+";
+        KeyDatabase::from_reader(Cursor::new(keys_text)).unwrap()
+    }
+
+    /// Re-encrypting a decrypted ROM restores the crypto flags, uses the main key for
+    /// `.code`, and decrypting it again yields a byte-identical image.
+    #[test]
+    fn test_encrypt_then_decrypt_roundtrip() {
+        use std::io::Write;
+
+        let (rom, p) = build_decrypted_rom();
+        let keydb = make_7x_keydb();
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let tmp_path = tmp_dir.join("temp_encrypt_roundtrip.3ds");
+        {
+            let mut f = std::fs::File::create(&tmp_path).expect("create temp file");
+            f.write_all(&rom).expect("write temp file");
+        }
+
+        let enc_result = encrypt_rom(&tmp_path, &keydb, CryptoMethod::Key7x, |_| {});
+        let encrypted = std::fs::read(&tmp_path).expect("read encrypted");
+        let dec_result = decrypt_rom(&tmp_path, &keydb, |_| {});
+        let decrypted = std::fs::read(&tmp_path).expect("read decrypted");
+        let _ = std::fs::remove_file(&tmp_path);
+
+        assert!(
+            enc_result.is_ok(),
+            "encrypt_rom failed: {:?}",
+            enc_result.err()
+        );
+        assert!(
+            dec_result.is_ok(),
+            "decrypt_rom failed: {:?}",
+            dec_result.err()
+        );
+
+        assert_eq!(encrypted[p + 0x18B], 0x01, "crypto method not restored");
+        assert_eq!(encrypted[p + 0x18F] & 0x04, 0, "NoCrypto bit still set");
+
+        // .code must be encrypted with the Key7x normal key alone
+        let ncch = NcchHeader::parse(&mut Cursor::new(&encrypted), p as u64).unwrap();
+        let key_y = ncch.key_y;
+        let main = derive_normal_key(0xFF02, key_y, 0xFEDCBA9876543210FEDCBA9876543210);
+        let code = p + 7 * 0x200;
+        let mut code_bytes = encrypted[code..code + 0x600].to_vec();
+        aes_ctr_decrypt(&main.to_be_bytes(), ncch.exefs_iv() + 0x20, &mut code_bytes);
+        assert_eq!(code_bytes, rom[code..code + 0x600]);
+
+        assert_eq!(decrypted, rom, "round-trip was not byte-identical");
+    }
+
+    /// A wrong KeyX is caught by the trial decryption against the NCCH header hashes,
+    /// before any byte of the image is changed.
+    #[test]
+    fn test_key_mismatch_leaves_image_unchanged() {
+        use sha2::{Digest, Sha256};
+
+        let (mut rom, p) = build_decrypted_rom();
+        let hash = Sha256::digest(&rom[p + 0x200..p + 0x600]);
+        rom[p + 0x160..p + 0x180].copy_from_slice(&hash);
+        let exefs = p + 6 * 0x200;
+        rom[p + 0x1A8..p + 0x1AC].copy_from_slice(&1u32.to_le_bytes());
+        let hash = Sha256::digest(&rom[exefs..exefs + 0x200]);
+        rom[p + 0x1C0..p + 0x1E0].copy_from_slice(&hash);
+        let romfs = p + 12 * 0x200;
+        rom[p + 0x1B8..p + 0x1BC].copy_from_slice(&1u32.to_le_bytes());
+        let hash = Sha256::digest(&rom[romfs..romfs + 0x200]);
+        rom[p + 0x1E0..p + 0x200].copy_from_slice(&hash);
+
+        let keydb = make_7x_keydb();
+        let mut encrypted = rom.clone();
+        encrypt_image(&mut encrypted, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+
+        let wrong_keys = |line: &str| {
+            let text = format!(
+                "generator=FEDCBA9876543210FEDCBA9876543210\n\
+                 slot0x2CKeyX=00000000000000000000000000000001\n\
+                 slot0x25KeyX=0000000000000000000000000000FF02\n{line}\n"
+            );
+            KeyDatabase::from_reader(Cursor::new(text)).unwrap()
+        };
+        for (line, region) in [
+            (
+                "slot0x2CKeyX=00000000000000000000000000000002",
+                Region::ExHeader,
+            ),
+            (
+                "slot0x25KeyX=0000000000000000000000000000FF03",
+                Region::RomFs,
+            ),
+        ] {
+            let mut image = encrypted.clone();
+            let err = decrypt_image(
+                &mut image,
+                &wrong_keys(line),
+                &CancelToken::new(),
+                &mut |_| {},
+            )
+            .unwrap_err();
+            assert!(
+                matches!(err, Error::KeyMismatch { partition: 0, region: r } if r == region),
+                "unexpected error: {err:?}"
+            );
+            assert!(image == encrypted, "image modified despite key mismatch");
+        }
+
+        let mut image = encrypted.clone();
+        decrypt_image(&mut image, &keydb, &CancelToken::new(), &mut |_| {}).unwrap();
+        assert!(image == rom);
+    }
+
+    /// A decryption killed part-way leaves a journal behind, from which the ROM can be
+    /// rolled back to its encrypted bytes or the decryption resumed to completion.
+    #[test]
+    fn test_interrupted_decrypt_rom_rolls_back_and_resumes() {
+        use crate::journal::tests::crash_after;
+
+        let (rom, _) = build_decrypted_rom();
+        let keydb = make_7x_keydb();
+        let mut encrypted = rom.clone();
+        encrypt_image(&mut encrypted, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let tmp_path = tmp_dir.join("temp_interrupted.3ds");
+        let journal_path = journal::journal_path(&tmp_path);
+        let _ = std::fs::remove_file(&journal_path);
+        std::fs::write(&tmp_path, &encrypted).unwrap();
+
+        crash_after(Some(2));
+        let result = decrypt_rom(&tmp_path, &keydb, |_| {});
+        crash_after(None);
+        assert!(result.is_err());
+        assert!(journal::is_pending(&tmp_path), "journal not left behind");
+        let err = encrypt_rom(&tmp_path, &keydb, CryptoMethod::Key7x, |_| {}).unwrap_err();
+        assert!(matches!(err, Error::Journal(_)), "{err:?}");
+
+        assert!(rollback_rom(&tmp_path, |_| {}).unwrap());
+        assert!(!journal::is_pending(&tmp_path));
+        assert!(
+            std::fs::read(&tmp_path).unwrap() == encrypted,
+            "rollback diverged"
+        );
+        assert!(!rollback_rom(&tmp_path, |_| {}).unwrap());
+
+        crash_after(Some(4));
+        assert!(decrypt_rom(&tmp_path, &keydb, |_| {}).is_err());
+        crash_after(None);
+        let messages = std::cell::RefCell::new(Vec::new());
+        decrypt_rom(&tmp_path, &make_test_keydb(), |m| {
+            messages.borrow_mut().push(m.to_string())
+        })
+        .unwrap();
+        let output = std::fs::read(&tmp_path).unwrap();
+        let _ = std::fs::remove_file(&tmp_path);
+
+        assert!(
+            messages
+                .borrow()
+                .iter()
+                .any(|m| m == "Resuming interrupted decryption...")
+        );
+        assert!(!journal::is_pending(&tmp_path));
+        assert!(output == rom, "resumed decryption diverged");
+    }
+
+    /// An interrupted encryption is only resumed by encrypting again: decrypting the ROM
+    /// refuses instead of finishing the encryption.
+    #[test]
+    fn test_interrupted_encrypt_rom_is_not_resumed_by_decrypt() {
+        use crate::journal::tests::crash_after;
+
+        let (rom, _) = build_decrypted_rom();
+        let keydb = make_7x_keydb();
+        let mut encrypted = rom.clone();
+        encrypt_image(&mut encrypted, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let tmp_path = tmp_dir.join("temp_interrupted_encrypt.3ds");
+        let _ = std::fs::remove_file(journal::journal_path(&tmp_path));
+        std::fs::write(&tmp_path, &rom).unwrap();
+
+        crash_after(Some(2));
+        let result = encrypt_rom(&tmp_path, &keydb, CryptoMethod::Key7x, |_| {});
+        crash_after(None);
+        assert!(result.is_err());
+        let interrupted = std::fs::read(&tmp_path).unwrap();
+
+        let decrypt = decrypt_rom(&tmp_path, &keydb, |_| {});
+        let repair = crate::repair::repair_rom(&tmp_path, &keydb, |_| {});
+        let after_refusal = std::fs::read(&tmp_path).unwrap();
+        let pending = journal::is_pending(&tmp_path);
+        let resume = encrypt_rom(&tmp_path, &keydb, CryptoMethod::Key7x, |_| {});
+        let output = std::fs::read(&tmp_path).unwrap();
+        let _ = std::fs::remove_file(&tmp_path);
+
+        for err in [decrypt.unwrap_err(), repair.unwrap_err()] {
+            assert!(
+                matches!(&err, Error::Journal(msg) if msg.contains("interrupted encryption")),
+                "{err:?}"
+            );
+        }
+        assert!(after_refusal == interrupted, "refused run modified the ROM");
+        assert!(pending, "journal removed by a refused run");
+        resume.unwrap();
+        assert!(!journal::is_pending(&tmp_path));
+        assert!(output == encrypted, "resumed encryption diverged");
+    }
+
+    /// Cancelling an in-place decryption part-way restores the original ROM.
+    #[test]
+    fn test_cancelled_decrypt_rom_is_rolled_back() {
+        let (rom, _) = build_decrypted_rom();
+        let keydb = make_7x_keydb();
+        let mut encrypted = rom.clone();
+        encrypt_image(&mut encrypted, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let tmp_path = tmp_dir.join("temp_cancelled.3ds");
+        let _ = std::fs::remove_file(journal::journal_path(&tmp_path));
+        std::fs::write(&tmp_path, &encrypted).unwrap();
+
+        let cancel = CancelToken::new();
+        let mut rolled_back = false;
+        let err = decrypt_rom_cancellable(&tmp_path, &keydb, &cancel, |event| match event {
+            ProgressEvent::Bytes { processed, .. } if *processed > 0 => cancel.cancel(),
+            ProgressEvent::RollingBack {
+                operation: Operation::Decrypt,
+            } => rolled_back = true,
+            _ => {}
+        })
+        .unwrap_err();
+        let output = std::fs::read(&tmp_path).unwrap();
+        let _ = std::fs::remove_file(&tmp_path);
+
+        assert!(matches!(err, Error::Cancelled), "{err:?}");
+        assert!(rolled_back);
+        assert!(!journal::is_pending(&tmp_path));
+        assert!(
+            output == encrypted,
+            "cancelled decryption was not rolled back"
+        );
+    }
+
+    /// Truncated ROMs and headers with absurd offsets are rejected with an error naming
+    /// the partition (and region), never a panic, and the image is left untouched.
+    #[test]
+    fn test_malformed_roms_are_rejected() {
+        let (rom, p) = build_decrypted_rom();
+        let keydb = make_7x_keydb();
+        let mut encrypted = rom.clone();
+        encrypt_image(&mut encrypted, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+        let decrypt = |image: &mut Vec<u8>| {
+            let before = image.clone();
+            let result = decrypt_image(image, &keydb, &CancelToken::new(), &mut |_| {});
+            assert!(
+                result.is_ok() || *image == before,
+                "failed decryption modified the image"
+            );
+            result
+        };
+
+        // RomFS cut short
+        let mut image = encrypted[..encrypted.len() - 0x200].to_vec();
+        let err = decrypt(&mut image).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::RegionOutOfBounds {
+                    partition: 0,
+                    region: Region::RomFs
+                }
+            ),
+            "{err:?}"
+        );
+
+        // Truncated inside the ExeFS header
+        let mut image = encrypted[..p + 6 * 0x200 + 0x100].to_vec();
+        let err = decrypt(&mut image).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::RegionOutOfBounds {
+                    partition: 0,
+                    region: Region::ExefsHeader
+                }
+            ),
+            "{err:?}"
+        );
+
+        // Partition table entry pointing past the end of the file
+        let mut image = encrypted.clone();
+        image[0x120..0x124].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = decrypt(&mut image).unwrap_err();
+        assert!(matches!(err, Error::PartitionOutOfBounds(0)), "{err:?}");
+
+        // Sector size exponent that overflows
+        let mut image = encrypted.clone();
+        image[0x18E] = 40;
+        assert!(matches!(decrypt(&mut image), Err(Error::NotNcsd)));
+
+        // NCCH media unit exponent that overflows
+        let mut image = encrypted.clone();
+        image[p + 0x18E] = 40;
+        let err = decrypt(&mut image).unwrap_err();
+        assert!(matches!(err, Error::InvalidNcch(0)), "{err:?}");
+
+        // Offsets and sizes at the top of the u32 range, with a huge media unit
+        for field in [0x1A0, 0x1A4, 0x1B0, 0x1B4, 0x1B8] {
+            for flags6 in [0, 22, 0xFF] {
+                let mut image = encrypted.clone();
+                image[p + field..p + field + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+                image[p + 0x18E] = flags6;
+                let _ = crate::repair::image_region_status(&image, &keydb);
+                let _ = decrypt(&mut image);
+            }
+        }
+    }
+
+    /// A bare NCCH (as in a `.cxi` or CDN `.app`) is detected and round-trips without
+    /// an NCSD wrapper.
+    #[test]
+    fn test_standalone_ncch_roundtrip() {
+        let (rom, p) = build_decrypted_rom();
+        let ncch = rom[p..].to_vec();
+        let keydb = make_7x_keydb();
+        assert_eq!(RomFormat::detect(&ncch), Some(RomFormat::Ncch));
+
+        let mut image = ncch.clone();
+        encrypt_image(&mut image, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+        assert_eq!(image[0x18B], 0x01);
+        assert_ne!(
+            image[0x200..0x600],
+            ncch[0x200..0x600],
+            "ExHeader not encrypted"
+        );
+
+        decrypt_ncch(&mut image, &keydb, |_| {}).unwrap();
+        assert_eq!(image, ncch, "round-trip was not byte-identical");
+    }
+
+    /// Seed-crypto partitions derive the method key from the seeded KeyY, and fail
+    /// without touching the image when the seed is missing or wrong.
+    #[test]
+    fn test_seed_crypto() {
+        use crate::seeddb::tests::{TEST_PROGRAM_ID, TEST_SEED, seed_check};
+        use crate::seeddb::{SeedDatabase, seeded_key_y};
+
+        let (mut rom, p) = build_decrypted_rom();
+        rom[p + 0x114..p + 0x118].copy_from_slice(&seed_check(&TEST_SEED, TEST_PROGRAM_ID));
+        rom[p + 0x118..p + 0x120].copy_from_slice(&TEST_PROGRAM_ID.to_le_bytes());
+        rom[p + 0x18F] |= 0x20;
+
+        let mut keydb = make_7x_keydb();
+        keydb.seeds_mut().insert(TEST_PROGRAM_ID, TEST_SEED);
+        let mut encrypted = rom.clone();
+        encrypt_image(&mut encrypted, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+
+        let ncch = NcchHeader::parse(&mut Cursor::new(&encrypted), p as u64).unwrap();
+        let main = derive_normal_key(
+            0xFF02,
+            seeded_key_y(ncch.key_y, &TEST_SEED),
+            0xFEDCBA9876543210FEDCBA9876543210,
+        );
+        let code = p + 7 * 0x200;
+        let mut code_bytes = encrypted[code..code + 0x600].to_vec();
+        aes_ctr_decrypt(&main.to_be_bytes(), ncch.exefs_iv() + 0x20, &mut code_bytes);
+        assert_eq!(code_bytes, rom[code..code + 0x600]);
+
+        let mut image = encrypted.clone();
+        let mut no_seeds = make_7x_keydb();
+        let err =
+            decrypt_image(&mut image, &no_seeds, &CancelToken::new(), &mut |_| {}).unwrap_err();
+        assert!(
+            matches!(err, Error::SeedNotFound(TEST_PROGRAM_ID)),
+            "{err:?}"
+        );
+        assert_eq!(image, encrypted, "image modified without a seed");
+
+        let mut wrong = SeedDatabase::new();
+        wrong.set_fallback([0x42; 16]);
+        no_seeds.set_seeds(wrong);
+        let err =
+            decrypt_image(&mut image, &no_seeds, &CancelToken::new(), &mut |_| {}).unwrap_err();
+        assert!(
+            matches!(err, Error::SeedMismatch(TEST_PROGRAM_ID)),
+            "{err:?}"
+        );
+
+        decrypt_image(&mut image, &keydb, &CancelToken::new(), &mut |_| {}).unwrap();
+        rom[p + 0x18F] &= !0x20;
+        assert_eq!(image, rom);
+    }
+
+    /// A CIA whose single content is title-key encrypted and holds a zero-key NCCH:
+    /// both layers are removed and the TMD record is marked unencrypted.
+    #[test]
+    fn test_decrypt_cia_removes_both_layers() {
+        use crate::cia::tests::build_test_cia;
+        use std::io::Write;
+
+        // Zero-key NCCH with an encrypted ExeFS filename table
+        let ss = 0x200usize;
+        let title_id = crate::cia::tests::TEST_TITLE_ID;
+        let mut ncch = vec![0u8; 6 * ss];
+        ncch[0x100..0x104].copy_from_slice(b"NCCH");
+        ncch[0x108..0x110].copy_from_slice(&title_id.to_le_bytes());
+        ncch[0x18F] = 0x01;
+        ncch[0x1A0..0x1A4].copy_from_slice(&4u32.to_le_bytes());
+        ncch[0x1A4..0x1A8].copy_from_slice(&2u32.to_le_bytes());
+        ncch[4 * ss..4 * ss + 8].copy_from_slice(b"icon\x00\x00\x00\x00");
+        let exefs_iv = ((title_id as u128) << 64) | 0x0200_0000_0000_0000u128;
+        aes_ctr_decrypt(&[0u8; 16], exefs_iv, &mut ncch[4 * ss..6 * ss]);
+
+        let title_key = [0x42u8; 16];
+        let common = 0x00112233445566778899AABBCCDDEEFFu128;
+        let cia_bytes = build_test_cia(&ncch, title_key, common.to_be_bytes(), true);
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let tmp_path = tmp_dir.join("temp_cia_decrypt.cia");
+        {
+            let mut f = std::fs::File::create(&tmp_path).expect("create temp file");
+            f.write_all(&cia_bytes).expect("write temp file");
+        }
+
+        let keys_text = format!("common0N={common:032X}\n");
+        let keydb = KeyDatabase::from_reader(Cursor::new(keys_text)).unwrap();
+        let result = decrypt_rom(&tmp_path, &keydb, |_| {});
+        let output = std::fs::read(&tmp_path).expect("read result");
+        let _ = std::fs::remove_file(&tmp_path);
+
+        assert!(result.is_ok(), "decrypt_rom failed: {:?}", result.err());
+
+        let cia = Cia::parse(&mut Cursor::new(&output)).unwrap();
+        let (chunk, offset) = cia.contents()[0];
+        assert!(!chunk.is_encrypted(), "TMD record still marked encrypted");
+
+        let content = &output[offset as usize..offset as usize + ncch.len()];
+        assert_eq!(&content[0x100..0x104], b"NCCH");
+        assert_eq!(content[0x18F] & 0x04, 0x04, "NoCrypto flag not set");
+        assert_eq!(&content[4 * ss..4 * ss + 8], b"icon\x00\x00\x00\x00");
+        assert!(content[4 * ss + 8..6 * ss].iter().all(|&b| b == 0));
+    }
+
+    /// CIA content indices above 255 (DLC) are reported in full, not truncated.
+    #[test]
+    fn test_decrypt_cia_reports_wide_content_index() {
+        use crate::cia::tests::build_test_cia;
+
+        let ss = 0x200usize;
+        let title_id = crate::cia::tests::TEST_TITLE_ID;
+        let mut ncch = vec![0u8; 6 * ss];
+        ncch[0x100..0x104].copy_from_slice(b"NCCH");
+        ncch[0x108..0x110].copy_from_slice(&title_id.to_le_bytes());
+        ncch[0x18F] = 0x01;
+        ncch[0x1A0..0x1A4].copy_from_slice(&4u32.to_le_bytes());
+        ncch[0x1A4..0x1A8].copy_from_slice(&2u32.to_le_bytes());
+        let exefs_iv = ((title_id as u128) << 64) | 0x0200_0000_0000_0000u128;
+        aes_ctr_decrypt(&[0u8; 16], exefs_iv, &mut ncch[4 * ss..6 * ss]);
+
+        // Move the single content from index 0 to index 0x101
+        let mut image = build_test_cia(&ncch, [0u8; 16], [0u8; 16], false);
+        let record = Cia::parse(&mut Cursor::new(&image))
+            .unwrap()
+            .tmd_chunk_record_offset(0);
+        image[record + 4..record + 6].copy_from_slice(&0x101u16.to_be_bytes());
+        image[0x20] = 0;
+        image[0x20 + 0x101 / 8] = 0x80 >> (0x101 % 8);
+
+        let mut partitions = Vec::new();
+        decrypt_image(
+            &mut image,
+            &make_7x_keydb(),
+            &CancelToken::new(),
+            &mut |event| {
+                if let ProgressEvent::RegionStart { partition, .. } = event {
+                    partitions.push(*partition);
+                }
+            },
+        )
+        .unwrap();
+        assert!(!partitions.is_empty());
+        assert!(partitions.iter().all(|&p| p == 0x101), "{partitions:?}");
+    }
+
     /// NoCrypto flag is set but ExeFS content is encrypted (random bytes).
     /// Verifies that decryption proceeds instead of blindly trusting the flag.
     #[test]
@@ -647,4 +2262,97 @@ mod tests {
             "NoCrypto flag should be set after decryption"
         );
     }
+
+    proptest::proptest! {
+        /// Parallel chunks, each starting from its own counter, must produce the same
+        /// keystream as a single pass, for any chunk size and across counter wraparound.
+        #[test]
+        fn prop_decrypt_slice_matches_single_pass(
+            key: [u8; 16],
+            iv in crate::crypto::tests::iv_strategy(),
+            chunk_blocks in 1usize..40,
+            data in proptest::collection::vec(proptest::prelude::any::<u8>(), 0..2048),
+        ) {
+            let mut chunked = data.clone();
+            decrypt_slice(&mut chunked, &key, iv, chunk_blocks * 16, &CancelToken::new()).unwrap();
+            let mut single = data;
+            aes_ctr_decrypt(&key, iv, &mut single);
+            proptest::prop_assert_eq!(chunked, single);
+        }
+    }
+
+    /// Write the seed corpus for the fuzz targets in `fuzz/`, along with the malformed
+    /// images of [`test_malformed_roms_are_rejected`] as regression inputs.
+    #[test]
+    #[ignore = "writes fuzz/corpus and fuzz/regressions; run with --ignored"]
+    fn write_fuzz_corpus() {
+        let fuzz = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../../fuzz");
+        let write = |dir: &str, name: &str, data: &[u8]| {
+            let dir = fuzz.join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(name), data).unwrap();
+        };
+
+        let (rom, p) = build_decrypted_rom();
+        let keydb = make_7x_keydb();
+        let mut encrypted = rom.clone();
+        encrypt_image(&mut encrypted, &keydb, CryptoMethod::Key7x, &mut |_| {}).unwrap();
+        let common = 0x00112233445566778899AABBCCDDEEFFu128;
+        let cia = crate::cia::tests::build_test_cia(
+            &encrypted[p..],
+            [0x42u8; 16],
+            common.to_be_bytes(),
+            true,
+        );
+
+        for (name, image) in [
+            ("decrypted.3ds", &rom[..]),
+            ("key7x.3ds", &encrypted[..]),
+            ("key7x.cxi", &encrypted[p..]),
+            ("key7x.cia", &cia[..]),
+        ] {
+            write("corpus/decrypt_image", name, image);
+        }
+        write("corpus/ncsd_header", "decrypted.3ds", &rom[..0x200]);
+        write("corpus/ncsd_header", "key7x.3ds", &encrypted[..0x200]);
+        write("corpus/ncch_header", "decrypted.cxi", &rom[p..]);
+        write("corpus/ncch_header", "key7x.cxi", &encrypted[p..]);
+        write(
+            "corpus/keydb",
+            "aes_keys.txt",
+            b"\xEF\xBB\xBF# comment\ngenerator=FEDCBA9876543210FEDCBA9876543210\n\
+              slot0x2CKeyX=00000000000000000000000000000001\n\
+              slot0x25KeyX=0000000000000000000000000000FF02\n\
+              common0N=00112233445566778899AABBCCDDEEFF\n",
+        );
+
+        let truncated = &encrypted[..encrypted.len() - 0x200];
+        write(
+            "regressions/decrypt_image",
+            "romfs-truncated.3ds",
+            truncated,
+        );
+        let mut image = encrypted.clone();
+        image[0x120..0x124].copy_from_slice(&u32::MAX.to_le_bytes());
+        write(
+            "regressions/decrypt_image",
+            "partition-past-eof.3ds",
+            &image,
+        );
+        let mut image = encrypted.clone();
+        image[0x18E] = 40;
+        write(
+            "regressions/ncsd_header",
+            "sector-size-overflow.3ds",
+            &image[..0x200],
+        );
+        let mut image = encrypted.clone();
+        image[p + 0x1B0..p + 0x1B8].copy_from_slice(&[0xFF; 8]);
+        image[p + 0x18E] = 22;
+        write(
+            "regressions/decrypt_image",
+            "romfs-offset-overflow.3ds",
+            &image,
+        );
+    }
 }
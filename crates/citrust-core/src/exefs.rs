@@ -0,0 +1,265 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::decrypt::Error;
+use crate::format;
+
+/// Size of the ExeFS header: file table, reserved area and hash table.
+pub const EXEFS_HEADER_SIZE: usize = 0x200;
+
+/// Number of file slots in the ExeFS header.
+const MAX_ENTRIES: usize = 10;
+
+/// One file in the ExeFS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExefsEntry {
+    /// File name, e.g. `.code`, `icon`, `banner` or `logo`.
+    pub name: String,
+    /// Offset of the file data, relative to the end of the ExeFS header.
+    pub offset: u32,
+    pub size: u32,
+    /// SHA-256 of the file data.
+    pub hash: [u8; 32],
+}
+
+impl ExefsEntry {
+    /// Offset of the file data relative to the start of the ExeFS.
+    pub fn data_offset(&self) -> u64 {
+        EXEFS_HEADER_SIZE as u64 + self.offset as u64
+    }
+
+    /// File name used when extracting to disk (`.code` becomes `code.bin`).
+    pub fn file_name(&self) -> io::Result<String> {
+        file_name(&self.name)
+    }
+
+    /// Slice the file data out of an in-memory ExeFS image.
+    pub fn data<'a>(&self, exefs: &'a [u8]) -> Option<&'a [u8]> {
+        let start = usize::try_from(self.data_offset()).ok()?;
+        exefs.get(start..start.checked_add(self.size as usize)?)
+    }
+}
+
+/// The ExeFS file table and its hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExefsHeader {
+    entries: Vec<ExefsEntry>,
+}
+
+impl ExefsHeader {
+    /// Parse a header from the first 0x200 bytes of `data`.
+    ///
+    /// Hashes are stored in reverse order at the end of the header: entry 0's hash is
+    /// the last 0x20 bytes.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let Some(data) = data.get(..EXEFS_HEADER_SIZE) else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "ExeFS header shorter than 0x200 bytes",
+            ));
+        };
+
+        let mut entries = Vec::new();
+        for (i, entry) in data[..MAX_ENTRIES * 0x10].chunks_exact(0x10).enumerate() {
+            if entry[..8].iter().all(|&b| b == 0) {
+                continue;
+            }
+            let end = entry[..8].iter().position(|&b| b == 0).unwrap_or(8);
+            let hash_off = EXEFS_HEADER_SIZE - (i + 1) * 0x20;
+            entries.push(ExefsEntry {
+                name: String::from_utf8_lossy(&entry[..end]).into_owned(),
+                offset: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+                size: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+                hash: data[hash_off..hash_off + 0x20].try_into().unwrap(),
+            });
+        }
+
+        Ok(ExefsHeader { entries })
+    }
+
+    /// Parse the header of the ExeFS located at `exefs_offset`.
+    pub fn parse<R: Read + Seek>(reader: &mut R, exefs_offset: u64) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(exefs_offset))?;
+        let mut data = [0u8; EXEFS_HEADER_SIZE];
+        reader.read_exact(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
+    /// Iterate over the files present in the ExeFS, in table order.
+    pub fn entries(&self) -> impl Iterator<Item = &ExefsEntry> {
+        self.entries.iter()
+    }
+
+    /// Look up a file by name.
+    pub fn find(&self, name: &str) -> Option<&ExefsEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Read a file of the ExeFS located at `exefs_offset` into a buffer.
+    pub fn read_file<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        exefs_offset: u64,
+        name: &str,
+    ) -> io::Result<Vec<u8>> {
+        let entry = self.find(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("ExeFS has no file named \"{name}\""),
+            )
+        })?;
+        reader.seek(SeekFrom::Start(exefs_offset + entry.data_offset()))?;
+        let mut data = vec![0u8; entry.size as usize];
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Write one file of the ExeFS located at `exefs_offset` to `path`.
+    pub fn extract_file<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        exefs_offset: u64,
+        name: &str,
+        path: &Path,
+    ) -> io::Result<()> {
+        let data = self.read_file(reader, exefs_offset, name)?;
+        fs::write(path, data)
+    }
+
+    /// Write every file of the ExeFS located at `exefs_offset` into `dest`, named after
+    /// its entry (`.code` becomes `code.bin`). Returns the paths written.
+    pub fn extract_all<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        exefs_offset: u64,
+        dest: &Path,
+    ) -> io::Result<Vec<PathBuf>> {
+        fs::create_dir_all(dest)?;
+        let mut written = Vec::new();
+        for entry in &self.entries {
+            let data = self.read_file(reader, exefs_offset, &entry.name)?;
+            let path = dest.join(entry.file_name()?);
+            fs::write(&path, data)?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+}
+
+/// Locate and parse the ExeFS of a decrypted ROM's main NCCH.
+/// Returns the header and the absolute offset of the ExeFS.
+pub fn locate<R: Read + Seek>(reader: &mut R) -> Result<(ExefsHeader, u64), Error> {
+    let (ncch, ncch_offset) = format::main_ncch(reader)?;
+    if !ncch.is_no_crypto() {
+        return Err(Error::NotDecrypted);
+    }
+    if ncch.exefs_length == 0 {
+        return Err(Error::NoExefs);
+    }
+    let exefs_offset =
+        ncch_offset.saturating_add(ncch.exefs_offset as u64 * ncch.media_unit_size() as u64);
+    let header = ExefsHeader::parse(reader, exefs_offset)?;
+    Ok((header, exefs_offset))
+}
+
+/// Map an ExeFS entry name to a safe file name on disk.
+fn file_name(name: &str) -> io::Result<String> {
+    if name.is_empty()
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || !name.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsafe ExeFS file name \"{name}\""),
+        ));
+    }
+    Ok(match name.strip_prefix('.') {
+        Some(stem) => format!("{stem}.bin"),
+        None => format!("{name}.bin"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn build_exefs() -> Vec<u8> {
+        let mut exefs = vec![0u8; EXEFS_HEADER_SIZE + 0x400];
+        let files: [(&[u8], u32, u32, u8); 2] =
+            [(b".code", 0, 0x300, 0xC0), (b"icon", 0x300, 0x100, 0x1C)];
+        for (i, (name, offset, size, fill)) in files.into_iter().enumerate() {
+            let entry = &mut exefs[i * 0x10..(i + 1) * 0x10];
+            entry[..name.len()].copy_from_slice(name);
+            entry[8..12].copy_from_slice(&offset.to_le_bytes());
+            entry[12..16].copy_from_slice(&size.to_le_bytes());
+            let hash_off = EXEFS_HEADER_SIZE - (i + 1) * 0x20;
+            exefs[hash_off..hash_off + 0x20].fill(fill);
+            let data = EXEFS_HEADER_SIZE + offset as usize;
+            exefs[data..data + size as usize].fill(fill);
+        }
+        exefs
+    }
+
+    #[test]
+    fn test_parse_entries_and_reversed_hashes() {
+        let header = ExefsHeader::from_bytes(&build_exefs()).unwrap();
+        let entries: Vec<_> = header.entries().collect();
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].name, ".code");
+        assert_eq!(entries[0].offset, 0);
+        assert_eq!(entries[0].size, 0x300);
+        assert_eq!(entries[0].hash, [0xC0; 32]);
+
+        assert_eq!(entries[1].name, "icon");
+        assert_eq!(entries[1].data_offset(), 0x500);
+        assert_eq!(entries[1].hash, [0x1C; 32]);
+
+        assert!(header.find("banner").is_none());
+    }
+
+    #[test]
+    fn test_read_file() {
+        let exefs = build_exefs();
+        let mut image = vec![0u8; 0x1000];
+        image.extend_from_slice(&exefs);
+        let mut cursor = Cursor::new(image);
+
+        let header = ExefsHeader::parse(&mut cursor, 0x1000).unwrap();
+        let icon = header.read_file(&mut cursor, 0x1000, "icon").unwrap();
+        assert_eq!(icon, vec![0x1C; 0x100]);
+        assert_eq!(header.find("icon").unwrap().data(&exefs), Some(&icon[..]));
+
+        let err = header.read_file(&mut cursor, 0x1000, "logo").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let exefs = build_exefs();
+        let header = ExefsHeader::from_bytes(&exefs).unwrap();
+        let dest = PathBuf::from("test-fixtures").join("exefs_extract");
+        let _ = fs::remove_dir_all(&dest);
+
+        let written = header
+            .extract_all(&mut Cursor::new(&exefs), 0, &dest)
+            .unwrap();
+        let code = fs::read(dest.join("code.bin")).unwrap();
+        let icon = fs::read(dest.join("icon.bin")).unwrap();
+        let _ = fs::remove_dir_all(&dest);
+
+        assert_eq!(written.len(), 2);
+        assert_eq!(code, vec![0xC0; 0x300]);
+        assert_eq!(icon, vec![0x1C; 0x100]);
+    }
+
+    #[test]
+    fn test_rejects_unsafe_names() {
+        assert!(file_name("../x").is_err());
+        assert!(file_name("..").is_err());
+        assert_eq!(file_name("banner").unwrap(), "banner.bin");
+    }
+}
@@ -0,0 +1,758 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Size of the extended header including the access descriptor.
+pub const EXHEADER_SIZE: usize = 0x800;
+
+/// Number of dependency title IDs in the System Control Info.
+const MAX_DEPENDENCIES: usize = 48;
+/// Number of service names (regular + extended access control).
+const MAX_SERVICES: usize = 34;
+/// Number of ARM11 kernel capability descriptors.
+const MAX_KERNEL_DESCRIPTORS: usize = 28;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Decode a NUL-padded ASCII name.
+fn read_name(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Encode a name into a NUL-padded field, rejecting names that do not fit. A field
+/// that already holds `name` is left as is, padding included.
+fn write_name(out: &mut [u8], name: &str, what: &str) -> io::Result<()> {
+    if read_name(out) == name {
+        return Ok(());
+    }
+    if name.len() > out.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{what} \"{name}\" is longer than {} bytes", out.len()),
+        ));
+    }
+    out.fill(0);
+    out[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(())
+}
+
+fn too_many(what: &str, max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("too many {what} (maximum {max})"),
+    )
+}
+
+/// Load address and size of one code segment (`.text`, `.rodata` or `.data`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodeSegment {
+    pub address: u32,
+    /// Size in 0x1000-byte pages of the physical region.
+    pub num_pages: u32,
+    /// Size in bytes.
+    pub size: u32,
+}
+
+impl CodeSegment {
+    fn parse(data: &[u8]) -> Self {
+        CodeSegment {
+            address: read_u32(data, 0x0),
+            num_pages: read_u32(data, 0x4),
+            size: read_u32(data, 0x8),
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        out[0x0..0x4].copy_from_slice(&self.address.to_le_bytes());
+        out[0x4..0x8].copy_from_slice(&self.num_pages.to_le_bytes());
+        out[0x8..0xC].copy_from_slice(&self.size.to_le_bytes());
+    }
+}
+
+/// System Control Info: code set layout, dependencies and save data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemControlInfo {
+    /// Code set name (at most 8 bytes).
+    pub name: String,
+    /// SCI flags: bit 0 is compressed `.code`, bit 1 is SD application.
+    pub flags: u8,
+    pub remaster_version: u16,
+    pub text: CodeSegment,
+    pub stack_size: u32,
+    pub ro: CodeSegment,
+    pub data: CodeSegment,
+    pub bss_size: u32,
+    /// Title IDs of the modules this title depends on (at most 48).
+    pub dependencies: Vec<u64>,
+    pub save_data_size: u64,
+    pub jump_id: u64,
+}
+
+impl SystemControlInfo {
+    /// Whether the ExeFS `.code` is BLZ-compressed.
+    pub fn is_code_compressed(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// Whether the title is installed to the SD card.
+    pub fn is_sd_application(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    /// Non-zero dependency slots, in order.
+    fn parse_dependencies(data: &[u8]) -> Vec<u64> {
+        (0..MAX_DEPENDENCIES)
+            .map(|i| read_u64(data, 0x40 + i * 8))
+            .filter(|&id| id != 0)
+            .collect()
+    }
+
+    fn parse(data: &[u8]) -> Self {
+        let dependencies = Self::parse_dependencies(data);
+        SystemControlInfo {
+            name: read_name(&data[0x00..0x08]),
+            flags: data[0x0D],
+            remaster_version: read_u16(data, 0x0E),
+            text: CodeSegment::parse(&data[0x10..0x1C]),
+            stack_size: read_u32(data, 0x1C),
+            ro: CodeSegment::parse(&data[0x20..0x2C]),
+            data: CodeSegment::parse(&data[0x30..0x3C]),
+            bss_size: read_u32(data, 0x3C),
+            dependencies,
+            save_data_size: read_u64(data, 0x1C0),
+            jump_id: read_u64(data, 0x1C8),
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) -> io::Result<()> {
+        if self.dependencies.len() > MAX_DEPENDENCIES {
+            return Err(too_many("dependencies", MAX_DEPENDENCIES));
+        }
+        write_name(&mut out[0x00..0x08], &self.name, "code set name")?;
+        out[0x0D] = self.flags;
+        out[0x0E..0x10].copy_from_slice(&self.remaster_version.to_le_bytes());
+        self.text.write(&mut out[0x10..0x1C]);
+        out[0x1C..0x20].copy_from_slice(&self.stack_size.to_le_bytes());
+        self.ro.write(&mut out[0x20..0x2C]);
+        self.data.write(&mut out[0x30..0x3C]);
+        out[0x3C..0x40].copy_from_slice(&self.bss_size.to_le_bytes());
+        // Rewrite the table only if it changed, so empty slots between entries survive
+        if Self::parse_dependencies(out) != self.dependencies {
+            out[0x40..0x40 + MAX_DEPENDENCIES * 8].fill(0);
+            for (i, id) in self.dependencies.iter().enumerate() {
+                out[0x40 + i * 8..0x48 + i * 8].copy_from_slice(&id.to_le_bytes());
+            }
+        }
+        out[0x1C0..0x1C8].copy_from_slice(&self.save_data_size.to_le_bytes());
+        out[0x1C8..0x1D0].copy_from_slice(&self.jump_id.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Save data and extdata access rights.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageInfo {
+    pub extdata_id: u64,
+    pub system_savedata_ids: [u32; 2],
+    pub accessible_unique_ids: u64,
+    /// Filesystem access flags (56 bits).
+    pub fs_access: u64,
+    pub other_attributes: u8,
+}
+
+impl StorageInfo {
+    fn parse(data: &[u8]) -> Self {
+        let access = read_u64(data, 0x18);
+        StorageInfo {
+            extdata_id: read_u64(data, 0x00),
+            system_savedata_ids: [read_u32(data, 0x08), read_u32(data, 0x0C)],
+            accessible_unique_ids: read_u64(data, 0x10),
+            fs_access: access & 0x00FF_FFFF_FFFF_FFFF,
+            other_attributes: (access >> 56) as u8,
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        out[0x00..0x08].copy_from_slice(&self.extdata_id.to_le_bytes());
+        out[0x08..0x0C].copy_from_slice(&self.system_savedata_ids[0].to_le_bytes());
+        out[0x0C..0x10].copy_from_slice(&self.system_savedata_ids[1].to_le_bytes());
+        out[0x10..0x18].copy_from_slice(&self.accessible_unique_ids.to_le_bytes());
+        let access =
+            (self.fs_access & 0x00FF_FFFF_FFFF_FFFF) | ((self.other_attributes as u64) << 56);
+        out[0x18..0x20].copy_from_slice(&access.to_le_bytes());
+    }
+}
+
+/// ARM11 local system capabilities: scheduling, memory mode, storage and services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arm11LocalCaps {
+    pub program_id: u64,
+    pub core_version: u32,
+    /// New 3DS: enable the L2 cache.
+    pub enable_l2_cache: bool,
+    /// New 3DS: run at 804 MHz.
+    pub high_cpu_speed: bool,
+    /// New 3DS system memory mode.
+    pub new3ds_system_mode: u8,
+    pub ideal_processor: u8,
+    pub affinity_mask: u8,
+    /// Old 3DS system memory mode.
+    pub system_mode: u8,
+    pub priority: u8,
+    pub resource_limits: [u16; 16],
+    pub storage: StorageInfo,
+    /// Accessible services, e.g. `fs:USER` (at most 34, each at most 8 bytes).
+    pub services: Vec<String>,
+    pub resource_limit_category: u8,
+}
+
+impl Arm11LocalCaps {
+    /// Non-empty service slots, in order.
+    fn parse_services(data: &[u8]) -> Vec<String> {
+        data[0x50..0x160]
+            .chunks_exact(8)
+            .map(read_name)
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    fn parse(data: &[u8]) -> Self {
+        let mut resource_limits = [0u16; 16];
+        for (i, limit) in resource_limits.iter_mut().enumerate() {
+            *limit = read_u16(data, 0x10 + i * 2);
+        }
+        let services = Self::parse_services(data);
+        Arm11LocalCaps {
+            program_id: read_u64(data, 0x00),
+            core_version: read_u32(data, 0x08),
+            enable_l2_cache: data[0x0C] & 0x01 != 0,
+            high_cpu_speed: data[0x0C] & 0x02 != 0,
+            new3ds_system_mode: data[0x0D] & 0x0F,
+            ideal_processor: data[0x0E] & 0x03,
+            affinity_mask: (data[0x0E] >> 2) & 0x03,
+            system_mode: data[0x0E] >> 4,
+            priority: data[0x0F],
+            resource_limits,
+            storage: StorageInfo::parse(&data[0x30..0x50]),
+            services,
+            resource_limit_category: data[0x16F],
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) -> io::Result<()> {
+        if self.services.len() > MAX_SERVICES {
+            return Err(too_many("services", MAX_SERVICES));
+        }
+        out[0x00..0x08].copy_from_slice(&self.program_id.to_le_bytes());
+        out[0x08..0x0C].copy_from_slice(&self.core_version.to_le_bytes());
+        out[0x0C] =
+            out[0x0C] & !0x03 | self.enable_l2_cache as u8 | (self.high_cpu_speed as u8) << 1;
+        out[0x0D] = out[0x0D] & 0xF0 | self.new3ds_system_mode & 0x0F;
+        out[0x0E] = (self.ideal_processor & 0x03)
+            | (self.affinity_mask & 0x03) << 2
+            | (self.system_mode & 0x0F) << 4;
+        out[0x0F] = self.priority;
+        for (i, limit) in self.resource_limits.iter().enumerate() {
+            out[0x10 + i * 2..0x12 + i * 2].copy_from_slice(&limit.to_le_bytes());
+        }
+        self.storage.write(&mut out[0x30..0x50]);
+        if Self::parse_services(out) != self.services {
+            out[0x50..0x160].fill(0);
+            for (i, service) in self.services.iter().enumerate() {
+                write_name(
+                    &mut out[0x50 + i * 8..0x58 + i * 8],
+                    service,
+                    "service name",
+                )?;
+            }
+        }
+        out[0x16F] = self.resource_limit_category;
+        Ok(())
+    }
+}
+
+/// One ARM11 kernel capability descriptor, decoded by its leading-ones prefix.
+///
+/// Descriptors whose reserved bits are set decode as [`KernelCapability::Unknown`],
+/// so every descriptor round-trips exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelCapability {
+    /// Four 7-bit interrupt numbers the process may bind.
+    InterruptInfo([u8; 4]),
+    /// 24 allowed SVCs starting at `index * 24`.
+    SystemCallMask {
+        index: u8,
+        mask: u32,
+    },
+    KernelVersion {
+        major: u8,
+        minor: u8,
+    },
+    HandleTableSize(u32),
+    KernelFlags(u32),
+    /// One end of a mapped static/IO address range (descriptors come in pairs).
+    MapAddressRange {
+        address: u32,
+        flag: bool,
+    },
+    MapIoPage {
+        address: u32,
+    },
+    Unused,
+    Unknown(u32),
+}
+
+impl KernelCapability {
+    /// Prefix of `ones` set bits followed by a clear bit.
+    const fn prefix(ones: u32) -> u32 {
+        !(u32::MAX >> ones)
+    }
+
+    pub fn from_descriptor(raw: u32) -> Self {
+        match raw.leading_ones() {
+            3 => KernelCapability::InterruptInfo([
+                (raw >> 21 & 0x7F) as u8,
+                (raw >> 14 & 0x7F) as u8,
+                (raw >> 7 & 0x7F) as u8,
+                (raw & 0x7F) as u8,
+            ]),
+            4 => KernelCapability::SystemCallMask {
+                index: (raw >> 24 & 0x07) as u8,
+                mask: raw & 0x00FF_FFFF,
+            },
+            6 if raw & 0x01FF_0000 == 0 => KernelCapability::KernelVersion {
+                major: (raw >> 8) as u8,
+                minor: raw as u8,
+            },
+            7 if raw & 0x00F8_0000 == 0 => KernelCapability::HandleTableSize(raw & 0x0007_FFFF),
+            8 => KernelCapability::KernelFlags(raw & 0x007F_FFFF),
+            9 if raw & 0x0020_0000 == 0 => KernelCapability::MapAddressRange {
+                address: (raw & 0x000F_FFFF) << 12,
+                flag: raw & 0x0010_0000 != 0,
+            },
+            11 => KernelCapability::MapIoPage {
+                address: (raw & 0x000F_FFFF) << 12,
+            },
+            32 => KernelCapability::Unused,
+            _ => KernelCapability::Unknown(raw),
+        }
+    }
+
+    pub fn descriptor(&self) -> u32 {
+        match *self {
+            KernelCapability::InterruptInfo(irqs) => {
+                Self::prefix(3)
+                    | (irqs[0] as u32 & 0x7F) << 21
+                    | (irqs[1] as u32 & 0x7F) << 14
+                    | (irqs[2] as u32 & 0x7F) << 7
+                    | (irqs[3] as u32 & 0x7F)
+            }
+            KernelCapability::SystemCallMask { index, mask } => {
+                Self::prefix(4) | (index as u32 & 0x07) << 24 | (mask & 0x00FF_FFFF)
+            }
+            KernelCapability::KernelVersion { major, minor } => {
+                Self::prefix(6) | (major as u32) << 8 | minor as u32
+            }
+            KernelCapability::HandleTableSize(size) => Self::prefix(7) | (size & 0x0007_FFFF),
+            KernelCapability::KernelFlags(flags) => Self::prefix(8) | (flags & 0x007F_FFFF),
+            KernelCapability::MapAddressRange { address, flag } => {
+                Self::prefix(9) | (flag as u32) << 20 | (address >> 12 & 0x000F_FFFF)
+            }
+            KernelCapability::MapIoPage { address } => {
+                Self::prefix(11) | (address >> 12 & 0x000F_FFFF)
+            }
+            KernelCapability::Unused => u32::MAX,
+            KernelCapability::Unknown(raw) => raw,
+        }
+    }
+}
+
+/// ARM11 kernel capabilities (unused descriptor slots are omitted).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Arm11KernelCaps {
+    pub descriptors: Vec<KernelCapability>,
+}
+
+impl Arm11KernelCaps {
+    fn parse(data: &[u8]) -> Self {
+        let descriptors = (0..MAX_KERNEL_DESCRIPTORS)
+            .map(|i| KernelCapability::from_descriptor(read_u32(data, i * 4)))
+            .filter(|cap| *cap != KernelCapability::Unused)
+            .collect();
+        Arm11KernelCaps { descriptors }
+    }
+
+    /// Whether the descriptor slots in `data` already hold `self`.
+    fn matches(&self, data: &[u8]) -> bool {
+        Self::parse(data) == *self
+    }
+
+    fn write(&self, out: &mut [u8]) -> io::Result<()> {
+        if self.descriptors.len() > MAX_KERNEL_DESCRIPTORS {
+            return Err(too_many("kernel descriptors", MAX_KERNEL_DESCRIPTORS));
+        }
+        if self.matches(out) {
+            return Ok(());
+        }
+        for i in 0..MAX_KERNEL_DESCRIPTORS {
+            let raw = self
+                .descriptors
+                .get(i)
+                .map_or(u32::MAX, KernelCapability::descriptor);
+            out[i * 4..i * 4 + 4].copy_from_slice(&raw.to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// ARM9 access control: filesystem permission descriptors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Arm9AccessControl {
+    pub descriptors: [u8; 15],
+    pub descriptor_version: u8,
+}
+
+impl Arm9AccessControl {
+    fn parse(data: &[u8]) -> Self {
+        Arm9AccessControl {
+            descriptors: data[..15].try_into().unwrap(),
+            descriptor_version: data[15],
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) {
+        out[..15].copy_from_slice(&self.descriptors);
+        out[15] = self.descriptor_version;
+    }
+}
+
+/// Access Control Info: the three capability blocks, as found in the ExHeader and
+/// again (as the permitted maximum) in the access descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessControlInfo {
+    pub arm11_local: Arm11LocalCaps,
+    pub arm11_kernel: Arm11KernelCaps,
+    pub arm9: Arm9AccessControl,
+}
+
+impl AccessControlInfo {
+    fn parse(data: &[u8]) -> Self {
+        AccessControlInfo {
+            arm11_local: Arm11LocalCaps::parse(&data[0x000..0x170]),
+            arm11_kernel: Arm11KernelCaps::parse(&data[0x170..0x1F0]),
+            arm9: Arm9AccessControl::parse(&data[0x1F0..0x200]),
+        }
+    }
+
+    fn write(&self, out: &mut [u8]) -> io::Result<()> {
+        self.arm11_local.write(&mut out[0x000..0x170])?;
+        self.arm11_kernel.write(&mut out[0x170..0x1F0])?;
+        self.arm9.write(&mut out[0x1F0..0x200]);
+        Ok(())
+    }
+}
+
+/// Signed access descriptor limiting what the ExHeader's ACI may request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessDescriptor {
+    /// RSA-2048 signature over the public key and ACI.
+    pub signature: Vec<u8>,
+    /// Public key used to verify the NCCH header signature.
+    pub ncch_public_key: Vec<u8>,
+    pub aci: AccessControlInfo,
+}
+
+/// A decrypted NCCH extended header.
+///
+/// The parsed bytes are kept, so that serializing writes back reserved fields and the
+/// layout of unchanged lists exactly; the ExHeader hash in the NCCH header covers both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExHeader {
+    pub sci: SystemControlInfo,
+    pub aci: AccessControlInfo,
+    pub access_desc: AccessDescriptor,
+    raw: Vec<u8>,
+}
+
+impl ExHeader {
+    /// Parse an ExHeader from the first 0x800 bytes of `data`.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let Some(data) = data.get(..EXHEADER_SIZE) else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "ExHeader shorter than 0x800 bytes",
+            ));
+        };
+        Ok(ExHeader {
+            sci: SystemControlInfo::parse(&data[0x000..0x200]),
+            aci: AccessControlInfo::parse(&data[0x200..0x400]),
+            access_desc: AccessDescriptor {
+                signature: data[0x400..0x500].to_vec(),
+                ncch_public_key: data[0x500..0x600].to_vec(),
+                aci: AccessControlInfo::parse(&data[0x600..0x800]),
+            },
+            raw: data.to_vec(),
+        })
+    }
+
+    /// Parse the ExHeader of the NCCH at `partition_offset` (it follows the 0x200-byte header).
+    pub fn parse<R: Read + Seek>(reader: &mut R, partition_offset: u64) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(partition_offset + 0x200))?;
+        let mut data = vec![0u8; EXHEADER_SIZE];
+        reader.read_exact(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
+    /// Serialize back to the 0x800-byte on-disk layout, overlaying the fields onto the
+    /// parsed bytes.
+    ///
+    /// Reserved fields keep their original bytes. A dependency, service or kernel
+    /// descriptor list is rewritten, packed from the first slot, only if it was changed.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        if self.access_desc.signature.len() != 0x100
+            || self.access_desc.ncch_public_key.len() != 0x100
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "access descriptor signature and public key must be 0x100 bytes",
+            ));
+        }
+        let mut out = self.raw.clone();
+        self.sci.write(&mut out[0x000..0x200])?;
+        self.aci.write(&mut out[0x200..0x400])?;
+        out[0x400..0x500].copy_from_slice(&self.access_desc.signature);
+        out[0x500..0x600].copy_from_slice(&self.access_desc.ncch_public_key);
+        self.access_desc.aci.write(&mut out[0x600..0x800])?;
+        Ok(out)
+    }
+
+    /// Serialize into `writer`.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn build_exheader() -> Vec<u8> {
+        let mut data = vec![0u8; EXHEADER_SIZE];
+
+        // --- SCI ---
+        data[0x00..0x06].copy_from_slice(b"CtrApp");
+        data[0x0D] = 0x03;
+        data[0x0E..0x10].copy_from_slice(&2u16.to_le_bytes());
+        data[0x10..0x14].copy_from_slice(&0x0010_0000u32.to_le_bytes());
+        data[0x14..0x18].copy_from_slice(&0x10u32.to_le_bytes());
+        data[0x18..0x1C].copy_from_slice(&0xF000u32.to_le_bytes());
+        data[0x1C..0x20].copy_from_slice(&0x4000u32.to_le_bytes());
+        data[0x20..0x24].copy_from_slice(&0x0011_0000u32.to_le_bytes());
+        data[0x30..0x34].copy_from_slice(&0x0012_0000u32.to_le_bytes());
+        data[0x3C..0x40].copy_from_slice(&0x800u32.to_le_bytes());
+        data[0x40..0x48].copy_from_slice(&0x0004_0130_0000_1002u64.to_le_bytes());
+        data[0x48..0x50].copy_from_slice(&0x0004_0130_0000_1502u64.to_le_bytes());
+        data[0x1C0..0x1C8].copy_from_slice(&0x80000u64.to_le_bytes());
+        data[0x1C8..0x1D0].copy_from_slice(&0x0004_0000_0005_5D00u64.to_le_bytes());
+
+        // --- ACI and access descriptor ACI ---
+        for aci in [0x200, 0x600] {
+            data[aci..aci + 8].copy_from_slice(&0x0004_0000_0005_5D00u64.to_le_bytes());
+            data[aci + 0x08..aci + 0x0C].copy_from_slice(&2u32.to_le_bytes());
+            data[aci + 0x0C] = 0x03;
+            data[aci + 0x0D] = 0x02;
+            data[aci + 0x0E] = 0x30 | 0x04;
+            data[aci + 0x0F] = 0x30;
+            data[aci + 0x38..aci + 0x40].copy_from_slice(&0x0000_0001u64.to_le_bytes());
+            data[aci + 0x50..aci + 0x58].copy_from_slice(b"fs:USER\0");
+            data[aci + 0x58..aci + 0x5D].copy_from_slice(b"hid:U");
+            let caps = &mut data[aci + 0x170..aci + 0x1F0];
+            for i in 0..MAX_KERNEL_DESCRIPTORS {
+                caps[i * 4..i * 4 + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            }
+            caps[0..4].copy_from_slice(&0xFE00_0200u32.to_le_bytes());
+            caps[4..8].copy_from_slice(&0xFC00_0222u32.to_le_bytes());
+            caps[8..12].copy_from_slice(&0xF0FF_FFFFu32.to_le_bytes());
+            data[aci + 0x1F0] = 0x3E;
+            data[aci + 0x1FF] = 2;
+        }
+        data[0x400..0x500].fill(0xAA);
+        data[0x500..0x600].fill(0xBB);
+        data
+    }
+
+    #[test]
+    fn test_parse_exheader() {
+        let data = build_exheader();
+        let exh = ExHeader::from_bytes(&data).unwrap();
+
+        assert_eq!(exh.sci.name, "CtrApp");
+        assert!(exh.sci.is_code_compressed());
+        assert!(exh.sci.is_sd_application());
+        assert_eq!(exh.sci.remaster_version, 2);
+        assert_eq!(
+            exh.sci.text,
+            CodeSegment {
+                address: 0x0010_0000,
+                num_pages: 0x10,
+                size: 0xF000
+            }
+        );
+        assert_eq!(exh.sci.stack_size, 0x4000);
+        assert_eq!(exh.sci.bss_size, 0x800);
+        assert_eq!(
+            exh.sci.dependencies,
+            vec![0x0004_0130_0000_1002, 0x0004_0130_0000_1502]
+        );
+        assert_eq!(exh.sci.save_data_size, 0x80000);
+        assert_eq!(exh.sci.jump_id, 0x0004_0000_0005_5D00);
+
+        let local = &exh.aci.arm11_local;
+        assert_eq!(local.program_id, 0x0004_0000_0005_5D00);
+        assert_eq!(local.core_version, 2);
+        assert!(local.enable_l2_cache && local.high_cpu_speed);
+        assert_eq!(local.new3ds_system_mode, 2);
+        assert_eq!(local.affinity_mask, 1);
+        assert_eq!(local.system_mode, 3);
+        assert_eq!(local.priority, 0x30);
+        assert_eq!(local.storage.system_savedata_ids, [1, 0]);
+        assert_eq!(local.services, vec!["fs:USER", "hid:U"]);
+
+        assert_eq!(
+            exh.aci.arm11_kernel.descriptors,
+            vec![
+                KernelCapability::HandleTableSize(0x200),
+                KernelCapability::KernelVersion {
+                    major: 2,
+                    minor: 0x22
+                },
+                KernelCapability::SystemCallMask {
+                    index: 0,
+                    mask: 0x00FF_FFFF
+                },
+            ]
+        );
+        assert_eq!(exh.aci.arm9.descriptors[0], 0x3E);
+        assert_eq!(exh.aci.arm9.descriptor_version, 2);
+        assert_eq!(exh.access_desc.aci, exh.aci);
+    }
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        let data = build_exheader();
+        let exh = ExHeader::from_bytes(&data).unwrap();
+        assert_eq!(exh.to_bytes().unwrap(), data);
+
+        let mut cursor = Cursor::new(vec![0u8; 0x200]);
+        cursor.get_mut().extend_from_slice(&data);
+        assert_eq!(ExHeader::parse(&mut cursor, 0).unwrap(), exh);
+    }
+
+    #[test]
+    fn test_serialize_keeps_reserved_bytes_and_gaps() {
+        let mut data = build_exheader();
+        data[0x08..0x0D].fill(0x5A); // SCI reserved
+        data[0x1D0..0x200].fill(0xA5); // SCI reserved tail
+        data[0x20C] |= 0x80; // reserved bit next to the New 3DS flags
+        data[0x360..0x36F].fill(0x11); // ARM11 local caps reserved
+        // A hole in the dependency list and in the service list, padding after a name
+        data[0x58..0x60].copy_from_slice(&0x0004_0130_0000_2702u64.to_le_bytes());
+        data[0x48..0x50].fill(0);
+        data[0x258..0x260].copy_from_slice(&[0; 8]);
+        data[0x260..0x268].copy_from_slice(b"ac:u\0\0\0\0");
+        data[0x06] = 0;
+        data[0x07] = 0x77;
+        data[0x384..0x388].copy_from_slice(&0xFF00_1234u32.to_le_bytes()); // after a hole
+
+        let exh = ExHeader::from_bytes(&data).unwrap();
+        assert_eq!(
+            exh.sci.dependencies,
+            vec![0x0004_0130_0000_1002, 0x0004_0130_0000_2702]
+        );
+        assert_eq!(exh.aci.arm11_local.services, vec!["fs:USER", "ac:u"]);
+        assert_eq!(
+            exh.aci.arm11_kernel.descriptors[3],
+            KernelCapability::KernelFlags(0x1234)
+        );
+        assert_eq!(exh.to_bytes().unwrap(), data);
+
+        // Changing one field leaves every other byte alone
+        let mut patched = exh.clone();
+        patched.sci.flags &= !0x01;
+        let bytes = patched.to_bytes().unwrap();
+        let changed: Vec<_> = (0..EXHEADER_SIZE)
+            .filter(|&i| bytes[i] != data[i])
+            .collect();
+        assert_eq!(changed, [0x0D]);
+    }
+
+    #[test]
+    fn test_patch_and_reparse() {
+        let mut exh = ExHeader::from_bytes(&build_exheader()).unwrap();
+        exh.sci.flags &= !0x01;
+        exh.aci.arm11_local.services.push("ac:u".into());
+        let patched = ExHeader::from_bytes(&exh.to_bytes().unwrap()).unwrap();
+        assert!(!patched.sci.is_code_compressed());
+        assert_eq!(
+            patched.aci.arm11_local.services,
+            vec!["fs:USER", "hid:U", "ac:u"]
+        );
+    }
+
+    #[test]
+    fn test_serialize_rejects_oversized_fields() {
+        let mut exh = ExHeader::from_bytes(&build_exheader()).unwrap();
+        exh.sci.name = "TooLongName".into();
+        assert!(exh.to_bytes().is_err());
+
+        let mut exh = ExHeader::from_bytes(&build_exheader()).unwrap();
+        exh.sci.dependencies = vec![1; MAX_DEPENDENCIES + 1];
+        assert!(exh.to_bytes().is_err());
+    }
+
+    #[test]
+    fn test_kernel_capability_roundtrip() {
+        for raw in [
+            0xE1234567u32,
+            0xF2ABCDEF,
+            0xFC00_0222,
+            0xFE00_0200,
+            0xFF00_1234,
+            0xFF81_F000,
+            0xFF91_F000,
+            0xFFE1_F000,
+            0xFFFF_FFFF,
+            0xFD01_0000, // reserved bits set in a kernel version
+            0x1234_5678,
+        ] {
+            let cap = KernelCapability::from_descriptor(raw);
+            assert_eq!(cap.descriptor(), raw, "{cap:?}");
+        }
+        assert!(matches!(
+            KernelCapability::from_descriptor(0xFF91_F000),
+            KernelCapability::MapAddressRange {
+                address: 0x1F00_0000,
+                flag: true
+            }
+        ));
+        assert_eq!(
+            KernelCapability::from_descriptor(0xFD01_0000),
+            KernelCapability::Unknown(0xFD01_0000)
+        );
+    }
+
+    #[test]
+    fn test_short_input() {
+        assert!(ExHeader::from_bytes(&[0u8; 0x400]).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use serde::Serialize;
+
+use crate::cia::{self, Cia};
+use crate::decrypt::Error;
+use crate::ncch::NcchHeader;
+use crate::ncsd::NcsdHeader;
+
+/// Container formats citrust can process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RomFormat {
+    /// NCSD card image (`.3ds` / `.cci`) holding up to eight NCCH partitions.
+    Ncsd,
+    /// A single NCCH (`.cxi`, `.cfa`, extracted partitions, CDN `.app` contents).
+    Ncch,
+    /// CTR Importable Archive (`.cia`).
+    Cia,
+}
+
+impl fmt::Display for RomFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RomFormat::Ncsd => "NCSD",
+            RomFormat::Ncch => "NCCH",
+            RomFormat::Cia => "CIA",
+        })
+    }
+}
+
+impl RomFormat {
+    /// Detect the container format from the start of a file.
+    pub fn detect(data: &[u8]) -> Option<RomFormat> {
+        match data.get(0x100..0x104) {
+            Some(b"NCSD") => return Some(RomFormat::Ncsd),
+            Some(b"NCCH") => return Some(RomFormat::Ncch),
+            _ => {}
+        }
+        cia::is_cia(data).then_some(RomFormat::Cia)
+    }
+
+    /// Detect the container format of a file from its header.
+    pub fn detect_reader<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<RomFormat>> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut head = Vec::new();
+        reader
+            .take(cia::CIA_HEADER_SIZE as u64)
+            .read_to_end(&mut head)?;
+        Ok(Self::detect(&head))
+    }
+}
+
+/// Locate the main NCCH of a ROM: partition 0 of an NCSD, the file itself for a bare
+/// NCCH, or the first content of a CIA. Returns the parsed header and its offset.
+///
+/// CIA contents must already be free of title-key encryption.
+pub fn main_ncch<R: Read + Seek>(reader: &mut R) -> Result<(NcchHeader, u64), Error> {
+    let offset = match RomFormat::detect_reader(reader)? {
+        Some(RomFormat::Ncsd) => {
+            let ncsd = NcsdHeader::parse(reader).map_err(|_| Error::NotNcsd)?;
+            ncsd.partitions[0].offset_bytes(ncsd.sector_size)
+        }
+        Some(RomFormat::Ncch) => 0,
+        Some(RomFormat::Cia) => {
+            let cia = Cia::parse(reader).map_err(|e| Error::InvalidCia(e.to_string()))?;
+            let Some((chunk, offset)) = cia.contents().into_iter().next() else {
+                return Err(Error::InvalidCia("CIA has no contents".into()));
+            };
+            if chunk.is_encrypted() {
+                return Err(Error::NotDecrypted);
+            }
+            offset
+        }
+        None => return Err(Error::UnknownFormat),
+    };
+
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut head = [0u8; 0x200];
+    reader
+        .read_exact(&mut head)
+        .map_err(|_| Error::InvalidNcch(0))?;
+    if &head[0x100..0x104] != b"NCCH" {
+        return Err(Error::InvalidNcch(0));
+    }
+    let ncch = NcchHeader::parse(&mut Cursor::new(&head[..]), 0)?;
+    Ok((ncch, offset))
+}
+
+/// Absolute offsets of every NCCH in a ROM: the NCSD partitions (by partition index), a
+/// bare NCCH (index 0) or the contents of a decrypted CIA (by content index).
+pub fn ncch_partitions<R: Read + Seek>(reader: &mut R) -> Result<Vec<(usize, u64)>, Error> {
+    match RomFormat::detect_reader(reader)? {
+        Some(RomFormat::Ncsd) => {
+            let ncsd = NcsdHeader::parse(reader).map_err(|_| Error::NotNcsd)?;
+            Ok(ncsd
+                .partitions
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| !p.is_empty())
+                .map(|(i, p)| (i, p.offset_bytes(ncsd.sector_size)))
+                .collect())
+        }
+        Some(RomFormat::Ncch) => Ok(vec![(0, 0)]),
+        Some(RomFormat::Cia) => {
+            let cia = Cia::parse(reader).map_err(|e| Error::InvalidCia(e.to_string()))?;
+            let contents = cia.contents();
+            if contents.iter().any(|(chunk, _)| chunk.is_encrypted()) {
+                return Err(Error::NotDecrypted);
+            }
+            Ok(contents
+                .into_iter()
+                .map(|(chunk, offset)| (chunk.index as usize, offset))
+                .collect())
+        }
+        None => Err(Error::UnknownFormat),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_by_magic() {
+        let mut data = vec![0u8; 0x200];
+        data[0x100..0x104].copy_from_slice(b"NCSD");
+        assert_eq!(RomFormat::detect(&data), Some(RomFormat::Ncsd));
+
+        data[0x100..0x104].copy_from_slice(b"NCCH");
+        assert_eq!(RomFormat::detect(&data), Some(RomFormat::Ncch));
+    }
+
+    #[test]
+    fn test_detect_cia_header() {
+        let mut data = vec![0u8; cia::CIA_HEADER_SIZE as usize];
+        data[0..4].copy_from_slice(&cia::CIA_HEADER_SIZE.to_le_bytes());
+        assert_eq!(RomFormat::detect(&data), Some(RomFormat::Cia));
+    }
+
+    #[test]
+    fn test_detect_unknown() {
+        assert_eq!(RomFormat::detect(&[0u8; 0x3000]), None);
+        assert_eq!(RomFormat::detect(&[]), None);
+    }
+}
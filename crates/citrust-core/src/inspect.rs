@@ -0,0 +1,377 @@
+//! Read-only description of a ROM: container, title, and the crypto state of each
+//! partition, without keys and without touching the file.
+//!
+//! The report derives `Serialize` for machine-readable output. Title and program IDs
+//! serialize as 16-digit hex strings, the way they are usually written.
+//!
+//! [`rom_status`] boils the same information down to one [`PartitionState`] per
+//! partition, for front-ends that only need to know what decryption would do.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use serde::{Serialize, Serializer};
+
+use crate::cia::Cia;
+use crate::decrypt::{Error, is_content_decrypted};
+use crate::format::RomFormat;
+use crate::keys::CryptoMethod;
+use crate::ncch::NcchHeader;
+use crate::ncsd::NcsdHeader;
+
+/// Upper bound on the ExHeader and ExeFS superblock bytes read per partition.
+const MAX_PREFIX: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RomInfo {
+    pub format: RomFormat,
+    pub file_size: u64,
+    /// NCSD sector size; NCCH and CIA images only have per-partition media units.
+    pub sector_size: Option<u32>,
+    /// Title ID of the main partition, or of the TMD for a CIA.
+    #[serde(serialize_with = "hex_opt")]
+    pub title_id: Option<u64>,
+    pub product_code: Option<String>,
+    pub partitions: Vec<PartitionInfo>,
+}
+
+/// One NCSD partition, the NCCH itself, or one CIA content.
+#[derive(Debug, Clone, Serialize)]
+pub struct PartitionInfo {
+    /// NCSD partition index, or CIA content index.
+    pub index: usize,
+    pub offset: u64,
+    pub size: u64,
+    /// The CIA content is still encrypted with the title key.
+    pub title_key_encrypted: bool,
+    /// `None` if no NCCH header could be read, e.g. under a title-key layer.
+    pub ncch: Option<NcchInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NcchInfo {
+    #[serde(serialize_with = "hex")]
+    pub title_id: u64,
+    #[serde(serialize_with = "hex")]
+    pub program_id: u64,
+    pub product_code: String,
+    pub media_unit_size: u32,
+    /// Method selected by flags[3], or `None` for an unknown value.
+    pub crypto_method: Option<CryptoMethod>,
+    /// Raw flags[3] byte.
+    pub crypto_flag: u8,
+    pub no_crypto: bool,
+    pub fixed_key: bool,
+    pub seed: bool,
+    /// Whether the content is encrypted, judged by [`is_content_decrypted`] rather
+    /// than the flags.
+    pub content_encrypted: bool,
+}
+
+/// What decryption would find in one partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionState {
+    /// Flagged encrypted and encrypted. Whether the key file holds the keys for it is
+    /// not checked.
+    Encrypted,
+    /// Flagged NoCrypto and plaintext.
+    Decrypted,
+    /// Flagged NoCrypto, but the content is encrypted.
+    FlaggedDecryptedButEncrypted,
+    /// Not flagged NoCrypto, but the content is already plaintext.
+    FlaggedEncryptedButDecrypted,
+    /// Encrypted with seed crypto: decryption also needs the title's seed.
+    SeedRequired,
+    /// Empty NCSD partition slot.
+    Missing,
+    /// No NCCH header could be read, e.g. the partition lies past the end of the file.
+    Unreadable,
+}
+
+impl fmt::Display for PartitionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PartitionState::Encrypted => "encrypted",
+            PartitionState::Decrypted => "decrypted",
+            PartitionState::FlaggedDecryptedButEncrypted => "flagged decrypted but encrypted",
+            PartitionState::FlaggedEncryptedButDecrypted => "flagged encrypted but decrypted",
+            PartitionState::SeedRequired => "encrypted, seed required",
+            PartitionState::Missing => "missing",
+            PartitionState::Unreadable => "unreadable",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PartitionStatus {
+    /// NCSD partition index, or CIA content index.
+    pub index: usize,
+    pub state: PartitionState,
+}
+
+impl From<&NcchInfo> for PartitionState {
+    fn from(ncch: &NcchInfo) -> Self {
+        match (ncch.no_crypto, ncch.content_encrypted) {
+            (true, false) => PartitionState::Decrypted,
+            (true, true) => PartitionState::FlaggedDecryptedButEncrypted,
+            (false, false) => PartitionState::FlaggedEncryptedButDecrypted,
+            (false, true) if ncch.seed && !ncch.fixed_key => PartitionState::SeedRequired,
+            (false, true) => PartitionState::Encrypted,
+        }
+    }
+}
+
+fn hex<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{value:016X}"))
+}
+
+fn hex_opt<S: Serializer>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => hex(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Describe the ROM read from `reader`.
+pub fn inspect<R: Read + Seek>(reader: &mut R) -> Result<RomInfo, Error> {
+    let format = RomFormat::detect_reader(reader)?.ok_or(Error::UnknownFormat)?;
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let mut info = RomInfo {
+        format,
+        file_size,
+        sector_size: None,
+        title_id: None,
+        product_code: None,
+        partitions: Vec::new(),
+    };
+
+    match format {
+        RomFormat::Ncsd => {
+            let ncsd = NcsdHeader::parse(reader).map_err(|_| Error::NotNcsd)?;
+            info.sector_size = Some(ncsd.sector_size);
+            for (index, part) in ncsd.partitions.iter().enumerate() {
+                if part.is_empty() {
+                    continue;
+                }
+                let offset = part.offset_bytes(ncsd.sector_size);
+                let size = part.length_bytes(ncsd.sector_size);
+                info.partitions
+                    .push(partition(reader, index, offset, size, false)?);
+            }
+        }
+        RomFormat::Ncch => info
+            .partitions
+            .push(partition(reader, 0, 0, file_size, false)?),
+        RomFormat::Cia => {
+            let cia = Cia::parse(reader).map_err(|e| Error::InvalidCia(e.to_string()))?;
+            info.title_id = Some(cia.tmd.title_id);
+            for (chunk, offset) in cia.contents() {
+                let encrypted = chunk.is_encrypted();
+                info.partitions.push(partition(
+                    reader,
+                    chunk.index as usize,
+                    offset,
+                    chunk.size,
+                    encrypted,
+                )?);
+            }
+        }
+    }
+
+    if let Some(ncch) = info.partitions.first().and_then(|p| p.ncch.as_ref()) {
+        info.title_id.get_or_insert(ncch.title_id);
+        info.product_code = Some(ncch.product_code.clone());
+    }
+    Ok(info)
+}
+
+/// Classify every partition of the ROM at `path`, opening it read-only.
+///
+/// NCSD images report all eight slots, empty ones as [`PartitionState::Missing`]. A
+/// CIA content still under its title key is [`PartitionState::Encrypted`]. Only a file
+/// that cannot be opened or is not a ROM at all is an error.
+pub fn rom_status(path: &Path) -> Result<Vec<PartitionStatus>, Error> {
+    partition_status(&mut BufReader::new(File::open(path)?))
+}
+
+/// Classify every partition of the ROM read from `reader`, like [`rom_status`].
+pub fn partition_status<R: Read + Seek>(reader: &mut R) -> Result<Vec<PartitionStatus>, Error> {
+    let format = RomFormat::detect_reader(reader)?.ok_or(Error::UnknownFormat)?;
+    let mut status = Vec::new();
+    match format {
+        RomFormat::Ncsd => {
+            let ncsd = NcsdHeader::parse(reader).map_err(|_| Error::NotNcsd)?;
+            for (index, part) in ncsd.partitions.iter().enumerate() {
+                let state = if part.is_empty() {
+                    PartitionState::Missing
+                } else {
+                    ncch_state(reader, part.offset_bytes(ncsd.sector_size))
+                };
+                status.push(PartitionStatus { index, state });
+            }
+        }
+        RomFormat::Ncch => status.push(PartitionStatus {
+            index: 0,
+            state: ncch_state(reader, 0),
+        }),
+        RomFormat::Cia => {
+            let cia = Cia::parse(reader).map_err(|e| Error::InvalidCia(e.to_string()))?;
+            for (chunk, offset) in cia.contents() {
+                let state = if chunk.is_encrypted() {
+                    PartitionState::Encrypted
+                } else {
+                    ncch_state(reader, offset)
+                };
+                status.push(PartitionStatus {
+                    index: chunk.index as usize,
+                    state,
+                });
+            }
+        }
+    }
+    Ok(status)
+}
+
+/// State of the NCCH at `offset`; read errors only make that partition unreadable.
+fn ncch_state<R: Read + Seek>(reader: &mut R, offset: u64) -> PartitionState {
+    match ncch_info(reader, offset) {
+        Ok(Some(ncch)) => PartitionState::from(&ncch),
+        Ok(None) | Err(_) => PartitionState::Unreadable,
+    }
+}
+
+fn partition<R: Read + Seek>(
+    reader: &mut R,
+    index: usize,
+    offset: u64,
+    size: u64,
+    title_key_encrypted: bool,
+) -> Result<PartitionInfo, Error> {
+    let ncch = if title_key_encrypted {
+        None
+    } else {
+        ncch_info(reader, offset)?
+    };
+    Ok(PartitionInfo {
+        index,
+        offset,
+        size,
+        title_key_encrypted,
+        ncch,
+    })
+}
+
+/// Read the NCCH at `offset`, or `None` if there is no NCCH header there.
+fn ncch_info<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Option<NcchInfo>, Error> {
+    let mut head = Vec::new();
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.by_ref().take(0x200).read_to_end(&mut head)?;
+    if head.get(0x100..0x104) != Some(b"NCCH") {
+        return Ok(None);
+    }
+    let ncch = NcchHeader::parse(&mut Cursor::new(&head), 0)?;
+    let unit = ncch.media_unit_size();
+
+    // Enough of the partition for the ExHeader and ExeFS superblock checks; a short
+    // read just leaves `is_content_decrypted` less to go on.
+    let exheader_end = 0x200 + ncch.exheader_length as u64;
+    let exefs_end = (ncch.exefs_offset as u64 + ncch.exefs_hash_region_size.max(1) as u64)
+        .saturating_mul(unit as u64);
+    let len = exheader_end.max(exefs_end).min(MAX_PREFIX);
+    let mut prefix = Vec::new();
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.by_ref().take(len).read_to_end(&mut prefix)?;
+
+    Ok(Some(NcchInfo {
+        title_id: ncch.title_id,
+        program_id: ncch.program_id,
+        product_code: ncch.product_code(),
+        media_unit_size: unit,
+        crypto_method: ncch.crypto_method(),
+        crypto_flag: ncch.partition_flags[3],
+        no_crypto: ncch.is_no_crypto(),
+        fixed_key: ncch.is_fixed_key(),
+        seed: ncch.uses_seed(),
+        content_encrypted: !is_content_decrypted(&prefix, &ncch, unit, 0),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::encrypt_image;
+    use crate::decrypt::tests::{build_decrypted_rom, make_7x_keydb};
+
+    #[test]
+    fn test_inspect_ncsd_before_and_after_encryption() {
+        let (mut rom, p) = build_decrypted_rom();
+        rom[p + 0x150..p + 0x15A].copy_from_slice(b"CTR-P-CTAP");
+
+        let info = inspect(&mut Cursor::new(&rom)).unwrap();
+        assert_eq!(info.format, RomFormat::Ncsd);
+        assert_eq!(info.file_size, rom.len() as u64);
+        assert_eq!(info.sector_size, Some(0x200));
+        assert_eq!(info.title_id, Some(0x0004000000055D00));
+        assert_eq!(info.product_code.as_deref(), Some("CTR-P-CTAP"));
+        assert_eq!(info.partitions.len(), 1);
+        let part = &info.partitions[0];
+        assert_eq!(
+            (part.index, part.offset, part.size),
+            (0, p as u64, 16 * 0x200)
+        );
+        let ncch = part.ncch.as_ref().unwrap();
+        assert!(ncch.no_crypto && !ncch.content_encrypted);
+
+        encrypt_image(&mut rom, &make_7x_keydb(), CryptoMethod::Key7x, &mut |_| {}).unwrap();
+        let info = inspect(&mut Cursor::new(&rom)).unwrap();
+        let ncch = info.partitions[0].ncch.as_ref().unwrap();
+        assert_eq!(ncch.crypto_method, Some(CryptoMethod::Key7x));
+        assert_eq!(ncch.crypto_flag, 0x01);
+        assert!(!ncch.no_crypto && !ncch.fixed_key && !ncch.seed);
+        assert!(ncch.content_encrypted);
+    }
+
+    #[test]
+    fn test_inspect_title_key_encrypted_cia() {
+        let (rom, p) = build_decrypted_rom();
+        let cia = crate::cia::tests::build_test_cia(&rom[p..], [0x42; 16], [0x24; 16], true);
+
+        let info = inspect(&mut Cursor::new(&cia)).unwrap();
+        assert_eq!(info.format, RomFormat::Cia);
+        assert_eq!(info.title_id, Some(crate::cia::tests::TEST_TITLE_ID));
+        assert_eq!(info.product_code, None);
+        assert!(info.partitions[0].title_key_encrypted);
+        assert!(info.partitions[0].ncch.is_none());
+    }
+
+    #[test]
+    fn test_partition_status_of_ncch_and_cia() {
+        let (mut rom, p) = build_decrypted_rom();
+        let status = partition_status(&mut Cursor::new(&rom[p..])).unwrap();
+        assert_eq!(
+            status,
+            [PartitionStatus {
+                index: 0,
+                state: PartitionState::Decrypted
+            }]
+        );
+
+        let cia = crate::cia::tests::build_test_cia(&rom[p..], [0x42; 16], [0x24; 16], true);
+        let status = partition_status(&mut Cursor::new(&cia)).unwrap();
+        assert_eq!(status[0].state, PartitionState::Encrypted);
+
+        encrypt_image(&mut rom, &make_7x_keydb(), CryptoMethod::Key7x, &mut |_| {}).unwrap();
+        rom[p + 0x18F] |= 0x20;
+        let status = partition_status(&mut Cursor::new(&rom[p..])).unwrap();
+        assert_eq!(status[0].state, PartitionState::SeedRequired);
+    }
+
+    #[test]
+    fn test_inspect_rejects_unknown_format() {
+        let err = inspect(&mut Cursor::new(vec![0u8; 0x400])).unwrap_err();
+        assert!(matches!(err, Error::UnknownFormat), "{err:?}");
+    }
+}
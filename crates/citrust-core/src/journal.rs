@@ -0,0 +1,945 @@
+//! Crash-safe, resumable execution of in-place operations.
+//!
+//! An in-place operation is planned up front as a list of [`Step`]s, which is written to
+//! a sidecar journal next to the ROM before the first byte is modified. Each step is
+//! applied in segments; before a segment is touched its original bytes are saved to the
+//! journal's undo area, and once it has been written back to disk the journal's cursor
+//! moves past it. After a crash the in-flight segment is restored from the undo area,
+//! which leaves every segment either fully done or untouched. From there the run can be
+//! resumed at the cursor or rolled back step by step: AES-CTR passes are undone by
+//! applying them again, CBC passes by re-encrypting and header patches by writing the
+//! original bytes back.
+//!
+//! The header records which [`Operation`] wrote the journal, so that a pending run is
+//! only resumed by the same operation.
+//!
+//! The plan holds the normal keys it was made with, so an interrupted run can be
+//! finished or undone without the key database. Progress notes are not part of the
+//! journal: a resumed run reports the events it can regenerate from the steps
+//! themselves, so the on-disk format does not depend on [`ProgressEvent`].
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::MmapMut;
+use sha2::{Digest, Sha256};
+
+use crate::cancel::CancelToken;
+use crate::crypto::aes_cbc_encrypt;
+use crate::decrypt::{self, Error};
+use crate::keys::Key128;
+use crate::ncch::Region;
+use crate::progress::{Direction, Operation, ProgressEvent};
+
+const MAGIC: &[u8; 4] = b"CTJL";
+const VERSION: u32 = 4;
+
+/// Size of the pieces steps are applied (and journaled) in.
+pub(crate) const SEGMENT_SIZE: usize = 32 * 1024 * 1024;
+
+/// Layout of the journal file: header, two alternating cursor slots, the plan, then the
+/// undo area (aligned to 0x1000).
+const HEADER_SIZE: u64 = 0x48;
+const SLOT_SIZE: u64 = 0x50;
+const PLAN_OFFSET: u64 = HEADER_SIZE + 2 * SLOT_SIZE;
+
+/// Sidecar journal path used while decrypting `rom` in-place.
+pub fn journal_path(rom: &Path) -> PathBuf {
+    let name = rom
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    rom.with_file_name(format!(".{name}.citrust-journal"))
+}
+
+/// Whether an interrupted in-place operation on `rom` is waiting to be resumed or
+/// rolled back.
+pub fn is_pending(rom: &Path) -> bool {
+    journal_path(rom).exists()
+}
+
+/// A byte image that steps are applied to.
+pub(crate) trait Image {
+    fn bytes(&mut self) -> &mut [u8];
+
+    /// Make sure a modified range has reached the disk.
+    fn persist(&self, offset: usize, len: usize) -> io::Result<()>;
+}
+
+impl Image for [u8] {
+    fn bytes(&mut self) -> &mut [u8] {
+        self
+    }
+
+    fn persist(&self, _offset: usize, _len: usize) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Image for MmapMut {
+    fn bytes(&mut self) -> &mut [u8] {
+        self
+    }
+
+    fn persist(&self, offset: usize, len: usize) -> io::Result<()> {
+        self.flush_range(offset, len)
+    }
+}
+
+/// One planned modification of the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// A progress event, emitted when the run reaches this point. Not journaled.
+    Note(ProgressEvent),
+    /// An AES-CTR pass over one region of an NCCH.
+    Ctr {
+        partition: u16,
+        region: Region,
+        direction: Direction,
+        start: usize,
+        len: usize,
+        key: Key128,
+        iv: u128,
+    },
+    /// Removal of a CIA content's title-key (AES-CBC) layer.
+    Cbc {
+        content: u16,
+        start: usize,
+        len: usize,
+        key: Key128,
+        iv: u128,
+    },
+    /// Overwrite `old` with `new` at `offset`.
+    Patch {
+        offset: usize,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+impl Step {
+    /// Whether the step changes the image.
+    pub(crate) fn modifies(&self) -> bool {
+        !matches!(self, Step::Note(_))
+    }
+
+    /// Number of bytes the step modifies.
+    fn bytes(&self) -> u64 {
+        match self {
+            Step::Note(_) => 0,
+            Step::Ctr { len, .. } | Step::Cbc { len, .. } => *len as u64,
+            Step::Patch { new, .. } => new.len() as u64,
+        }
+    }
+
+    fn segments(&self, segment_size: usize) -> usize {
+        match self {
+            Step::Note(_) => 0,
+            Step::Ctr { len, .. } | Step::Cbc { len, .. } => len.div_ceil(segment_size),
+            Step::Patch { .. } => 1,
+        }
+    }
+
+    /// Byte range of the `i`-th segment in execution order.
+    ///
+    /// CBC segments are processed last to first, so the ciphertext block that chains
+    /// into each segment is still intact when it is decrypted, and already restored
+    /// when it is re-encrypted during a rollback.
+    fn segment(&self, i: usize, segment_size: usize) -> (usize, usize) {
+        match *self {
+            Step::Note(_) => (0, 0),
+            Step::Ctr { start, len, .. } => {
+                let off = i * segment_size;
+                (start + off, (len - off).min(segment_size))
+            }
+            Step::Cbc { start, len, .. } => {
+                let k = self.segments(segment_size) - 1 - i;
+                let off = k * segment_size;
+                (start + off, (len - off).min(segment_size))
+            }
+            Step::Patch {
+                offset, ref new, ..
+            } => (offset, new.len()),
+        }
+    }
+
+    /// Apply (`forward`) or undo segment `i`. If `cancel` is set part-way, the segment
+    /// is left partly applied and [`Error::Cancelled`] is returned.
+    fn apply(
+        &self,
+        data: &mut [u8],
+        i: usize,
+        segment_size: usize,
+        forward: bool,
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        let (at, len) = self.segment(i, segment_size);
+        match self {
+            Step::Note(_) => {}
+            Step::Ctr {
+                region,
+                start,
+                key,
+                iv,
+                ..
+            } => {
+                let chunk_size = if *region == Region::RomFs {
+                    decrypt::CHUNK_SIZE
+                } else {
+                    1024 * 1024
+                };
+                let iv = iv.wrapping_add(((at - start) / 0x10) as u128);
+                decrypt::decrypt_slice(&mut data[at..at + len], key, iv, chunk_size, cancel)?;
+            }
+            Step::Cbc { start, key, iv, .. } => {
+                let iv = if at == *start {
+                    *iv
+                } else {
+                    u128::from_be_bytes(data[at - 16..at].try_into().unwrap())
+                };
+                if forward {
+                    decrypt::cbc_decrypt_slice(
+                        &mut data[at..at + len],
+                        key,
+                        iv,
+                        decrypt::CHUNK_SIZE,
+                        cancel,
+                    )?;
+                } else {
+                    aes_cbc_encrypt(key, iv, &mut data[at..at + len]);
+                }
+            }
+            Step::Patch { old, new, .. } => {
+                data[at..at + len].copy_from_slice(if forward { new } else { old });
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the journal encoding of the step; notes encode to nothing.
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Step::Note(_) => {}
+            Step::Ctr {
+                partition,
+                region,
+                direction,
+                start,
+                len,
+                key,
+                iv,
+            } => {
+                out.push(1);
+                out.extend_from_slice(&partition.to_le_bytes());
+                out.push(region_code(*region));
+                out.push((*direction == Direction::Encrypt) as u8);
+                out.extend_from_slice(&(*start as u64).to_le_bytes());
+                out.extend_from_slice(&(*len as u64).to_le_bytes());
+                out.extend_from_slice(key);
+                out.extend_from_slice(&iv.to_le_bytes());
+            }
+            Step::Cbc {
+                content,
+                start,
+                len,
+                key,
+                iv,
+            } => {
+                out.push(2);
+                out.extend_from_slice(&content.to_le_bytes());
+                out.extend_from_slice(&(*start as u64).to_le_bytes());
+                out.extend_from_slice(&(*len as u64).to_le_bytes());
+                out.extend_from_slice(key);
+                out.extend_from_slice(&iv.to_le_bytes());
+            }
+            Step::Patch { offset, old, new } => {
+                out.push(3);
+                out.extend_from_slice(&(*offset as u64).to_le_bytes());
+                put_bytes(out, old);
+                put_bytes(out, new);
+            }
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<Step> {
+        Some(match take::<1>(input)?[0] {
+            1 => Step::Ctr {
+                partition: u16::from_le_bytes(take(input)?),
+                region: region_from_code(take::<1>(input)?[0])?,
+                direction: match take::<1>(input)?[0] {
+                    0 => Direction::Decrypt,
+                    1 => Direction::Encrypt,
+                    _ => return None,
+                },
+                start: take_usize(input)?,
+                len: take_usize(input)?,
+                key: take(input)?,
+                iv: u128::from_le_bytes(take(input)?),
+            },
+            2 => Step::Cbc {
+                content: u16::from_le_bytes(take(input)?),
+                start: take_usize(input)?,
+                len: take_usize(input)?,
+                key: take(input)?,
+                iv: u128::from_le_bytes(take(input)?),
+            },
+            3 => {
+                let offset = take_usize(input)?;
+                let old = take_bytes(input)?;
+                let new = take_bytes(input)?;
+                if old.len() != new.len() {
+                    return None;
+                }
+                Step::Patch { offset, old, new }
+            }
+            _ => return None,
+        })
+    }
+
+    /// Byte range the step touches, for validating a plan against the image.
+    fn extent(&self) -> Option<usize> {
+        match self {
+            Step::Note(_) => Some(0),
+            Step::Ctr { start, len, .. } | Step::Cbc { start, len, .. } => start.checked_add(*len),
+            Step::Patch { offset, new, .. } => offset.checked_add(new.len()),
+        }
+    }
+}
+
+fn operation_code(operation: Operation) -> u8 {
+    match operation {
+        Operation::Decrypt => 0,
+        Operation::Encrypt => 1,
+        Operation::Repair => 2,
+    }
+}
+
+fn operation_from_code(code: u8) -> Option<Operation> {
+    Some(match code {
+        0 => Operation::Decrypt,
+        1 => Operation::Encrypt,
+        2 => Operation::Repair,
+        _ => return None,
+    })
+}
+
+fn region_code(region: Region) -> u8 {
+    match region {
+        Region::ExHeader => 0,
+        Region::ExefsHeader => 1,
+        Region::ExefsData => 2,
+        Region::Code => 3,
+        Region::RomFs => 4,
+    }
+}
+
+fn region_from_code(code: u8) -> Option<Region> {
+    Some(match code {
+        0 => Region::ExHeader,
+        1 => Region::ExefsHeader,
+        2 => Region::ExefsData,
+        3 => Region::Code,
+        4 => Region::RomFs,
+        _ => return None,
+    })
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take<const N: usize>(input: &mut &[u8]) -> Option<[u8; N]> {
+    let (head, rest) = input.split_first_chunk::<N>()?;
+    *input = rest;
+    Some(*head)
+}
+
+fn take_usize(input: &mut &[u8]) -> Option<usize> {
+    usize::try_from(u64::from_le_bytes(take(input)?)).ok()
+}
+
+fn take_bytes(input: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(take(input)?) as usize;
+    let bytes = input.get(..len)?.to_vec();
+    *input = &input[len..];
+    Some(bytes)
+}
+
+/// Position in a plan: every segment before `segment` of step `step` is done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Cursor {
+    pub step: usize,
+    pub segment: usize,
+}
+
+/// One cursor slot of the journal. While `in_flight` is set, the undo area holds the
+/// bytes that put segment `flight` back in the state `cursor` describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    seq: u64,
+    cursor: Cursor,
+    flight: Option<(Cursor, u64)>,
+}
+
+impl Slot {
+    fn encode(&self) -> [u8; SLOT_SIZE as usize] {
+        let mut out = [0u8; SLOT_SIZE as usize];
+        let (flight, undo_len) = self.flight.unwrap_or_default();
+        let fields = [
+            self.seq,
+            self.cursor.step as u64,
+            self.cursor.segment as u64,
+            self.flight.is_some() as u64,
+            flight.step as u64,
+            flight.segment as u64,
+            undo_len,
+        ];
+        for (i, field) in fields.into_iter().enumerate() {
+            out[i * 8..i * 8 + 8].copy_from_slice(&field.to_le_bytes());
+        }
+        let hash = Sha256::digest(&out[..0x38]);
+        out[0x38..0x48].copy_from_slice(&hash[..0x10]);
+        out
+    }
+
+    fn decode(data: &[u8]) -> Option<Slot> {
+        if Sha256::digest(&data[..0x38])[..0x10] != data[0x38..0x48] {
+            return None;
+        }
+        let field = |i: usize| u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap());
+        let cursor = |i: usize| {
+            Some(Cursor {
+                step: usize::try_from(field(i)).ok()?,
+                segment: usize::try_from(field(i + 1)).ok()?,
+            })
+        };
+        Some(Slot {
+            seq: field(0),
+            cursor: cursor(1)?,
+            flight: match field(3) {
+                0 => None,
+                _ => Some((cursor(4)?, field(6))),
+            },
+        })
+    }
+}
+
+/// The sidecar journal of an in-place operation.
+pub(crate) struct Journal {
+    file: File,
+    path: PathBuf,
+    operation: Operation,
+    segment_size: usize,
+    undo_offset: u64,
+    seq: u64,
+}
+
+fn invalid(path: &Path, what: &str) -> Error {
+    Error::Journal(format!("{}: {what}", path.display()))
+}
+
+impl Journal {
+    /// Write a new journal of `operation` for the steps of `steps` that modify the
+    /// image, and sync it to disk.
+    pub(crate) fn create(
+        path: &Path,
+        operation: Operation,
+        rom_len: usize,
+        segment_size: usize,
+        steps: &[Step],
+    ) -> io::Result<Self> {
+        let mut plan = Vec::new();
+        for step in steps {
+            step.encode(&mut plan);
+        }
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        header[8..16].copy_from_slice(&(segment_size as u64).to_le_bytes());
+        header[16..24].copy_from_slice(&(rom_len as u64).to_le_bytes());
+        header[24..32].copy_from_slice(&(plan.len() as u64).to_le_bytes());
+        header[32..64].copy_from_slice(&Sha256::digest(&plan));
+        header[64] = operation_code(operation);
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(&header)?;
+        let slot = Slot {
+            seq: 0,
+            cursor: Cursor::default(),
+            flight: None,
+        };
+        file.write_all(&slot.encode())?;
+        file.write_all(&[0u8; SLOT_SIZE as usize])?;
+        file.write_all(&plan)?;
+        file.sync_all()?;
+
+        Ok(Journal {
+            file,
+            path: path.to_path_buf(),
+            operation,
+            segment_size,
+            undo_offset: (PLAN_OFFSET + plan.len() as u64).next_multiple_of(0x1000),
+            seq: 0,
+        })
+    }
+
+    /// Open the journal at `path`, if there is one, returning it with its plan and the
+    /// latest slot.
+    fn open(path: &Path, rom_len: usize) -> Result<Option<(Self, Vec<Step>, Slot)>, Error> {
+        let mut file = match File::options().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut head = [0u8; PLAN_OFFSET as usize];
+        file.read_exact(&mut head)
+            .map_err(|_| invalid(path, "journal is truncated"))?;
+        if &head[0..4] != MAGIC || head[4..8] != VERSION.to_le_bytes() {
+            return Err(invalid(path, "not a citrust journal"));
+        }
+        let field = |at: usize| u64::from_le_bytes(head[at..at + 8].try_into().unwrap());
+        let segment_size = usize::try_from(field(8)).unwrap_or(0);
+        if field(16) != rom_len as u64 {
+            return Err(invalid(path, "journal was written for a different file"));
+        }
+        let plan_len = field(24);
+        if segment_size == 0 || plan_len > 0x1000_0000 {
+            return Err(invalid(path, "corrupt journal header"));
+        }
+        let operation =
+            operation_from_code(head[64]).ok_or_else(|| invalid(path, "corrupt journal header"))?;
+
+        let mut plan = vec![0u8; plan_len as usize];
+        file.read_exact(&mut plan)
+            .map_err(|_| invalid(path, "journal is truncated"))?;
+        if Sha256::digest(&plan)[..] != head[32..64] {
+            return Err(invalid(path, "corrupt journal plan"));
+        }
+        let mut input = &plan[..];
+        let mut steps = Vec::new();
+        while !input.is_empty() {
+            let step = Step::decode(&mut input).ok_or_else(|| invalid(path, "corrupt plan"))?;
+            if step.extent().is_none_or(|end| end > rom_len) {
+                return Err(invalid(path, "plan does not fit the file"));
+            }
+            steps.push(step);
+        }
+
+        let slot = (0..2)
+            .filter_map(|i| {
+                let at = (HEADER_SIZE + i * SLOT_SIZE) as usize;
+                Slot::decode(&head[at..at + SLOT_SIZE as usize])
+            })
+            .max_by_key(|slot| slot.seq)
+            .ok_or_else(|| invalid(path, "no valid progress record"))?;
+
+        let journal = Journal {
+            file,
+            path: path.to_path_buf(),
+            operation,
+            segment_size,
+            undo_offset: (PLAN_OFFSET + plan_len).next_multiple_of(0x1000),
+            seq: slot.seq,
+        };
+        Ok(Some((journal, steps, slot)))
+    }
+
+    fn write_slot(&mut self, cursor: Cursor, flight: Option<(Cursor, u64)>) -> io::Result<()> {
+        self.seq += 1;
+        let slot = Slot {
+            seq: self.seq,
+            cursor,
+            flight,
+        };
+        let at = HEADER_SIZE + (self.seq % 2) * SLOT_SIZE;
+        self.file.seek(SeekFrom::Start(at))?;
+        self.file.write_all(&slot.encode())?;
+        self.file.sync_data()
+    }
+
+    /// Save the original bytes of segment `flight` before it is modified.
+    fn begin(&mut self, cursor: Cursor, flight: Cursor, undo: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.undo_offset))?;
+        self.file.write_all(undo)?;
+        self.file.sync_data()?;
+        self.write_slot(cursor, Some((flight, undo.len() as u64)))
+    }
+
+    /// Record that everything before `cursor` is done and nothing is in flight.
+    fn commit(&mut self, cursor: Cursor) -> io::Result<()> {
+        self.write_slot(cursor, None)
+    }
+
+    /// The operation this journal was written for.
+    pub(crate) fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// Delete the journal once the operation has completed or been rolled back.
+    pub(crate) fn remove(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)
+    }
+}
+
+/// An interrupted operation, with its in-flight segment already restored.
+pub(crate) struct Interrupted {
+    pub journal: Journal,
+    pub steps: Vec<Step>,
+    pub cursor: Cursor,
+}
+
+/// The operation of the journal at `path`, if there is one, without touching the image.
+pub(crate) fn pending_operation(path: &Path, rom_len: usize) -> Result<Option<Operation>, Error> {
+    Ok(Journal::open(path, rom_len)?.map(|(journal, _, _)| journal.operation))
+}
+
+/// Open the journal at `path` and undo its in-flight segment, if any.
+pub(crate) fn recover<I: Image + ?Sized>(
+    path: &Path,
+    image: &mut I,
+) -> Result<Option<Interrupted>, Error> {
+    let len = image.bytes().len();
+    let Some((mut journal, steps, slot)) = Journal::open(path, len)? else {
+        return Ok(None);
+    };
+
+    if let Some((flight, undo_len)) = slot.flight {
+        let step = steps
+            .get(flight.step)
+            .filter(|s| flight.segment < s.segments(journal.segment_size))
+            .ok_or_else(|| invalid(path, "corrupt progress record"))?;
+        let (at, seg_len) = step.segment(flight.segment, journal.segment_size);
+        if undo_len != seg_len as u64 {
+            return Err(invalid(path, "corrupt progress record"));
+        }
+        journal.file.seek(SeekFrom::Start(journal.undo_offset))?;
+        journal
+            .file
+            .read_exact(&mut image.bytes()[at..at + seg_len])
+            .map_err(|_| invalid(path, "undo area is truncated"))?;
+        image.persist(at, seg_len)?;
+        journal.commit(slot.cursor)?;
+    }
+
+    Ok(Some(Interrupted {
+        journal,
+        steps,
+        cursor: slot.cursor,
+    }))
+}
+
+/// Apply `steps` to `image` starting at `from`, recording progress in `journal`.
+///
+/// `cancel` is checked between segments and between the chunks of a segment. With a
+/// journal, a cancelled run can be rolled back or resumed like an interrupted one.
+///
+/// The journal holds only the steps that modify the image, so cursors are recorded as
+/// positions in that list.
+pub(crate) fn run<I: Image + ?Sized>(
+    image: &mut I,
+    steps: &[Step],
+    mut journal: Option<&mut Journal>,
+    from: Cursor,
+    cancel: &CancelToken,
+    on_progress: &mut impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    let segment_size = journal.as_ref().map_or(SEGMENT_SIZE, |j| j.segment_size);
+    let mut cursor = from;
+
+    // journaled[i]: number of modifying steps before step i
+    let journaled: Vec<usize> = std::iter::once(0)
+        .chain(steps.iter().scan(0, |n, step| {
+            *n += step.modifies() as usize;
+            Some(*n)
+        }))
+        .collect();
+    let in_journal = |c: Cursor| Cursor {
+        step: journaled[c.step],
+        segment: c.segment,
+    };
+
+    let total: u64 = steps.iter().map(Step::bytes).sum();
+    let mut processed: u64 = steps[..cursor.step.min(steps.len())]
+        .iter()
+        .map(Step::bytes)
+        .sum();
+    if let Some(step) = steps.get(cursor.step) {
+        processed += (0..cursor.segment)
+            .map(|i| step.segment(i, segment_size).1 as u64)
+            .sum::<u64>();
+    }
+    if total > 0 {
+        on_progress(&ProgressEvent::Bytes { processed, total });
+    }
+
+    while let Some(step) = steps.get(cursor.step) {
+        let segments = step.segments(segment_size);
+        if cursor.segment == 0 {
+            report(step, false, on_progress);
+        }
+
+        while cursor.segment < segments {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let (at, len) = step.segment(cursor.segment, segment_size);
+            if let Some(journal) = journal.as_deref_mut() {
+                let at_cursor = in_journal(cursor);
+                journal.begin(at_cursor, at_cursor, &image.bytes()[at..at + len])?;
+            }
+            step.apply(image.bytes(), cursor.segment, segment_size, true, cancel)?;
+            #[cfg(test)]
+            tests::maybe_crash()?;
+
+            cursor.segment += 1;
+            if let Some(journal) = journal.as_deref_mut() {
+                image.persist(at, len)?;
+                let next = if cursor.segment == segments {
+                    Cursor {
+                        step: cursor.step + 1,
+                        segment: 0,
+                    }
+                } else {
+                    cursor
+                };
+                journal.commit(in_journal(next))?;
+            }
+            processed += len as u64;
+            on_progress(&ProgressEvent::Bytes { processed, total });
+        }
+
+        report(step, true, on_progress);
+        cursor = Cursor {
+            step: cursor.step + 1,
+            segment: 0,
+        };
+    }
+    Ok(())
+}
+
+/// Undo every completed segment before `from`, newest first.
+pub(crate) fn rollback<I: Image + ?Sized>(
+    image: &mut I,
+    steps: &[Step],
+    journal: &mut Journal,
+    from: Cursor,
+) -> Result<(), Error> {
+    let segment_size = journal.segment_size;
+    let mut cursor = from;
+
+    loop {
+        if cursor.segment == 0 {
+            if cursor.step == 0 {
+                break;
+            }
+            cursor.step -= 1;
+            cursor.segment = steps[cursor.step].segments(segment_size);
+            continue;
+        }
+
+        let step = &steps[cursor.step];
+        let done = cursor;
+        cursor.segment -= 1;
+        let (at, len) = step.segment(cursor.segment, segment_size);
+        journal.begin(done, cursor, &image.bytes()[at..at + len])?;
+        step.apply(
+            image.bytes(),
+            cursor.segment,
+            segment_size,
+            false,
+            &CancelToken::new(),
+        )?;
+        image.persist(at, len)?;
+        journal.commit(cursor)?;
+    }
+    Ok(())
+}
+
+/// Progress events before (`done == false`) and after a step.
+fn report(step: &Step, done: bool, on_progress: &mut impl FnMut(&ProgressEvent)) {
+    match *step {
+        Step::Note(ref event) if !done => on_progress(event),
+        Step::Ctr {
+            partition,
+            region,
+            direction,
+            len,
+            ..
+        } => on_progress(&if done {
+            ProgressEvent::RegionDone {
+                partition,
+                region,
+                direction,
+            }
+        } else {
+            ProgressEvent::RegionStart {
+                partition,
+                region,
+                direction,
+                bytes: len as u64,
+            }
+        }),
+        Step::Cbc { content, len, .. } if !done => on_progress(&ProgressEvent::TitleKeyLayer {
+            content,
+            bytes: len as u64,
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        static CRASH_AFTER: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// Make the `n`-th segment applied on this thread (counting from 0) fail right
+    /// after it has been modified, as if the process had been killed.
+    pub(crate) fn crash_after(n: Option<usize>) {
+        CRASH_AFTER.with(|c| c.set(n));
+    }
+
+    pub(super) fn maybe_crash() -> io::Result<()> {
+        CRASH_AFTER.with(|c| match c.get() {
+            Some(0) => {
+                c.set(None);
+                Err(io::Error::other("simulated crash"))
+            }
+            Some(n) => {
+                c.set(Some(n - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        })
+    }
+
+    fn sample_steps(data: &[u8]) -> Vec<Step> {
+        vec![
+            Step::Note(ProgressEvent::CryptoMethod {
+                partition: 0,
+                method: Some(crate::keys::CryptoMethod::Key7x),
+                fixed_key: false,
+                seed: false,
+            }),
+            Step::Cbc {
+                content: 0,
+                start: 0x100,
+                len: 0x500,
+                key: [7; 16],
+                iv: 0x1234,
+            },
+            Step::Note(ProgressEvent::PartitionStart {
+                partition: 0,
+                bytes: 0x380,
+            }),
+            Step::Ctr {
+                partition: 0,
+                region: Region::RomFs,
+                direction: Direction::Decrypt,
+                start: 0x200,
+                len: 0x380,
+                key: [9; 16],
+                iv: 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF,
+            },
+            Step::Patch {
+                offset: 0x10,
+                old: data[0x10..0x14].to_vec(),
+                new: vec![1, 2, 3, 4],
+            },
+        ]
+    }
+
+    fn sample_image() -> Vec<u8> {
+        (0..0x800u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_step_encoding_roundtrip() {
+        let steps = sample_steps(&sample_image());
+        let mut plan = Vec::new();
+        for step in &steps {
+            step.encode(&mut plan);
+        }
+        let mut input = &plan[..];
+        let decoded: Vec<_> = std::iter::from_fn(|| Step::decode(&mut input)).collect();
+        let modifying: Vec<_> = steps.into_iter().filter(Step::modifies).collect();
+        assert_eq!(decoded, modifying);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_interrupted_run_resumes_and_rolls_back() {
+        let original = sample_image();
+        let steps = sample_steps(&original);
+        let mut expected = original.clone();
+        run(
+            &mut expected[..],
+            &steps,
+            None,
+            Cursor::default(),
+            &CancelToken::new(),
+            &mut |_| {},
+        )
+        .unwrap();
+
+        let dir = PathBuf::from("test-fixtures");
+        let _ = fs::create_dir_all(&dir);
+        for crash in 0..8 {
+            for resume in [true, false] {
+                let path = dir.join(format!("journal_{crash}_{resume}.journal"));
+                let _ = fs::remove_file(&path);
+                let mut image = original.clone();
+                let mut journal =
+                    Journal::create(&path, Operation::Decrypt, image.len(), 0x100, &steps).unwrap();
+
+                crash_after(Some(crash));
+                let result = run(
+                    &mut image[..],
+                    &steps,
+                    Some(&mut journal),
+                    Cursor::default(),
+                    &CancelToken::new(),
+                    &mut |_| {},
+                );
+                crash_after(None);
+                drop(journal);
+
+                let interrupted = recover(&path, &mut image[..]).unwrap().unwrap();
+                let Interrupted {
+                    mut journal,
+                    steps,
+                    cursor,
+                } = interrupted;
+                if resume {
+                    run(
+                        &mut image[..],
+                        &steps,
+                        Some(&mut journal),
+                        cursor,
+                        &CancelToken::new(),
+                        &mut |_| {},
+                    )
+                    .unwrap();
+                    assert!(image == expected, "resume after crash {crash} diverged");
+                } else {
+                    rollback(&mut image[..], &steps, &mut journal, cursor).unwrap();
+                    assert!(image == original, "rollback after crash {crash} diverged");
+                }
+                journal.remove().unwrap();
+                assert!(result.is_err());
+            }
+        }
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 use std::io::BufRead;
 use std::path::{Path, PathBuf};
 
+use crate::seeddb::SeedDatabase;
+
 #[derive(Debug, thiserror::Error)]
 pub enum KeyDbError {
     #[error("key file not found at {0}")]
@@ -12,10 +14,12 @@ pub enum KeyDbError {
     Io(#[from] std::io::Error),
 }
 
-/// A database of 128-bit AES keys parsed from a Citra-compatible `aes_keys.txt` file.
+/// A database of 128-bit AES keys parsed from a Citra-compatible `aes_keys.txt` file,
+/// plus any title seeds attached for seed crypto.
 #[derive(Debug, Clone)]
 pub struct KeyDatabase {
     keys: HashMap<String, u128>,
+    seeds: SeedDatabase,
 }
 
 impl KeyDatabase {
@@ -91,7 +95,10 @@ impl KeyDatabase {
             keys.insert(name, parsed);
         }
 
-        Ok(KeyDatabase { keys })
+        Ok(KeyDatabase {
+            keys,
+            seeds: SeedDatabase::new(),
+        })
     }
 
     /// Parse key database from a file path.
@@ -165,6 +172,21 @@ impl KeyDatabase {
         self.keys.get(&name.to_lowercase()).copied()
     }
 
+    /// Attach title seeds used to decrypt seed-crypto NCCHs.
+    pub fn set_seeds(&mut self, seeds: SeedDatabase) {
+        self.seeds = seeds;
+    }
+
+    /// Title seeds attached to this database.
+    pub fn seeds(&self) -> &SeedDatabase {
+        &self.seeds
+    }
+
+    /// Mutable access to the attached title seeds.
+    pub fn seeds_mut(&mut self) -> &mut SeedDatabase {
+        &mut self.seeds
+    }
+
     /// Number of keys loaded.
     pub fn len(&self) -> usize {
         self.keys.len()
@@ -1,6 +1,8 @@
+use serde::Serialize;
+
 pub type Key128 = [u8; 16];
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum CryptoMethod {
     Original,
     Key7x,
@@ -18,6 +20,16 @@ impl CryptoMethod {
             _ => None,
         }
     }
+
+    /// The flags[3] byte that selects this method in an NCCH header.
+    pub fn flag(self) -> u8 {
+        match self {
+            CryptoMethod::Original => 0x00,
+            CryptoMethod::Key7x => 0x01,
+            CryptoMethod::Key93 => 0x0A,
+            CryptoMethod::Key96 => 0x0B,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -33,4 +45,16 @@ mod tests {
         assert_eq!(CryptoMethod::from_flag(0xFF), None);
         assert_eq!(CryptoMethod::from_flag(0x02), None);
     }
+
+    #[test]
+    fn test_crypto_method_flag_roundtrip() {
+        for method in [
+            CryptoMethod::Original,
+            CryptoMethod::Key7x,
+            CryptoMethod::Key93,
+            CryptoMethod::Key96,
+        ] {
+            assert_eq!(CryptoMethod::from_flag(method.flag()), Some(method));
+        }
+    }
 }
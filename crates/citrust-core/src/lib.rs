@@ -1,6 +1,21 @@
+pub mod blz;
+pub mod cancel;
+pub mod cia;
 pub mod crypto;
 pub mod decrypt;
+pub mod exefs;
+pub mod exheader;
+pub mod format;
+pub mod inspect;
+pub mod journal;
 pub mod keydb;
 pub mod keys;
 pub mod ncch;
 pub mod ncsd;
+pub mod progress;
+pub mod repair;
+pub mod romfs;
+pub mod romfs_builder;
+pub mod seeddb;
+pub mod smdh;
+pub mod verify;
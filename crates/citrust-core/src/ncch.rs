@@ -1,11 +1,40 @@
+use std::fmt;
 use std::io::{self, Read, Seek, SeekFrom};
 
 use crate::keys::CryptoMethod;
 
+/// Independently encrypted regions of an NCCH partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Region {
+    ExHeader,
+    ExefsHeader,
+    ExefsData,
+    Code,
+    RomFs,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Region::ExHeader => "ExHeader",
+            Region::ExefsHeader => "ExeFS Filename Table",
+            Region::ExefsData => "data",
+            Region::Code => ".code",
+            Region::RomFs => "RomFS",
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NcchHeader {
     pub key_y: u128,
     pub title_id: u64,
+    pub seed_check: [u8; 4],
+    pub program_id: u64,
+    /// Product code (e.g. `CTR-P-CTAP`), NUL-padded ASCII.
+    pub product_code: [u8; 16],
+    /// SHA-256 of the ExHeader (`exheader_length` bytes).
+    pub exheader_hash: [u8; 32],
     pub partition_flags: [u8; 8],
     pub exheader_length: u32,
     pub plain_offset: u32,
@@ -14,13 +43,33 @@ pub struct NcchHeader {
     pub logo_length: u32,
     pub exefs_offset: u32,
     pub exefs_length: u32,
+    /// Size in media units of the ExeFS region covered by `exefs_superblock_hash`.
+    pub exefs_hash_region_size: u32,
     pub romfs_offset: u32,
     pub romfs_length: u32,
+    /// Size in media units of the RomFS region covered by `romfs_superblock_hash`.
+    pub romfs_hash_region_size: u32,
+    pub exefs_superblock_hash: [u8; 32],
+    pub romfs_superblock_hash: [u8; 32],
+}
+
+/// `0x200 << exponent`, or `None` if that does not fit in a `u32`.
+fn unit_size(exponent: u8) -> Option<u32> {
+    1u32.checked_shl(exponent as u32)
+        .and_then(|units| units.checked_mul(0x200))
 }
 
 impl NcchHeader {
     /// Parse NCCH header at the given partition offset
     pub fn parse<R: Read + Seek>(reader: &mut R, partition_offset: u64) -> io::Result<Self> {
+        // Every field below lies in the first 0x200 bytes, so this makes the offsets safe
+        if partition_offset.checked_add(0x200).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "NCCH header offset out of range",
+            ));
+        }
+
         // KeyY: first 16 bytes of partition (from RSA signature)
         reader.seek(SeekFrom::Start(partition_offset))?;
         let mut key_y_bytes = [0u8; 16];
@@ -33,6 +82,24 @@ impl NcchHeader {
         reader.read_exact(&mut tid_bytes)?;
         let title_id = u64::from_le_bytes(tid_bytes);
 
+        // Seed check hash at partition+0x114, program ID at partition+0x118 (LE u64)
+        reader.seek(SeekFrom::Start(partition_offset + 0x114))?;
+        let mut seed_check = [0u8; 4];
+        reader.read_exact(&mut seed_check)?;
+        let mut pid_bytes = [0u8; 8];
+        reader.read_exact(&mut pid_bytes)?;
+        let program_id = u64::from_le_bytes(pid_bytes);
+
+        // Product code at partition+0x150
+        reader.seek(SeekFrom::Start(partition_offset + 0x150))?;
+        let mut product_code = [0u8; 16];
+        reader.read_exact(&mut product_code)?;
+
+        // ExHeader SHA-256 at partition+0x160
+        reader.seek(SeekFrom::Start(partition_offset + 0x160))?;
+        let mut exheader_hash = [0u8; 32];
+        reader.read_exact(&mut exheader_hash)?;
+
         // ExHeader length at partition+0x180, LE u32
         reader.seek(SeekFrom::Start(partition_offset + 0x180))?;
         let mut buf4 = [0u8; 4];
@@ -43,6 +110,15 @@ impl NcchHeader {
         reader.seek(SeekFrom::Start(partition_offset + 0x188))?;
         let mut partition_flags = [0u8; 8];
         reader.read_exact(&mut partition_flags)?;
+        if unit_size(partition_flags[6]).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "NCCH media unit size exponent {} is too large",
+                    partition_flags[6]
+                ),
+            ));
+        }
 
         // Plain region at partition+0x190
         reader.seek(SeekFrom::Start(partition_offset + 0x190))?;
@@ -63,18 +139,33 @@ impl NcchHeader {
         reader.read_exact(&mut buf4)?;
         let exefs_length = u32::from_le_bytes(buf4);
 
-        // Skip 8 bytes (ExeFS hash region size + reserved)
+        // ExeFS hash region size at partition+0x1A8 (followed by 4 reserved bytes)
+        reader.read_exact(&mut buf4)?;
+        let exefs_hash_region_size = u32::from_le_bytes(buf4);
         reader.seek(SeekFrom::Start(partition_offset + 0x1B0))?;
 
-        // RomFS at partition+0x1B0
+        // RomFS at partition+0x1B0, hash region size at partition+0x1B8
         reader.read_exact(&mut buf4)?;
         let romfs_offset = u32::from_le_bytes(buf4);
         reader.read_exact(&mut buf4)?;
         let romfs_length = u32::from_le_bytes(buf4);
+        reader.read_exact(&mut buf4)?;
+        let romfs_hash_region_size = u32::from_le_bytes(buf4);
+
+        // Superblock hashes at partition+0x1C0 (ExeFS) and partition+0x1E0 (RomFS)
+        reader.seek(SeekFrom::Start(partition_offset + 0x1C0))?;
+        let mut exefs_superblock_hash = [0u8; 32];
+        reader.read_exact(&mut exefs_superblock_hash)?;
+        let mut romfs_superblock_hash = [0u8; 32];
+        reader.read_exact(&mut romfs_superblock_hash)?;
 
         Ok(NcchHeader {
             key_y,
             title_id,
+            seed_check,
+            program_id,
+            product_code,
+            exheader_hash,
             partition_flags,
             exheader_length,
             plain_offset,
@@ -83,11 +174,30 @@ impl NcchHeader {
             logo_length,
             exefs_offset,
             exefs_length,
+            exefs_hash_region_size,
             romfs_offset,
             romfs_length,
+            romfs_hash_region_size,
+            exefs_superblock_hash,
+            romfs_superblock_hash,
         })
     }
 
+    /// Product code up to the first NUL, with anything outside printable ASCII replaced.
+    pub fn product_code(&self) -> String {
+        self.product_code
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '?'
+                }
+            })
+            .collect()
+    }
+
     /// Get crypto method from flags[3]
     pub fn crypto_method(&self) -> Option<CryptoMethod> {
         CryptoMethod::from_flag(self.partition_flags[3])
@@ -103,6 +213,17 @@ impl NcchHeader {
         self.partition_flags[7] & 0x01 != 0
     }
 
+    /// Check if SeedCrypto bit is set (flags[7] & 0x20)
+    pub fn uses_seed(&self) -> bool {
+        self.partition_flags[7] & 0x20 != 0
+    }
+
+    /// Media unit size in bytes, from flags[6]. [`NcchHeader::parse`] rejects an exponent
+    /// too large for a `u32`; one set by hand falls back to 0x200.
+    pub fn media_unit_size(&self) -> u32 {
+        unit_size(self.partition_flags[6]).unwrap_or(0x200)
+    }
+
     /// Plain region IV
     pub fn plain_iv(&self) -> u128 {
         ((self.title_id as u128) << 64) | 0x0100_0000_0000_0000u128
@@ -136,6 +257,16 @@ mod tests {
         let title_id = 0x0004000000055D00u64;
         data[0x108..0x110].copy_from_slice(&title_id.to_le_bytes());
 
+        // Seed check at 0x114, ProgramID at 0x118
+        data[0x114..0x118].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        data[0x118..0x120].copy_from_slice(&title_id.to_le_bytes());
+
+        // Product code at 0x150
+        data[0x150..0x15A].copy_from_slice(b"CTR-P-EKJA");
+
+        // ExHeader hash at 0x160
+        data[0x160..0x180].fill(0x11);
+
         // ExHeader length at 0x180
         data[0x180..0x184].copy_from_slice(&0x800u32.to_le_bytes());
 
@@ -160,10 +291,16 @@ mod tests {
         // ExeFS at 0x1A0
         data[0x1A0..0x1A4].copy_from_slice(&0x1000u32.to_le_bytes());
         data[0x1A4..0x1A8].copy_from_slice(&0x800u32.to_le_bytes());
+        data[0x1A8..0x1AC].copy_from_slice(&0x1u32.to_le_bytes());
 
         // RomFS at 0x1B0
         data[0x1B0..0x1B4].copy_from_slice(&0x2000u32.to_le_bytes());
         data[0x1B4..0x1B8].copy_from_slice(&0x4000u32.to_le_bytes());
+        data[0x1B8..0x1BC].copy_from_slice(&0x1u32.to_le_bytes());
+
+        // Superblock hashes at 0x1C0 (ExeFS) and 0x1E0 (RomFS)
+        data[0x1C0..0x1E0].fill(0x22);
+        data[0x1E0..0x200].fill(0x33);
 
         data
     }
@@ -177,11 +314,19 @@ mod tests {
 
         assert_eq!(header.key_y, 0x12345678_9ABCDEF0_FEDCBA98_76543210u128);
         assert_eq!(header.title_id, 0x0004000000055D00u64);
+        assert_eq!(header.seed_check, [0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(header.program_id, 0x0004000000055D00u64);
+        assert_eq!(header.product_code(), "CTR-P-EKJA");
         assert_eq!(header.exheader_length, 0x800);
         assert_eq!(header.exefs_offset, 0x1000);
         assert_eq!(header.exefs_length, 0x800);
         assert_eq!(header.romfs_offset, 0x2000);
         assert_eq!(header.romfs_length, 0x4000);
+        assert_eq!(header.exheader_hash, [0x11; 32]);
+        assert_eq!(header.exefs_hash_region_size, 1);
+        assert_eq!(header.romfs_hash_region_size, 1);
+        assert_eq!(header.exefs_superblock_hash, [0x22; 32]);
+        assert_eq!(header.romfs_superblock_hash, [0x33; 32]);
     }
 
     #[test]
@@ -234,6 +379,30 @@ mod tests {
         assert!(!header.is_no_crypto());
     }
 
+    #[test]
+    fn test_seed_flag() {
+        let mut data = create_minimal_ncch_header();
+
+        data[0x18F] = 0x20; // Set SeedCrypto bit
+        let mut cursor = Cursor::new(data);
+        let header = NcchHeader::parse(&mut cursor, 0).unwrap();
+
+        assert!(header.uses_seed());
+        assert!(!header.is_fixed_key());
+    }
+
+    #[test]
+    fn test_media_unit_size() {
+        let mut data = create_minimal_ncch_header();
+
+        data[0x18E] = 2;
+        let header = NcchHeader::parse(&mut Cursor::new(data.clone()), 0).unwrap();
+        assert_eq!(header.media_unit_size(), 0x800);
+
+        data[0x18E] = 23;
+        assert!(NcchHeader::parse(&mut Cursor::new(data), 0).is_err());
+    }
+
     #[test]
     fn test_iv_construction() {
         let data = create_minimal_ncch_header();
@@ -43,7 +43,15 @@ impl NcsdHeader {
         reader.seek(SeekFrom::Start(0x188))?;
         let mut flags = [0u8; 8];
         reader.read_exact(&mut flags)?;
-        let sector_size = 0x200u32 * 2u32.pow(flags[6] as u32);
+        let sector_size = 1u32
+            .checked_shl(flags[6] as u32)
+            .and_then(|units| units.checked_mul(0x200))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("NCSD sector size exponent {} is too large", flags[6]),
+                )
+            })?;
 
         // Read 8 partition entries at 0x120
         reader.seek(SeekFrom::Start(0x120))?;
@@ -116,6 +124,16 @@ mod tests {
         assert_eq!(header.sector_size, 0x400);
     }
 
+    #[test]
+    fn test_reject_oversized_sector_size() {
+        let mut data = vec![0u8; 512];
+        data[0x100..0x104].copy_from_slice(b"NCSD");
+        data[0x18E] = 23;
+
+        let mut cursor = Cursor::new(data);
+        assert!(NcsdHeader::parse(&mut cursor).is_err());
+    }
+
     #[test]
     fn test_partition_entry_helpers() {
         let entry = PartitionEntry {
@@ -0,0 +1,330 @@
+//! Typed progress reporting for long-running ROM operations.
+//!
+//! Every event renders as the line of text citrust has always printed for it (see
+//! [`ProgressEvent::message`]), so a front-end can print the log as before, while
+//! drawing progress bars, throughput and ETA from the typed fields.
+
+use std::fmt;
+
+use crate::keys;
+use crate::ncch::Region;
+
+/// Whether a pass decrypts or encrypts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Decrypt,
+    Encrypt,
+}
+
+impl Direction {
+    fn verb(self) -> &'static str {
+        match self {
+            Direction::Decrypt => "Decrypting",
+            Direction::Encrypt => "Encrypting",
+        }
+    }
+}
+
+/// The in-place operation a journal was written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Decrypt,
+    Encrypt,
+    Repair,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Decrypt => "decryption",
+            Operation::Encrypt => "encryption",
+            Operation::Repair => "repair",
+        })
+    }
+}
+
+/// Top-level filesystem of an NCCH that a region belongs to, as used in progress lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Exefs,
+    Romfs,
+}
+
+impl Section {
+    pub fn of(region: Region) -> Self {
+        if region == Region::RomFs {
+            Section::Romfs
+        } else {
+            Section::Exefs
+        }
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Section::Exefs => "ExeFS",
+            Section::Romfs => "RomFS",
+        })
+    }
+}
+
+/// Why a partition was left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The NCSD partition table entry is empty.
+    NotFound,
+    /// There is no NCCH header at the partition's offset.
+    InvalidHeader,
+    AlreadyDecrypted,
+    AlreadyEncrypted,
+    /// The partition is flagged NoCrypto but its content is still encrypted.
+    ContentEncrypted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The key database in use, with its number of keys.
+    KeysLoaded {
+        count: usize,
+    },
+    /// An interrupted in-place operation is being resumed from its journal.
+    Resuming {
+        operation: Operation,
+    },
+    /// An interrupted in-place operation is being rolled back.
+    RollingBack {
+        operation: Operation,
+    },
+    /// There was no interrupted operation to roll back.
+    NothingToRollBack,
+    PartitionSkipped {
+        partition: u16,
+        reason: SkipReason,
+    },
+    /// The partition is about to be processed; `bytes` is the amount it will touch.
+    PartitionStart {
+        partition: u16,
+        bytes: u64,
+    },
+    /// The crypto method of the first partition.
+    CryptoMethod {
+        partition: u16,
+        /// Method selected by the NCCH flags, or `None` for an unknown value (decrypted
+        /// like [`keys::CryptoMethod::Original`]).
+        method: Option<keys::CryptoMethod>,
+        /// FixedCryptoKey flag: every region uses the zero key, whatever `method` says.
+        fixed_key: bool,
+        /// Seed crypto: the method key is derived with the title's seed.
+        seed: bool,
+    },
+    /// The partition is flagged NoCrypto but its content is encrypted.
+    FlaggedDecryptedButEncrypted {
+        partition: u16,
+    },
+    /// The partition is not flagged NoCrypto but its content is already plaintext.
+    ContentAlreadyDecrypted {
+        partition: u16,
+    },
+    /// The NoCrypto flag of a partition is being set.
+    SettingNoCryptoFlag {
+        partition: u16,
+    },
+    /// The partition has no ExeFS or no RomFS.
+    SectionMissing {
+        partition: u16,
+        section: Section,
+    },
+    RegionStart {
+        partition: u16,
+        region: Region,
+        direction: Direction,
+        bytes: u64,
+    },
+    RegionDone {
+        partition: u16,
+        region: Region,
+        direction: Direction,
+    },
+    /// The title-key (AES-CBC) layer of a CIA content is being removed.
+    TitleKeyLayer {
+        content: u16,
+        bytes: u64,
+    },
+    /// A CIA content that does not hold an NCCH.
+    ContentNotNcch {
+        content: u16,
+    },
+    /// Overall progress through the image, sent after every piece of work.
+    Bytes {
+        processed: u64,
+        total: u64,
+    },
+    Done,
+}
+
+impl ProgressEvent {
+    /// The log line for this event, or `None` for events that only carry numbers.
+    pub fn message(&self) -> Option<String> {
+        match self {
+            ProgressEvent::PartitionStart { .. } | ProgressEvent::Bytes { .. } => None,
+            event => Some(event.to_string()),
+        }
+    }
+}
+
+fn mb(bytes: u64) -> u64 {
+    bytes / (1024 * 1024)
+}
+
+impl fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressEvent::KeysLoaded { count } => {
+                write!(f, "Using external key database ({count} keys loaded)")
+            }
+            ProgressEvent::Resuming { operation } => {
+                write!(f, "Resuming interrupted {operation}...")
+            }
+            ProgressEvent::RollingBack { operation } => {
+                write!(f, "Rolling back interrupted {operation}...")
+            }
+            ProgressEvent::NothingToRollBack => {
+                f.write_str("No interrupted operation to roll back")
+            }
+            ProgressEvent::PartitionSkipped { partition, reason } => match reason {
+                SkipReason::NotFound => write!(f, "Partition {partition} Not found... Skipping..."),
+                SkipReason::InvalidHeader => {
+                    write!(f, "Partition {partition} Unable to read NCCH header")
+                }
+                SkipReason::AlreadyDecrypted => {
+                    write!(f, "Partition {partition}: Already Decrypted ✓")
+                }
+                SkipReason::AlreadyEncrypted => {
+                    write!(f, "Partition {partition}: Already Encrypted ✓")
+                }
+                SkipReason::ContentEncrypted => write!(
+                    f,
+                    "Partition {partition}: Flagged decrypted but content is encrypted... Skipping..."
+                ),
+            },
+            ProgressEvent::PartitionStart { partition, bytes } => {
+                write!(f, "Partition {partition}: {} mb", mb(*bytes))
+            }
+            ProgressEvent::CryptoMethod {
+                method,
+                fixed_key,
+                seed,
+                ..
+            } => {
+                f.write_str("Encryption Method: ")?;
+                if *fixed_key {
+                    return f.write_str("Zero Key");
+                }
+                write!(f, "{:?}", method.unwrap_or(keys::CryptoMethod::Original))?;
+                if *seed {
+                    f.write_str(" + Seed")?;
+                }
+                Ok(())
+            }
+            ProgressEvent::FlaggedDecryptedButEncrypted { partition } => write!(
+                f,
+                "Partition {partition}: Flagged as decrypted but content is encrypted, decrypting..."
+            ),
+            ProgressEvent::ContentAlreadyDecrypted { partition } => write!(
+                f,
+                "Partition {partition}: Content already decrypted (mis-flagged ROM), setting NoCrypto flag..."
+            ),
+            ProgressEvent::SettingNoCryptoFlag { partition } => {
+                write!(f, "Partition {partition}: Setting NoCrypto flag...")
+            }
+            ProgressEvent::SectionMissing { partition, section } => {
+                write!(f, "Partition {partition} {section}: No Data... Skipping...")
+            }
+            ProgressEvent::RegionStart {
+                partition,
+                region,
+                direction,
+                bytes,
+            } => write!(
+                f,
+                "Partition {partition} {}: {}: {region} ({} mb)",
+                Section::of(*region),
+                direction.verb(),
+                mb(*bytes)
+            ),
+            ProgressEvent::RegionDone {
+                partition,
+                region,
+                direction,
+            } => write!(
+                f,
+                "Partition {partition} {}: {}: {region}... Done!",
+                Section::of(*region),
+                direction.verb()
+            ),
+            ProgressEvent::TitleKeyLayer { content, bytes } => write!(
+                f,
+                "Content {content}: Decrypting: title key layer ({} mb)",
+                mb(*bytes)
+            ),
+            ProgressEvent::ContentNotNcch { content } => {
+                write!(f, "Content {content}: Not an NCCH... Skipping...")
+            }
+            ProgressEvent::Bytes { processed, total } => {
+                write!(f, "{} / {} mb", mb(*processed), mb(*total))
+            }
+            ProgressEvent::Done => f.write_str("Done..."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_match_log_lines() {
+        let event = ProgressEvent::RegionStart {
+            partition: 0,
+            region: Region::RomFs,
+            direction: Direction::Decrypt,
+            bytes: 3 * 1024 * 1024,
+        };
+        assert_eq!(
+            event.message().unwrap(),
+            "Partition 0 RomFS: Decrypting: RomFS (3 mb)"
+        );
+        let event = ProgressEvent::RegionDone {
+            partition: 1,
+            region: Region::Code,
+            direction: Direction::Encrypt,
+        };
+        assert_eq!(
+            event.message().unwrap(),
+            "Partition 1 ExeFS: Encrypting: .code... Done!"
+        );
+        assert_eq!(
+            ProgressEvent::Bytes {
+                processed: 1,
+                total: 2
+            }
+            .message(),
+            None
+        );
+        let event = ProgressEvent::CryptoMethod {
+            partition: 0,
+            method: Some(keys::CryptoMethod::Key7x),
+            fixed_key: false,
+            seed: true,
+        };
+        assert_eq!(event.message().unwrap(), "Encryption Method: Key7x + Seed");
+        let event = ProgressEvent::CryptoMethod {
+            partition: 0,
+            method: None,
+            fixed_key: true,
+            seed: false,
+        };
+        assert_eq!(event.message().unwrap(), "Encryption Method: Zero Key");
+    }
+}
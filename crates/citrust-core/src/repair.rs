@@ -0,0 +1,743 @@
+//! Region-level encryption status of NCCH partitions, and repair of ROMs that were only
+//! partly decrypted.
+//!
+//! Each region is classified by its SHA-256 in the NCCH (or ExeFS) header: a region
+//! matching its hash as stored is decrypted, one that matches after decryption is
+//! still encrypted. This replaces the flag and filename heuristics for ROMs that went
+//! through a broken decryptor, which typically have e.g. a decrypted ExeFS but an
+//! encrypted RomFS.
+//!
+//! The RomFS is judged by its superblock (IVFC header and master hash), the only part
+//! the NCCH header hashes, and by the first and last block of each IVFC level against
+//! the level above, which catches a decryptor that stopped part-way through the RomFS.
+//! `verify` checks every block.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+
+use crate::cancel::CancelToken;
+use crate::crypto::aes_ctr_decrypt;
+use crate::decrypt::{self, Error, PartitionKeys, offset_of};
+use crate::exefs::ExefsHeader;
+use crate::format::{self, RomFormat};
+use crate::journal::Step;
+use crate::keydb::KeyDatabase;
+use crate::ncch::{NcchHeader, Region};
+use crate::ncsd::NcsdHeader;
+use crate::progress::{Direction, Operation, ProgressEvent, SkipReason};
+use crate::romfs::{IVFC_HEADER_SIZE, IvfcHeader};
+use crate::verify::{self, HASH_CHUNK, HashStatus};
+
+/// Encryption state of one region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionState {
+    /// The region matches its hash as stored.
+    Decrypted,
+    /// The region matches its hash once decrypted.
+    Encrypted,
+    /// No hash is recorded, the region is truncated, or it matches in neither state
+    /// (corrupt data or a wrong key).
+    Unverified,
+}
+
+impl fmt::Display for RegionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RegionState::Decrypted => "decrypted",
+            RegionState::Encrypted => "encrypted",
+            RegionState::Unverified => "unverified",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionStatus {
+    pub region: Region,
+    pub state: RegionState,
+}
+
+/// Region states of one NCCH partition. Regions the partition does not have are
+/// omitted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionRegions {
+    /// NCSD partition index, or CIA content index.
+    pub index: usize,
+    /// The NCCH header is flagged NoCrypto.
+    pub flagged_decrypted: bool,
+    pub regions: Vec<RegionStatus>,
+}
+
+impl PartitionRegions {
+    /// Every region is decrypted.
+    pub fn is_decrypted(&self) -> bool {
+        self.regions
+            .iter()
+            .all(|r| r.state == RegionState::Decrypted)
+    }
+
+    /// Some regions are decrypted and others are not, or the content disagrees with
+    /// the NoCrypto flag.
+    pub fn is_inconsistent(&self) -> bool {
+        let decrypted = self
+            .regions
+            .iter()
+            .filter(|r| r.state == RegionState::Decrypted)
+            .count();
+        (decrypted > 0 && decrypted < self.regions.len())
+            || (self.flagged_decrypted && !self.is_decrypted())
+            || (!self.flagged_decrypted && self.is_decrypted() && decrypted > 0)
+    }
+}
+
+/// Classify every region of every NCCH partition of the ROM at `path`, mapping it
+/// read-only.
+///
+/// Keys are only needed for regions that do not match their hash as stored. CIAs with
+/// a title-key layer are rejected with [`Error::NotDecrypted`].
+pub fn region_status(path: &Path, keydb: &KeyDatabase) -> Result<Vec<PartitionRegions>, Error> {
+    let file = File::open(path)?;
+    // SAFETY: the mapping is only read, and only while classifying
+    let mmap = unsafe { Mmap::map(&file)? };
+    image_region_status(&mmap, keydb)
+}
+
+/// Classify every region of every NCCH partition in a ROM image, like [`region_status`].
+pub fn image_region_status(
+    data: &[u8],
+    keydb: &KeyDatabase,
+) -> Result<Vec<PartitionRegions>, Error> {
+    partitions(data)?
+        .into_iter()
+        .map(|part| analyse(data, &part, keydb).map(|analysis| analysis.status))
+        .collect()
+}
+
+/// Decrypt only the regions of a partly decrypted ROM that are still encrypted, and set
+/// the NoCrypto flag of every partition, in-place.
+///
+/// Nothing is modified if any region cannot be classified; such a region is reported
+/// as [`Error::RegionUnverified`]. The repair is journaled like [`decrypt::decrypt_rom`]:
+/// an interrupted repair is resumed by calling this again, and a ROM left with another
+/// operation's journal is refused.
+pub fn repair_rom(
+    path: &Path,
+    keydb: &KeyDatabase,
+    on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    repair_rom_cancellable(path, keydb, &CancelToken::new(), on_progress)
+}
+
+/// [`repair_rom`] that stops when `cancel` is set, rolling back like
+/// [`decrypt::decrypt_rom_cancellable`].
+pub fn repair_rom_cancellable(
+    path: &Path,
+    keydb: &KeyDatabase,
+    cancel: &CancelToken,
+    mut on_progress: impl FnMut(&ProgressEvent),
+) -> Result<(), Error> {
+    on_progress(&ProgressEvent::KeysLoaded { count: keydb.len() });
+    decrypt::run_in_place(
+        path,
+        Operation::Repair,
+        |data| plan_repair(data, keydb),
+        cancel,
+        &mut on_progress,
+    )?;
+    on_progress(&ProgressEvent::Done);
+    Ok(())
+}
+
+fn plan_repair(data: &[u8], keydb: &KeyDatabase) -> Result<Vec<Step>, Error> {
+    let mut steps = Vec::new();
+    for part in partitions(data)? {
+        let Analysis {
+            status,
+            ops,
+            patch_flags,
+        } = analyse(data, &part, keydb)?;
+        let p = status.index;
+
+        if let Some(unverified) = status
+            .regions
+            .iter()
+            .find(|r| r.state == RegionState::Unverified)
+        {
+            return Err(Error::RegionUnverified {
+                partition: p as u16,
+                region: unverified.region,
+            });
+        }
+        if ops.is_empty() && status.flagged_decrypted {
+            steps.push(Step::Note(ProgressEvent::PartitionSkipped {
+                partition: p as u16,
+                reason: SkipReason::AlreadyDecrypted,
+            }));
+            continue;
+        }
+
+        if !ops.is_empty() {
+            steps.push(Step::Note(ProgressEvent::PartitionStart {
+                partition: p as u16,
+                bytes: ops.iter().map(|op| op.len as u64).sum(),
+            }));
+        }
+        for op in ops {
+            steps.push(Step::Ctr {
+                partition: p as u16,
+                region: op.region,
+                direction: Direction::Decrypt,
+                start: op.start,
+                len: op.len,
+                key: op.key,
+                iv: op.iv,
+            });
+        }
+        if !status.flagged_decrypted || data[part.offset + 0x18B] != 0 {
+            steps.push(Step::Note(ProgressEvent::SettingNoCryptoFlag {
+                partition: p as u16,
+            }));
+            steps.push(patch_flags);
+        }
+    }
+    Ok(steps)
+}
+
+/// An NCCH partition of a ROM image.
+struct Partition {
+    index: usize,
+    offset: usize,
+    /// Media unit size of the NCSD, for NCSD partitions.
+    sector_size: Option<u32>,
+    /// Crypto method byte kept in the NCSD header, which only backs up partition 0;
+    /// other partitions keep their own flags[3].
+    backup_crypto: Option<u8>,
+}
+
+fn partitions(data: &[u8]) -> Result<Vec<Partition>, Error> {
+    let ncsd = match RomFormat::detect(data) {
+        Some(RomFormat::Ncsd) => {
+            Some(NcsdHeader::parse(&mut Cursor::new(data)).map_err(|_| Error::NotNcsd)?)
+        }
+        _ => None,
+    };
+    Ok(format::ncch_partitions(&mut Cursor::new(data))?
+        .into_iter()
+        .filter(|&(_, offset)| {
+            usize::try_from(offset)
+                .ok()
+                .and_then(|offset| data.get(offset.checked_add(0x100)?..offset.checked_add(0x104)?))
+                == Some(b"NCCH")
+        })
+        .map(|(index, offset)| Partition {
+            index,
+            offset: offset as usize,
+            sector_size: ncsd.as_ref().map(|ncsd| ncsd.sector_size),
+            backup_crypto: ncsd
+                .as_ref()
+                .filter(|_| index == 0)
+                .and_then(|_| data.get(0x118B).copied()),
+        })
+        .collect())
+}
+
+struct Analysis {
+    status: PartitionRegions,
+    /// AES-CTR passes over the regions that are still encrypted.
+    ops: Vec<decrypt::CryptOp>,
+    patch_flags: Step,
+}
+
+/// Classifies regions of one partition, deriving its keys on first use.
+struct Classifier<'a> {
+    data: &'a [u8],
+    ncch: NcchHeader,
+    keydb: &'a KeyDatabase,
+    keys: Option<PartitionKeys>,
+}
+
+impl Classifier<'_> {
+    fn keys(&mut self) -> Result<&PartitionKeys, Error> {
+        if self.keys.is_none() {
+            self.keys = Some(decrypt::partition_keys(&self.ncch, self.keydb)?);
+        }
+        Ok(self.keys.as_ref().unwrap())
+    }
+
+    /// Classify the `len` bytes at `start`, encrypted with the main (or base) key at
+    /// counter `iv`.
+    fn classify(
+        &mut self,
+        start: usize,
+        len: usize,
+        hash: &[u8; 32],
+        main: bool,
+        iv: u128,
+    ) -> Result<RegionState, Error> {
+        let data = self.data;
+        let Some(region) = start.checked_add(len).and_then(|end| data.get(start..end)) else {
+            return Ok(RegionState::Unverified);
+        };
+        if *hash == [0u8; 32] {
+            return Ok(RegionState::Unverified);
+        }
+        if verify::check_hash(region, hash) == HashStatus::Valid {
+            return Ok(RegionState::Decrypted);
+        }
+
+        let keys = self.keys()?;
+        let key = if main { keys.main } else { keys.base };
+        let mut buf = region.to_vec();
+        aes_ctr_decrypt(&key, iv, &mut buf);
+        Ok(if verify::check_hash(&buf, hash) == HashStatus::Valid {
+            RegionState::Encrypted
+        } else {
+            RegionState::Unverified
+        })
+    }
+}
+
+impl Classifier<'_> {
+    /// The `len` bytes at `offset` into the RomFS at `romfs`, decrypted if `state` is
+    /// [`RegionState::Encrypted`]. `None` if they lie outside the image.
+    fn romfs_bytes(
+        &mut self,
+        romfs: usize,
+        offset: u64,
+        len: u64,
+        state: RegionState,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let data = self.data;
+        let range = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| romfs.checked_add(offset))
+            .and_then(|start| Some(start..start.checked_add(usize::try_from(len).ok()?)?));
+        let Some(bytes) = range.and_then(|range| data.get(range)) else {
+            return Ok(None);
+        };
+        let mut bytes = bytes.to_vec();
+        if state == RegionState::Encrypted {
+            if !offset.is_multiple_of(0x10) {
+                return Ok(None);
+            }
+            let iv = self.ncch.romfs_iv().wrapping_add((offset / 0x10) as u128);
+            aes_ctr_decrypt(&self.keys()?.main, iv, &mut bytes);
+        }
+        Ok(Some(bytes))
+    }
+
+    /// Whether the first and last block of every IVFC level of the RomFS at `romfs`
+    /// hash to their entries in the level above, reading the RomFS as `state`.
+    fn romfs_levels_match(&mut self, romfs: usize, state: RegionState) -> Result<bool, Error> {
+        let Some(header) = self.romfs_bytes(romfs, 0, IVFC_HEADER_SIZE, state)? else {
+            return Ok(false);
+        };
+        let Ok(ivfc) = IvfcHeader::parse(&mut Cursor::new(header), 0) else {
+            return Ok(false);
+        };
+        let offsets = ivfc.level_offsets();
+        let mut hashes = ivfc.master_hash_offset();
+        for (level, &offset) in ivfc.levels.iter().zip(&offsets) {
+            let block_size = level.block_size();
+            if block_size == 0 || block_size > HASH_CHUNK as u64 {
+                return Ok(false);
+            }
+            let last = level.size.div_ceil(block_size).saturating_sub(1);
+            for block in std::iter::once(0).chain((last > 0).then_some(last)) {
+                let start = block * block_size;
+                let len = level.size.saturating_sub(start).min(block_size);
+                let hash_at = hashes.saturating_add(block * 0x20);
+                let (Some(hash), Some(mut bytes)) = (
+                    self.romfs_bytes(romfs, hash_at, 0x20, state)?,
+                    self.romfs_bytes(romfs, offset.saturating_add(start), len, state)?,
+                ) else {
+                    return Ok(false);
+                };
+                bytes.resize(block_size as usize, 0);
+                if Sha256::digest(&bytes)[..] != hash[..] {
+                    return Ok(false);
+                }
+            }
+            hashes = offset;
+        }
+        Ok(true)
+    }
+}
+
+/// Fold the states of several pieces of one region.
+fn combine(acc: Option<RegionState>, state: RegionState) -> Option<RegionState> {
+    match acc {
+        Some(acc) if acc != state => Some(RegionState::Unverified),
+        _ => Some(state),
+    }
+}
+
+fn analyse(data: &[u8], part: &Partition, keydb: &KeyDatabase) -> Result<Analysis, Error> {
+    let p = part.index as u16;
+    let part_off = part.offset;
+    let original = part_off
+        .checked_add(0x200)
+        .and_then(|end| data.get(part_off..end))
+        .ok_or(Error::InvalidNcch(p))?;
+    let parse = |head: &[u8]| {
+        NcchHeader::parse(&mut Cursor::new(head), 0).map_err(|_| Error::InvalidNcch(p))
+    };
+    let flagged_decrypted = parse(original)?.is_no_crypto();
+
+    // Keys are derived from the header as it was before any NoCrypto flag was set
+    let mut head = original.to_vec();
+    if flagged_decrypted {
+        decrypt::restore_crypto_flags(&mut head, part.backup_crypto);
+    }
+    let ncch = parse(&head)?;
+    let sector_size = part.sector_size.unwrap_or(ncch.media_unit_size());
+    let ss = sector_size as usize;
+
+    let mut classifier = Classifier {
+        data,
+        ncch: ncch.clone(),
+        keydb,
+        keys: None,
+    };
+    let mut regions = Vec::new();
+
+    if ncch.exheader_length > 0 {
+        let state = classifier.classify(
+            offset_of(p, Region::ExHeader, part_off, 0x200, 1)?,
+            ncch.exheader_length as usize,
+            &ncch.exheader_hash,
+            false,
+            ncch.plain_iv(),
+        )?;
+        regions.push(RegionStatus {
+            region: Region::ExHeader,
+            state,
+        });
+    }
+
+    let mut table = Vec::new();
+    if ncch.exefs_length > 0 {
+        let exefs_base = offset_of(p, Region::ExefsHeader, part_off, ncch.exefs_offset, ss)?;
+        let exefs_iv = ncch.exefs_iv();
+        let header_state = classifier.classify(
+            exefs_base,
+            offset_of(p, Region::ExefsHeader, 0, ncch.exefs_hash_region_size, ss)?,
+            &ncch.exefs_superblock_hash,
+            false,
+            exefs_iv,
+        )?;
+        regions.push(RegionStatus {
+            region: Region::ExefsHeader,
+            state: header_state,
+        });
+
+        // The file hashes can only be read from a plaintext filename table
+        if header_state != RegionState::Unverified
+            && let Some(header) = data.get(exefs_base..).and_then(|exefs| exefs.get(..ss))
+        {
+            table = header.to_vec();
+        }
+        if header_state == RegionState::Encrypted {
+            aes_ctr_decrypt(&classifier.keys()?.base, exefs_iv, &mut table);
+        }
+
+        let (mut code, mut other) = (None, None);
+        match ExefsHeader::from_bytes(&table) {
+            Ok(header) => {
+                for entry in header.entries().filter(|e| e.size > 0) {
+                    let start = offset_of(p, Region::ExefsData, exefs_base, 1, ss)?
+                        .saturating_add(entry.offset as usize);
+                    let is_code = entry.name == ".code";
+                    let iv = exefs_iv.wrapping_add(((start - exefs_base) / 0x10) as u128);
+                    let state = classifier.classify(
+                        start,
+                        entry.size as usize,
+                        &entry.hash,
+                        is_code,
+                        iv,
+                    )?;
+                    if is_code {
+                        code = combine(code, state);
+                    } else {
+                        other = combine(other, state);
+                    }
+                }
+            }
+            Err(_) => {
+                code = Some(RegionState::Unverified);
+                other = Some(RegionState::Unverified);
+            }
+        }
+        for (region, state) in [(Region::ExefsData, other), (Region::Code, code)] {
+            if let Some(state) = state {
+                regions.push(RegionStatus { region, state });
+            }
+        }
+    }
+
+    if ncch.romfs_offset != 0 {
+        let romfs = offset_of(p, Region::RomFs, part_off, ncch.romfs_offset, ss)?;
+        let mut state = classifier.classify(
+            romfs,
+            offset_of(p, Region::RomFs, 0, ncch.romfs_hash_region_size, ss)?,
+            &ncch.romfs_superblock_hash,
+            true,
+            ncch.romfs_iv(),
+        )?;
+        // The superblock only vouches for the start of the RomFS
+        if state != RegionState::Unverified && !classifier.romfs_levels_match(romfs, state)? {
+            state = RegionState::Unverified;
+        }
+        regions.push(RegionStatus {
+            region: Region::RomFs,
+            state,
+        });
+    }
+
+    let state_of = |region: Region| regions.iter().find(|r| r.region == region).map(|r| r.state);
+    let ops = match classifier.keys {
+        Some(ref keys) if regions.iter().any(|r| r.state == RegionState::Encrypted) => {
+            decrypt::plan_partition(p, part_off, sector_size, &ncch, keys, &table)?
+                .into_iter()
+                .filter(|op| state_of(op.region) == Some(RegionState::Encrypted))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    Ok(Analysis {
+        status: PartitionRegions {
+            index: part.index,
+            flagged_decrypted,
+            regions,
+        },
+        ops,
+        patch_flags: decrypt::no_crypto_patch(part_off, original),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::tests::{build_decrypted_rom, make_7x_keydb};
+    use crate::journal;
+    use crate::keys::CryptoMethod;
+    use sha2::{Digest, Sha256};
+
+    /// A decrypted ROM with every NCCH and ExeFS hash filled in, and the same ROM
+    /// encrypted with Key7x.
+    fn hashed_rom() -> (Vec<u8>, Vec<u8>, usize) {
+        let (mut rom, p) = build_decrypted_rom();
+        let exefs = p + 6 * 0x200;
+        for i in 0..10 {
+            let entry = &rom[exefs + i * 0x10..exefs + i * 0x10 + 0x10];
+            let offset = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+            let size = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+            if size == 0 {
+                continue;
+            }
+            let start = exefs + 0x200 + offset;
+            let hash = Sha256::digest(&rom[start..start + size]);
+            let at = exefs + 0x200 - (i + 1) * 0x20;
+            rom[at..at + 0x20].copy_from_slice(&hash);
+        }
+        let hash = Sha256::digest(&rom[p + 0x200..p + 0x600]);
+        rom[p + 0x160..p + 0x180].copy_from_slice(&hash);
+        rom[p + 0x1A8..p + 0x1AC].copy_from_slice(&1u32.to_le_bytes());
+        let hash = Sha256::digest(&rom[exefs..exefs + 0x200]);
+        rom[p + 0x1C0..p + 0x1E0].copy_from_slice(&hash);
+        let romfs = p + 12 * 0x200;
+        write_ivfc_tree(&mut rom[romfs..romfs + 0x800]);
+        rom[p + 0x1B8..p + 0x1BC].copy_from_slice(&1u32.to_le_bytes());
+        let hash = Sha256::digest(&rom[romfs..romfs + 0x200]);
+        rom[p + 0x1E0..p + 0x200].copy_from_slice(&hash);
+
+        let mut encrypted = rom.clone();
+        crate::decrypt::encrypt_image(
+            &mut encrypted,
+            &make_7x_keydb(),
+            CryptoMethod::Key7x,
+            &mut |_| {},
+        )
+        .unwrap();
+        (rom, encrypted, p)
+    }
+
+    /// IVFC header and hash levels over the patterned RomFS bytes: 0x20-byte blocks in
+    /// levels 1 and 2 (at 0x500 and 0x540), and two 0x200-byte level 3 blocks at 0x200,
+    /// the last one partial.
+    fn write_ivfc_tree(romfs: &mut [u8]) {
+        romfs[..4].copy_from_slice(b"IVFC");
+        romfs[4..8].copy_from_slice(&0x10000u32.to_le_bytes());
+        romfs[8..0xC].copy_from_slice(&0x40u32.to_le_bytes());
+        for (i, (size, log2)) in [(0x40u64, 5u32), (0x40, 5), (0x300, 9)].iter().enumerate() {
+            let base = 0x0C + i * 0x18;
+            romfs[base..base + 8].fill(0);
+            romfs[base + 8..base + 0x10].copy_from_slice(&size.to_le_bytes());
+            romfs[base + 0x10..base + 0x14].copy_from_slice(&log2.to_le_bytes());
+        }
+
+        let mut last = romfs[0x400..0x500].to_vec();
+        last.resize(0x200, 0);
+        let level2 = [Sha256::digest(&romfs[0x200..0x400]), Sha256::digest(&last)].concat();
+        romfs[0x540..0x580].copy_from_slice(&level2);
+        let level1 = [
+            Sha256::digest(&level2[..0x20]),
+            Sha256::digest(&level2[0x20..]),
+        ]
+        .concat();
+        romfs[0x500..0x540].copy_from_slice(&level1);
+        let master = [
+            Sha256::digest(&level1[..0x20]),
+            Sha256::digest(&level1[0x20..]),
+        ]
+        .concat();
+        romfs[0x60..0xA0].copy_from_slice(&master);
+    }
+
+    fn states(data: &[u8]) -> Vec<(Region, RegionState)> {
+        let status = image_region_status(data, &make_7x_keydb()).unwrap();
+        assert_eq!(status.len(), 1);
+        status[0]
+            .regions
+            .iter()
+            .map(|r| (r.region, r.state))
+            .collect()
+    }
+
+    #[test]
+    fn test_region_status() {
+        use RegionState::*;
+        let (rom, encrypted, p) = hashed_rom();
+
+        let all = |state| {
+            vec![
+                (Region::ExHeader, state),
+                (Region::ExefsHeader, state),
+                (Region::ExefsData, state),
+                (Region::Code, state),
+                (Region::RomFs, state),
+            ]
+        };
+        assert_eq!(states(&rom), all(Decrypted));
+        assert_eq!(states(&encrypted), all(Encrypted));
+
+        // Plaintext everywhere but the RomFS, still flagged as encrypted
+        let mut partial = encrypted.clone();
+        partial[..p + 12 * 0x200].copy_from_slice(&rom[..p + 12 * 0x200]);
+        partial[p + 0x188..p + 0x190].copy_from_slice(&encrypted[p + 0x188..p + 0x190]);
+        let mut expected = all(Decrypted);
+        expected[4].1 = Encrypted;
+        assert_eq!(states(&partial), expected);
+        let status = &image_region_status(&partial, &make_7x_keydb()).unwrap()[0];
+        assert!(status.is_inconsistent() && !status.is_decrypted());
+
+        let mut corrupt = rom.clone();
+        corrupt[p + 0x300] ^= 0xFF;
+        assert_eq!(states(&corrupt)[0], (Region::ExHeader, Unverified));
+
+        // A RomFS decrypted up to its last level 3 block: the superblock alone would
+        // pass it as decrypted
+        let romfs = p + 12 * 0x200;
+        let mut stopped = rom.clone();
+        stopped[romfs + 0x400..].copy_from_slice(&encrypted[romfs + 0x400..]);
+        assert_eq!(states(&stopped)[4], (Region::RomFs, Unverified));
+        let mut stopped = encrypted.clone();
+        stopped[romfs..romfs + 0x400].copy_from_slice(&rom[romfs..romfs + 0x400]);
+        assert_eq!(states(&stopped)[4], (Region::RomFs, Unverified));
+    }
+
+    #[test]
+    fn test_repair_rom_decrypts_remaining_regions() {
+        let (rom, encrypted, p) = hashed_rom();
+        let code = p + 7 * 0x200;
+
+        // A broken decryptor got through the ExeFS except .code, then stopped
+        let mut partial = encrypted.clone();
+        let exefs = p + 6 * 0x200;
+        partial[p + 0x200..code].copy_from_slice(&rom[p + 0x200..code]);
+        partial[code + 0x600..exefs + 5 * 0x200]
+            .copy_from_slice(&rom[code + 0x600..exefs + 5 * 0x200]);
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let tmp_path = tmp_dir.join("temp_repair.3ds");
+        std::fs::write(&tmp_path, &partial).unwrap();
+        let result = repair_rom(&tmp_path, &make_7x_keydb(), |_| {});
+        let output = std::fs::read(&tmp_path).unwrap();
+
+        // Decrypting the repaired ROM again must not touch it
+        let again = repair_rom(&tmp_path, &make_7x_keydb(), |_| {});
+        let output_again = std::fs::read(&tmp_path).unwrap();
+        let _ = std::fs::remove_file(&tmp_path);
+
+        result.unwrap();
+        again.unwrap();
+        assert!(output == rom, "repaired ROM differs from the decrypted ROM");
+        assert!(output_again == rom);
+    }
+
+    /// Cancelling a repair part-way restores the partly decrypted ROM.
+    #[test]
+    fn test_cancelled_repair_rom_is_rolled_back() {
+        let (rom, encrypted, p) = hashed_rom();
+        let mut partial = encrypted.clone();
+        partial[p + 0x200..p + 7 * 0x200].copy_from_slice(&rom[p + 0x200..p + 7 * 0x200]);
+
+        let tmp_dir = std::path::PathBuf::from("test-fixtures");
+        let _ = std::fs::create_dir_all(&tmp_dir);
+        let tmp_path = tmp_dir.join("temp_repair_cancelled.3ds");
+        let _ = std::fs::remove_file(journal::journal_path(&tmp_path));
+        std::fs::write(&tmp_path, &partial).unwrap();
+
+        let cancel = CancelToken::new();
+        let mut rolled_back = false;
+        let err =
+            repair_rom_cancellable(&tmp_path, &make_7x_keydb(), &cancel, |event| match event {
+                ProgressEvent::Bytes { processed, .. } if *processed > 0 => cancel.cancel(),
+                ProgressEvent::RollingBack {
+                    operation: Operation::Repair,
+                } => rolled_back = true,
+                _ => {}
+            })
+            .unwrap_err();
+        let output = std::fs::read(&tmp_path).unwrap();
+        let _ = std::fs::remove_file(&tmp_path);
+
+        assert!(matches!(err, Error::Cancelled), "{err:?}");
+        assert!(rolled_back);
+        assert!(!journal::is_pending(&tmp_path));
+        assert!(output == partial, "cancelled repair was not rolled back");
+    }
+
+    #[test]
+    fn test_repair_refuses_unverified_regions() {
+        let (rom, encrypted, p) = hashed_rom();
+        let mut partial = encrypted.clone();
+        partial[p + 0x200..p + 0xA00].copy_from_slice(&rom[p + 0x200..p + 0xA00]);
+        partial[p + 12 * 0x200] ^= 0xFF;
+
+        // A RomFS whose superblock is decrypted but whose last level 3 block is not
+        let romfs = p + 12 * 0x200;
+        let mut stopped = encrypted.clone();
+        stopped[..romfs + 0x400].copy_from_slice(&rom[..romfs + 0x400]);
+
+        for image in [partial, stopped] {
+            let err = plan_repair(&image, &make_7x_keydb()).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    Error::RegionUnverified {
+                        partition: 0,
+                        region: Region::RomFs
+                    }
+                ),
+                "{err:?}"
+            );
+        }
+    }
+}
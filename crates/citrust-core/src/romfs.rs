@@ -0,0 +1,702 @@
+//! Read-only access to a decrypted RomFS: the IVFC hash-tree header, and the level 3
+//! file system with its directory and file metadata tables.
+//!
+//! Offsets in the metadata come straight from the image, so every lookup is bounds
+//! checked and extracted paths are kept inside the destination directory.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::decrypt::Error;
+use crate::format;
+
+/// Marks the end of a sibling list or hash-bucket chain in the metadata tables.
+pub const INVALID: u32 = 0xFFFF_FFFF;
+
+/// Size of the IVFC header; the master hash follows at the next 0x10 boundary.
+pub const IVFC_HEADER_SIZE: u64 = 0x5C;
+
+/// Size of the level 3 header.
+pub const LEVEL3_HEADER_SIZE: u32 = 0x28;
+
+/// Size of the buffer used when copying files out of the RomFS.
+const COPY_CHUNK: usize = 1024 * 1024;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn le_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid(format!("metadata offset {offset:#x} out of range")))
+}
+
+fn le_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid(format!("metadata offset {offset:#x} out of range")))
+}
+
+/// Hash of a directory or file name within its parent, used to index the hash buckets.
+pub fn path_hash(parent: u32, name: &[u16]) -> u32 {
+    let mut hash = parent ^ 123_456_789;
+    for &c in name {
+        hash = hash.rotate_right(5) ^ c as u32;
+    }
+    hash
+}
+
+/// One level of the IVFC hash tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IvfcLevel {
+    pub logical_offset: u64,
+    pub size: u64,
+    pub block_size_log2: u32,
+}
+
+impl IvfcLevel {
+    pub fn block_size(&self) -> u64 {
+        1u64.checked_shl(self.block_size_log2).unwrap_or(0)
+    }
+}
+
+/// The IVFC header at the start of a RomFS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IvfcHeader {
+    pub master_hash_size: u32,
+    /// Levels 1-3; level 3 holds the actual file system.
+    pub levels: [IvfcLevel; 3],
+}
+
+impl IvfcHeader {
+    pub fn parse<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = [0u8; IVFC_HEADER_SIZE as usize];
+        reader.read_exact(&mut data)?;
+        if &data[0..4] != b"IVFC" || le_u32(&data, 4)? != 0x10000 {
+            return Err(invalid("Invalid IVFC magic"));
+        }
+
+        let mut levels = [IvfcLevel::default(); 3];
+        for (i, level) in levels.iter_mut().enumerate() {
+            let base = 0x0C + i * 0x18;
+            *level = IvfcLevel {
+                logical_offset: le_u64(&data, base)?,
+                size: le_u64(&data, base + 0x08)?,
+                block_size_log2: le_u32(&data, base + 0x10)?,
+            };
+        }
+
+        Ok(IvfcHeader {
+            master_hash_size: le_u32(&data, 0x08)?,
+            levels,
+        })
+    }
+
+    /// Offset of the master hash, relative to the start of the RomFS.
+    pub fn master_hash_offset(&self) -> u64 {
+        IVFC_HEADER_SIZE.next_multiple_of(0x10)
+    }
+
+    /// Offset of level 3, relative to the start of the RomFS. Level 3 follows the
+    /// master hash, aligned to its own block size.
+    pub fn level3_offset(&self) -> u64 {
+        let end = self.master_hash_offset() + self.master_hash_size as u64;
+        end.next_multiple_of(self.levels[2].block_size().max(1))
+    }
+
+    /// Offsets of levels 1, 2 and 3, relative to the start of the RomFS. Levels 1 and 2
+    /// follow level 3, each aligned to its own block size.
+    pub fn level_offsets(&self) -> [u64; 3] {
+        let align = |offset: u64, level: &IvfcLevel| {
+            offset
+                .checked_next_multiple_of(level.block_size().max(1))
+                .unwrap_or(u64::MAX)
+        };
+        let level3 = self.level3_offset();
+        let level1 = align(level3.saturating_add(self.levels[2].size), &self.levels[0]);
+        let level2 = align(level1.saturating_add(self.levels[0].size), &self.levels[1]);
+        [level1, level2, level3]
+    }
+}
+
+/// A directory entry from the directory metadata table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// Offset of this entry in the directory metadata table.
+    pub offset: u32,
+    pub parent: u32,
+    pub next_sibling: u32,
+    pub first_child: u32,
+    pub first_file: u32,
+    pub next_in_bucket: u32,
+    pub name: String,
+}
+
+/// A file entry from the file metadata table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Offset of this entry in the file metadata table.
+    pub offset: u32,
+    pub parent: u32,
+    pub next_sibling: u32,
+    /// Offset of the file data, relative to the level 3 file data region.
+    pub data_offset: u64,
+    pub data_size: u64,
+    pub next_in_bucket: u32,
+    pub name: String,
+}
+
+/// A file listed by [`RomFs::list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomFsFile {
+    /// Absolute path inside the RomFS, e.g. `/data/message.bin`.
+    pub path: String,
+    pub size: u64,
+}
+
+/// Read a UTF-16LE name of `len` bytes starting at `offset`.
+fn read_utf16(table: &[u8], offset: usize, len: usize) -> io::Result<Vec<u16>> {
+    let bytes = table
+        .get(offset..offset + len)
+        .ok_or_else(|| invalid(format!("name at {offset:#x} out of range")))?;
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect())
+}
+
+/// Read a metadata table or hash-bucket table of `size` bytes.
+fn read_table<R: Read + Seek>(reader: &mut R, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    reader.take(size as u64).read_to_end(&mut data)?;
+    if data.len() != size as usize {
+        return Err(invalid("RomFS metadata table truncated"));
+    }
+    Ok(data)
+}
+
+fn buckets(table: &[u8]) -> Vec<u32> {
+    table
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect()
+}
+
+/// A read-only view of a decrypted RomFS (IVFC level 3 file system).
+pub struct RomFs<R> {
+    reader: R,
+    ivfc: IvfcHeader,
+    /// Absolute offset of the file data region.
+    file_data_offset: u64,
+    dir_buckets: Vec<u32>,
+    dir_meta: Vec<u8>,
+    file_buckets: Vec<u32>,
+    file_meta: Vec<u8>,
+}
+
+impl<R: Read + Seek> RomFs<R> {
+    /// Open the RomFS that starts at `offset` in `reader`.
+    pub fn new(mut reader: R, offset: u64) -> io::Result<Self> {
+        let ivfc = IvfcHeader::parse(&mut reader, offset)?;
+        let level3 = offset + ivfc.level3_offset();
+
+        reader.seek(SeekFrom::Start(level3))?;
+        let mut header = [0u8; LEVEL3_HEADER_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        if le_u32(&header, 0)? != LEVEL3_HEADER_SIZE {
+            return Err(invalid("Invalid RomFS level 3 header"));
+        }
+        let field = |i: usize| le_u32(&header, 4 + i * 4);
+
+        let dir_buckets = buckets(&read_table(
+            &mut reader,
+            level3 + field(0)? as u64,
+            field(1)?,
+        )?);
+        let dir_meta = read_table(&mut reader, level3 + field(2)? as u64, field(3)?)?;
+        let file_buckets = buckets(&read_table(
+            &mut reader,
+            level3 + field(4)? as u64,
+            field(5)?,
+        )?);
+        let file_meta = read_table(&mut reader, level3 + field(6)? as u64, field(7)?)?;
+        let file_data_offset = level3 + field(8)? as u64;
+
+        Ok(RomFs {
+            reader,
+            ivfc,
+            file_data_offset,
+            dir_buckets,
+            dir_meta,
+            file_buckets,
+            file_meta,
+        })
+    }
+
+    /// Open the RomFS of a decrypted ROM's main NCCH.
+    pub fn locate(mut reader: R) -> Result<Self, Error> {
+        let (ncch, ncch_offset) = format::main_ncch(&mut reader)?;
+        if !ncch.is_no_crypto() {
+            return Err(Error::NotDecrypted);
+        }
+        if ncch.romfs_length == 0 {
+            return Err(Error::NoRomfs);
+        }
+        let offset =
+            ncch_offset.saturating_add(ncch.romfs_offset as u64 * ncch.media_unit_size() as u64);
+        Ok(Self::new(reader, offset)?)
+    }
+
+    pub fn ivfc(&self) -> &IvfcHeader {
+        &self.ivfc
+    }
+
+    /// Parse the directory entry at `offset` in the directory metadata table.
+    pub fn dir(&self, offset: u32) -> io::Result<DirEntry> {
+        let t = &self.dir_meta;
+        let o = offset as usize;
+        let name_len = le_u32(t, o + 0x14)? as usize;
+        Ok(DirEntry {
+            offset,
+            parent: le_u32(t, o)?,
+            next_sibling: le_u32(t, o + 0x04)?,
+            first_child: le_u32(t, o + 0x08)?,
+            first_file: le_u32(t, o + 0x0C)?,
+            next_in_bucket: le_u32(t, o + 0x10)?,
+            name: String::from_utf16_lossy(&read_utf16(t, o + 0x18, name_len)?),
+        })
+    }
+
+    /// Parse the file entry at `offset` in the file metadata table.
+    pub fn file(&self, offset: u32) -> io::Result<FileEntry> {
+        let t = &self.file_meta;
+        let o = offset as usize;
+        let name_len = le_u32(t, o + 0x1C)? as usize;
+        Ok(FileEntry {
+            offset,
+            parent: le_u32(t, o)?,
+            next_sibling: le_u32(t, o + 0x04)?,
+            data_offset: le_u64(t, o + 0x08)?,
+            data_size: le_u64(t, o + 0x10)?,
+            next_in_bucket: le_u32(t, o + 0x18)?,
+            name: String::from_utf16_lossy(&read_utf16(t, o + 0x20, name_len)?),
+        })
+    }
+
+    /// Find a child directory of `parent` through the directory hash buckets.
+    fn find_dir(&self, parent: u32, name: &str) -> io::Result<Option<DirEntry>> {
+        if self.dir_buckets.is_empty() {
+            return Ok(None);
+        }
+        let utf16: Vec<u16> = name.encode_utf16().collect();
+        let bucket = path_hash(parent, &utf16) as usize % self.dir_buckets.len();
+        let mut offset = self.dir_buckets[bucket];
+        for _ in 0..=self.dir_meta.len() {
+            if offset == INVALID {
+                return Ok(None);
+            }
+            let dir = self.dir(offset)?;
+            if dir.parent == parent && dir.name == name {
+                return Ok(Some(dir));
+            }
+            offset = dir.next_in_bucket;
+        }
+        Err(invalid("cycle in RomFS directory hash chain"))
+    }
+
+    /// Find a file in directory `parent` through the file hash buckets.
+    fn find_file(&self, parent: u32, name: &str) -> io::Result<Option<FileEntry>> {
+        if self.file_buckets.is_empty() {
+            return Ok(None);
+        }
+        let utf16: Vec<u16> = name.encode_utf16().collect();
+        let bucket = path_hash(parent, &utf16) as usize % self.file_buckets.len();
+        let mut offset = self.file_buckets[bucket];
+        for _ in 0..=self.file_meta.len() {
+            if offset == INVALID {
+                return Ok(None);
+            }
+            let file = self.file(offset)?;
+            if file.parent == parent && file.name == name {
+                return Ok(Some(file));
+            }
+            offset = file.next_in_bucket;
+        }
+        Err(invalid("cycle in RomFS file hash chain"))
+    }
+
+    /// Look up a file by its path (e.g. `/data/message.bin`).
+    pub fn lookup(&self, path: &str) -> io::Result<FileEntry> {
+        let not_found = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("RomFS has no file \"{path}\""),
+            )
+        };
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let file_name = components.pop().ok_or_else(not_found)?;
+
+        let mut dir = 0;
+        for component in components {
+            dir = self.find_dir(dir, component)?.ok_or_else(not_found)?.offset;
+        }
+        self.find_file(dir, file_name)?.ok_or_else(not_found)
+    }
+
+    /// Visit every directory and file below the root, depth first.
+    fn walk(
+        &self,
+        mut visit: impl FnMut(&str, Option<&FileEntry>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // Each table entry is at least 0x18 bytes, which bounds the number of steps
+        let mut budget = self.dir_meta.len() / 0x18 + self.file_meta.len() / 0x20 + 1;
+        let mut stack = vec![(0u32, String::new())];
+        while let Some((offset, path)) = stack.pop() {
+            let dir = self.dir(offset)?;
+
+            let mut file_offset = dir.first_file;
+            while file_offset != INVALID {
+                budget = budget
+                    .checked_sub(1)
+                    .ok_or_else(|| invalid("cycle in RomFS tree"))?;
+                let file = self.file(file_offset)?;
+                visit(&format!("{path}/{}", file.name), Some(&file))?;
+                file_offset = file.next_sibling;
+            }
+
+            let mut child = dir.first_child;
+            while child != INVALID {
+                budget = budget
+                    .checked_sub(1)
+                    .ok_or_else(|| invalid("cycle in RomFS tree"))?;
+                let sub = self.dir(child)?;
+                let sub_path = format!("{path}/{}", sub.name);
+                visit(&sub_path, None)?;
+                stack.push((child, sub_path));
+                child = sub.next_sibling;
+            }
+        }
+        Ok(())
+    }
+
+    /// List every file in the RomFS with its full path and size.
+    pub fn list(&self) -> io::Result<Vec<RomFsFile>> {
+        let mut files = Vec::new();
+        self.walk(|path, file| {
+            if let Some(file) = file {
+                files.push(RomFsFile {
+                    path: path.to_string(),
+                    size: file.data_size,
+                });
+            }
+            Ok(())
+        })?;
+        Ok(files)
+    }
+
+    /// Open a file for reading by its path.
+    pub fn open(&mut self, path: &str) -> io::Result<RomFsFileReader<'_, R>> {
+        let file = self.lookup(path)?;
+        let start = self
+            .file_data_offset
+            .checked_add(file.data_offset)
+            .ok_or_else(|| invalid("file data offset overflows"))?;
+        Ok(RomFsFileReader {
+            reader: &mut self.reader,
+            start,
+            size: file.data_size,
+            pos: 0,
+        })
+    }
+
+    /// Extract the whole tree into `dest`. Returns the number of files written.
+    pub fn extract_all(&mut self, dest: &Path) -> io::Result<usize> {
+        let mut entries = Vec::new();
+        self.walk(|path, file| {
+            entries.push((path.to_string(), file.map(|f| (f.data_offset, f.data_size))));
+            Ok(())
+        })?;
+
+        fs::create_dir_all(dest)?;
+        let mut buf = vec![0u8; COPY_CHUNK];
+        let mut count = 0;
+        for (path, file) in entries {
+            let target = safe_join(dest, &path)?;
+            let Some((data_offset, size)) = file else {
+                fs::create_dir_all(&target)?;
+                continue;
+            };
+
+            let start = self
+                .file_data_offset
+                .checked_add(data_offset)
+                .ok_or_else(|| invalid("file data offset overflows"))?;
+            let mut out = File::create(&target)?;
+            self.reader.seek(SeekFrom::Start(start))?;
+            let mut remaining = size;
+            while remaining > 0 {
+                let n = remaining.min(buf.len() as u64) as usize;
+                self.reader.read_exact(&mut buf[..n])?;
+                io::Write::write_all(&mut out, &buf[..n])?;
+                remaining -= n as u64;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Join a RomFS path onto `dest`, rejecting components that would escape it.
+fn safe_join(dest: &Path, path: &str) -> io::Result<PathBuf> {
+    let mut target = dest.to_path_buf();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if component == "." || component == ".." || component.contains(['\\', ':', '\0']) {
+            return Err(invalid(format!("unsafe RomFS path \"{path}\"")));
+        }
+        target.push(component);
+    }
+    Ok(target)
+}
+
+/// A single RomFS file, readable and seekable independently of the rest of the image.
+pub struct RomFsFileReader<'a, R> {
+    reader: &'a mut R,
+    start: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl<R> RomFsFileReader<'_, R> {
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl<R: Read + Seek> Read for RomFsFileReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.pos);
+        let n = (buf.len() as u64).min(remaining) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        self.reader.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.reader.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for RomFsFileReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = new.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn utf16(name: &str) -> Vec<u8> {
+        let mut bytes: Vec<u8> = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+        bytes
+    }
+
+    /// Build a RomFS by hand: `/a.txt`, `/dir/b.bin`, `/dir/sub/` (empty).
+    pub(crate) fn build_test_romfs() -> Vec<u8> {
+        let name_a = utf16("a.txt");
+        let name_dir = utf16("dir");
+        let name_sub = utf16("sub");
+        let name_b = utf16("b.bin");
+
+        // Directory table: root @0, dir @0x18, sub @0x18+0x18+len(dir)
+        let dir_off = 0x18u32;
+        let sub_off = dir_off + 0x18 + name_dir.len() as u32;
+        let mut dirs = Vec::new();
+        for (parent, sibling, child, file, name) in [
+            (0u32, INVALID, dir_off, 0u32, &Vec::new()),
+            (0, INVALID, sub_off, 0x20 + name_a.len() as u32, &name_dir),
+            (dir_off, INVALID, INVALID, INVALID, &name_sub),
+        ] {
+            for v in [parent, sibling, child, file, INVALID, (name.len() as u32)] {
+                dirs.extend_from_slice(&v.to_le_bytes());
+            }
+            dirs.extend_from_slice(name);
+        }
+        // Names are padded, so store the real UTF-16 lengths
+        dirs[dir_off as usize + 0x14..dir_off as usize + 0x18].copy_from_slice(&6u32.to_le_bytes());
+        dirs[sub_off as usize + 0x14..sub_off as usize + 0x18].copy_from_slice(&6u32.to_le_bytes());
+
+        let b_off = 0x20 + name_a.len() as u32;
+        let mut files = Vec::new();
+        for (parent, data_off, size, name, len) in [
+            (0u32, 0u64, 5u64, &name_a, 10u32),
+            (dir_off, 0x10, 3, &name_b, 10),
+        ] {
+            for v in [parent, INVALID] {
+                files.extend_from_slice(&v.to_le_bytes());
+            }
+            files.extend_from_slice(&data_off.to_le_bytes());
+            files.extend_from_slice(&size.to_le_bytes());
+            files.extend_from_slice(&INVALID.to_le_bytes());
+            files.extend_from_slice(&len.to_le_bytes());
+            files.extend_from_slice(name);
+        }
+
+        // Single-bucket hash tables chain every entry
+        let mut dir_buckets = Vec::new();
+        dir_buckets.extend_from_slice(&0u32.to_le_bytes());
+        for (entry, next) in [(0u32, dir_off), (dir_off, sub_off)] {
+            let at = entry as usize + 0x10;
+            dirs[at..at + 4].copy_from_slice(&next.to_le_bytes());
+        }
+        let mut file_buckets = Vec::new();
+        file_buckets.extend_from_slice(&0u32.to_le_bytes());
+        files[0x18..0x1C].copy_from_slice(&b_off.to_le_bytes());
+
+        let mut level3 = vec![0u8; LEVEL3_HEADER_SIZE as usize];
+        let mut tables: Vec<u8> = Vec::new();
+        let mut fields = Vec::new();
+        for table in [&dir_buckets, &dirs, &file_buckets, &files] {
+            fields.push(LEVEL3_HEADER_SIZE + tables.len() as u32);
+            fields.push(table.len() as u32);
+            tables.extend_from_slice(table);
+        }
+        let data_off = (LEVEL3_HEADER_SIZE as usize + tables.len()).next_multiple_of(0x10);
+        fields.push(data_off as u32);
+        level3[0..4].copy_from_slice(&LEVEL3_HEADER_SIZE.to_le_bytes());
+        for (i, v) in fields.iter().enumerate() {
+            level3[4 + i * 4..8 + i * 4].copy_from_slice(&v.to_le_bytes());
+        }
+        level3.extend_from_slice(&tables);
+        level3.resize(data_off, 0);
+        level3.extend_from_slice(b"hello\0\0\0\0\0\0\0\0\0\0\0xyz");
+
+        let mut romfs = vec![0u8; 0x1000];
+        romfs[0..4].copy_from_slice(b"IVFC");
+        romfs[4..8].copy_from_slice(&0x10000u32.to_le_bytes());
+        romfs[8..12].copy_from_slice(&0x20u32.to_le_bytes());
+        romfs[0x3C + 0x08..0x3C + 0x10].copy_from_slice(&(level3.len() as u64).to_le_bytes());
+        romfs[0x3C + 0x10..0x3C + 0x14].copy_from_slice(&12u32.to_le_bytes());
+        romfs.extend_from_slice(&level3);
+        romfs
+    }
+
+    #[test]
+    fn test_path_hash() {
+        let name: Vec<u16> = "a".encode_utf16().collect();
+        let expected = 123_456_789u32.rotate_right(5) ^ 'a' as u32;
+        assert_eq!(path_hash(0, &name), expected);
+    }
+
+    #[test]
+    fn test_list() {
+        let romfs = RomFs::new(Cursor::new(build_test_romfs()), 0).unwrap();
+        assert_eq!(romfs.ivfc().level3_offset(), 0x1000);
+        let files = romfs.list().unwrap();
+        assert_eq!(
+            files,
+            vec![
+                RomFsFile {
+                    path: "/a.txt".into(),
+                    size: 5
+                },
+                RomFsFile {
+                    path: "/dir/b.bin".into(),
+                    size: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_open_and_seek() {
+        let mut romfs = RomFs::new(Cursor::new(build_test_romfs()), 0).unwrap();
+
+        let mut text = String::new();
+        romfs
+            .open("/a.txt")
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "hello");
+
+        let mut file = romfs.open("dir/b.bin").unwrap();
+        assert_eq!(file.len(), 3);
+        file.seek(SeekFrom::End(-1)).unwrap();
+        let mut last = Vec::new();
+        file.read_to_end(&mut last).unwrap();
+        assert_eq!(last, b"z");
+
+        let err = romfs.open("/dir/missing").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(romfs.open("/sub/b.bin").is_err());
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let dest = PathBuf::from("test-fixtures").join("romfs_extract");
+        let _ = fs::remove_dir_all(&dest);
+
+        let mut romfs = RomFs::new(Cursor::new(build_test_romfs()), 0).unwrap();
+        let count = romfs.extract_all(&dest).unwrap();
+        let a = fs::read(dest.join("a.txt")).unwrap();
+        let b = fs::read(dest.join("dir").join("b.bin")).unwrap();
+        let sub = dest.join("dir").join("sub").is_dir();
+        let _ = fs::remove_dir_all(&dest);
+
+        assert_eq!(count, 2);
+        assert_eq!(a, b"hello");
+        assert_eq!(b, b"xyz");
+        assert!(sub);
+    }
+
+    #[test]
+    fn test_extract_all_rejects_overflowing_data_offset() {
+        let dest = PathBuf::from("test-fixtures").join("romfs_extract_overflow");
+        let _ = fs::remove_dir_all(&dest);
+
+        // First file entry of the file metadata table; its data offset is at 0x08
+        let mut data = build_test_romfs();
+        let files = 0x1000 + u32::from_le_bytes(data[0x101C..0x1020].try_into().unwrap()) as usize;
+        data[files + 0x08..files + 0x10].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let mut romfs = RomFs::new(Cursor::new(data), 0).unwrap();
+        let err = romfs.extract_all(&dest).unwrap_err();
+        let _ = fs::remove_dir_all(&dest);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_rejects_bad_magic() {
+        let mut data = build_test_romfs();
+        data[0] = b'X';
+        assert!(RomFs::new(Cursor::new(data), 0).is_err());
+    }
+
+    #[test]
+    fn test_safe_join() {
+        assert!(safe_join(Path::new("out"), "/../etc/passwd").is_err());
+        assert_eq!(
+            safe_join(Path::new("out"), "/a/b").unwrap(),
+            Path::new("out").join("a").join("b")
+        );
+    }
+}
@@ -0,0 +1,685 @@
+//! Building a RomFS from a directory tree, and writing a ROM with its RomFS replaced.
+//!
+//! The output is a complete IVFC image: metadata tables with hash buckets, file data,
+//! and the three hash levels, so the NCCH superblock hash can be updated to match.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::decrypt::{Error, temp_path_for};
+use crate::format::RomFormat;
+use crate::ncch::NcchHeader;
+use crate::ncsd::NcsdHeader;
+use crate::romfs::{INVALID, IVFC_HEADER_SIZE, LEVEL3_HEADER_SIZE, path_hash};
+
+/// Block size used for every IVFC level (log2).
+const BLOCK_LOG2: u32 = 12;
+const BLOCK_SIZE: u64 = 1 << BLOCK_LOG2;
+
+/// Alignment of file data within level 3.
+const FILE_ALIGN: u64 = 0x10;
+
+/// Offset of the master hash (the IVFC header rounded up to 0x10).
+const MASTER_HASH_OFFSET: u64 = 0x60;
+
+/// Size of the buffer used when copying file and ROM data.
+const COPY_CHUNK: usize = 1024 * 1024;
+
+/// Layout of a RomFS written by [`build`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuiltRomFs {
+    /// Total size in bytes.
+    pub size: u64,
+    /// IVFC header and master hash: the region covered by the NCCH superblock hash.
+    pub superblock: Vec<u8>,
+    pub file_count: usize,
+}
+
+struct Dir {
+    name: Vec<u16>,
+    parent: usize,
+    children: Vec<usize>,
+    files: Vec<usize>,
+    offset: u32,
+}
+
+struct SourceFile {
+    name: Vec<u16>,
+    parent: usize,
+    path: PathBuf,
+    size: u64,
+    offset: u32,
+    data_offset: u64,
+}
+
+/// Number of hash buckets for `entries` entries, as used by the official tools.
+fn bucket_count(entries: usize) -> usize {
+    match entries {
+        0..3 => 3,
+        3..19 => entries | 1,
+        _ => {
+            let mut count = entries;
+            while [2, 3, 5, 7, 11, 13, 17]
+                .iter()
+                .any(|&p| count.is_multiple_of(p))
+            {
+                count += 1;
+            }
+            count
+        }
+    }
+}
+
+fn entry_name(path: &Path) -> io::Result<Vec<u16>> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "non UTF-8 file name"))?;
+    Ok(name.encode_utf16().collect())
+}
+
+/// Collect the directory tree under `root`, with entries sorted by name.
+fn scan(root: &Path) -> io::Result<(Vec<Dir>, Vec<SourceFile>)> {
+    let mut dirs = vec![Dir {
+        name: Vec::new(),
+        parent: 0,
+        children: Vec::new(),
+        files: Vec::new(),
+        offset: 0,
+    }];
+    let mut files = Vec::new();
+    let mut pending = vec![(0usize, root.to_path_buf())];
+
+    while let Some((index, path)) = pending.pop() {
+        let mut entries = fs::read_dir(&path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for entry in entries {
+            let meta = fs::metadata(&entry)?;
+            if meta.is_dir() {
+                let child = dirs.len();
+                dirs.push(Dir {
+                    name: entry_name(&entry)?,
+                    parent: index,
+                    children: Vec::new(),
+                    files: Vec::new(),
+                    offset: 0,
+                });
+                dirs[index].children.push(child);
+                pending.push((child, entry));
+            } else {
+                let file = files.len();
+                files.push(SourceFile {
+                    name: entry_name(&entry)?,
+                    parent: index,
+                    path: entry,
+                    size: meta.len(),
+                    offset: 0,
+                    data_offset: 0,
+                });
+                dirs[index].files.push(file);
+            }
+        }
+    }
+    Ok((dirs, files))
+}
+
+fn name_bytes(name: &[u16]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = name.iter().flat_map(|c| c.to_le_bytes()).collect();
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+    bytes
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Build the level 3 header and metadata tables; returns them and the file data offset.
+fn build_metadata(dirs: &mut [Dir], files: &mut [SourceFile]) -> io::Result<(Vec<u8>, u64)> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "RomFS metadata too large");
+
+    let mut offset = 0u32;
+    for dir in dirs.iter_mut() {
+        dir.offset = offset;
+        let len = 0x18 + name_bytes(&dir.name).len();
+        offset = offset.checked_add(len as u32).ok_or_else(too_large)?;
+    }
+    let mut offset = 0u32;
+    let mut data_offset = 0u64;
+    for file in files.iter_mut() {
+        file.offset = offset;
+        let len = 0x20 + name_bytes(&file.name).len();
+        offset = offset.checked_add(len as u32).ok_or_else(too_large)?;
+        file.data_offset = data_offset.next_multiple_of(FILE_ALIGN);
+        data_offset = file.data_offset + file.size;
+    }
+
+    // Sibling links follow the sorted order of each directory's children
+    let mut dir_sibling = vec![INVALID; dirs.len()];
+    let mut file_sibling = vec![INVALID; files.len()];
+    for dir in dirs.iter() {
+        for pair in dir.children.windows(2) {
+            dir_sibling[pair[0]] = dirs[pair[1]].offset;
+        }
+        for pair in dir.files.windows(2) {
+            file_sibling[pair[0]] = files[pair[1]].offset;
+        }
+    }
+
+    let mut dir_buckets = vec![INVALID; bucket_count(dirs.len())];
+    let mut dir_table = Vec::new();
+    for (i, dir) in dirs.iter().enumerate() {
+        let parent = dirs[dir.parent].offset;
+        let bucket = path_hash(parent, &dir.name) as usize % dir_buckets.len();
+        push_u32(&mut dir_table, parent);
+        push_u32(&mut dir_table, dir_sibling[i]);
+        push_u32(
+            &mut dir_table,
+            dir.children.first().map_or(INVALID, |&c| dirs[c].offset),
+        );
+        push_u32(
+            &mut dir_table,
+            dir.files.first().map_or(INVALID, |&f| files[f].offset),
+        );
+        push_u32(&mut dir_table, dir_buckets[bucket]);
+        push_u32(&mut dir_table, (dir.name.len() * 2) as u32);
+        dir_table.extend_from_slice(&name_bytes(&dir.name));
+        dir_buckets[bucket] = dir.offset;
+    }
+
+    let mut file_buckets = vec![INVALID; bucket_count(files.len())];
+    let mut file_table = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let parent = dirs[file.parent].offset;
+        let bucket = path_hash(parent, &file.name) as usize % file_buckets.len();
+        push_u32(&mut file_table, parent);
+        push_u32(&mut file_table, file_sibling[i]);
+        file_table.extend_from_slice(&file.data_offset.to_le_bytes());
+        file_table.extend_from_slice(&file.size.to_le_bytes());
+        push_u32(&mut file_table, file_buckets[bucket]);
+        push_u32(&mut file_table, (file.name.len() * 2) as u32);
+        file_table.extend_from_slice(&name_bytes(&file.name));
+        file_buckets[bucket] = file.offset;
+    }
+
+    let mut tables = Vec::new();
+    let mut header = vec![0u8; LEVEL3_HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(&LEVEL3_HEADER_SIZE.to_le_bytes());
+    let dir_buckets: Vec<u8> = dir_buckets.iter().flat_map(|b| b.to_le_bytes()).collect();
+    let file_buckets: Vec<u8> = file_buckets.iter().flat_map(|b| b.to_le_bytes()).collect();
+    for (i, table) in [&dir_buckets, &dir_table, &file_buckets, &file_table]
+        .into_iter()
+        .enumerate()
+    {
+        let at = LEVEL3_HEADER_SIZE as usize + tables.len();
+        header[4 + i * 8..8 + i * 8].copy_from_slice(&(at as u32).to_le_bytes());
+        header[8 + i * 8..12 + i * 8].copy_from_slice(&(table.len() as u32).to_le_bytes());
+        tables.extend_from_slice(table);
+    }
+    let file_data = (header.len() + tables.len()) as u64;
+    let file_data = file_data.next_multiple_of(FILE_ALIGN);
+    header[0x24..0x28].copy_from_slice(&(file_data as u32).to_le_bytes());
+    header.extend_from_slice(&tables);
+    header.resize(file_data as usize, 0);
+    Ok((header, file_data))
+}
+
+/// Hashes data in fixed-size blocks as it is written, zero-padding the last block.
+struct BlockHasher {
+    block: Vec<u8>,
+    hashes: Vec<u8>,
+}
+
+impl BlockHasher {
+    fn new() -> Self {
+        BlockHasher {
+            block: Vec::with_capacity(BLOCK_SIZE as usize),
+            hashes: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = (BLOCK_SIZE as usize - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.block.len() == BLOCK_SIZE as usize {
+                self.hashes.extend_from_slice(&Sha256::digest(&self.block));
+                self.block.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if !self.block.is_empty() {
+            self.block.resize(BLOCK_SIZE as usize, 0);
+            self.hashes.extend_from_slice(&Sha256::digest(&self.block));
+        }
+        self.hashes
+    }
+}
+
+/// Hash a level's data in blocks.
+fn hash_blocks(data: &[u8]) -> Vec<u8> {
+    let mut hasher = BlockHasher::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+fn write_zeros<W: Write>(out: &mut W, len: u64) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(len), out)?;
+    Ok(())
+}
+
+/// Build an IVFC RomFS from the directory tree at `source`, writing it to `out` at its
+/// current position.
+///
+/// Layout: IVFC header, master hash, level 3 (the file system), level 1, level 2 — each
+/// level aligned to the 0x1000-byte block size.
+pub fn build<W: Write + Seek>(source: &Path, out: &mut W) -> io::Result<BuiltRomFs> {
+    let (mut dirs, mut files) = scan(source)?;
+    let (metadata, file_data) = build_metadata(&mut dirs, &mut files)?;
+    let level3_size = file_data + files.last().map_or(0, |f| f.data_offset + f.size);
+
+    let hash_size = |len: u64| len.div_ceil(BLOCK_SIZE) * 0x20;
+    let level2_size = hash_size(level3_size);
+    let level1_size = hash_size(level2_size);
+    let master_size = hash_size(level1_size);
+
+    let level3_pos = (MASTER_HASH_OFFSET + master_size).next_multiple_of(BLOCK_SIZE);
+    let level1_pos = (level3_pos + level3_size).next_multiple_of(BLOCK_SIZE);
+    let level2_pos = (level1_pos + level1_size).next_multiple_of(BLOCK_SIZE);
+    let size = level2_pos + level2_size;
+
+    let start = out.stream_position()?;
+    write_zeros(out, level3_pos)?;
+
+    // Level 3, hashed on the fly into level 2
+    let mut level3 = BlockHasher::new();
+    out.write_all(&metadata)?;
+    level3.update(&metadata);
+    let mut written = file_data;
+    let mut buf = vec![0u8; COPY_CHUNK];
+    for file in &files {
+        let pad = vec![0u8; (file_data + file.data_offset - written) as usize];
+        out.write_all(&pad)?;
+        level3.update(&pad);
+
+        let mut reader = BufReader::new(File::open(&file.path)?);
+        let mut remaining = file.size;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            reader.read_exact(&mut buf[..n])?;
+            out.write_all(&buf[..n])?;
+            level3.update(&buf[..n]);
+            remaining -= n as u64;
+        }
+        written = file_data + file.data_offset + file.size;
+    }
+    let level2 = level3.finish();
+    let level1 = hash_blocks(&level2);
+    let master = hash_blocks(&level1);
+
+    write_zeros(out, level1_pos - (level3_pos + level3_size))?;
+    out.write_all(&level1)?;
+    write_zeros(out, level2_pos - (level1_pos + level1_size))?;
+    out.write_all(&level2)?;
+    let end = out.stream_position()?;
+
+    let mut superblock = vec![0u8; MASTER_HASH_OFFSET as usize];
+    superblock[0..4].copy_from_slice(b"IVFC");
+    superblock[4..8].copy_from_slice(&0x10000u32.to_le_bytes());
+    superblock[8..12].copy_from_slice(&(master_size as u32).to_le_bytes());
+    let level2_logical = level1_size.next_multiple_of(BLOCK_SIZE);
+    let level3_logical = (level2_logical + level2_size).next_multiple_of(BLOCK_SIZE);
+    for (i, (logical, len)) in [
+        (0, level1_size),
+        (level2_logical, level2_size),
+        (level3_logical, level3_size),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let base = 0x0C + i * 0x18;
+        superblock[base..base + 8].copy_from_slice(&logical.to_le_bytes());
+        superblock[base + 8..base + 16].copy_from_slice(&len.to_le_bytes());
+        superblock[base + 16..base + 20].copy_from_slice(&BLOCK_LOG2.to_le_bytes());
+    }
+    superblock[0x54..0x58].copy_from_slice(&(IVFC_HEADER_SIZE as u32).to_le_bytes());
+    superblock.extend_from_slice(&master);
+
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&superblock)?;
+    out.seek(SeekFrom::Start(end))?;
+
+    Ok(BuiltRomFs {
+        size,
+        superblock,
+        file_count: files.len(),
+    })
+}
+
+/// Copy `len` bytes from `input` at `from` to the current position of `out`.
+fn copy_range<R: Read + Seek, W: Write>(
+    input: &mut R,
+    from: u64,
+    len: u64,
+    out: &mut W,
+) -> io::Result<()> {
+    input.seek(SeekFrom::Start(from))?;
+    let copied = io::copy(&mut input.take(len), out)?;
+    if copied != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "input ROM is truncated",
+        ));
+    }
+    Ok(())
+}
+
+/// Write the main NCCH of `input` (at `ncch_offset`) to `out` with its RomFS replaced by
+/// one built from `source`, patching the RomFS fields and superblock hash in its header.
+/// Returns the new NCCH size in bytes and the RomFS layout.
+fn write_patched_ncch<R: Read + Seek>(
+    input: &mut R,
+    ncch_offset: u64,
+    ncch: &NcchHeader,
+    source: &Path,
+    out: &mut File,
+) -> Result<(u64, BuiltRomFs), Error> {
+    if !ncch.is_no_crypto() {
+        return Err(Error::NotDecrypted);
+    }
+    let unit = ncch.media_unit_size() as u64;
+
+    // The RomFS is the last region of an NCCH; keep everything before it
+    let romfs_start = if ncch.romfs_length > 0 {
+        ncch.romfs_offset as u64 * unit
+    } else {
+        let ends = [
+            0x200 + ncch.exheader_length as u64 * 2,
+            (ncch.plain_offset as u64 + ncch.plain_length as u64) * unit,
+            (ncch.logo_offset as u64 + ncch.logo_length as u64) * unit,
+            (ncch.exefs_offset as u64 + ncch.exefs_length as u64) * unit,
+        ];
+        ends.into_iter().max().unwrap().next_multiple_of(unit)
+    };
+
+    let out_start = out.stream_position()?;
+    copy_range(input, ncch_offset, romfs_start, out)?;
+    let romfs = build(source, out)?;
+    let padded = romfs.size.next_multiple_of(unit);
+    write_zeros(out, padded - romfs.size)?;
+    let end = out.stream_position()?;
+
+    let mut superblock = romfs.superblock.clone();
+    let hash_region = (superblock.len() as u64).next_multiple_of(unit);
+    superblock.resize(hash_region as usize, 0);
+    let hash = Sha256::digest(&superblock);
+
+    let units = |bytes: u64| -> Result<[u8; 4], Error> {
+        u32::try_from(bytes / unit)
+            .map(u32::to_le_bytes)
+            .map_err(|_| Error::InvalidNcch(0))
+    };
+    let ncch_size = end - out_start;
+    let fields: [(u64, [u8; 4]); 4] = [
+        (0x104, units(ncch_size)?),
+        (0x1B0, units(romfs_start)?),
+        (0x1B4, units(padded)?),
+        (0x1B8, units(hash_region)?),
+    ];
+    for (at, value) in fields {
+        out.seek(SeekFrom::Start(out_start + at))?;
+        out.write_all(&value)?;
+    }
+    out.seek(SeekFrom::Start(out_start + 0x1E0))?;
+    out.write_all(&hash)?;
+    out.seek(SeekFrom::Start(end))?;
+
+    Ok((ncch_size, romfs))
+}
+
+/// Write a copy of the decrypted ROM `input` to `output` with the RomFS of its main NCCH
+/// rebuilt from the directory `source`.
+///
+/// Works on NCSD images and bare NCCHs. Partitions after the first are moved to follow
+/// the new RomFS, and the NCSD partition table and image size are updated.
+pub fn rebuild_rom(input: &Path, source: &Path, output: &Path) -> Result<BuiltRomFs, Error> {
+    let tmp_path = temp_path_for(output);
+    let result = rebuild_to(input, source, &tmp_path).and_then(|built| {
+        fs::rename(&tmp_path, output)?;
+        Ok(built)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn rebuild_to(input: &Path, source: &Path, tmp_path: &Path) -> Result<BuiltRomFs, Error> {
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut out = File::create(tmp_path)?;
+
+    let romfs = match RomFormat::detect_reader(&mut reader)? {
+        Some(RomFormat::Ncch) => {
+            let ncch = NcchHeader::parse(&mut reader, 0)?;
+            let (_, romfs) = write_patched_ncch(&mut reader, 0, &ncch, source, &mut out)?;
+            romfs
+        }
+        Some(RomFormat::Ncsd) => {
+            let ncsd = NcsdHeader::parse(&mut reader).map_err(|_| Error::NotNcsd)?;
+            let sector = ncsd.sector_size as u64;
+            let part0 = ncsd.partitions[0];
+            if part0.is_empty() {
+                return Err(Error::InvalidNcch(0));
+            }
+            let ncch = NcchHeader::parse(&mut reader, part0.offset_bytes(ncsd.sector_size))?;
+
+            copy_range(
+                &mut reader,
+                0,
+                part0.offset_bytes(ncsd.sector_size),
+                &mut out,
+            )?;
+            let mut table = [(0u32, 0u32); 8];
+            let (size, romfs) = write_patched_ncch(
+                &mut reader,
+                part0.offset_bytes(ncsd.sector_size),
+                &ncch,
+                source,
+                &mut out,
+            )?;
+            table[0] = (part0.offset_sectors, (size / sector) as u32);
+
+            for (p, part) in ncsd.partitions.iter().enumerate().skip(1) {
+                if part.is_empty() {
+                    continue;
+                }
+                let pos = out.stream_position()?;
+                let offset = u32::try_from(pos / sector).map_err(|_| Error::NotNcsd)?;
+                let len = part.length_bytes(ncsd.sector_size);
+                copy_range(
+                    &mut reader,
+                    part.offset_bytes(ncsd.sector_size),
+                    len,
+                    &mut out,
+                )?;
+                table[p] = (offset, part.length_sectors);
+            }
+            let image_size = out.stream_position()? / sector;
+
+            out.seek(SeekFrom::Start(0x104))?;
+            out.write_all(&(image_size as u32).to_le_bytes())?;
+            out.seek(SeekFrom::Start(0x120))?;
+            for (offset, len) in table {
+                out.write_all(&offset.to_le_bytes())?;
+                out.write_all(&len.to_le_bytes())?;
+            }
+            romfs
+        }
+        Some(RomFormat::Cia) => {
+            return Err(Error::InvalidCia(
+                "rebuilding the RomFS of a CIA is not supported".into(),
+            ));
+        }
+        None => return Err(Error::UnknownFormat),
+    };
+
+    out.sync_all()?;
+    Ok(romfs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::romfs::RomFs;
+    use std::io::Cursor;
+
+    fn write_tree(root: &Path) {
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("data/sub")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("a.txt"), b"hello").unwrap();
+        fs::write(root.join("data/b.bin"), vec![0x5A; 0x2345]).unwrap();
+        fs::write(root.join("data/sub/c.bin"), b"").unwrap();
+        fs::write(root.join("data/sub/d.bin"), vec![0xA5; 0x11]).unwrap();
+    }
+
+    fn read_all<R: Read + Seek>(romfs: &mut RomFs<R>, path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        romfs.open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_bucket_count() {
+        assert_eq!(bucket_count(0), 3);
+        assert_eq!(bucket_count(4), 5);
+        assert_eq!(bucket_count(18), 19);
+        assert_eq!(bucket_count(19), 19);
+        assert_eq!(bucket_count(20), 23);
+    }
+
+    #[test]
+    fn test_build_roundtrip() {
+        let root = PathBuf::from("test-fixtures").join("romfs_build_tree");
+        write_tree(&root);
+        let mut image = Cursor::new(Vec::new());
+        let built = build(&root, &mut image).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        let image = image.into_inner();
+        assert_eq!(built.size, image.len() as u64);
+        assert_eq!(built.file_count, 4);
+        assert_eq!(&built.superblock[..], &image[..built.superblock.len()]);
+
+        let mut romfs = RomFs::new(Cursor::new(&image), 0).unwrap();
+        let ivfc = romfs.ivfc().clone();
+        let listed: Vec<_> = romfs
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|f| (f.path, f.size))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("/a.txt".to_string(), 5),
+                ("/data/b.bin".to_string(), 0x2345),
+                ("/data/sub/c.bin".to_string(), 0),
+                ("/data/sub/d.bin".to_string(), 0x11),
+            ]
+        );
+        assert_eq!(read_all(&mut romfs, "/a.txt"), b"hello");
+        assert_eq!(read_all(&mut romfs, "/data/b.bin"), vec![0x5A; 0x2345]);
+        assert_eq!(read_all(&mut romfs, "/data/sub/d.bin"), vec![0xA5; 0x11]);
+
+        // Level 3 hashes into level 2, which hashes into level 1 and the master hash
+        let level3 = ivfc.level3_offset() as usize;
+        let level3_size = ivfc.levels[2].size as usize;
+        let level1 = (level3 + level3_size).next_multiple_of(0x1000);
+        let level1_size = ivfc.levels[0].size as usize;
+        let level2 = (level1 + level1_size).next_multiple_of(0x1000);
+        let level2_size = ivfc.levels[1].size as usize;
+
+        let mut padded = image[level3..level3 + level3_size].to_vec();
+        padded.resize(padded.len().next_multiple_of(0x1000), 0);
+        assert_eq!(hash_blocks(&padded), image[level2..level2 + level2_size]);
+        assert_eq!(
+            hash_blocks(&image[level2..level2 + level2_size]),
+            image[level1..level1 + level1_size]
+        );
+        let master = &built.superblock[MASTER_HASH_OFFSET as usize..];
+        assert_eq!(hash_blocks(&image[level1..level1 + level1_size]), master);
+    }
+
+    /// NoCrypto NCSD: partition 0 has an ExeFS but no RomFS, partition 1 is filler.
+    fn build_ncsd() -> Vec<u8> {
+        let mut rom = vec![0u8; 0x5000];
+        rom[0x100..0x104].copy_from_slice(b"NCSD");
+        rom[0x104..0x108].copy_from_slice(&0x28u32.to_le_bytes());
+        for (i, (offset, len)) in [(0x20u32, 0x4u32), (0x24, 0x4)].into_iter().enumerate() {
+            rom[0x120 + i * 8..0x124 + i * 8].copy_from_slice(&offset.to_le_bytes());
+            rom[0x124 + i * 8..0x128 + i * 8].copy_from_slice(&len.to_le_bytes());
+        }
+
+        let ncch = &mut rom[0x4000..0x4800];
+        ncch[0x100..0x104].copy_from_slice(b"NCCH");
+        ncch[0x104..0x108].copy_from_slice(&0x4u32.to_le_bytes());
+        ncch[0x18F] = 0x04;
+        ncch[0x1A0..0x1A4].copy_from_slice(&1u32.to_le_bytes());
+        ncch[0x1A4..0x1A8].copy_from_slice(&3u32.to_le_bytes());
+        ncch[0x200..0x800].fill(0xEE);
+        rom[0x4800..0x5000].fill(0x77);
+        rom
+    }
+
+    #[test]
+    fn test_rebuild_rom() {
+        let dir = PathBuf::from("test-fixtures");
+        let root = dir.join("romfs_rebuild_tree");
+        let input = dir.join("romfs_rebuild_in.3ds");
+        let output = dir.join("romfs_rebuild_out.3ds");
+        write_tree(&root);
+        fs::write(&input, build_ncsd()).unwrap();
+
+        let built = rebuild_rom(&input, &root, &output).unwrap();
+        let rom = fs::read(&output).unwrap();
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_file(&input);
+        let _ = fs::remove_file(&output);
+
+        let ncsd = NcsdHeader::parse(&mut Cursor::new(&rom)).unwrap();
+        let ncch = NcchHeader::parse(&mut Cursor::new(&rom), 0x4000).unwrap();
+        assert_eq!(ncch.romfs_offset, 4);
+        assert_eq!(ncch.romfs_length as u64, built.size.div_ceil(0x200));
+
+        let romfs_start = 0x4800;
+        let hash_region = u32::from_le_bytes(rom[0x41B8..0x41BC].try_into().unwrap());
+        let region = &rom[romfs_start..romfs_start + hash_region as usize * 0x200];
+        assert_eq!(rom[0x41E0..0x4200], Sha256::digest(region)[..]);
+        assert_eq!(rom[0x4200..0x4800], [0xEE; 0x600]);
+
+        let part1 = ncsd.partitions[1];
+        let part1_offset = part1.offset_bytes(0x200) as usize;
+        assert_eq!(
+            part1_offset,
+            romfs_start + ncch.romfs_length as usize * 0x200
+        );
+        assert_eq!(rom[part1_offset..], [0x77; 0x800]);
+        assert_eq!(
+            u32::from_le_bytes(rom[0x104..0x108].try_into().unwrap()) as usize,
+            rom.len() / 0x200
+        );
+
+        let mut romfs = RomFs::locate(Cursor::new(&rom)).unwrap();
+        assert_eq!(romfs.list().unwrap().len(), 4);
+        assert_eq!(read_all(&mut romfs, "/data/sub/d.bin"), vec![0xA5; 0x11]);
+    }
+}
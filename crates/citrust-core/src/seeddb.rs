@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// A 16-byte title seed used by NCCH seed crypto (titles released after firmware 9.6).
+pub type Seed = [u8; 16];
+
+/// Size of the `seeddb.bin` header (entry count followed by padding).
+const HEADER_SIZE: usize = 0x10;
+/// Size of one `seeddb.bin` entry: title ID, seed and padding.
+const ENTRY_SIZE: usize = 0x20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeedDbError {
+    #[error("seed database not found at {0}")]
+    FileNotFound(PathBuf),
+    #[error("seed database truncated: header declares {expected} entries, found {found}")]
+    Truncated { expected: usize, found: usize },
+    #[error("invalid seed: {0}")]
+    InvalidSeed(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Title seeds keyed by program ID, parsed from a `seeddb.bin` file (GodMode9 / Citra format).
+#[derive(Debug, Clone, Default)]
+pub struct SeedDatabase {
+    seeds: HashMap<u64, Seed>,
+    fallback: Option<Seed>,
+}
+
+impl SeedDatabase {
+    /// Create an empty seed database.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a `seeddb.bin` image from any reader.
+    pub fn from_reader(mut reader: impl Read) -> Result<Self, SeedDbError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let Some(header) = data.get(..HEADER_SIZE) else {
+            return Err(SeedDbError::Truncated {
+                expected: 0,
+                found: 0,
+            });
+        };
+        let count = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let found = (data.len() - HEADER_SIZE) / ENTRY_SIZE;
+        if found < count {
+            return Err(SeedDbError::Truncated {
+                expected: count,
+                found,
+            });
+        }
+
+        let mut seeds = HashMap::with_capacity(count);
+        for entry in data[HEADER_SIZE..].chunks_exact(ENTRY_SIZE).take(count) {
+            let title_id = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let seed: Seed = entry[8..24].try_into().unwrap();
+            seeds.insert(title_id, seed);
+        }
+
+        Ok(SeedDatabase {
+            seeds,
+            fallback: None,
+        })
+    }
+
+    /// Parse a `seeddb.bin` file from a path.
+    pub fn from_file(path: &Path) -> Result<Self, SeedDbError> {
+        if !path.exists() {
+            return Err(SeedDbError::FileNotFound(path.to_path_buf()));
+        }
+        Self::from_reader(std::fs::File::open(path)?)
+    }
+
+    /// Search default locations for a `seeddb.bin` file. Returns the first found.
+    pub fn search_default_locations() -> Option<PathBuf> {
+        let mut candidates: Vec<PathBuf> = vec![PathBuf::from("seeddb.bin")];
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(home) = std::env::var_os("HOME") {
+                let home = PathBuf::from(home);
+                candidates.push(home.join(".config/citrust/seeddb.bin"));
+                candidates.push(home.join(".local/share/citra-emu/sysdata/seeddb.bin"));
+                candidates.push(home.join(".local/share/azahar-emu/sysdata/seeddb.bin"));
+            }
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            if let Some(appdata) = std::env::var_os("APPDATA") {
+                let appdata = PathBuf::from(appdata);
+                candidates.push(appdata.join("citrust\\seeddb.bin"));
+                candidates.push(appdata.join("Citra\\sysdata\\seeddb.bin"));
+            }
+        }
+
+        candidates.into_iter().find(|p| p.exists())
+    }
+
+    /// Parse a seed given as 32 hex characters.
+    pub fn parse_seed(hex: &str) -> Result<Seed, SeedDbError> {
+        let hex = hex.trim();
+        if hex.len() != 32 {
+            return Err(SeedDbError::InvalidSeed(format!(
+                "expected 32 hex characters, got {}",
+                hex.len()
+            )));
+        }
+        let value = u128::from_str_radix(hex, 16)
+            .map_err(|e| SeedDbError::InvalidSeed(format!("hex parse error: {e}")))?;
+        Ok(value.to_be_bytes())
+    }
+
+    /// Add or replace the seed for a program ID.
+    pub fn insert(&mut self, program_id: u64, seed: Seed) {
+        self.seeds.insert(program_id, seed);
+    }
+
+    /// Set a seed to try for titles that have no entry of their own.
+    ///
+    /// Every seed is checked against the NCCH seed-check hash before use, so a
+    /// fallback that belongs to a different title is rejected rather than applied.
+    pub fn set_fallback(&mut self, seed: Seed) {
+        self.fallback = Some(seed);
+    }
+
+    /// Look up the seed for a program ID, falling back to the single-seed input if any.
+    pub fn get(&self, program_id: u64) -> Option<Seed> {
+        self.seeds.get(&program_id).copied().or(self.fallback)
+    }
+
+    /// Number of title seeds loaded (not counting the fallback).
+    pub fn len(&self) -> usize {
+        self.seeds.len()
+    }
+
+    /// Whether no seeds are loaded.
+    pub fn is_empty(&self) -> bool {
+        self.seeds.is_empty() && self.fallback.is_none()
+    }
+}
+
+/// Check a seed against the NCCH seed-check hash: the first 4 bytes of
+/// SHA-256(seed || program ID as little-endian).
+pub fn verify_seed(seed: &Seed, program_id: u64, check: [u8; 4]) -> bool {
+    let digest = Sha256::new()
+        .chain_update(seed)
+        .chain_update(program_id.to_le_bytes())
+        .finalize();
+    digest[..4] == check
+}
+
+/// Derive the seeded KeyY: the first 16 bytes of SHA-256(KeyY || seed).
+pub fn seeded_key_y(key_y: u128, seed: &Seed) -> u128 {
+    let digest = Sha256::new()
+        .chain_update(key_y.to_be_bytes())
+        .chain_update(seed)
+        .finalize();
+    u128::from_be_bytes(digest[..16].try_into().unwrap())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    pub(crate) const TEST_PROGRAM_ID: u64 = 0x0004000000055D00;
+    pub(crate) const TEST_SEED: Seed = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+
+    /// The NCCH seed-check bytes for `seed` and `program_id`.
+    pub(crate) fn seed_check(seed: &Seed, program_id: u64) -> [u8; 4] {
+        let digest = Sha256::new()
+            .chain_update(seed)
+            .chain_update(program_id.to_le_bytes())
+            .finalize();
+        digest[..4].try_into().unwrap()
+    }
+
+    fn build_seeddb(entries: &[(u64, Seed)]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[0..4].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        for (title_id, seed) in entries {
+            data.extend_from_slice(&title_id.to_le_bytes());
+            data.extend_from_slice(seed);
+            data.extend_from_slice(&[0u8; 8]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_seeddb() {
+        let data = build_seeddb(&[(TEST_PROGRAM_ID, TEST_SEED), (1, [0x42; 16])]);
+        let db = SeedDatabase::from_reader(Cursor::new(data)).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(TEST_PROGRAM_ID), Some(TEST_SEED));
+        assert_eq!(db.get(1), Some([0x42; 16]));
+        assert_eq!(db.get(2), None);
+    }
+
+    #[test]
+    fn test_truncated_seeddb() {
+        let mut data = build_seeddb(&[(TEST_PROGRAM_ID, TEST_SEED)]);
+        data[0..4].copy_from_slice(&2u32.to_le_bytes());
+        let err = SeedDatabase::from_reader(Cursor::new(data)).unwrap_err();
+        assert!(matches!(
+            err,
+            SeedDbError::Truncated {
+                expected: 2,
+                found: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_seed_hex() {
+        let seed = SeedDatabase::parse_seed("00112233445566778899AABBCCDDEEFF").unwrap();
+        assert_eq!(seed, TEST_SEED);
+        assert!(SeedDatabase::parse_seed("0011").is_err());
+        assert!(SeedDatabase::parse_seed("ZZ112233445566778899AABBCCDDEEFF").is_err());
+    }
+
+    #[test]
+    fn test_fallback_seed() {
+        let mut db = SeedDatabase::new();
+        assert!(db.is_empty());
+        db.set_fallback(TEST_SEED);
+        assert_eq!(db.get(TEST_PROGRAM_ID), Some(TEST_SEED));
+        db.insert(TEST_PROGRAM_ID, [1; 16]);
+        assert_eq!(db.get(TEST_PROGRAM_ID), Some([1; 16]));
+    }
+
+    #[test]
+    fn test_verify_seed() {
+        let check = seed_check(&TEST_SEED, TEST_PROGRAM_ID);
+        assert!(verify_seed(&TEST_SEED, TEST_PROGRAM_ID, check));
+        assert!(!verify_seed(&[0; 16], TEST_PROGRAM_ID, check));
+        assert!(!verify_seed(&TEST_SEED, TEST_PROGRAM_ID + 1, check));
+    }
+
+    #[test]
+    fn test_seeded_key_y_matches_sha256() {
+        let key_y = 0x0123456789ABCDEF0123456789ABCDEFu128;
+        let mut input = key_y.to_be_bytes().to_vec();
+        input.extend_from_slice(&TEST_SEED);
+        let digest = Sha256::digest(&input);
+        assert_eq!(seeded_key_y(key_y, &TEST_SEED).to_be_bytes(), digest[..16]);
+    }
+}
@@ -0,0 +1,524 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+use crate::decrypt::Error;
+use crate::exefs::{self, ExefsHeader};
+
+/// Size of an SMDH file.
+pub const SMDH_SIZE: usize = 0x36C0;
+
+/// Number of application title slots (12 languages are in use, 4 are reserved).
+const TITLE_COUNT: usize = 16;
+const TITLES_OFFSET: usize = 0x8;
+const TITLE_SIZE: usize = 0x200;
+const SETTINGS_OFFSET: usize = 0x2008;
+const SMALL_ICON_OFFSET: usize = 0x2040;
+const LARGE_ICON_OFFSET: usize = 0x24C0;
+
+/// Region lockout value of region-free titles.
+const REGION_FREE: u32 = 0x7FFF_FFFF;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Decode a NUL-padded UTF-16LE string.
+fn read_utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Title languages, in SMDH slot order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    Japanese,
+    English,
+    French,
+    German,
+    Italian,
+    Spanish,
+    SimplifiedChinese,
+    Korean,
+    Dutch,
+    Portuguese,
+    Russian,
+    TraditionalChinese,
+}
+
+impl Language {
+    pub const ALL: [Language; 12] = [
+        Language::Japanese,
+        Language::English,
+        Language::French,
+        Language::German,
+        Language::Italian,
+        Language::Spanish,
+        Language::SimplifiedChinese,
+        Language::Korean,
+        Language::Dutch,
+        Language::Portuguese,
+        Language::Russian,
+        Language::TraditionalChinese,
+    ];
+}
+
+/// Localized names of the application.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplicationTitle {
+    pub short_description: String,
+    pub long_description: String,
+    pub publisher: String,
+}
+
+impl ApplicationTitle {
+    fn parse(data: &[u8]) -> Self {
+        ApplicationTitle {
+            short_description: read_utf16(&data[0x0..0x80]),
+            long_description: read_utf16(&data[0x80..0x180]),
+            publisher: read_utf16(&data[0x180..0x200]),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.short_description.is_empty()
+            && self.long_description.is_empty()
+            && self.publisher.is_empty()
+    }
+}
+
+/// Age rating boards, in SMDH slot order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RatingBoard {
+    Cero,
+    Esrb,
+    Usk,
+    PegiGen,
+    PegiPrt,
+    PegiBbfc,
+    Cob,
+    Grb,
+    Cgsrr,
+}
+
+impl RatingBoard {
+    pub const ALL: [RatingBoard; 9] = [
+        RatingBoard::Cero,
+        RatingBoard::Esrb,
+        RatingBoard::Usk,
+        RatingBoard::PegiGen,
+        RatingBoard::PegiPrt,
+        RatingBoard::PegiBbfc,
+        RatingBoard::Cob,
+        RatingBoard::Grb,
+        RatingBoard::Cgsrr,
+    ];
+
+    /// Index of this board's byte in the rating table (slots 2 and 5 are reserved).
+    fn slot(self) -> usize {
+        match self {
+            RatingBoard::Cero => 0,
+            RatingBoard::Esrb => 1,
+            RatingBoard::Usk => 3,
+            RatingBoard::PegiGen => 4,
+            RatingBoard::PegiPrt => 6,
+            RatingBoard::PegiBbfc => 7,
+            RatingBoard::Cob => 8,
+            RatingBoard::Grb => 9,
+            RatingBoard::Cgsrr => 10,
+        }
+    }
+}
+
+/// One age rating byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AgeRating {
+    /// Minimum age (ignored when `no_restriction` is set).
+    pub age: u8,
+    pub pending: bool,
+    pub no_restriction: bool,
+}
+
+impl AgeRating {
+    /// Decode a rating byte; `None` when the rating is not active.
+    fn from_byte(byte: u8) -> Option<Self> {
+        (byte & 0x80 != 0).then_some(AgeRating {
+            age: byte & 0x1F,
+            pending: byte & 0x40 != 0,
+            no_restriction: byte & 0x20 != 0,
+        })
+    }
+}
+
+/// Regions a title may be played in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Region {
+    Japan,
+    NorthAmerica,
+    Europe,
+    Australia,
+    China,
+    Korea,
+    Taiwan,
+}
+
+impl Region {
+    pub const ALL: [Region; 7] = [
+        Region::Japan,
+        Region::NorthAmerica,
+        Region::Europe,
+        Region::Australia,
+        Region::China,
+        Region::Korea,
+        Region::Taiwan,
+    ];
+}
+
+/// Region lockout bit field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionLockout(pub u32);
+
+impl RegionLockout {
+    pub fn is_region_free(&self) -> bool {
+        self.0 == REGION_FREE
+    }
+
+    pub fn allows(&self, region: Region) -> bool {
+        let bit = Region::ALL.iter().position(|&r| r == region).unwrap();
+        self.0 & (1 << bit) != 0
+    }
+
+    /// The regions whose bit is set.
+    pub fn regions(&self) -> Vec<Region> {
+        Region::ALL
+            .into_iter()
+            .filter(|&r| self.allows(r))
+            .collect()
+    }
+}
+
+/// Application settings stored after the titles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmdhSettings {
+    /// Raw rating bytes, indexed by slot; see [`Smdh::age_rating`].
+    pub age_ratings: [u8; 16],
+    pub region_lockout: RegionLockout,
+    pub match_maker_id: u32,
+    pub match_maker_bit_id: u64,
+    /// Visibility, auto-boot, 3D, EULA, save data and similar flags.
+    pub flags: u32,
+    pub eula_version: u16,
+    pub optimal_animation_frame: f32,
+    pub cec_id: u32,
+}
+
+impl SmdhSettings {
+    fn parse(data: &[u8]) -> Self {
+        SmdhSettings {
+            age_ratings: data[0x0..0x10].try_into().unwrap(),
+            region_lockout: RegionLockout(read_u32(data, 0x10)),
+            match_maker_id: read_u32(data, 0x14),
+            match_maker_bit_id: u64::from_le_bytes(data[0x18..0x20].try_into().unwrap()),
+            flags: read_u32(data, 0x20),
+            eula_version: read_u16(data, 0x24),
+            optimal_animation_frame: f32::from_le_bytes(data[0x28..0x2C].try_into().unwrap()),
+            cec_id: read_u32(data, 0x2C),
+        }
+    }
+}
+
+/// An icon decoded to row-major RGBA8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Icon {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl Icon {
+    /// Decode a tiled RGB565 icon.
+    ///
+    /// Pixels are stored in 8x8 tiles, left to right and top to bottom; within a tile
+    /// they follow a Morton (Z-order) curve, with x in the even bits of the index and y
+    /// in the odd bits.
+    fn decode(data: &[u8], size: u32) -> Self {
+        let size_px = size as usize;
+        let mut rgba = vec![0u8; size_px * size_px * 4];
+        for (i, pixel) in data.chunks_exact(2).take(size_px * size_px).enumerate() {
+            let tile = i / 64;
+            let within = i % 64;
+            let x = (tile % (size_px / 8)) * 8 + deinterleave(within);
+            let y = (tile / (size_px / 8)) * 8 + deinterleave(within >> 1);
+
+            let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+            let r = ((value >> 11) & 0x1F) as u8;
+            let g = ((value >> 5) & 0x3F) as u8;
+            let b = (value & 0x1F) as u8;
+            let out = (y * size_px + x) * 4;
+            rgba[out..out + 4].copy_from_slice(&[
+                (r << 3) | (r >> 2),
+                (g << 2) | (g >> 4),
+                (b << 3) | (b >> 2),
+                0xFF,
+            ]);
+        }
+        Icon {
+            width: size,
+            height: size,
+            rgba,
+        }
+    }
+
+    /// Encode the icon as a PNG.
+    pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgba)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Save the icon as a PNG file.
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_png(&mut file)?;
+        file.flush()
+    }
+}
+
+/// Collect bits 0, 2 and 4 of a Morton index into a 3-bit coordinate.
+fn deinterleave(index: usize) -> usize {
+    (index & 1) | ((index >> 1) & 2) | ((index >> 2) & 4)
+}
+
+/// The SMDH stored in the ExeFS `icon` file: titles, settings and icons.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Smdh {
+    pub version: u16,
+    /// Title slots in [`Language::ALL`] order, followed by 4 reserved slots.
+    pub titles: Vec<ApplicationTitle>,
+    pub settings: SmdhSettings,
+    /// 24x24 icon.
+    pub small_icon: Icon,
+    /// 48x48 icon.
+    pub large_icon: Icon,
+}
+
+impl Smdh {
+    /// Parse an SMDH from the first 0x36C0 bytes of `data`.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let Some(data) = data.get(..SMDH_SIZE) else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMDH shorter than 0x36C0 bytes",
+            ));
+        };
+        if &data[..4] != b"SMDH" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid SMDH magic",
+            ));
+        }
+
+        let titles = (0..TITLE_COUNT)
+            .map(|i| {
+                let start = TITLES_OFFSET + i * TITLE_SIZE;
+                ApplicationTitle::parse(&data[start..start + TITLE_SIZE])
+            })
+            .collect();
+
+        Ok(Smdh {
+            version: read_u16(data, 0x4),
+            titles,
+            settings: SmdhSettings::parse(&data[SETTINGS_OFFSET..SMALL_ICON_OFFSET]),
+            small_icon: Icon::decode(&data[SMALL_ICON_OFFSET..LARGE_ICON_OFFSET], 24),
+            large_icon: Icon::decode(&data[LARGE_ICON_OFFSET..SMDH_SIZE], 48),
+        })
+    }
+
+    /// Parse the SMDH from the `icon` file of an in-memory, decrypted ExeFS.
+    pub fn from_exefs(exefs: &[u8]) -> io::Result<Self> {
+        let header = ExefsHeader::from_bytes(exefs)?;
+        let data = header
+            .find("icon")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "ExeFS has no icon"))?
+            .data(exefs)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "ExeFS icon is truncated")
+            })?;
+        Self::from_bytes(data)
+    }
+
+    /// Read the SMDH from the ExeFS of a decrypted ROM's main NCCH.
+    pub fn read_from_rom<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        let (header, exefs_offset) = exefs::locate(reader)?;
+        let data = header.read_file(reader, exefs_offset, "icon")?;
+        Ok(Self::from_bytes(&data)?)
+    }
+
+    /// Title for `language`, if that slot is filled in.
+    pub fn title(&self, language: Language) -> Option<&ApplicationTitle> {
+        let slot = Language::ALL.iter().position(|&l| l == language).unwrap();
+        self.titles.get(slot).filter(|t| !t.is_empty())
+    }
+
+    /// English title, falling back to the first filled-in language.
+    pub fn preferred_title(&self) -> Option<&ApplicationTitle> {
+        self.title(Language::English)
+            .or_else(|| self.titles.iter().find(|t| !t.is_empty()))
+    }
+
+    /// Rating for `board`, or `None` when the board has no active rating.
+    pub fn age_rating(&self, board: RatingBoard) -> Option<AgeRating> {
+        AgeRating::from_byte(self.settings.age_ratings[board.slot()])
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn write_utf16(out: &mut [u8], text: &str) {
+        for (i, unit) in text.encode_utf16().enumerate() {
+            out[i * 2..i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+
+    /// Tile and encode a row-major RGB565 image the way SMDH icons are stored.
+    fn tile_icon(pixels: &[u16], size: usize) -> Vec<u8> {
+        let mut out = vec![0u8; size * size * 2];
+        for i in 0..size * size {
+            let tile = i / 64;
+            let x = (tile % (size / 8)) * 8 + deinterleave(i % 64);
+            let y = (tile / (size / 8)) * 8 + deinterleave((i % 64) >> 1);
+            out[i * 2..i * 2 + 2].copy_from_slice(&pixels[y * size + x].to_le_bytes());
+        }
+        out
+    }
+
+    /// Row-major RGB565 pattern: red grows along x, blue along y.
+    fn gradient(size: usize) -> Vec<u16> {
+        (0..size * size)
+            .map(|i| (((i % size) as u16 & 0x1F) << 11) | ((i / size) as u16 & 0x1F))
+            .collect()
+    }
+
+    pub(crate) fn build_test_smdh() -> Vec<u8> {
+        let mut smdh = vec![0u8; SMDH_SIZE];
+        smdh[..4].copy_from_slice(b"SMDH");
+        let english = TITLES_OFFSET + TITLE_SIZE;
+        write_utf16(&mut smdh[english..english + 0x80], "Citrust Test");
+        write_utf16(
+            &mut smdh[english + 0x80..english + 0x180],
+            "Citrust Test Application",
+        );
+        write_utf16(&mut smdh[english + 0x180..english + 0x200], "citrust");
+
+        smdh[SETTINGS_OFFSET + 1] = 0x80 | 12; // ESRB 12+
+        smdh[SETTINGS_OFFSET + 4] = 0x80 | 0x20; // PEGI GEN, no restriction
+        smdh[SETTINGS_OFFSET + 0x10..SETTINGS_OFFSET + 0x14]
+            .copy_from_slice(&0x06u32.to_le_bytes());
+
+        smdh[SMALL_ICON_OFFSET..LARGE_ICON_OFFSET].copy_from_slice(&tile_icon(&gradient(24), 24));
+        smdh[LARGE_ICON_OFFSET..].copy_from_slice(&tile_icon(&gradient(48), 48));
+        smdh
+    }
+
+    #[test]
+    fn test_parse_titles_and_settings() {
+        let smdh = Smdh::from_bytes(&build_test_smdh()).unwrap();
+        let title = smdh.title(Language::English).unwrap();
+        assert_eq!(title.short_description, "Citrust Test");
+        assert_eq!(title.long_description, "Citrust Test Application");
+        assert_eq!(title.publisher, "citrust");
+        assert!(smdh.title(Language::Japanese).is_none());
+        assert_eq!(smdh.preferred_title(), Some(title));
+
+        assert_eq!(
+            smdh.age_rating(RatingBoard::Esrb),
+            Some(AgeRating {
+                age: 12,
+                pending: false,
+                no_restriction: false
+            })
+        );
+        assert!(
+            smdh.age_rating(RatingBoard::PegiGen)
+                .unwrap()
+                .no_restriction
+        );
+        assert_eq!(smdh.age_rating(RatingBoard::Cero), None);
+
+        let lockout = smdh.settings.region_lockout;
+        assert!(!lockout.is_region_free());
+        assert_eq!(
+            lockout.regions(),
+            vec![Region::NorthAmerica, Region::Europe]
+        );
+        assert!(RegionLockout(REGION_FREE).allows(Region::Taiwan));
+    }
+
+    #[test]
+    fn test_icon_deswizzle() {
+        // Stored pixel i holds the RGB565 value i; (x, y) from the Morton order by hand
+        let known = [
+            (0, (0, 0)),
+            (1, (1, 0)),
+            (2, (0, 1)),
+            (3, (1, 1)),
+            (4, (2, 0)),
+            (8, (0, 2)),
+            (16, (4, 0)),
+            (32, (0, 4)),
+            (63, (7, 7)),
+            (64, (8, 0)),
+        ];
+        for (size, extra) in [
+            (24usize, [(191, (23, 7)), (192, (0, 8)), (575, (23, 23))]),
+            (48, [(383, (47, 7)), (384, (0, 8)), (2303, (47, 47))]),
+        ] {
+            let data: Vec<u8> = (0..(size * size) as u16)
+                .flat_map(u16::to_le_bytes)
+                .collect();
+            let icon = Icon::decode(&data, size as u32);
+            for (index, (x, y)) in known.into_iter().chain(extra) {
+                let px = &icon.rgba[(y * size + x) * 4..][..4];
+                let value =
+                    ((px[0] as u16 >> 3) << 11) | ((px[1] as u16 >> 2) << 5) | (px[2] as u16 >> 3);
+                assert_eq!(value, index, "{size}x{size} icon at ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_png_export() {
+        let smdh = Smdh::from_bytes(&build_test_smdh()).unwrap();
+        let mut png_data = Vec::new();
+        smdh.large_icon.write_png(&mut png_data).unwrap();
+
+        let decoder = png::Decoder::new(io::Cursor::new(png_data));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0u8; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (48, 48));
+        assert_eq!(pixels, smdh.large_icon.rgba);
+    }
+
+    #[test]
+    fn test_rejects_bad_magic() {
+        let mut data = build_test_smdh();
+        data[0] = b'X';
+        assert!(Smdh::from_bytes(&data).is_err());
+        assert!(Smdh::from_bytes(&data[..0x100]).is_err());
+    }
+}
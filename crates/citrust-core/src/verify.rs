@@ -0,0 +1,413 @@
+//! Hash verification of decrypted NCCH partitions.
+//!
+//! The ExHeader, ExeFS superblock and RomFS superblock are checked against the NCCH
+//! header, ExeFS files against the ExeFS header, and the RomFS levels against the IVFC
+//! hash tree above them.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use sha2::{Digest, Sha256};
+
+use crate::decrypt::Error;
+use crate::exefs::ExefsHeader;
+use crate::format;
+use crate::ncch::NcchHeader;
+use crate::romfs::IvfcHeader;
+
+/// Size of the buffer used when hashing large regions.
+pub(crate) const HASH_CHUNK: usize = 1024 * 1024;
+
+/// A hashed region of an NCCH.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyRegion {
+    ExHeader,
+    /// Start of the ExeFS covered by the NCCH header's ExeFS hash.
+    ExefsSuperblock,
+    /// One ExeFS file, checked against the hash in the ExeFS header.
+    ExefsFile(String),
+    /// IVFC header and master hash covered by the NCCH header's RomFS hash.
+    RomfsSuperblock,
+    /// One level (1-3) of the IVFC hash tree, checked against the level above it.
+    RomfsLevel(u8),
+}
+
+impl fmt::Display for VerifyRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyRegion::ExHeader => f.write_str("ExHeader"),
+            VerifyRegion::ExefsSuperblock => f.write_str("ExeFS superblock"),
+            VerifyRegion::ExefsFile(name) => write!(f, "ExeFS {name}"),
+            VerifyRegion::RomfsSuperblock => f.write_str("RomFS superblock"),
+            VerifyRegion::RomfsLevel(level) => write!(f, "RomFS level {level}"),
+        }
+    }
+}
+
+/// Outcome of one hash check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashStatus {
+    Valid,
+    Mismatch,
+    /// The region extends past the end of the file.
+    Truncated,
+}
+
+impl fmt::Display for HashStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashStatus::Valid => "OK",
+            HashStatus::Mismatch => "MISMATCH",
+            HashStatus::Truncated => "TRUNCATED",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionCheck {
+    pub region: VerifyRegion,
+    pub status: HashStatus,
+}
+
+/// Hash checks for one NCCH partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionReport {
+    /// NCSD partition index, or CIA content index.
+    pub index: usize,
+    /// The partition is still encrypted, so its hashes were not checked.
+    pub encrypted: bool,
+    pub checks: Vec<RegionCheck>,
+}
+
+impl PartitionReport {
+    pub fn is_ok(&self) -> bool {
+        !self.encrypted && self.checks.iter().all(|c| c.status == HashStatus::Valid)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub partitions: Vec<PartitionReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.partitions.iter().all(PartitionReport::is_ok)
+    }
+}
+
+/// Compare `data` against an expected SHA-256.
+pub fn check_hash(data: &[u8], expected: &[u8; 32]) -> HashStatus {
+    if Sha256::digest(data)[..] == expected[..] {
+        HashStatus::Valid
+    } else {
+        HashStatus::Mismatch
+    }
+}
+
+/// Report a region that runs past the end of the file as truncated.
+fn or_truncated(result: io::Result<HashStatus>) -> io::Result<HashStatus> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(HashStatus::Truncated),
+        result => result,
+    }
+}
+
+/// SHA-256 of `len` bytes at `offset`.
+fn hash_range<R: Read + Seek>(reader: &mut R, offset: u64, len: u64) -> io::Result<[u8; 32]> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_CHUNK.min(len as usize)];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..n])?;
+        hasher.update(&buf[..n]);
+        remaining -= n as u64;
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn check_range<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    len: u64,
+    expected: &[u8; 32],
+) -> io::Result<HashStatus> {
+    or_truncated(hash_range(reader, offset, len).map(|hash| {
+        if &hash == expected {
+            HashStatus::Valid
+        } else {
+            HashStatus::Mismatch
+        }
+    }))
+}
+
+/// Check that each `block_size` block of the `len` bytes at `offset` (the last one
+/// zero-padded) hashes to the matching entry of the hash table at `hashes_offset`.
+///
+/// The hash table is read `HASH_CHUNK` bytes at a time, so a bogus `len` costs reads,
+/// not memory.
+fn check_blocks<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    len: u64,
+    block_size: u64,
+    hashes_offset: u64,
+) -> io::Result<HashStatus> {
+    if block_size == 0 || block_size > HASH_CHUNK as u64 {
+        return Ok(HashStatus::Mismatch);
+    }
+    let blocks = len.div_ceil(block_size);
+    let blocks_per_chunk = (HASH_CHUNK / 0x20) as u64;
+    let mut expected = vec![0u8; blocks.saturating_mul(0x20).min(HASH_CHUNK as u64) as usize];
+    let mut block = vec![0u8; block_size as usize];
+    let mut first = 0;
+    while first < blocks {
+        let count = (blocks - first).min(blocks_per_chunk);
+        let expected = &mut expected[..count as usize * 0x20];
+        reader.seek(SeekFrom::Start(
+            hashes_offset.saturating_add(first.saturating_mul(0x20)),
+        ))?;
+        reader.read_exact(expected)?;
+
+        reader.seek(SeekFrom::Start(
+            offset.saturating_add(first.saturating_mul(block_size)),
+        ))?;
+        for (i, expected) in expected.chunks_exact(0x20).enumerate() {
+            let n = (len - (first + i as u64) * block_size).min(block_size) as usize;
+            block[n..].fill(0);
+            reader.read_exact(&mut block[..n])?;
+            if Sha256::digest(&block)[..] != expected[..] {
+                return Ok(HashStatus::Mismatch);
+            }
+        }
+        first += count;
+    }
+    Ok(HashStatus::Valid)
+}
+
+/// Verify every hashed region of the NCCH at `offset`.
+pub fn verify_ncch<R: Read + Seek>(
+    reader: &mut R,
+    index: usize,
+    offset: u64,
+) -> Result<PartitionReport, Error> {
+    let ncch = NcchHeader::parse(reader, offset)?;
+    let mut report = PartitionReport {
+        index,
+        encrypted: !ncch.is_no_crypto(),
+        checks: Vec::new(),
+    };
+    if report.encrypted {
+        return Ok(report);
+    }
+    let unit = ncch.media_unit_size() as u64;
+    let mut check = |region, status| report.checks.push(RegionCheck { region, status });
+
+    if ncch.exheader_length > 0 {
+        let status = check_range(
+            reader,
+            offset.saturating_add(0x200),
+            ncch.exheader_length as u64,
+            &ncch.exheader_hash,
+        )?;
+        check(VerifyRegion::ExHeader, status);
+    }
+
+    if ncch.exefs_length > 0 {
+        let exefs = offset.saturating_add(ncch.exefs_offset as u64 * unit);
+        let region = ncch.exefs_hash_region_size as u64 * unit;
+        let status = check_range(reader, exefs, region, &ncch.exefs_superblock_hash)?;
+        check(VerifyRegion::ExefsSuperblock, status);
+
+        if status == HashStatus::Valid {
+            let header = ExefsHeader::parse(reader, exefs)?;
+            for entry in header.entries() {
+                let status = check_range(
+                    reader,
+                    exefs.saturating_add(entry.data_offset()),
+                    entry.size as u64,
+                    &entry.hash,
+                )?;
+                check(VerifyRegion::ExefsFile(entry.name.clone()), status);
+            }
+        }
+    }
+
+    if ncch.romfs_length > 0 {
+        let romfs = offset.saturating_add(ncch.romfs_offset as u64 * unit);
+        let region = ncch.romfs_hash_region_size as u64 * unit;
+        let status = check_range(reader, romfs, region, &ncch.romfs_superblock_hash)?;
+        check(VerifyRegion::RomfsSuperblock, status);
+
+        if status == HashStatus::Valid {
+            let ivfc = IvfcHeader::parse(reader, romfs)?;
+            let offsets = ivfc.level_offsets();
+            let mut hashes = romfs.saturating_add(ivfc.master_hash_offset());
+            let romfs_size = ncch.romfs_length as u64 * unit;
+            for (i, level) in ivfc.levels.iter().enumerate() {
+                // A level past the end of the RomFS cannot be backed by the file
+                let in_bounds = offsets[i]
+                    .checked_add(level.size)
+                    .is_some_and(|end| end <= romfs_size);
+                let status = if in_bounds {
+                    or_truncated(check_blocks(
+                        reader,
+                        romfs.saturating_add(offsets[i]),
+                        level.size,
+                        level.block_size(),
+                        hashes,
+                    ))?
+                } else {
+                    HashStatus::Truncated
+                };
+                check(VerifyRegion::RomfsLevel(i as u8 + 1), status);
+                hashes = romfs.saturating_add(offsets[i]);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Verify the content hashes of every NCCH partition in a ROM.
+///
+/// Encrypted partitions are reported but not checked; decrypt the ROM first.
+pub fn verify<R: Read + Seek>(reader: &mut R) -> Result<VerifyReport, Error> {
+    let partitions = format::ncch_partitions(reader)?
+        .into_iter()
+        .map(|(index, offset)| verify_ncch(reader, index, offset))
+        .collect::<Result<_, _>>()?;
+    Ok(VerifyReport { partitions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::romfs_builder;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    /// NoCrypto NCCH with a hashed ExHeader, ExeFS and RomFS.
+    fn build_hashed_ncch() -> Vec<u8> {
+        let mut ncch = vec![0u8; 0x1000];
+        ncch[0x100..0x104].copy_from_slice(b"NCCH");
+        ncch[0x18F] = 0x04;
+
+        // ExHeader
+        ncch[0x180..0x184].copy_from_slice(&0x400u32.to_le_bytes());
+        ncch[0x200..0x600].fill(0x42);
+        let hash = Sha256::digest(&ncch[0x200..0x600]);
+        ncch[0x160..0x180].copy_from_slice(&hash);
+
+        // ExeFS at unit 6 with one 0x100-byte file
+        ncch[0x1A0..0x1A4].copy_from_slice(&6u32.to_le_bytes());
+        ncch[0x1A4..0x1A8].copy_from_slice(&2u32.to_le_bytes());
+        ncch[0x1A8..0x1AC].copy_from_slice(&1u32.to_le_bytes());
+        let exefs = 0xC00;
+        ncch[exefs..exefs + 5].copy_from_slice(b"icon\0");
+        ncch[exefs + 12..exefs + 16].copy_from_slice(&0x100u32.to_le_bytes());
+        ncch[exefs + 0x200..exefs + 0x300].fill(0x1C);
+        let hash = Sha256::digest(&ncch[exefs + 0x200..exefs + 0x300]);
+        ncch[exefs + 0x1E0..exefs + 0x200].copy_from_slice(&hash);
+        let hash = Sha256::digest(&ncch[exefs..exefs + 0x200]);
+        ncch[0x1C0..0x1E0].copy_from_slice(&hash);
+
+        // RomFS at unit 8
+        let root = PathBuf::from("test-fixtures").join("verify_romfs_tree");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("file.bin"), vec![0x77; 0x1800]).unwrap();
+        let mut romfs = Cursor::new(Vec::new());
+        let built = romfs_builder::build(&root, &mut romfs).unwrap();
+        let _ = fs::remove_dir_all(&root);
+        let mut romfs = romfs.into_inner();
+        romfs.resize(romfs.len().next_multiple_of(0x200), 0);
+
+        let region = built.superblock.len().next_multiple_of(0x200);
+        ncch[0x1B0..0x1B4].copy_from_slice(&8u32.to_le_bytes());
+        ncch[0x1B4..0x1B8].copy_from_slice(&((romfs.len() / 0x200) as u32).to_le_bytes());
+        ncch[0x1B8..0x1BC].copy_from_slice(&((region / 0x200) as u32).to_le_bytes());
+        let hash = Sha256::digest(&romfs[..region]);
+        ncch[0x1E0..0x200].copy_from_slice(&hash);
+        ncch.extend_from_slice(&romfs);
+        ncch
+    }
+
+    fn statuses(report: &VerifyReport) -> Vec<(String, HashStatus)> {
+        report.partitions[0]
+            .checks
+            .iter()
+            .map(|c| (c.region.to_string(), c.status))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_valid_ncch() {
+        let report = verify(&mut Cursor::new(build_hashed_ncch())).unwrap();
+        assert!(report.is_ok());
+        let names: Vec<_> = statuses(&report).into_iter().map(|(n, _)| n).collect();
+        assert_eq!(
+            names,
+            [
+                "ExHeader",
+                "ExeFS superblock",
+                "ExeFS icon",
+                "RomFS superblock",
+                "RomFS level 1",
+                "RomFS level 2",
+                "RomFS level 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_detects_corruption() {
+        let mut ncch = build_hashed_ncch();
+        ncch[0xE10] ^= 1; // ExeFS icon data
+        ncch[0x3000] ^= 1; // RomFS level 3 file data
+        let report = verify(&mut Cursor::new(&ncch)).unwrap();
+        assert!(!report.is_ok());
+        let statuses = statuses(&report);
+        assert_eq!(statuses[2], ("ExeFS icon".into(), HashStatus::Mismatch));
+        assert_eq!(statuses[4].1, HashStatus::Valid);
+        assert_eq!(statuses[6], ("RomFS level 3".into(), HashStatus::Mismatch));
+
+        ncch.truncate(0x3100);
+        let report = verify(&mut Cursor::new(&ncch)).unwrap();
+        assert!(
+            report.partitions[0]
+                .checks
+                .iter()
+                .any(|c| c.status == HashStatus::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_verify_reports_oversized_level_as_truncated() {
+        let mut ncch = build_hashed_ncch();
+        // Level 3 size in the IVFC header, then a superblock hash that matches it
+        let romfs = 0x1000;
+        let region = u32::from_le_bytes(ncch[0x1B8..0x1BC].try_into().unwrap()) as usize * 0x200;
+        ncch[romfs + 0x44..romfs + 0x4C].copy_from_slice(&(1u64 << 50).to_le_bytes());
+        let hash = Sha256::digest(&ncch[romfs..romfs + region]);
+        ncch[0x1E0..0x200].copy_from_slice(&hash);
+
+        let report = verify(&mut Cursor::new(&ncch)).unwrap();
+        let statuses = statuses(&report);
+        assert_eq!(statuses[3].1, HashStatus::Valid);
+        assert_eq!(statuses[6], ("RomFS level 3".into(), HashStatus::Truncated));
+    }
+
+    #[test]
+    fn test_verify_skips_encrypted_partition() {
+        let mut ncch = build_hashed_ncch();
+        ncch[0x18F] = 0;
+        let report = verify(&mut Cursor::new(ncch)).unwrap();
+        assert!(report.partitions[0].encrypted);
+        assert!(report.partitions[0].checks.is_empty());
+        assert!(!report.is_ok());
+    }
+}
@@ -0,0 +1,106 @@
+//! Replays the fuzz seed corpus and saved crash inputs on stable Rust.
+//!
+//! Each test feeds every file in `fuzz/corpus/<target>` and `fuzz/regressions/<target>`
+//! through the same calls as the matching cargo-fuzz target; a panic fails the test and
+//! names the input that caused it.
+//! Inputs that once crashed a harness go into `fuzz/regressions/<target>/`.
+
+use std::fs;
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use citrust_core::cancel::CancelToken;
+use citrust_core::decrypt::{decrypt_image, is_content_decrypted};
+use citrust_core::inspect::{inspect, partition_status};
+use citrust_core::keydb::KeyDatabase;
+use citrust_core::ncch::NcchHeader;
+use citrust_core::ncsd::NcsdHeader;
+use citrust_core::repair::image_region_status;
+
+/// Same keys as `fuzz/fuzz_targets/decrypt_image.rs`.
+const KEYS: &str = "\
+generator=FEDCBA9876543210FEDCBA9876543210
+slot0x2CKeyX=00000000000000000000000000000001
+slot0x25KeyX=0000000000000000000000000000FF02
+common0N=00112233445566778899AABBCCDDEEFF
+";
+
+/// Run `harness` on every corpus and regression input of a fuzz target.
+fn replay(target: &str, harness: impl Fn(Vec<u8>)) {
+    for (path, data) in inputs(target) {
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| harness(data))) {
+            let message = panic
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| panic.downcast_ref::<&str>().copied())
+                .unwrap_or("non-string panic payload");
+            panic!("{target} panicked on {}: {message}", path.display());
+        }
+    }
+}
+
+/// All corpus and regression inputs for a fuzz target.
+fn inputs(target: &str) -> Vec<(PathBuf, Vec<u8>)> {
+    let fuzz_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../fuzz");
+    let mut inputs = Vec::new();
+    for dir in ["corpus", "regressions"] {
+        let Ok(entries) = fs::read_dir(fuzz_dir.join(dir).join(target)) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry.unwrap().path();
+            let data = fs::read(&path).unwrap();
+            inputs.push((path, data));
+        }
+    }
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no fuzz inputs for {target}");
+    inputs
+}
+
+#[test]
+fn replay_ncsd_header() {
+    replay("ncsd_header", |data| {
+        if let Ok(ncsd) = NcsdHeader::parse(&mut Cursor::new(&data)) {
+            for part in &ncsd.partitions {
+                let _ = part.offset_bytes(ncsd.sector_size);
+                let _ = part.length_bytes(ncsd.sector_size);
+            }
+        }
+    });
+}
+
+#[test]
+fn replay_ncch_header() {
+    replay("ncch_header", |data| {
+        if let Ok(ncch) = NcchHeader::parse(&mut Cursor::new(&data), 0) {
+            let _ = ncch.crypto_method();
+            is_content_decrypted(&data, &ncch, ncch.media_unit_size(), 0);
+        }
+    });
+}
+
+#[test]
+fn replay_keydb() {
+    replay("keydb", |data| {
+        if let Ok(keydb) = KeyDatabase::from_reader(data.as_slice()) {
+            let _ = keydb.generator();
+            for slot in [0x18, 0x1B, 0x25, 0x2C] {
+                let _ = keydb.get_key_x(slot);
+            }
+        }
+    });
+}
+
+#[test]
+fn replay_decrypt_image() {
+    let keydb = KeyDatabase::from_reader(Cursor::new(KEYS)).unwrap();
+    replay("decrypt_image", |data| {
+        let _ = inspect(&mut Cursor::new(&data));
+        let _ = partition_status(&mut Cursor::new(&data));
+        let _ = image_region_status(&data, &keydb);
+        let mut image = data;
+        let _ = decrypt_image(&mut image, &keydb, &CancelToken::new(), &mut |_| {});
+    });
+}
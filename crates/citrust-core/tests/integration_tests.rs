@@ -1,17 +1,25 @@
 //! Integration tests for end-to-end decryption correctness.
 //!
-//! These tests require actual ROM files in `Test Files/` and are marked `#[ignore]`.
-//! Run with: `cargo test -- --ignored`
+//! The synthetic ROM tests run everywhere, on images built by [`support::RomBuilder`].
+//! The retail ROM tests require actual ROM files in `Test Files/` and are marked
+//! `#[ignore]`. Run with: `cargo test -- --ignored`
 //!
 //! These also serve as **Phase 2 regression tests**: identical SHA256 hashes confirm
 //! that AES-NI acceleration and chunk-size tuning preserve byte-identical output.
 
+mod support;
+
 use std::fs;
-use std::io::Read;
+use std::io::{Cursor, Read};
 use std::path::PathBuf;
 
+use citrust_core::exefs::ExefsHeader;
+use citrust_core::inspect::PartitionState;
 use citrust_core::keydb::KeyDatabase;
+use citrust_core::keys::CryptoMethod;
+use proptest::prelude::*;
 use sha2::{Digest, Sha256};
+use support::{PartitionCrypto, RomBuilder, test_keydb};
 
 const POKEMON_Y: &str = "0451 - Pokemon Y (Europe) (En,Ja,Fr,De,Es,It,Ko) Decrypted.3ds";
 const OMEGA_RUBY: &str = "1325 - Pokemon Omega Ruby (Europe) (En,Ja,Fr,De,Es,It,Ko) Decrypted.3ds";
@@ -40,6 +48,314 @@ fn sha256_file(path: &PathBuf) -> String {
     format!("{:X}", hasher.finalize())
 }
 
+// ---------------------------------------------------------------------------
+// Synthetic ROM tests
+// ---------------------------------------------------------------------------
+
+/// Each of the four crypto methods decrypts to the known plaintext, with `.code`
+/// readable through the ExeFS header afterwards.
+#[test]
+fn decrypt_synthetic_rom_with_every_crypto_method() {
+    let rom = RomBuilder::new()
+        .partition(PartitionCrypto::Method(CryptoMethod::Original))
+        .partition(PartitionCrypto::Method(CryptoMethod::Key7x))
+        .partition(PartitionCrypto::Method(CryptoMethod::Key93))
+        .partition(PartitionCrypto::Method(CryptoMethod::Key96))
+        .build();
+    let tmp = rom.write("temp_synthetic_methods.3ds");
+
+    let result = citrust_core::decrypt::decrypt_rom(&tmp, &test_keydb(), |_| {});
+    let output = fs::read(&tmp).expect("Failed to read decrypted ROM");
+    let _ = fs::remove_file(&tmp);
+    assert!(result.is_ok(), "Decryption failed: {:?}", result.err());
+
+    for (p, &offset) in rom.partitions.iter().enumerate() {
+        assert_ne!(rom.encrypted[rom.code_range(p)], *rom.code(p));
+        let exefs = offset as u64 + 5 * 0x200;
+        let mut reader = Cursor::new(&output);
+        let header = ExefsHeader::parse(&mut reader, exefs).expect("ExeFS header");
+        let code = header.read_file(&mut reader, exefs, ".code").unwrap();
+        assert_eq!(code, rom.code(p), "partition {p}: .code mismatch");
+    }
+    assert!(output == rom.decrypted, "decrypted image differs");
+}
+
+/// Fixed-key and mis-flagged partitions are decrypted; a genuine NoCrypto partition
+/// is left alone.
+#[test]
+fn decrypt_synthetic_rom_with_special_flags() {
+    let rom = RomBuilder::new()
+        .partition(PartitionCrypto::FixedKey)
+        .partition(PartitionCrypto::NoCrypto)
+        .partition(PartitionCrypto::MisFlagged(CryptoMethod::Key7x))
+        .build();
+    let tmp = rom.write("temp_synthetic_flags.3ds");
+
+    let mut messages = Vec::new();
+    let result = citrust_core::decrypt::decrypt_rom(&tmp, &test_keydb(), |msg| {
+        messages.push(msg.to_string());
+    });
+    let output = fs::read(&tmp).expect("Failed to read decrypted ROM");
+    let _ = fs::remove_file(&tmp);
+    assert!(result.is_ok(), "Decryption failed: {:?}", result.err());
+
+    assert!(
+        messages
+            .iter()
+            .any(|m| m.starts_with("Partition 2: Flagged as decrypted but content is encrypted")),
+        "mis-flagged partition not reported: {messages:?}"
+    );
+    let nocrypto = rom.partitions[1]..rom.partitions[2];
+    assert!(output[nocrypto.clone()] == rom.encrypted[nocrypto]);
+    assert!(output == rom.decrypted, "decrypted image differs");
+}
+
+/// `encrypt_rom` leaves a partition flagged NoCrypto whose content is still encrypted
+/// as it is, and still encrypts the plaintext partitions around it.
+#[test]
+fn encrypt_synthetic_rom_skips_misflagged_partition() {
+    let rom = RomBuilder::new()
+        .partition(PartitionCrypto::NoCrypto)
+        .partition(PartitionCrypto::MisFlagged(CryptoMethod::Key96))
+        .build();
+    let tmp = rom.write("temp_synthetic_encrypt_misflagged.3ds");
+
+    let mut messages = Vec::new();
+    let result =
+        citrust_core::decrypt::encrypt_rom(&tmp, &test_keydb(), CryptoMethod::Key93, |msg| {
+            messages.push(msg.to_string());
+        });
+    let output = fs::read(&tmp).expect("Failed to read encrypted ROM");
+    let _ = fs::remove_file(&tmp);
+    assert!(result.is_ok(), "Encryption failed: {:?}", result.err());
+
+    assert!(
+        messages
+            .iter()
+            .any(|m| m.starts_with("Partition 1: Flagged decrypted but content is encrypted")),
+        "mis-flagged partition not reported: {messages:?}"
+    );
+    let misflagged = rom.partitions[1]..;
+    assert!(output[misflagged.clone()] == rom.encrypted[misflagged]);
+    assert_ne!(&output[rom.code_range(0)], rom.code(0));
+}
+
+/// Decrypt the image of `rom` in a temporary file, encrypt it again with `method` and
+/// return both results.
+fn decrypt_then_encrypt(rom: &support::SyntheticRom, method: CryptoMethod) -> (Vec<u8>, Vec<u8>) {
+    let tmp = rom.write("temp_synthetic_reencrypt.3ds");
+    let keydb = test_keydb();
+    let decrypt = citrust_core::decrypt::decrypt_rom(&tmp, &keydb, |_| {});
+    let decrypted = fs::read(&tmp).expect("Failed to read decrypted ROM");
+    let encrypt = citrust_core::decrypt::encrypt_rom(&tmp, &keydb, method, |_| {});
+    let encrypted = fs::read(&tmp).expect("Failed to read encrypted ROM");
+    let _ = fs::remove_file(&tmp);
+    assert!(decrypt.is_ok(), "Decryption failed: {:?}", decrypt.err());
+    assert!(encrypt.is_ok(), "Encryption failed: {:?}", encrypt.err());
+    (decrypted, encrypted)
+}
+
+/// Re-encrypting a decrypted ROM restores partition 0's crypto flags from the NCSD
+/// backup, and encrypts the other partitions with the given method.
+#[test]
+fn encrypt_synthetic_rom_restores_partition_0_flags() {
+    // Partition 0 under every method with a non-zero backup, then a partition that is
+    // re-encrypted with the method given
+    for first in [
+        PartitionCrypto::Method(CryptoMethod::Key7x),
+        PartitionCrypto::Method(CryptoMethod::Key93),
+        PartitionCrypto::Method(CryptoMethod::Key96),
+        PartitionCrypto::FixedKey,
+        PartitionCrypto::Seed(CryptoMethod::Original),
+        PartitionCrypto::Seed(CryptoMethod::Key96),
+    ] {
+        let rom = RomBuilder::new()
+            .partition(first)
+            .partition(PartitionCrypto::Method(CryptoMethod::Key93))
+            .build();
+        let (decrypted, encrypted) = decrypt_then_encrypt(&rom, CryptoMethod::Key93);
+        assert!(
+            decrypted == rom.decrypted,
+            "{first:?}: decrypted image differs"
+        );
+        assert!(
+            encrypted == rom.encrypted,
+            "{first:?}: re-encrypted image differs"
+        );
+    }
+
+    // Partition 1's flags are not backed up: it takes the method given, not what lies
+    // at the stride of partition 0's backup
+    let mut rom = RomBuilder::new()
+        .partition(PartitionCrypto::Seed(CryptoMethod::Key7x))
+        .partition(PartitionCrypto::Seed(CryptoMethod::Key96))
+        .build();
+    for image in [&mut rom.encrypted, &mut rom.decrypted] {
+        image[0x1190..0x1198].copy_from_slice(&[
+            0,
+            0,
+            0,
+            CryptoMethod::Key96.flag(),
+            0,
+            0,
+            0,
+            0x20,
+        ]);
+    }
+    let (_, encrypted) = decrypt_then_encrypt(&rom, CryptoMethod::Key93);
+    let (p0, p1) = (rom.partitions[0], rom.partitions[1]);
+    assert!(
+        encrypted[p0..p1] == rom.encrypted[p0..p1],
+        "partition 0 differs"
+    );
+    assert_eq!(
+        encrypted[p1 + 0x188..p1 + 0x190],
+        [0, 0, 0, CryptoMethod::Key93.flag(), 0, 0, 0, 0]
+    );
+    let tmp = support::SyntheticRom {
+        encrypted,
+        decrypted: rom.decrypted.clone(),
+        partitions: rom.partitions.clone(),
+    }
+    .write("temp_synthetic_reencrypt_again.3ds");
+    let again = citrust_core::decrypt::decrypt_rom(&tmp, &test_keydb(), |_| {});
+    let output = fs::read(&tmp).expect("Failed to read decrypted ROM");
+    let _ = fs::remove_file(&tmp);
+    assert!(again.is_ok(), "Decryption failed: {:?}", again.err());
+    assert!(output == rom.decrypted, "round-trip image differs");
+}
+
+/// `rom_status` reports every state `decrypt_rom` distinguishes, without writing to
+/// the ROM.
+#[test]
+fn rom_status_of_synthetic_rom() {
+    let mut rom = RomBuilder::new()
+        .partition(PartitionCrypto::Method(CryptoMethod::Key96))
+        .partition(PartitionCrypto::NoCrypto)
+        .partition(PartitionCrypto::MisFlagged(CryptoMethod::Key7x))
+        .partition(PartitionCrypto::Method(CryptoMethod::Original))
+        .partition(PartitionCrypto::NoCrypto)
+        .partition(PartitionCrypto::Method(CryptoMethod::Key93))
+        .build();
+    // Partition 3 flagged seed crypto, partition 4 plaintext without NoCrypto, and
+    // partition 5 without its NCCH magic
+    rom.encrypted[rom.partitions[3] + 0x18F] |= 0x20;
+    rom.encrypted[rom.partitions[4] + 0x18F] &= !0x04;
+    rom.encrypted[rom.partitions[5] + 0x100] = 0;
+    let tmp = rom.write("temp_synthetic_status.3ds");
+
+    let status = citrust_core::inspect::rom_status(&tmp);
+    let after = fs::read(&tmp).expect("Failed to read ROM");
+    let _ = fs::remove_file(&tmp);
+
+    let states: Vec<_> = status
+        .expect("rom_status failed")
+        .iter()
+        .map(|s| s.state)
+        .collect();
+    assert_eq!(
+        states,
+        [
+            PartitionState::Encrypted,
+            PartitionState::Decrypted,
+            PartitionState::FlaggedDecryptedButEncrypted,
+            PartitionState::SeedRequired,
+            PartitionState::FlaggedEncryptedButDecrypted,
+            PartitionState::Unreadable,
+            PartitionState::Missing,
+            PartitionState::Missing,
+        ]
+    );
+    assert!(after == rom.encrypted, "rom_status modified the ROM");
+}
+
+/// Decrypting to a new file leaves the input alone, and decrypting the result again
+/// changes nothing.
+#[test]
+fn decrypt_synthetic_rom_to_then_again_is_noop() {
+    let rom = RomBuilder::new()
+        .partition(PartitionCrypto::Method(CryptoMethod::Key7x))
+        .partition(PartitionCrypto::FixedKey)
+        .build();
+    let src = rom.write("temp_synthetic_src.3ds");
+    let dst = PathBuf::from("test-fixtures").join("temp_synthetic_dst.3ds");
+    let keydb = test_keydb();
+
+    let first = citrust_core::decrypt::decrypt_rom_to(&src, &dst, &keydb, |_| {});
+    let input = fs::read(&src).expect("Failed to read input ROM");
+    let once = fs::read(&dst).expect("Failed to read output ROM");
+    let second = citrust_core::decrypt::decrypt_rom(&dst, &keydb, |_| {});
+    let twice = fs::read(&dst).expect("Failed to read output ROM");
+    let _ = fs::remove_file(&src);
+    let _ = fs::remove_file(&dst);
+
+    assert!(first.is_ok(), "Decryption failed: {:?}", first.err());
+    assert!(
+        second.is_ok(),
+        "Second decryption failed: {:?}",
+        second.err()
+    );
+    assert!(input == rom.encrypted, "input ROM was modified");
+    assert!(once == rom.decrypted, "decrypted image differs");
+    assert!(twice == once, "second decryption modified the ROM");
+}
+
+fn crypto_method() -> impl Strategy<Value = CryptoMethod> {
+    prop_oneof![
+        Just(CryptoMethod::Original),
+        Just(CryptoMethod::Key7x),
+        Just(CryptoMethod::Key93),
+        Just(CryptoMethod::Key96),
+    ]
+}
+
+fn partition_crypto() -> impl Strategy<Value = PartitionCrypto> {
+    prop_oneof![
+        crypto_method().prop_map(PartitionCrypto::Method),
+        Just(PartitionCrypto::FixedKey),
+        Just(PartitionCrypto::NoCrypto),
+        crypto_method().prop_map(PartitionCrypto::MisFlagged),
+        crypto_method().prop_map(PartitionCrypto::Seed),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(24))]
+
+    /// Generated images decrypt to their plaintext, and re-encrypting that plaintext
+    /// with `encrypt_rom` under any method decrypts back to it again.
+    #[test]
+    fn synthetic_rom_encrypt_decrypt_roundtrip(
+        partitions in proptest::collection::vec(partition_crypto(), 1..=8),
+        method in crypto_method(),
+        seed: u64,
+    ) {
+        let rom = partitions
+            .iter()
+            .fold(RomBuilder::new().seed(seed), |builder, &crypto| builder.partition(crypto))
+            .build();
+        let tmp = rom.write("temp_synthetic_roundtrip.3ds");
+        let keydb = test_keydb();
+
+        let first = citrust_core::decrypt::decrypt_rom(&tmp, &keydb, |_| {});
+        let decrypted = fs::read(&tmp).expect("Failed to read decrypted ROM");
+        let encrypt = citrust_core::decrypt::encrypt_rom(&tmp, &keydb, method, |_| {});
+        let encrypted = fs::read(&tmp).expect("Failed to read encrypted ROM");
+        let second = citrust_core::decrypt::decrypt_rom(&tmp, &keydb, |_| {});
+        let roundtrip = fs::read(&tmp).expect("Failed to read decrypted ROM");
+        let _ = fs::remove_file(&tmp);
+
+        prop_assert!(first.is_ok(), "Decryption failed: {:?}", first.err());
+        prop_assert!(decrypted == rom.decrypted, "decrypted image differs");
+        prop_assert!(encrypt.is_ok(), "Encryption failed: {:?}", encrypt.err());
+        for p in 0..rom.partitions.len() {
+            prop_assert_ne!(&encrypted[rom.code_range(p)], rom.code(p));
+        }
+        prop_assert!(second.is_ok(), "Second decryption failed: {:?}", second.err());
+        prop_assert!(roundtrip == rom.decrypted, "round-trip image differs");
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Issue #8: Round-trip decryption tests
 // ---------------------------------------------------------------------------
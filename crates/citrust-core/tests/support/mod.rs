@@ -0,0 +1,354 @@
+//! Synthetic encrypted ROMs, so end-to-end tests don't need retail dumps.
+//!
+//! [`RomBuilder`] lays out an NCSD image whose partitions each hold an ExHeader, an
+//! ExeFS with `.code` and `banner`, and a RomFS, all filled with known plaintext. Each
+//! partition is then encrypted here, independently of citrust's own encryption code,
+//! with keys from [`test_keydb`]. [`SyntheticRom::decrypted`] is the image that
+//! decryption is expected to produce.
+
+use std::fs;
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use citrust_core::crypto::{aes_ctr_decrypt, derive_normal_key};
+use citrust_core::keydb::KeyDatabase;
+use citrust_core::keys::CryptoMethod;
+use sha2::{Digest, Sha256};
+
+const GENERATOR: u128 = 0xFEDCBA9876543210FEDCBA9876543210;
+
+/// Made-up KeyX for each slot: 0x2C, 0x25 (Key7x), 0x18 (Key93) and 0x1B (Key96).
+const KEY_X: [(u8, u128); 4] = [
+    (0x2C, 0x00000000000000000000000000000001),
+    (0x25, 0x0000000000000000000000000000FF02),
+    (0x18, 0x00000000000000000000000000001803),
+    (0x1B, 0x00000000000000000000000000001B04),
+];
+
+const SECTOR: usize = 0x200;
+/// Partitions start after the NCSD header and card info area.
+const FIRST_PARTITION: usize = 0x4000;
+const EXHEADER_LEN: usize = 0x400;
+/// ExHeader plus access descriptor, the span that is encrypted.
+const EXHEADER_CRYPT_LEN: usize = 0x800;
+const EXEFS_SECTOR: usize = 5;
+const EXEFS_SECTORS: usize = 5;
+const CODE_LEN: usize = 0x600;
+const BANNER_LEN: usize = 0x200;
+const ROMFS_SECTOR: usize = 10;
+const ROMFS_SECTORS: usize = 4;
+const PARTITION_SECTORS: usize = ROMFS_SECTOR + ROMFS_SECTORS;
+
+/// Made-up seed for every seed-crypto partition, found through the seed database's
+/// fallback.
+const SEED: [u8; 16] = *b"citrust test sd!";
+
+/// How a synthetic partition is protected.
+#[derive(Debug, Clone, Copy)]
+pub enum PartitionCrypto {
+    /// Encrypted normally; `.code` and the RomFS use the method's key.
+    Method(CryptoMethod),
+    /// FixedCryptoKey flag set: every region is encrypted with the zero key.
+    FixedKey,
+    /// Seed crypto: `.code` and the RomFS use the method's key with a KeyY seeded
+    /// with [`SEED`].
+    Seed(CryptoMethod),
+    /// Plaintext and flagged NoCrypto.
+    NoCrypto,
+    /// Encrypted with the method, but flagged NoCrypto with its method byte cleared.
+    /// The method survives only in the NCSD backup flags.
+    MisFlagged(CryptoMethod),
+}
+
+/// A built image along with its expected decryption.
+pub struct SyntheticRom {
+    pub encrypted: Vec<u8>,
+    pub decrypted: Vec<u8>,
+    /// Byte offset of each partition.
+    pub partitions: Vec<usize>,
+}
+
+impl SyntheticRom {
+    /// Where the `.code` of partition `p` lies in the image.
+    pub fn code_range(&self, p: usize) -> Range<usize> {
+        let start = self.partitions[p] + (EXEFS_SECTOR + 1) * SECTOR;
+        start..start + CODE_LEN
+    }
+
+    /// The plaintext `.code` of partition `p`.
+    pub fn code(&self, p: usize) -> &[u8] {
+        &self.decrypted[self.code_range(p)]
+    }
+
+    /// Write the encrypted image to `test-fixtures/<name>` and return its path.
+    pub fn write(&self, name: &str) -> PathBuf {
+        let dir = PathBuf::from("test-fixtures");
+        fs::create_dir_all(&dir).expect("create test-fixtures");
+        let path = dir.join(name);
+        fs::write(&path, &self.encrypted).expect("write synthetic ROM");
+        path
+    }
+}
+
+#[derive(Default)]
+pub struct RomBuilder {
+    partitions: Vec<PartitionCrypto>,
+    seed: u64,
+}
+
+impl RomBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the next partition (up to eight).
+    pub fn partition(mut self, crypto: PartitionCrypto) -> Self {
+        assert!(
+            self.partitions.len() < 8,
+            "an NCSD holds at most 8 partitions"
+        );
+        self.partitions.push(crypto);
+        self
+    }
+
+    /// Vary the filler bytes, KeyYs and title IDs.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn build(&self) -> SyntheticRom {
+        let part_len = PARTITION_SECTORS * SECTOR;
+        let total = FIRST_PARTITION + self.partitions.len() * part_len;
+        let mut encrypted = vec![0u8; total];
+
+        encrypted[0x100..0x104].copy_from_slice(b"NCSD");
+        encrypted[0x104..0x108].copy_from_slice(&((total / SECTOR) as u32).to_le_bytes());
+        let mut partitions = Vec::new();
+        for (p, &crypto) in self.partitions.iter().enumerate() {
+            let offset = FIRST_PARTITION + p * part_len;
+            let entry = 0x120 + p * 8;
+            encrypted[entry..entry + 4].copy_from_slice(&((offset / SECTOR) as u32).to_le_bytes());
+            encrypted[entry + 4..entry + 8]
+                .copy_from_slice(&(PARTITION_SECTORS as u32).to_le_bytes());
+            encrypted[offset..offset + part_len]
+                .copy_from_slice(&plaintext_partition(p, self.seed));
+            // The NCSD header keeps a backup of partition 0's flags[3..8]; decryption
+            // also looks up a mis-flagged partition's method byte at the same stride
+            let backup = 0x1188 + p * 8;
+            let (method_flag, flags) = match crypto {
+                PartitionCrypto::Method(method) | PartitionCrypto::MisFlagged(method) => {
+                    (method.flag(), 0x00)
+                }
+                PartitionCrypto::FixedKey => (0x00, 0x01),
+                PartitionCrypto::Seed(method) => (method.flag(), 0x20),
+                PartitionCrypto::NoCrypto => (0x00, 0x00),
+            };
+            if p == 0 || matches!(crypto, PartitionCrypto::MisFlagged(_)) {
+                encrypted[backup + 3] = method_flag;
+                encrypted[backup + 7] = flags;
+            }
+            if let PartitionCrypto::Seed(_) = crypto {
+                let program_id = &encrypted[offset + 0x118..offset + 0x120];
+                let check = Sha256::new()
+                    .chain_update(SEED)
+                    .chain_update(program_id)
+                    .finalize();
+                encrypted[offset + 0x114..offset + 0x118].copy_from_slice(&check[..4]);
+            }
+            partitions.push(offset);
+        }
+
+        // Decryption leaves the method byte cleared and NoCrypto set, and drops FixedKey
+        // and seed
+        let mut decrypted = encrypted.clone();
+        for &offset in &partitions {
+            decrypted[offset + 0x18B] = 0x00;
+            decrypted[offset + 0x18F] = 0x04;
+        }
+
+        for (p, &crypto) in self.partitions.iter().enumerate() {
+            encrypt_partition(&mut encrypted[partitions[p]..][..part_len], crypto);
+        }
+
+        SyntheticRom {
+            encrypted,
+            decrypted,
+            partitions,
+        }
+    }
+}
+
+/// A key file holding the generator and every KeyX the builder encrypts with, and
+/// [`SEED`] as the fallback seed.
+pub fn test_keydb() -> KeyDatabase {
+    let mut text = format!("generator={GENERATOR:032X}\n");
+    for (slot, key_x) in KEY_X {
+        text += &format!("slot0x{slot:02X}KeyX={key_x:032X}\n");
+    }
+    let mut keydb = KeyDatabase::from_reader(Cursor::new(text)).expect("valid test keys");
+    keydb.seeds_mut().set_fallback(SEED);
+    keydb
+}
+
+fn key_x(slot: u8) -> u128 {
+    KEY_X.iter().find(|(s, _)| *s == slot).unwrap().1
+}
+
+fn method_slot(method: CryptoMethod) -> u8 {
+    match method {
+        CryptoMethod::Original => 0x2C,
+        CryptoMethod::Key7x => 0x25,
+        CryptoMethod::Key93 => 0x18,
+        CryptoMethod::Key96 => 0x1B,
+    }
+}
+
+/// Repeating bytes that differ between partitions and regions.
+fn fill(buf: &mut [u8], seed: u64) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = ((i as u64 * 7).wrapping_add(seed.wrapping_mul(13)) % 251) as u8;
+    }
+}
+
+/// Partition `p` in plaintext, flagged NoCrypto, with valid ExHeader, ExeFS and RomFS
+/// hashes in its NCCH header.
+fn plaintext_partition(p: usize, seed: u64) -> Vec<u8> {
+    let fill_seed = seed.wrapping_add(p as u64 * 4);
+    let mut part = vec![0u8; PARTITION_SECTORS * SECTOR];
+    let put = |part: &mut [u8], at: usize, bytes: &[u8]| {
+        part[at..at + bytes.len()].copy_from_slice(bytes)
+    };
+
+    // --- ExHeader: application title, then filler for the rest and the access descriptor ---
+    fill(&mut part[0x200..0x200 + EXHEADER_CRYPT_LEN], fill_seed);
+    put(&mut part, 0x200, b"CtrApp\x00\x00");
+
+    // --- ExeFS: header, .code, banner ---
+    let exefs = EXEFS_SECTOR * SECTOR;
+    let code = exefs + SECTOR;
+    let banner = code + CODE_LEN;
+    fill(&mut part[code..code + CODE_LEN], fill_seed.wrapping_add(1));
+    fill(
+        &mut part[banner..banner + BANNER_LEN],
+        fill_seed.wrapping_add(2),
+    );
+    put(&mut part, exefs, b".code\x00\x00\x00");
+    put(&mut part, exefs + 0xC, &(CODE_LEN as u32).to_le_bytes());
+    put(&mut part, exefs + 0x10, b"banner\x00\x00");
+    put(&mut part, exefs + 0x18, &(CODE_LEN as u32).to_le_bytes());
+    put(&mut part, exefs + 0x1C, &(BANNER_LEN as u32).to_le_bytes());
+    // File hashes are stored from the end of the header backwards
+    let code_hash = Sha256::digest(&part[code..code + CODE_LEN]);
+    let banner_hash = Sha256::digest(&part[banner..banner + BANNER_LEN]);
+    put(&mut part, exefs + 0x1E0, &code_hash);
+    put(&mut part, exefs + 0x1C0, &banner_hash);
+
+    // --- RomFS ---
+    let romfs = ROMFS_SECTOR * SECTOR;
+    fill(
+        &mut part[romfs..romfs + ROMFS_SECTORS * SECTOR],
+        fill_seed.wrapping_add(3),
+    );
+    put(&mut part, romfs, b"IVFC");
+
+    // --- NCCH header ---
+    let key_y = (0x0123456789ABCDEF0123456789ABCDE0u128 ^ ((seed as u128) << 8)) | p as u128;
+    let title_id = (0x0004000000055D00 ^ seed.rotate_left(32)).wrapping_add(p as u64 * 0x100);
+    put(&mut part, 0, &key_y.to_be_bytes());
+    put(&mut part, 0x100, b"NCCH");
+    put(&mut part, 0x104, &(PARTITION_SECTORS as u32).to_le_bytes());
+    put(&mut part, 0x108, &title_id.to_le_bytes());
+    put(&mut part, 0x118, &title_id.to_le_bytes());
+    let hash = Sha256::digest(&part[0x200..0x200 + EXHEADER_LEN]);
+    put(&mut part, 0x160, &hash);
+    put(&mut part, 0x180, &(EXHEADER_LEN as u32).to_le_bytes());
+    part[0x18F] = 0x04;
+    put(&mut part, 0x1A0, &(EXEFS_SECTOR as u32).to_le_bytes());
+    put(&mut part, 0x1A4, &(EXEFS_SECTORS as u32).to_le_bytes());
+    put(&mut part, 0x1A8, &1u32.to_le_bytes());
+    put(&mut part, 0x1B0, &(ROMFS_SECTOR as u32).to_le_bytes());
+    put(&mut part, 0x1B4, &(ROMFS_SECTORS as u32).to_le_bytes());
+    put(&mut part, 0x1B8, &1u32.to_le_bytes());
+    let hash = Sha256::digest(&part[exefs..exefs + SECTOR]);
+    put(&mut part, 0x1C0, &hash);
+    let hash = Sha256::digest(&part[romfs..romfs + SECTOR]);
+    put(&mut part, 0x1E0, &hash);
+
+    part
+}
+
+/// AES-CTR is its own inverse, so the decryption primitive also encrypts.
+fn ctr(key: u128, iv: u128, data: &mut [u8]) {
+    aes_ctr_decrypt(&key.to_be_bytes(), iv, data);
+}
+
+/// Encrypt a partition from [`plaintext_partition`] in place and set its flags.
+fn encrypt_partition(part: &mut [u8], crypto: PartitionCrypto) {
+    let (method, fixed_key) = match crypto {
+        PartitionCrypto::NoCrypto => return,
+        PartitionCrypto::FixedKey => (CryptoMethod::Original, true),
+        PartitionCrypto::Method(method)
+        | PartitionCrypto::MisFlagged(method)
+        | PartitionCrypto::Seed(method) => (method, false),
+    };
+
+    let key_y = u128::from_be_bytes(part[..16].try_into().unwrap());
+    // Seed crypto replaces the method key's KeyY with SHA-256(KeyY || seed)
+    let main_key_y = match crypto {
+        PartitionCrypto::Seed(_) => {
+            let digest = Sha256::new()
+                .chain_update(key_y.to_be_bytes())
+                .chain_update(SEED)
+                .finalize();
+            u128::from_be_bytes(digest[..16].try_into().unwrap())
+        }
+        _ => key_y,
+    };
+    let (base, main) = if fixed_key {
+        (0, 0)
+    } else {
+        (
+            derive_normal_key(key_x(0x2C), key_y, GENERATOR),
+            derive_normal_key(key_x(method_slot(method)), main_key_y, GENERATOR),
+        )
+    };
+
+    let tid = (u64::from_le_bytes(part[0x108..0x110].try_into().unwrap()) as u128) << 64;
+    let (plain_iv, exefs_iv, romfs_iv) = (
+        tid | 0x0100_0000_0000_0000,
+        tid | 0x0200_0000_0000_0000,
+        tid | 0x0300_0000_0000_0000,
+    );
+
+    ctr(base, plain_iv, &mut part[0x200..0x200 + EXHEADER_CRYPT_LEN]);
+    let exefs = EXEFS_SECTOR * SECTOR;
+    let code = exefs + SECTOR;
+    let banner = code + CODE_LEN;
+    let iv_at = |offset: usize| exefs_iv + ((offset - exefs) / 0x10) as u128;
+    ctr(base, exefs_iv, &mut part[exefs..code]);
+    ctr(main, iv_at(code), &mut part[code..banner]);
+    ctr(base, iv_at(banner), &mut part[banner..banner + BANNER_LEN]);
+    let romfs = ROMFS_SECTOR * SECTOR;
+    ctr(
+        main,
+        romfs_iv,
+        &mut part[romfs..romfs + ROMFS_SECTORS * SECTOR],
+    );
+
+    match crypto {
+        PartitionCrypto::MisFlagged(_) => {
+            part[0x18B] = 0x00;
+            part[0x18F] = 0x04;
+        }
+        PartitionCrypto::Seed(_) => {
+            part[0x18B] = method.flag();
+            part[0x18F] = 0x20;
+        }
+        _ => {
+            part[0x18B] = method.flag();
+            part[0x18F] = if fixed_key { 0x01 } else { 0x00 };
+        }
+    }
+}
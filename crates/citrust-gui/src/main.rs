@@ -1,4 +1,9 @@
+use citrust_core::cancel::CancelToken;
+use citrust_core::decrypt::Error;
 use citrust_core::keydb::KeyDatabase;
+use citrust_core::keys::CryptoMethod;
+use citrust_core::progress::ProgressEvent;
+use citrust_core::seeddb::SeedDatabase;
 use eframe::egui;
 use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, Sender, channel};
@@ -42,8 +47,9 @@ fn main() -> eframe::Result<()> {
 #[derive(Debug, Clone)]
 enum ProgressMessage {
     Started,
-    Update(String),
+    Event(ProgressEvent),
     Done,
+    Cancelled,
     Error(String),
 }
 
@@ -59,7 +65,14 @@ struct DecryptState {
     progress_messages: Vec<String>,
     current_section: String,
     encryption_method: Option<String>,
+    /// Latest (processed, total) byte counts.
+    bytes: Option<(u64, u64)>,
+    /// Time and byte count of the first byte report, for throughput and ETA.
+    first_bytes: Option<(Instant, u64)>,
     start_time: Instant,
+    cancel: CancelToken,
+    /// Set once the decryption has failed or been cancelled.
+    stopped: bool,
     rx: Receiver<ProgressMessage>,
 }
 
@@ -81,6 +94,7 @@ impl Default for CitrustApp {
     fn default() -> Self {
         let (keydb, key_status, screen) = match KeyDatabase::search_default_locations()
             .and_then(|path| KeyDatabase::from_file(&path).ok())
+            .map(with_default_seeds)
         {
             Some(db) => {
                 let status = format!("🔑 Keys loaded ({} keys)", db.len());
@@ -101,6 +115,33 @@ impl Default for CitrustApp {
     }
 }
 
+/// Attach a `seeddb.bin` from the default locations, if one is present.
+fn with_default_seeds(mut keydb: KeyDatabase) -> KeyDatabase {
+    if let Some(seeds) = SeedDatabase::search_default_locations()
+        .and_then(|path| SeedDatabase::from_file(&path).ok())
+    {
+        keydb.set_seeds(seeds);
+    }
+    keydb
+}
+
+/// Label for the crypto method shown while decrypting.
+fn method_label(method: Option<CryptoMethod>, fixed_key: bool, seed: bool) -> String {
+    let name = match method {
+        _ if fixed_key => "Fixed zero key",
+        Some(CryptoMethod::Original) => "Original (KeyX 0x2C)",
+        Some(CryptoMethod::Key7x) => "Key7x (0x25)",
+        Some(CryptoMethod::Key93) => "Key93 (0x18)",
+        Some(CryptoMethod::Key96) => "Key96 (0x1B)",
+        None => "Unknown method",
+    };
+    if seed && !fixed_key {
+        format!("Encryption: {name} with seed")
+    } else {
+        format!("Encryption: {name}")
+    }
+}
+
 impl CitrustApp {
     fn show_key_setup_screen(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
         ui.vertical_centered(|ui| {
@@ -163,7 +204,7 @@ impl CitrustApp {
                 .add_sized(button_size, egui::Button::new("📁 Select ROM File"))
                 .clicked()
                 && let Some(path) = rfd::FileDialog::new()
-                    .add_filter("3DS ROM", &["3ds"])
+                    .add_filter("3DS ROM", &["3ds", "cci", "cia", "cxi", "cfa", "app"])
                     .set_title("Select 3DS ROM to Decrypt")
                     .pick_file()
             {
@@ -210,25 +251,45 @@ impl CitrustApp {
                     ProgressMessage::Started => {
                         state.progress_messages.clear();
                     }
-                    ProgressMessage::Update(text) => {
-                        // Extract encryption method from first message
-                        if text.starts_with("Encryption Method:") {
-                            state.encryption_method = Some(text.clone());
+                    ProgressMessage::Event(event) => {
+                        match event {
+                            ProgressEvent::CryptoMethod {
+                                method,
+                                fixed_key,
+                                seed,
+                                ..
+                            } => {
+                                state.encryption_method =
+                                    Some(method_label(method, fixed_key, seed));
+                            }
+                            ProgressEvent::RegionStart { .. } => {
+                                state.current_section = event.to_string();
+                            }
+                            ProgressEvent::Bytes { processed, total } => {
+                                state.bytes = Some((processed, total));
+                                state.first_bytes.get_or_insert((Instant::now(), processed));
+                            }
+                            _ => {}
                         }
-                        // Track current section
-                        if text.contains("ExeFS") || text.contains("RomFS") {
-                            state.current_section = text.clone();
+                        if let Some(text) = event.message() {
+                            state.progress_messages.push(text);
                         }
-                        state.progress_messages.push(text);
                     }
                     ProgressMessage::Done => {
                         let duration = state.start_time.elapsed();
                         completion_duration = duration.as_secs();
                         should_complete = true;
                     }
+                    ProgressMessage::Cancelled => {
+                        state
+                            .progress_messages
+                            .push("Cancelled — changes rolled back".to_string());
+                        state.stopped = true;
+                    }
                     ProgressMessage::Error(err) => {
                         state.progress_messages.push(format!("ERROR: {}", err));
                         // Stay on this screen to show error
+                        state.stopped = true;
                     }
                 }
             }
@@ -243,6 +304,7 @@ impl CitrustApp {
             self.decrypt_state = None;
         }
 
+        let mut go_back = false;
         if let Some(state) = &self.decrypt_state {
             ui.vertical_centered(|ui| {
                 ui.add_space(80.0);
@@ -270,6 +332,32 @@ impl CitrustApp {
 
                 ui.add_space(30.0);
 
+                if let Some((processed, total)) = state.bytes
+                    && total > 0
+                {
+                    ui.add_space(20.0);
+                    let fraction = processed as f32 / total as f32;
+                    ui.add(
+                        egui::ProgressBar::new(fraction)
+                            .desired_width(800.0)
+                            .show_percentage(),
+                    );
+                    if let Some((since, from)) = state.first_bytes {
+                        let secs = since.elapsed().as_secs_f64();
+                        let rate = processed.saturating_sub(from) as f64 / secs.max(1e-3);
+                        if rate > 0.0 {
+                            let eta = (total - processed) as f64 / rate;
+                            ui.label(format!(
+                                "{:.0} MB/s — about {:.0}s left",
+                                rate / (1024.0 * 1024.0),
+                                eta.ceil()
+                            ));
+                        }
+                    }
+                }
+
+                ui.add_space(20.0);
+
                 // Show elapsed time
                 let elapsed = state.start_time.elapsed().as_secs();
                 ui.label(format!("Elapsed: {}s", elapsed));
@@ -291,10 +379,30 @@ impl CitrustApp {
                 });
 
                 ui.add_space(20.0);
-                ui.label("⚠️ Cannot cancel — decryption modifies file in-place");
+                let button_size = egui::vec2(400.0, 80.0);
+                if state.stopped {
+                    if ui
+                        .add_sized(button_size, egui::Button::new("⬅ Back"))
+                        .clicked()
+                    {
+                        go_back = true;
+                    }
+                } else if state.cancel.is_cancelled() {
+                    ui.label("Cancelling — restoring the original ROM...");
+                } else if ui
+                    .add_sized(button_size, egui::Button::new("✖ Cancel"))
+                    .clicked()
+                {
+                    state.cancel.cancel();
+                }
             });
         }
 
+        if go_back {
+            self.screen = Screen::SelectFile;
+            self.decrypt_state = None;
+        }
+
         ctx.request_repaint();
     }
 
@@ -354,7 +462,7 @@ impl CitrustApp {
                 }
 
                 self.key_status = format!("🔑 Keys loaded ({} keys)", db.len());
-                self.keydb = Some(db);
+                self.keydb = Some(with_default_seeds(db));
                 self.screen = Screen::SelectFile;
             }
             Err(e) => {
@@ -371,18 +479,27 @@ impl CitrustApp {
             .keydb
             .clone()
             .expect("KeyDatabase must be loaded before decryption");
+        let cancel = CancelToken::new();
+        let decrypt_cancel = cancel.clone();
         thread::spawn(move || {
             let _ = tx.send(ProgressMessage::Started);
 
-            let result =
-                citrust_core::decrypt::decrypt_rom(&decrypt_path, &keydb, |progress_text| {
-                    let _ = tx.send(ProgressMessage::Update(progress_text.to_string()));
-                });
+            let result = citrust_core::decrypt::decrypt_rom_cancellable(
+                &decrypt_path,
+                &keydb,
+                &decrypt_cancel,
+                |event| {
+                    let _ = tx.send(ProgressMessage::Event(event.clone()));
+                },
+            );
 
             match result {
                 Ok(_) => {
                     let _ = tx.send(ProgressMessage::Done);
                 }
+                Err(Error::Cancelled) => {
+                    let _ = tx.send(ProgressMessage::Cancelled);
+                }
                 Err(e) => {
                     let _ = tx.send(ProgressMessage::Error(e.to_string()));
                 }
@@ -394,7 +511,11 @@ impl CitrustApp {
             progress_messages: Vec::new(),
             current_section: String::new(),
             encryption_method: None,
+            bytes: None,
+            first_bytes: None,
             start_time: Instant::now(),
+            cancel,
+            stopped: false,
             rx,
         });
 
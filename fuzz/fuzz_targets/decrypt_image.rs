@@ -0,0 +1,30 @@
+#![no_main]
+
+use std::io::Cursor;
+use std::sync::LazyLock;
+
+use citrust_core::cancel::CancelToken;
+use citrust_core::decrypt::decrypt_image;
+use citrust_core::inspect::{inspect, partition_status};
+use citrust_core::keydb::KeyDatabase;
+use citrust_core::repair::image_region_status;
+use libfuzzer_sys::fuzz_target;
+
+/// The keys the seed corpus was encrypted with (see `write_fuzz_corpus` in citrust-core).
+static KEYDB: LazyLock<KeyDatabase> = LazyLock::new(|| {
+    let keys = "\
+generator=FEDCBA9876543210FEDCBA9876543210
+slot0x2CKeyX=00000000000000000000000000000001
+slot0x25KeyX=0000000000000000000000000000FF02
+common0N=00112233445566778899AABBCCDDEEFF
+";
+    KeyDatabase::from_reader(Cursor::new(keys)).unwrap()
+});
+
+fuzz_target!(|data: &[u8]| {
+    let _ = inspect(&mut Cursor::new(data));
+    let _ = partition_status(&mut Cursor::new(data));
+    let _ = image_region_status(data, &KEYDB);
+    let mut image = data.to_vec();
+    let _ = decrypt_image(&mut image, &KEYDB, &CancelToken::new(), &mut |_| {});
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use citrust_core::keydb::KeyDatabase;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(keydb) = KeyDatabase::from_reader(data) {
+        let _ = keydb.generator();
+        for slot in [0x18, 0x1B, 0x25, 0x2C] {
+            let _ = keydb.get_key_x(slot);
+        }
+    }
+});
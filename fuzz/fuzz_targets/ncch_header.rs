@@ -0,0 +1,14 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use citrust_core::decrypt::is_content_decrypted;
+use citrust_core::ncch::NcchHeader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ncch) = NcchHeader::parse(&mut Cursor::new(data), 0) {
+        let _ = ncch.crypto_method();
+        is_content_decrypted(data, &ncch, ncch.media_unit_size(), 0);
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use citrust_core::ncsd::NcsdHeader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ncsd) = NcsdHeader::parse(&mut Cursor::new(data)) {
+        for part in &ncsd.partitions {
+            let _ = part.offset_bytes(ncsd.sector_size);
+            let _ = part.length_bytes(ncsd.sector_size);
+        }
+    }
+});